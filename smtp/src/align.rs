@@ -0,0 +1,139 @@
+//! Module implementing checking the alignment of the envelop and `From` header domains.
+use new_tokio_smtp::send_mail::MailAddress;
+
+use ::{
+    error::MailSendError,
+    trace::header_value
+};
+
+/// How the domain of the reverse path (`MAIL FROM`) has to align with the `From` header.
+///
+/// DMARC (RFC 7489) requires the domain used by SPF, which is the domain
+/// of the reverse path, to align with the domain of the `From` header.
+/// Sending a mail with a reverse path of another domain (e.g. because of
+/// a misconfigured bounce address) hurts its deliverability. Set it using
+/// `SendConfig::domain_alignment` to refuse sending such mails, they fail
+/// with `MailSendError::DomainMisaligned` instead.
+///
+/// Domains are compared case-insensitive. Mails without a `From` header
+/// or with the null reverse path (`MAIL FROM:<>`) are not checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DomainAlignment {
+    /// The domains have to be the same.
+    Strict,
+
+    /// The domains have to be the same or one has to be a subdomain of the other.
+    ///
+    /// E.g. `bounces.example.com` is aligned with `example.com`. This is
+    /// stricter than the relaxed mode of DMARC, which compares the
+    /// organizational domains (so `a.example.com` would be aligned with
+    /// `b.example.com`), as determining them requires the public suffix list.
+    Relaxed
+}
+
+impl DomainAlignment {
+
+    /// Returns true if the domains are aligned.
+    fn is_aligned(self, header_from: &str, envelop_from: &str) -> bool {
+        match self {
+            DomainAlignment::Strict => header_from == envelop_from,
+            DomainAlignment::Relaxed => {
+                is_same_or_subdomain(header_from, envelop_from)
+                    || is_same_or_subdomain(envelop_from, header_from)
+            }
+        }
+    }
+}
+
+fn is_same_or_subdomain(domain: &str, parent: &str) -> bool {
+    domain == parent
+        || (domain.ends_with(parent) && domain[..domain.len() - parent.len()].ends_with('.'))
+}
+
+/// Checks the alignment of the reverse path with the `From` header of the raw mail.
+pub(crate) fn check_alignment(raw_data: &[u8], from: Option<&MailAddress>, alignment: DomainAlignment)
+    -> Result<(), MailSendError>
+{
+    let envelop_from = match from.and_then(|from| domain_of(from.as_str())) {
+        Some(domain) => domain,
+        None => return Ok(())
+    };
+    let header_from = match header_value(raw_data, "From").and_then(|value| header_from_domain(&value)) {
+        Some(domain) => domain,
+        None => return Ok(())
+    };
+
+    if alignment.is_aligned(&header_from, &envelop_from) {
+        Ok(())
+    } else {
+        Err(MailSendError::DomainMisaligned { header_from, envelop_from })
+    }
+}
+
+/// Returns the domain of the first mailbox of a `From` header value.
+fn header_from_domain(value: &str) -> Option<String> {
+    let address = match value.find('<') {
+        Some(start) => {
+            let rest = &value[start + 1..];
+            &rest[..rest.find('>')?]
+        },
+        None => value.split(',').next()?
+    };
+    domain_of(address)
+}
+
+/// Returns the lowercase domain of the address.
+fn domain_of(address: &str) -> Option<String> {
+    let at = address.rfind('@')?;
+    let domain = address[at + 1..].trim().trim_end_matches('.');
+    if domain.is_empty() {
+        None
+    } else {
+        Some(domain.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod check_alignment {
+        use new_tokio_smtp::send_mail::MailAddress;
+        use ::error::MailSendError;
+        use super::super::{check_alignment, DomainAlignment};
+
+        const RAW: &[u8] = b"From: \"Some Name\" <news@Example.com>\r\nSubject: x\r\n\r\nbody\r\n";
+
+        fn check(reverse_path: &str, alignment: DomainAlignment) -> Result<(), MailSendError> {
+            let from = MailAddress::new_unchecked(reverse_path.to_owned(), false);
+            check_alignment(RAW, Some(&from), alignment)
+        }
+
+        #[test]
+        fn strict_requires_the_same_domain() {
+            assert!(check("bounce@example.com", DomainAlignment::Strict).is_ok());
+            assert!(check("bounce@bounces.example.com", DomainAlignment::Strict).is_err());
+        }
+
+        #[test]
+        fn relaxed_accepts_subdomains() {
+            assert!(check("bounce@bounces.example.com", DomainAlignment::Relaxed).is_ok());
+            assert!(check("bounce@notexample.com", DomainAlignment::Relaxed).is_err());
+        }
+
+        #[test]
+        fn error_names_both_domains() {
+            match check("bounce@mailer.test", DomainAlignment::Relaxed) {
+                Err(MailSendError::DomainMisaligned { header_from, envelop_from }) => {
+                    assert_eq!(header_from, "example.com");
+                    assert_eq!(envelop_from, "mailer.test");
+                },
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn null_reverse_path_is_not_checked() {
+            assert!(check_alignment(RAW, None, DomainAlignment::Strict).is_ok());
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Module implementing choosing the auth mechanism based on the servers EHLO response.
+use failure::Fail;
+use futures::future::{self, Future};
+use base64;
+use md5;
+
+use new_tokio_smtp::{
+    Cmd, Io, ExecFuture, EhloData,
+    command::auth::{Plain, Login},
+    error::{LogicError, MissingCapabilities}
+};
+
+use ::{
+    error::AuthSelectionError,
+    reply::check_response
+};
+
+/// Creates an auth command choosing the mechanism once connected.
+///
+/// See `AutoAuth` for how the mechanism is chosen.
+pub fn auto<U, P>(username: U, password: P) -> AutoAuth
+    where U: Into<String>, P: Into<String>
+{
+    AutoAuth { username: username.into(), password: password.into() }
+}
+
+/// Auth command using the strongest mechanism announced by the server.
+///
+/// After `EHLO` the mechanisms announced with the `AUTH` keyword are
+/// inspected and the first usable one in the following order is used:
+///
+/// 1. `CRAM-MD5`, as the password is never send
+/// 2. `PLAIN`, only if the connection is encrypted (TLS)
+/// 3. `LOGIN`, only if the connection is encrypted (TLS)
+///
+/// If no mechanism is usable (e.g. the server only announces `PLAIN` on an
+/// unencrypted connection) setting up the connection fails without sending
+/// any credentials. The announced mechanisms can be inspected using
+/// `ConnectionReport::capability_params("AUTH")`.
+#[derive(Debug, Clone)]
+pub struct AutoAuth {
+    username: String,
+    password: String
+}
+
+/// The auth mechanisms `AutoAuth` can choose from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMechanism {
+    /// `AUTH CRAM-MD5` (RFC 2195).
+    CramMd5,
+
+    /// `AUTH PLAIN` (RFC 4616).
+    Plain,
+
+    /// `AUTH LOGIN` (non-standard, but widely supported).
+    Login
+}
+
+impl AuthMechanism {
+
+    /// The name of the mechanism as announced by servers, e.g. `CRAM-MD5`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuthMechanism::CramMd5 => "CRAM-MD5",
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::Login => "LOGIN"
+        }
+    }
+
+    /// Returns true if the mechanism sends the password (encoded but not encrypted).
+    fn sends_password(&self) -> bool {
+        *self != AuthMechanism::CramMd5
+    }
+}
+
+/// Chooses the mechanism to use given the announced mechanisms.
+pub(crate) fn select_mechanism<'a, I>(announced: I, is_secure: bool)
+    -> Result<AuthMechanism, AuthSelectionError>
+    where I: IntoIterator<Item=&'a str>
+{
+    let announced = announced.into_iter()
+        .map(|name| name.to_uppercase())
+        .collect::<Vec<_>>();
+
+    let mut insecure = None;
+    for &mechanism in &[AuthMechanism::CramMd5, AuthMechanism::Plain, AuthMechanism::Login] {
+        if !announced.iter().any(|name| name == mechanism.name()) {
+            continue;
+        }
+        if mechanism.sends_password() && !is_secure {
+            insecure = insecure.or(Some(mechanism));
+            continue;
+        }
+        return Ok(mechanism);
+    }
+
+    match insecure {
+        Some(mechanism) => Err(AuthSelectionError::Unencrypted(mechanism.name())),
+        None => Err(AuthSelectionError::NoSupportedMechanism)
+    }
+}
+
+impl Cmd for AutoAuth {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        // the mechanism (and if there is a usable one) is only known in `exec`
+        Ok(())
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        let selected = {
+            let announced = io.ehlo_data()
+                .and_then(|ehlo_data| ehlo_data.get_capability_params("AUTH"))
+                .map(|params| params.iter().map(|param| param.as_str()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            select_mechanism(announced, io.is_secure())
+        };
+
+        let AutoAuth { username, password } = self;
+        match selected {
+            Ok(AuthMechanism::CramMd5) => CramMd5 { username, password }.exec(io),
+            Ok(AuthMechanism::Plain) => match Plain::from_username(username, password) {
+                Ok(cmd) => cmd.exec(io),
+                Err(_) => failed(io, AuthSelectionError::InvalidCredentials)
+            },
+            Ok(AuthMechanism::Login) => Login::new(username, password).exec(io),
+            Err(err) => failed(io, err)
+        }
+    }
+}
+
+fn failed(io: Io, err: AuthSelectionError) -> ExecFuture {
+    Box::new(future::ok((io, Err(LogicError::Custom(Box::new(err.compat()))))))
+}
+
+/// `AUTH CRAM-MD5` (RFC 2195).
+struct CramMd5 {
+    username: String,
+    password: String
+}
+
+impl Cmd for CramMd5 {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        let CramMd5 { username, password } = self;
+        io.write_line_from_parts(&["AUTH CRAM-MD5"]);
+        let fut = io.flush()
+            .and_then(Io::parse_response)
+            .and_then(move |(mut io, response)| -> ExecFuture {
+                let challenge = match check_response(response, 3) {
+                    Ok(response) => response.msg().first().cloned().unwrap_or_default(),
+                    Err(err) => return Box::new(future::ok((io, Err(err))))
+                };
+                let challenge = match base64::decode(challenge.trim()) {
+                    Ok(challenge) => challenge,
+                    Err(_) => return failed(io, AuthSelectionError::InvalidChallenge)
+                };
+
+                let answer = cram_md5_answer(&username, &password, &challenge);
+                io.write_line_from_parts(&[answer.as_str()]);
+                let fut = io.flush()
+                    .and_then(Io::parse_response)
+                    .map(|(io, response)| (io, check_response(response, 2)));
+                Box::new(fut)
+            });
+
+        Box::new(fut)
+    }
+}
+
+/// Returns the (base64 encoded) answer to the challenge.
+fn cram_md5_answer(username: &str, password: &str, challenge: &[u8]) -> String {
+    let digest = hmac_md5(password.as_bytes(), challenge);
+    let hex_digest = digest.iter()
+        .map(|bch| format!("{:02x}", bch))
+        .collect::<String>();
+    base64::encode(&format!("{} {}", username, hex_digest))
+}
+
+/// HMAC (RFC 2104) using MD5 as hash function.
+fn hmac_md5(key: &[u8], message: &[u8]) -> [u8; 16] {
+    const BLOCK_SIZE: usize = 64;
+    let mut key_block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        key_block[..16].copy_from_slice(&md5::compute(key).0);
+    } else {
+        key_block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = key_block.iter().map(|bch| bch ^ 0x36).collect::<Vec<_>>();
+    inner.extend_from_slice(message);
+    let mut outer = key_block.iter().map(|bch| bch ^ 0x5c).collect::<Vec<_>>();
+    outer.extend_from_slice(&md5::compute(&inner).0);
+    md5::compute(&outer).0
+}
+
+#[cfg(test)]
+mod test {
+
+    mod select_mechanism {
+        use ::error::AuthSelectionError;
+        use super::super::{select_mechanism, AuthMechanism};
+
+        #[test]
+        fn prefers_cram_md5() {
+            let announced = vec!["LOGIN", "cram-md5", "PLAIN"];
+            assert_eq!(select_mechanism(announced.clone(), true).unwrap(), AuthMechanism::CramMd5);
+            assert_eq!(select_mechanism(announced, false).unwrap(), AuthMechanism::CramMd5);
+        }
+
+        #[test]
+        fn uses_login_if_it_is_the_only_mechanism() {
+            assert_eq!(select_mechanism(vec!["LOGIN"], true).unwrap(), AuthMechanism::Login);
+        }
+
+        #[test]
+        fn prefers_plain_over_login() {
+            assert_eq!(select_mechanism(vec!["LOGIN", "PLAIN"], true).unwrap(), AuthMechanism::Plain);
+        }
+
+        #[test]
+        fn refuses_to_send_passwords_unencrypted() {
+            match select_mechanism(vec!["LOGIN", "PLAIN"], false) {
+                Err(AuthSelectionError::Unencrypted("PLAIN")) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn fails_without_supported_mechanism() {
+            match select_mechanism(vec!["XOAUTH2", "GSSAPI"], true) {
+                Err(AuthSelectionError::NoSupportedMechanism) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    mod auto_auth {
+        use futures::Future;
+        use new_tokio_smtp::{ClientId, Domain, command::Ehlo};
+        use ::test_utils::{FakeServer, Reply, run};
+        use super::super::{auto, cram_md5_answer};
+
+        #[test]
+        fn computes_the_cram_md5_answer() {
+            // example from RFC 2195
+            let answer = cram_md5_answer("tim", "tanstaaftanstaaf", b"<1896.697170952@postoffice.reston.mci.net>");
+            assert_eq!(answer, "dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw");
+        }
+
+        #[test]
+        fn authenticates_using_cram_md5_if_announced() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-mx.test.test greets you\r\n250 AUTH LOGIN CRAM-MD5\r\n"),
+                Reply::Lines("334 PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2UucmVzdG9uLm1jaS5uZXQ+\r\n"),
+                Reply::Lines("235 2.7.0 Authentication successful\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let fut = server.connection()
+                .send(Ehlo::new(client_id))
+                .and_then(|(con, result)| {
+                    result.unwrap();
+                    con.send(auto("tim", "tanstaaftanstaaf"))
+                });
+
+            let (_con, result) = run(fut).unwrap();
+            result.unwrap();
+            assert!(server.written().ends_with(
+                "AUTH CRAM-MD5\r\ndGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw\r\n"
+            ));
+        }
+
+        #[test]
+        fn does_not_send_credentials_if_only_login_is_announced_unencrypted() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-mx.test.test greets you\r\n250 AUTH LOGIN\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let fut = server.connection()
+                .send(Ehlo::new(client_id))
+                .and_then(|(con, result)| {
+                    result.unwrap();
+                    con.send(auto("tim", "tanstaaftanstaaf"))
+                });
+
+            let (_con, result) = run(fut).unwrap();
+            assert!(result.is_err());
+            assert!(!server.written().contains("AUTH"));
+        }
+    }
+}
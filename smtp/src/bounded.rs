@@ -0,0 +1,154 @@
+//! Module implementing reading server responses within the `ResponseLimits`.
+use std::{
+    error::Error,
+    fmt,
+    io as std_io
+};
+
+use futures::{Future, Poll, Async};
+use tokio::io::AsyncRead;
+use new_tokio_smtp::{Io, Response};
+
+use ::{
+    config::ResponseLimits,
+    error::MailSendError
+};
+
+/// Reads the next response, failing as soon as it exceeds the limits.
+///
+/// The response is read into the input buffer of the `Io` until it's
+/// complete and then parsed using `Io::parse_response`. Reading stops once
+/// the lines or bytes received so far exceed the limits, so a server sending
+/// an endless response can't make the buffer grow without bound.
+///
+/// Exceeding the limits fails with an I/O error of the kind `InvalidData`,
+/// which `MailSendError::from` turns into `MailSendError::ResponseTooLarge`.
+pub(crate) fn read_response(io: Io, limits: ResponseLimits)
+    -> impl Future<Item=(Io, Response), Error=std_io::Error>
+{
+    ReadComplete { io: Some(io), limits }.and_then(Io::parse_response)
+}
+
+/// Future returned by `read_response` resolving once a complete response is buffered.
+struct ReadComplete {
+    io: Option<Io>,
+    limits: ResponseLimits
+}
+
+impl Future for ReadComplete {
+    type Item = Io;
+    type Error = std_io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            let io = self.io.as_mut().expect("[BUG] polled ReadComplete after completion");
+            if is_complete(io.in_buffer(), self.limits)? {
+                break;
+            }
+            let read = match io.socket_mut().poll_read(&mut chunk)? {
+                Async::Ready(read) => read,
+                Async::NotReady => return Ok(Async::NotReady)
+            };
+            if read == 0 {
+                let msg = "connection closed while reading the response";
+                return Err(std_io::Error::new(std_io::ErrorKind::UnexpectedEof, msg));
+            }
+            io.in_buffer().extend_from_slice(&chunk[..read]);
+        }
+        Ok(Async::Ready(self.io.take().expect("[BUG] polled ReadComplete after completion")))
+    }
+}
+
+/// Returns true if the buffer starts with a complete response.
+///
+/// Fails if the (possibly incomplete) response exceeds the limits.
+fn is_complete(buffer: &[u8], limits: ResponseLimits) -> Result<bool, std_io::Error> {
+    let mut lines = 0;
+    let mut bytes = 0;
+    while let Some(end) = buffer[bytes..].iter().position(|&bch| bch == b'\n') {
+        let line = &buffer[bytes..bytes + end + 1];
+        lines += 1;
+        bytes += end + 1;
+        check_limits(lines, bytes, limits)?;
+        // all lines but the last one have a `-` after the reply code
+        if line.get(3) != Some(&b'-') {
+            return Ok(true);
+        }
+    }
+    check_limits(lines, buffer.len(), limits)?;
+    Ok(false)
+}
+
+fn check_limits(lines: usize, bytes: usize, limits: ResponseLimits) -> Result<(), std_io::Error> {
+    if lines > limits.max_lines || bytes > limits.max_bytes {
+        let err = ResponseTooLarge { lines, bytes };
+        Err(std_io::Error::new(std_io::ErrorKind::InvalidData, err))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the `MailSendError::ResponseTooLarge` if reading a response failed because of the limits.
+pub(crate) fn response_too_large(err: &std_io::Error) -> Option<MailSendError> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<ResponseTooLarge>())
+        .map(|&ResponseTooLarge { lines, bytes }| MailSendError::ResponseTooLarge { lines, bytes })
+}
+
+/// Error used (wrapped in an I/O error) if a response exceeds the limits.
+#[derive(Debug)]
+struct ResponseTooLarge {
+    lines: usize,
+    bytes: usize
+}
+
+impl fmt::Display for ResponseTooLarge {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "server response too large (at least {} lines, {} bytes)", self.lines, self.bytes)
+    }
+}
+
+impl Error for ResponseTooLarge {
+    fn description(&self) -> &str {
+        "server response too large"
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod is_complete {
+        use ::config::ResponseLimits;
+        use super::super::{is_complete, response_too_large};
+
+        fn limits(max_lines: usize, max_bytes: usize) -> ResponseLimits {
+            ResponseLimits { max_lines, max_bytes }
+        }
+
+        #[test]
+        fn detects_the_last_line() {
+            let limits = ResponseLimits::default();
+            assert!(!is_complete(b"250-one\r\n250-tw", limits).unwrap());
+            assert!(is_complete(b"250-one\r\n250 two\r\n", limits).unwrap());
+            // only the first of multiple (pipelined) responses matters
+            assert!(is_complete(b"250 one\r\n354-go", limits).unwrap());
+        }
+
+        #[test]
+        fn fails_once_the_lines_exceed_the_limit() {
+            assert!(!is_complete(b"250-a\r\n250-b\r\n250-c", limits(2, 1024)).unwrap());
+            let err = is_complete(b"250-a\r\n250-b\r\n250-c\r\n", limits(2, 1024)).unwrap_err();
+            match response_too_large(&err) {
+                Some(::error::MailSendError::ResponseTooLarge { lines: 3, bytes: 21 }) => {},
+                other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn fails_on_an_incomplete_line_exceeding_the_bytes() {
+            let err = is_complete(b"250-aaaaaaaaaaaaaaaaaaaa", limits(10, 16)).unwrap_err();
+            assert!(response_too_large(&err).is_some());
+        }
+    }
+}
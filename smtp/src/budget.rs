@@ -0,0 +1,333 @@
+//! Module implementing limiting the size of encoded mails which wait to be send.
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::{Stream, Future, Poll, Async, task::{self, Task}};
+
+/// Limits the total size of mails which are encoded but not yet send.
+///
+/// See `SendConfig::max_in_flight_bytes`. Clones share the same state.
+#[derive(Debug, Clone)]
+pub(crate) struct ByteBudget {
+    limit: usize,
+    state: Arc<Mutex<BudgetState>>
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    /// The number of mails which are being encoded.
+    encoding: usize,
+    /// The number of mails which are encoded but not yet send.
+    mails: usize,
+    /// The total size of the mails which are encoded but not yet send.
+    bytes: usize,
+    /// The number of mails encoded so far, used to estimate the size of the next ones.
+    encoded_mails: usize,
+    /// The total size of the mails encoded so far.
+    encoded_bytes: usize,
+    /// The task waiting for the budget to allow starting another encoding.
+    waiting: Option<Task>
+}
+
+impl BudgetState {
+
+    /// Returns true if encoding another mail can be started.
+    ///
+    /// This is the case if the encoded mails, the mails being encoded and
+    /// the new one fit into the budget. The size of a mail being encoded is
+    /// estimated as the average size of the mails encoded so far, so
+    /// before the first mail is encoded only one mail is encoded at once.
+    ///
+    /// If no mail is being encoded and at most one mail is in flight (e.g.
+    /// the one being send) a mail can always be encoded, so that mails
+    /// larger than the limit are still send and a pipelined mail, which
+    /// is only finished once the next mail is send, can't block it.
+    fn can_start(&self, limit: usize) -> bool {
+        if self.encoding == 0 && self.mails <= 1 {
+            return true;
+        }
+        if self.encoded_mails == 0 {
+            return false;
+        }
+        let estimate = self.encoded_bytes / self.encoded_mails;
+        self.bytes + (self.encoding + 1) * estimate <= limit
+    }
+
+    /// Notifies the waiting task (if any), as the budget changed.
+    fn notify(&mut self) {
+        if let Some(task) = self.waiting.take() {
+            task.notify();
+        }
+    }
+}
+
+impl ByteBudget {
+
+    pub(crate) fn new(limit: usize) -> Self {
+        ByteBudget { limit, state: Default::default() }
+    }
+
+    /// Returns true if encoding another mail can be started.
+    ///
+    /// If not, the current task is notified once the budget changed.
+    fn poll_can_start(&self) -> bool {
+        let mut state = self.lock();
+        if state.can_start(self.limit) {
+            true
+        } else {
+            state.waiting = Some(task::current());
+            false
+        }
+    }
+
+    /// Marks an encoding as started, see `Encoding`.
+    fn start(&self) -> Encoding {
+        self.lock().encoding += 1;
+        Encoding { budget: Some(self.clone()) }
+    }
+
+    /// The total size of the mails which are encoded but not yet send.
+    #[cfg(test)]
+    pub(crate) fn in_flight_bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    fn release(&self, size: usize) {
+        let mut state = self.lock();
+        state.mails -= 1;
+        state.bytes -= size;
+        state.notify();
+    }
+
+    fn lock(&self) -> MutexGuard<BudgetState> {
+        self.state.lock().expect("[BUG] byte budget panicked")
+    }
+}
+
+/// A started encoding counted against a `ByteBudget`.
+///
+/// Once the mail is encoded it's turned into the `InFlight` size of the
+/// mail using `encoded`. If it's dropped before (e.g. because the encoding
+/// failed or was dropped) the encoding no longer counts against the budget.
+#[derive(Debug)]
+pub(crate) struct Encoding {
+    budget: Option<ByteBudget>
+}
+
+impl Encoding {
+
+    /// Marks the encoding as done, adding the size of the encoded mail.
+    ///
+    /// The size is released once the returned `InFlight` is dropped.
+    pub(crate) fn encoded(mut self, size: usize) -> InFlight {
+        let budget = self.budget.take().expect("[BUG] encoding is only finished once");
+        {
+            let mut state = budget.lock();
+            state.encoding -= 1;
+            state.mails += 1;
+            state.bytes += size;
+            state.encoded_mails += 1;
+            state.encoded_bytes += size;
+            state.notify();
+        }
+        InFlight { budget, size }
+    }
+}
+
+impl Drop for Encoding {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget.take() {
+            let mut state = budget.lock();
+            state.encoding -= 1;
+            state.notify();
+        }
+    }
+}
+
+/// The size of an encoded mail counted against a `ByteBudget`.
+///
+/// It's kept (shared by clones of the `OutgoingMail`) until the mail is
+/// send and released once dropped.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    budget: ByteBudget,
+    size: usize
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.budget.release(self.size);
+    }
+}
+
+/// Only yields the next (encoding) future of the stream if the budget allows starting it.
+///
+/// Each future is yielded together with the `Encoding` it's counted as,
+/// which has to be turned into the `InFlight` size of the mail once it's
+/// encoded. If the budget doesn't allow starting another encoding the
+/// task is notified once an encoding finished or a mail was send.
+pub(crate) fn gated<S>(stream: S, budget: ByteBudget) -> Gated<S>
+    where S: Stream, S::Item: Future
+{
+    Gated { stream, budget }
+}
+
+/// Stream returned by `gated`.
+pub(crate) struct Gated<S> {
+    stream: S,
+    budget: ByteBudget
+}
+
+impl<S> Stream for Gated<S>
+    where S: Stream, S::Item: Future
+{
+    type Item = (Encoding, S::Item);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.budget.poll_can_start() {
+            return Ok(Async::NotReady);
+        }
+        match self.stream.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(item.map(|item| (self.budget.start(), item)))),
+            Async::NotReady => Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod gated {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering}
+        };
+        use futures::{
+            Async, Future, Stream, stream, future,
+            executor::{self, Notify, NotifyHandle}
+        };
+        use super::super::{ByteBudget, Encoding, InFlight, gated};
+
+        /// Counts how often the task was notified.
+        #[derive(Default)]
+        struct CountingNotify(AtomicUsize);
+
+        impl Notify for CountingNotify {
+            fn notify(&self, _id: usize) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn encodings(started: &Arc<AtomicUsize>)
+            -> impl Stream<Item=impl Future<Item=usize, Error=()>, Error=()>
+        {
+            let started = started.clone();
+            stream::iter_ok::<_, ()>(0..10)
+                .map(move |_| {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    future::lazy(|| Ok::<_, ()>(100))
+                })
+        }
+
+        fn encoded((encoding, size): (Encoding, impl Future<Item=usize, Error=()>))
+            -> impl Future<Item=InFlight, Error=()>
+        {
+            size.map(move |size| encoding.encoded(size))
+        }
+
+        #[test]
+        fn stops_encoding_while_the_budget_is_used_up() {
+            let budget = ByteBudget::new(300);
+            let started = Arc::new(AtomicUsize::new(0));
+            let mut mails = executor::spawn(gated(encodings(&started), budget.clone()).map(encoded).buffered(10));
+            let notify = NotifyHandle::from(Arc::new(CountingNotify::default()));
+            let mut poll = || match mails.poll_stream_notify(&notify, 0).unwrap() {
+                Async::Ready(Some(in_flight)) => Some(in_flight),
+                Async::Ready(None) => panic!("unexpected end of the stream"),
+                Async::NotReady => None
+            };
+
+            let mut in_flight = (0..3).map(|_| poll().unwrap()).collect::<Vec<_>>();
+            // 300 bytes are encoded but not send, which uses up the budget
+            assert!(poll().is_none());
+            assert_eq!(started.load(Ordering::SeqCst), 3);
+            assert_eq!(budget.in_flight_bytes(), 300);
+
+            // the first mail was send
+            in_flight.remove(0);
+            in_flight.push(poll().unwrap());
+            assert_eq!(started.load(Ordering::SeqCst), 4);
+            assert_eq!(budget.in_flight_bytes(), 300);
+        }
+
+        #[test]
+        fn encodes_concurrently_once_the_size_of_the_mails_is_known() {
+            let budget = ByteBudget::new(600);
+            let started = Arc::new(AtomicUsize::new(0));
+            let mut gated = executor::spawn(gated(encodings(&started), budget.clone()));
+            let notify = NotifyHandle::from(Arc::new(CountingNotify::default()));
+            let mut poll = || gated.poll_stream_notify(&notify, 0).unwrap();
+
+            // the size of the first mail isn't known before it's encoded
+            let first = match poll() {
+                Async::Ready(Some(encoding)) => encoding,
+                _ => panic!("expected the first encoding")
+            };
+            assert!(poll().is_not_ready());
+            let first = encoded(first).wait().unwrap();
+
+            // with 100 bytes per mail the budget allows 5 more mails being encoded at once
+            let mut encodings = Vec::new();
+            while let Async::Ready(Some(encoding)) = poll() {
+                encodings.push(encoding);
+            }
+            assert_eq!(encodings.len(), 5);
+            assert_eq!(started.load(Ordering::SeqCst), 6);
+            drop(first);
+        }
+
+        #[test]
+        fn dropping_an_unfinished_encoding_releases_it() {
+            let budget = ByteBudget::new(300);
+            let started = Arc::new(AtomicUsize::new(0));
+            let mut gated = executor::spawn(gated(encodings(&started), budget.clone()));
+            let counter = Arc::new(CountingNotify::default());
+            let notify = NotifyHandle::from(counter.clone());
+            let mut poll = || gated.poll_stream_notify(&notify, 0).unwrap();
+
+            let first = match poll() {
+                Async::Ready(Some(encoding)) => encoding,
+                _ => panic!("expected the first encoding")
+            };
+            assert!(poll().is_not_ready());
+
+            // e.g. the encoding failed or was dropped by the executor
+            drop(first);
+            assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+            assert!(poll().is_ready());
+            assert_eq!(started.load(Ordering::SeqCst), 2);
+        }
+
+        #[test]
+        fn notifies_the_waiting_task_once_a_mail_was_send() {
+            let budget = ByteBudget::new(200);
+            let started = Arc::new(AtomicUsize::new(0));
+            let mut mails = executor::spawn(gated(encodings(&started), budget.clone()).map(encoded).buffered(10));
+            let counter = Arc::new(CountingNotify::default());
+            let notify = NotifyHandle::from(counter.clone());
+            let mut poll = || match mails.poll_stream_notify(&notify, 0).unwrap() {
+                Async::Ready(Some(in_flight)) => Some(in_flight),
+                Async::Ready(None) => panic!("unexpected end of the stream"),
+                Async::NotReady => None
+            };
+
+            let mut in_flight = (0..2).map(|_| poll().unwrap()).collect::<Vec<_>>();
+            assert!(poll().is_none());
+            let notified = counter.0.load(Ordering::SeqCst);
+
+            in_flight.remove(0);
+            assert_eq!(counter.0.load(Ordering::SeqCst), notified + 1);
+            assert!(poll().is_some());
+        }
+    }
+}
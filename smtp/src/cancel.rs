@@ -0,0 +1,164 @@
+//! Module implementing cancelling sends using a `CancelToken`.
+use std::{
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering}
+    }
+};
+
+use futures::{
+    Future, Poll, Async,
+    task::AtomicTask
+};
+
+use ::error::MailSendError;
+
+/// A token which allows the caller to cancel sending mails.
+///
+/// Set it in the `SendConfig` and call `cancel` to cancel all sends using
+/// the config (or a clone of the token). A mail whose sending is cancelled
+/// fails with `MailSendError::Cancelled`, mails which would be send after
+/// the token was cancelled (or while the connection is set up) fail with
+/// `MailSendError::CancelledBeforeSending`. If the token is cancelled while a
+/// mail is being send, the connection is dropped (without `QUIT`), as the
+/// state of the mail transaction is unknown.
+///
+/// Clones share the same state, cancelling one cancels all of them.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    waiting: Mutex<Vec<Arc<AtomicTask>>>
+}
+
+impl CancelToken {
+
+    /// Creates a new token which is not cancelled.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Cancels all sends using this token.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let waiting = {
+            let mut waiting = self.inner.waiting.lock().expect("[BUG] cancel token panicked");
+            ::std::mem::replace(&mut *waiting, Vec::new())
+        };
+        for task in waiting.iter() {
+            task.notify();
+        }
+    }
+
+    /// Returns true if the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Adds a task to notify once the token is cancelled.
+    fn add_waiting(&self, task: Arc<AtomicTask>) {
+        self.inner.waiting.lock().expect("[BUG] cancel token panicked").push(task);
+    }
+
+    fn remove_waiting(&self, task: &Arc<AtomicTask>) {
+        let mut waiting = self.inner.waiting.lock().expect("[BUG] cancel token panicked");
+        waiting.retain(|waiting| !Arc::ptr_eq(waiting, task));
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Wraps the future so that it fails with `MailSendError::Cancelled` once the token is cancelled.
+///
+/// The wrapped future is dropped when this happens.
+pub(crate) fn cancellable<F>(fut: F, token: Option<CancelToken>) -> Cancellable<F>
+    where F: Future<Error=MailSendError>
+{
+    let task = token.as_ref().map(|token| {
+        let task = Arc::new(AtomicTask::new());
+        token.add_waiting(task.clone());
+        task
+    });
+    Cancellable { fut, token, task }
+}
+
+/// Future returned by `cancellable`.
+pub(crate) struct Cancellable<F> {
+    fut: F,
+    token: Option<CancelToken>,
+    task: Option<Arc<AtomicTask>>
+}
+
+impl<F> Future for Cancellable<F>
+    where F: Future<Error=MailSendError>
+{
+    type Item = F::Item;
+    type Error = MailSendError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let (Some(token), Some(task)) = (self.token.as_ref(), self.task.as_ref()) {
+            task.register();
+            if token.is_cancelled() {
+                return Err(MailSendError::Cancelled);
+            }
+        }
+        match self.fut.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(item)),
+            Async::NotReady => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<F> Drop for Cancellable<F> {
+    fn drop(&mut self) {
+        if let (Some(token), Some(task)) = (self.token.as_ref(), self.task.as_ref()) {
+            token.remove_waiting(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod cancellable {
+        use std::{thread, time::Duration};
+        use futures::future;
+        use ::{
+            error::MailSendError,
+            test_utils::run
+        };
+        use super::super::{CancelToken, cancellable};
+
+        #[test]
+        fn fails_with_cancelled_once_cancelled() {
+            let token = CancelToken::new();
+            let fut = cancellable(future::empty::<(), MailSendError>(), Some(token.clone()));
+
+            let canceller = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            });
+
+            let err = run(fut).unwrap_err();
+            canceller.join().unwrap();
+            assert!(err.is_cancelled());
+        }
+
+        #[test]
+        fn does_nothing_if_not_cancelled() {
+            let fut = cancellable(future::ok::<_, MailSendError>(12), Some(CancelToken::new()));
+            assert_eq!(run(fut).unwrap(), 12);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Module providing `std::future::Future` based versions of the send functions.
+//!
+//! The functions in this module behave exactly like the functions with the
+//! same name in the crate root, but return `std::future::Future`s (and
+//! `futures` 0.3 `Stream`s) which can be used with `.await`.
+//!
+//! They are thin wrappers around the `futures` 0.1 based API, which means
+//! that they still need to be polled from within a tokio runtime providing
+//! the (0.1) reactor, see the crate level documentation about runtimes.
+//!
+//! To use them from another runtime (e.g. async-std) `BackgroundRuntime`
+//! runs them on a tokio runtime on background threads and forwards
+//! the results, which can be awaited from any executor.
+//!
+//! This module is only available with the `futures03` feature.
+use std::{
+    io as std_io,
+    future::Future as StdFuture
+};
+
+use futures::{
+    Async, Poll,
+    Future as Future01,
+    Stream as Stream01,
+    Sink as Sink01,
+    stream,
+    sync::{oneshot, mpsc}
+};
+
+use futures03::{
+    Stream as Stream03,
+    FutureExt,
+    compat::{Future01CompatExt, Stream01CompatExt}
+};
+
+use tokio::runtime::Runtime;
+
+use new_tokio_smtp::{
+    Cmd, Connection, ConnectionConfig, SetupTls,
+    send_mail::{MailEnvelop, EnvelopData}
+};
+use mail::Context;
+
+use ::{
+    config::SendConfig,
+    error::MailSendError,
+    request::MailRequest,
+    response::{MailResponse, BatchOutcome},
+    send_mail
+};
+
+/// `std::future::Future` version of `send`.
+pub fn send<A, S>(mail: MailRequest, conconf: ConnectionConfig<A, S>, ctx: impl Context)
+    -> impl StdFuture<Output=Result<MailResponse, MailSendError>>
+    where A: Cmd, S: SetupTls
+{
+    send_mail::send(mail, conconf, ctx).compat()
+}
+
+/// `std::future::Future` version of `send_with`.
+pub fn send_with<A, S>(
+    mail: MailRequest,
+    conconf: ConnectionConfig<A, S>,
+    ctx: impl Context,
+    config: SendConfig
+) -> impl StdFuture<Output=Result<MailResponse, MailSendError>>
+    where A: Cmd, S: SetupTls
+{
+    send_mail::send_with(mail, conconf, ctx, config).compat()
+}
+
+/// `futures` 0.3 `Stream` version of `send_batch`.
+pub fn send_batch<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C
+) -> impl Stream03<Item=Result<MailResponse, MailSendError>>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    send_mail::send_batch(mails, conconf, ctx).compat()
+}
+
+/// `futures` 0.3 `Stream` version of `send_batch_with`.
+pub fn send_batch_with<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream03<Item=Result<MailResponse, MailSendError>>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    send_mail::send_batch_with(mails, conconf, ctx, config).compat()
+}
+
+/// `futures` 0.3 `Stream` version of `send_batch_resumable`.
+pub fn send_batch_resumable<A, S, C>(
+    mails: Vec<MailRequest>,
+    skip_first: usize,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream03<Item=Result<BatchOutcome, MailSendError>>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    send_mail::send_batch_resumable(mails, skip_first, conconf, ctx, config).compat()
+}
+
+/// `futures` 0.3 `Stream` version of `send_batch_resilient`.
+pub fn send_batch_resilient<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream03<Item=Result<MailResponse, MailSendError>>
+    where A: Cmd + Clone, S: SetupTls + Clone, C: Context
+{
+    send_mail::send_batch_resilient(mails, conconf, ctx, config).compat()
+}
+
+/// `std::future::Future` version of `send_over`.
+pub fn send_over(con: Connection, mail: MailRequest, ctx: impl Context)
+    -> impl StdFuture<Output=Result<(Connection, Result<MailResponse, MailSendError>), MailSendError>>
+{
+    send_mail::send_over(con, mail, ctx).compat()
+}
+
+/// `std::future::Future` version of `send_over_with`.
+pub fn send_over_with(con: Connection, mail: MailRequest, ctx: impl Context, config: SendConfig)
+    -> impl StdFuture<Output=Result<(Connection, Result<MailResponse, MailSendError>), MailSendError>>
+{
+    send_mail::send_over_with(con, mail, ctx, config).compat()
+}
+
+/// `std::future::Future` version of `send_streamed`.
+///
+/// The body still has to be a `futures` 0.1 `Stream`.
+pub fn send_streamed<A, S, B>(
+    envelop_data: EnvelopData,
+    body: B,
+    conconf: ConnectionConfig<A, S>,
+    config: SendConfig
+) -> impl StdFuture<Output=Result<MailResponse, MailSendError>>
+    where A: Cmd, S: SetupTls, B: Stream01<Error=std_io::Error> + Send + 'static, B::Item: AsRef<[u8]>
+{
+    send_mail::send_streamed(envelop_data, body, conconf, config).compat()
+}
+
+/// `std::future::Future` version of `encode`.
+pub fn encode<C>(request: MailRequest, ctx: C)
+    -> impl StdFuture<Output=Result<MailEnvelop, MailSendError>>
+    where C: Context
+{
+    send_mail::encode(request, ctx).compat()
+}
+
+/// A tokio runtime driving the send functions for another runtime.
+///
+/// The futures and streams of this crate need tokio's (0.1) reactor, as
+/// `new-tokio-smtp` only supports tokio's TCP stream. This owns a tokio
+/// runtime (with its own background threads) and spawns them on it, the
+/// results are forwarded through channels which don't need a reactor.
+/// So the returned futures and streams can be awaited from any executor,
+/// e.g. the one of async-std:
+///
+/// ```no_run
+/// extern crate futures03;
+/// extern crate mail_core;
+/// extern crate mail_smtp;
+///
+/// use futures03::executor::block_on;
+/// use mail_core::{Mail, default_impl::simple_context};
+/// use mail_smtp::{self as smtp, ConnectionConfig, MailRequest, compat::BackgroundRuntime};
+///
+/// # fn main() {
+/// # let ctx = simple_context::new("example.com".parse().unwrap(), "asdkds".parse().unwrap()).unwrap();
+/// # let mail: Mail = unimplemented!();
+/// let runtime = BackgroundRuntime::new().unwrap();
+/// let con_config = ConnectionConfig::build_local_unencrypted().build();
+/// let sending = runtime.spawn(smtp::send(MailRequest::new(mail), con_config, ctx));
+/// // e.g. `async_std::task::block_on` or `.await` within an async-std task
+/// let response = block_on(sending);
+/// # }
+/// ```
+///
+/// The connections are still opened (and the mails encoded) on the tokio
+/// runtime, only the results are passed to the other runtime. Dropping the
+/// `BackgroundRuntime` shuts the runtime down, which drops all sends which
+/// are not done yet.
+pub struct BackgroundRuntime {
+    runtime: Runtime
+}
+
+impl BackgroundRuntime {
+
+    /// Starts a new tokio runtime.
+    pub fn new() -> Result<Self, std_io::Error> {
+        Ok(BackgroundRuntime { runtime: Runtime::new()? })
+    }
+
+    /// Runs the (`futures` 0.1) future on the runtime, returning a `std::future::Future` of its result.
+    ///
+    /// If the runtime is shut down before the future is done the result is
+    /// an I/O error.
+    pub fn spawn<F>(&self, fut: F) -> impl StdFuture<Output=Result<F::Item, MailSendError>>
+        where F: Future01<Error=MailSendError> + Send + 'static, F::Item: Send + 'static
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.runtime.executor().spawn(fut.then(move |result| {
+            let _ = sender.send(result);
+            Ok(())
+        }));
+
+        receiver.compat().map(|result| match result {
+            Ok(result) => result,
+            Err(_canceled) => Err(MailSendError::Io(shut_down()))
+        })
+    }
+
+    /// Runs the (`futures` 0.1) stream on the runtime, returning a `futures` 0.3 `Stream` of its results.
+    ///
+    /// At most one result is buffered, so like with the stream itself
+    /// a slow consumer of the results applies backpressure to the
+    /// sending. If the runtime is shut down before the stream is done
+    /// the last result is an I/O error.
+    pub fn spawn_stream<S>(&self, source: S) -> impl Stream03<Item=Result<S::Item, MailSendError>>
+        where S: Stream01<Error=MailSendError> + Send + 'static, S::Item: Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel(0);
+        // `None` marks the end of the stream, so that it can be told apart from the runtime shutting down
+        let results = source
+            .then(|result| Ok::<_, mpsc::SendError<_>>(Some(result)))
+            .chain(stream::once(Ok(None)));
+        self.runtime.executor().spawn(sender.send_all(results).then(|_| Ok(())));
+
+        Forwarded { receiver, done: false }.compat()
+    }
+}
+
+/// Stream of the results forwarded by `BackgroundRuntime::spawn_stream`.
+struct Forwarded<T> {
+    receiver: mpsc::Receiver<Option<Result<T, MailSendError>>>,
+    done: bool
+}
+
+impl<T> Stream01 for Forwarded<T> {
+    type Item = T;
+    type Error = MailSendError;
+
+    fn poll(&mut self) -> Poll<Option<T>, MailSendError> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match self.receiver.poll() {
+            Ok(Async::Ready(Some(Some(result)))) => result.map(|item| Async::Ready(Some(item))),
+            Ok(Async::Ready(Some(None))) => {
+                self.done = true;
+                Ok(Async::Ready(None))
+            },
+            Ok(Async::Ready(None)) => {
+                self.done = true;
+                Err(MailSendError::Io(shut_down()))
+            },
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => unreachable!("[BUG] mpsc receivers never fail")
+        }
+    }
+}
+
+fn shut_down() -> std_io::Error {
+    std_io::Error::new(std_io::ErrorKind::Other, "the background runtime was shut down")
+}
+
+#[cfg(test)]
+mod test {
+
+    mod background_runtime {
+        use futures03::{StreamExt, executor::block_on};
+        use ::{
+            request::MailRequest,
+            send_mail,
+            test_utils::{con_config, simple_mail, spawn_smtp_server, test_context}
+        };
+        use super::super::BackgroundRuntime;
+
+        #[test]
+        fn forwards_the_result_of_a_future() {
+            let runtime = BackgroundRuntime::new().unwrap();
+            let mail = MailRequest::new(simple_mail("a@test.test"));
+            let sending = send_mail::send(mail, con_config(spawn_smtp_server()), test_context());
+
+            // not polled from within a tokio runtime
+            block_on(runtime.spawn(sending)).unwrap();
+        }
+
+        #[test]
+        fn forwards_all_results_of_a_stream() {
+            let runtime = BackgroundRuntime::new().unwrap();
+            let mails = vec![
+                MailRequest::new(simple_mail("a@test.test")),
+                MailRequest::new(simple_mail("b@test.test"))
+            ];
+            let sending = send_mail::send_batch(mails, con_config(spawn_smtp_server()), test_context());
+
+            let results = block_on(runtime.spawn_stream(sending).collect::<Vec<_>>());
+            assert_eq!(results.len(), 2);
+            for result in results {
+                result.unwrap();
+            }
+        }
+
+        #[test]
+        fn fails_if_the_runtime_is_shut_down() {
+            let runtime = BackgroundRuntime::new().unwrap();
+            let sending = runtime.spawn(::futures::future::empty::<(), _>());
+            drop(runtime);
+
+            assert!(block_on(sending).is_err());
+        }
+    }
+}
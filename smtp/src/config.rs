@@ -0,0 +1,1200 @@
+//! Module containing the configuration of the send path.
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant}
+};
+
+use new_tokio_smtp::{BoxedCmd, ClientId, Response, send_mail::MailAddress};
+
+use ::{
+    cancel::CancelToken,
+    credentials::CredentialProvider,
+    error::MailSendError,
+    align::DomainAlignment,
+    rewrite::{RecipientRewriter, RedirectPolicy},
+    tls_pin::TlsHostCache
+};
+
+/// Configuration used by `send_with` and `send_batch_with`.
+///
+/// The default configuration is used by `send` and `send_batch`.
+#[derive(Clone, Debug, Default)]
+pub struct SendConfig {
+    /// Timeouts for the different phases of sending mails.
+    pub timeouts: Timeouts,
+
+    /// The client id send with `EHLO`, used instead of the one of the `ConnectionConfig`.
+    ///
+    /// If `None` (the default) the `client_id` of the connection config
+    /// is used. `Context` doesn't expose it's domain, so to use the domain
+    /// a context was created with (e.g. the one passed to `simple_context::new`)
+    /// set it here, too. This allows using it with connection configs not
+    /// created by the caller, e.g. the ones of `SmtpUrl::connection_config`.
+    pub client_id: Option<ClientId>,
+
+    /// The local address the outgoing TCP connection is bound to.
+    ///
+    /// This allows choosing the source IP used to connect to the server
+    /// (e.g. if the host has multiple IPs with different reputation).
+    /// The local port is always chosen by the OS.
+    ///
+    /// If binding fails, setting up the connection fails with
+    /// `MailSendError::Connecting`.
+    ///
+    /// The local address is only used for server addresses of the same
+    /// address family (IPv4 or IPv6): Connecting to a server address of
+    /// the other family fails with an I/O error of the kind `InvalidInput`
+    /// instead of falling back to an unbound socket, so mails never egress
+    /// from an unexpected source IP. If there are `additional_addrs` the
+    /// next address is tried as with any other failed connection attempt.
+    pub local_addr: Option<IpAddr>,
+
+    /// Additional addresses of the server.
+    ///
+    /// If the server has multiple addresses (e.g. a IPv4 and a IPv6
+    /// address) they can be given here. They are used if connecting
+    /// to the address in the `ConnectionConfig` fails, or are raced
+    /// against it if `happy_eyeballs` is enabled.
+    pub additional_addrs: Vec<SocketAddr>,
+
+    /// The host name of the server, resolved each time a connection is opened.
+    ///
+    /// If set, the host name is resolved (on a background thread, so that
+    /// the lookup doesn't block the executor) before each connection is
+    /// opened. All resolved addresses are tried like the `additional_addrs`
+    /// (which are tried after them), using the port of the address in the
+    /// `ConnectionConfig`, whose IP is ignored. If resolving fails, setting
+    /// up the connection fails with `MailSendError::Connecting`.
+    ///
+    /// This is set by `SmtpUrl::connection_config` for urls with a host name.
+    pub resolve_host: Option<String>,
+
+    /// Enables Happy Eyeballs (RFC 8305) with the given connection attempt delay.
+    ///
+    /// If enabled, the addresses of the server are ordered so that IPv4 and
+    /// IPv6 addresses alternate and a new connection attempt is started each
+    /// time the delay elapsed without an attempt succeeding (or immediately
+    /// if an attempt failed). The first attempt to succeed is used.
+    ///
+    /// If disabled (the default) the addresses are tried one after another.
+    ///
+    /// RFC 8305 recommends a delay of 250ms.
+    pub happy_eyeballs: Option<Duration>,
+
+    /// Which address family (IPv4/IPv6) to use when connecting.
+    ///
+    /// See `AddressFamilyPreference` for more details.
+    pub address_family: AddressFamilyPreference,
+
+    /// Provides the credentials used to authenticate each new connection.
+    ///
+    /// If set it's used instead of the `auth_cmd` of the `ConnectionConfig`.
+    /// See `auth::CredentialProvider` for more details.
+    pub credential_provider: Option<Arc<CredentialProvider>>,
+
+    /// Use `STARTTLS` opportunistically (default `false`).
+    ///
+    /// By default `Security::StartTls` is mandatory, i.e. setting up the
+    /// connection fails if the server doesn't announce `STARTTLS`. If this
+    /// is set such connections continue unencrypted instead, which allows
+    /// an attacker to strip `STARTTLS` from the `EHLO` response. Use a
+    /// `tls_host_cache` to limit this to hosts which never supported it.
+    pub opportunistic_starttls: bool,
+
+    /// Remembers the hosts which supported `STARTTLS` with `opportunistic_starttls`.
+    ///
+    /// Connections to a host in the cache which doesn't announce `STARTTLS`
+    /// fail with `MailSendError::StartTlsDowngrade` instead of continuing
+    /// unencrypted. It has no effect unless `opportunistic_starttls` is set.
+    /// See `TlsHostCache` for more details.
+    pub tls_host_cache: Option<Arc<TlsHostCache>>,
+
+    /// Commands to run on each new connection after authenticating.
+    ///
+    /// See `PostAuthCmds` for more details.
+    pub post_auth_cmds: Option<PostAuthCmds>,
+
+    /// How often to retry connecting if the server doesn't send a greeting in time.
+    ///
+    /// If the `greeting` timeout elapses, the connection is closed and a new
+    /// connection is opened, up to this many times (default `0`). If the last
+    /// attempt doesn't receive a greeting either, sending fails with
+    /// `MailSendError::Timeout { phase: TimeoutPhase::Greeting }`.
+    ///
+    /// All attempts together are still limited by the `connect` timeout.
+    pub greeting_retries: u32,
+
+    /// The maximal number of recipients used in a single mail transaction.
+    ///
+    /// Mails with more recipients are send using multiple transactions
+    /// (`MAIL`, `RCPT`s, `DATA`) each with at most this many recipients
+    /// and the same mail body. The `recipient_codes` of the `MailResponse`
+    /// contain the codes of all recipients in order. Use it for servers
+    /// rejecting transactions with too many recipients (e.g. with
+    /// `452 Too many recipients`) without announcing their limit.
+    ///
+    /// If the server announces a limit using the `LIMITS` extension
+    /// (`RCPTMAX`) it is used, too, i.e. the smaller one of both limits
+    /// applies. If this is `None` (the default) only the limit announced
+    /// by the server applies.
+    pub max_recipients_per_transaction: Option<usize>,
+
+    /// How rejected recipients are handled, see `RecipientPolicy`.
+    pub recipient_policy: RecipientPolicy,
+
+    /// Called after each mail of a batch which was send successfully.
+    ///
+    /// See `Checkpoint` for more details.
+    pub checkpoint: Option<Checkpoint>,
+
+    /// Limits how often mails are retried by `send_batch_resilient`.
+    ///
+    /// See `RetryBudget` for more details. Without a budget (the default)
+    /// each mail answered with `421` is retried once.
+    pub retry_budget: Option<RetryBudget>,
+
+    /// Allows cancelling the send, see `CancelToken`.
+    pub cancel_token: Option<CancelToken>,
+
+    /// What `send_batch_resilient` does if the server closes the connection using `421`.
+    ///
+    /// See `ServiceClosingPolicy` for more details. The other batch functions
+    /// can't reconnect, so they always behave like `FailRemaining`.
+    pub service_closing: ServiceClosingPolicy,
+
+    /// Closes the connection and opens a new one after this many mails.
+    ///
+    /// Some providers throttle connections after a number of mails, this
+    /// allows proactively sending `QUIT` and reconnecting (including auth)
+    /// before this happens. The results are still returned in the order
+    /// of the mails. Mails which failed to encode don't count.
+    ///
+    /// This is used by the functions which can reconnect, i.e.
+    /// `send_batch_cycling`, `send_stream_cycling` and `send_batch_resilient`,
+    /// which is why they require the auth command and TLS setup to be `Clone`.
+    /// The other batch and stream functions send all mails over a single
+    /// connection and ignore this option. It disables `pipelining`.
+    pub max_mails_per_connection: Option<usize>,
+
+    /// Closes the connection and opens a new one before exceeding this many bytes.
+    ///
+    /// Some relays limit the number of bytes send over a connection. If
+    /// sending the next mail would exceed this limit, `QUIT` is send and
+    /// the mail is send over a new connection (including auth). The size
+    /// of a mail is the size of the encoded mail. A mail larger than the
+    /// limit is still send, over a new connection on it's own.
+    ///
+    /// Like `max_mails_per_connection` this is only used by the functions
+    /// which can reconnect and disables `pipelining`.
+    pub max_bytes_per_connection: Option<usize>,
+
+    /// The maximal time sending a single mail of a batch may take.
+    ///
+    /// This starts once the connection is set up and covers the whole mail
+    /// transaction. If it elapses the mail fails with a `MailSendError::Timeout`
+    /// with `TimeoutPhase::Mail` and the connection is dropped, as the state
+    /// of the transaction is unknown. The functions which can reconnect
+    /// (see `max_mails_per_connection`) then continue with the next mail
+    /// over a new connection (set up with the same connection config,
+    /// including auth), the other ones fail the remaining mails like after
+    /// any other timeout. Setting a deadline disables
+    /// `pipelining`, so that it only covers a single mail.
+    ///
+    /// A dead connection is still detected by the `timeouts` of the single
+    /// commands (which are normally much shorter), in which case the remaining
+    /// mails fail. So this limits the time spend on a single slow mail (e.g.
+    /// a server slowly accepting a large body) without retrying a connection
+    /// which stopped responding.
+    pub per_mail_deadline: Option<Duration>,
+
+    /// Refuses to send mails with more `Received` headers than this.
+    ///
+    /// Each relay adds a `Received` header, so a mail with a large number of
+    /// them is most likely caught in a forwarding loop (RFC 5321, section 6.3).
+    /// Such mails fail with `MailSendError::LoopDetected` without being send.
+    /// This is meant for relays, `DEFAULT_MAX_RECEIVED_HEADERS` is a sensible
+    /// limit. By default (`None`) the headers are not counted.
+    pub max_received_headers: Option<usize>,
+
+    /// Refuses to send mails whose reverse path doesn't align with the `From` header.
+    ///
+    /// Such mails fail with `MailSendError::DomainMisaligned` without being
+    /// send, see `DomainAlignment`. By default (`None`) it's not checked.
+    /// Mails whose body is streamed (`send_streamed`) are never checked.
+    pub domain_alignment: Option<DomainAlignment>,
+
+    /// Lowercases the domain of each recipient before it's send with `RCPT TO`.
+    ///
+    /// Domains are case-insensitive, but some servers handle mixed-case
+    /// domains inconsistently. If enabled, the (ASCII) domain of each
+    /// recipient is lowercased while the local part, which can be case
+    /// sensitive, is kept as it is. Recipients which are the same afterwards
+    /// (e.g. `a@Example.com` as `To` and `a@example.com` as `Bcc`) only
+    /// receive the mail once. This is applied before the `recipient_rewriter`.
+    pub lowercase_recipient_domains: bool,
+
+    /// Rewrites the recipients of each mail before they are send.
+    ///
+    /// See `RecipientRewriter` for more details.
+    pub recipient_rewriter: Option<Arc<RecipientRewriter>>,
+
+    /// Sends all mails to a single recipient instead of their real recipients.
+    ///
+    /// This is meant for staging environments, see `RedirectPolicy`.
+    pub redirect: Option<RedirectPolicy>,
+
+    /// Called with the duration of each SMTP command.
+    ///
+    /// See `CommandObserver` for more details.
+    pub command_observer: Option<CommandObserver>,
+
+    /// Called with the outcome of each recipient (`RCPT`).
+    ///
+    /// See `RecipientProgress` for more details.
+    pub recipient_progress: Option<RecipientProgress>,
+
+    /// Measure the time spend in the different phases of sending each mail.
+    ///
+    /// The timings are returned as part of the `MailResponse`, see `SendTimings`.
+    pub record_timings: bool,
+
+    /// Limits the size of the server responses.
+    ///
+    /// See `ResponseLimits` for more details.
+    pub response_limits: ResponseLimits,
+
+    /// The kind of server mails are send to, see `SendTarget`.
+    pub send_target: SendTarget,
+
+    /// Encode the mails of a batch while sending them.
+    ///
+    /// By default all mails of a batch are encoded before the first mail
+    /// is send, so the connection is only opened once all of them are
+    /// encoded. If this is set, the first mail is send as soon as it is
+    /// encoded and the following mails are encoded while the previous
+    /// ones are send, with at most this many mails being encoded ahead
+    /// (at least one). This reduces the latency of large batches.
+    ///
+    /// The encodings are spawned on the default executor so that they
+    /// progress while a mail is send. Without a default executor the
+    /// mails are only encoded while the sender waits for the next mail.
+    ///
+    /// The results are still returned in the order of the mails.
+    pub pipelined_encoding: Option<usize>,
+
+    /// Limits the total size of mails which are encoded but not yet send.
+    ///
+    /// When mails are encoded while sending (`send_stream` or a batch with
+    /// `pipelined_encoding`) a large number of large mails can be encoded
+    /// ahead. If this is set, no further mail is encoded while the encoded
+    /// mails waiting to be send or being send are this many bytes or more,
+    /// so the memory used for them is bounded independent of the number of
+    /// mails. As the size of a mail is only known once it's encoded, mails
+    /// being encoded are assumed to be as large as the average of the mails
+    /// encoded before, so the limit can be exceeded by mails larger than
+    /// the average. A mail is always encoded if no other mail is being
+    /// encoded and at most one (e.g. the one being send) is in flight, so
+    /// mails larger than the limit are still send.
+    ///
+    /// A batch is always encoded while sending if this is set, with the
+    /// number of mails encoded ahead only limited by `pipelined_encoding`
+    /// if it's set, too.
+    pub max_in_flight_bytes: Option<usize>,
+
+    /// Overlaps the transactions of two mails (RFC 2920).
+    ///
+    /// By default a mail is only send once the final reply to the previous
+    /// mail was received. If this is enabled and the server announces
+    /// `PIPELINING`, the data of a mail is send together with the `MAIL`
+    /// and `RCPT` commands of the next mail, so the server processes the
+    /// previous mail while the next one is started. The `MAIL` and `RCPT`
+    /// commands of a mail are send at once, too. At most two transactions
+    /// can overlap, as the data of a mail can only be send once `DATA` was
+    /// accepted, which is answered after the final reply to the previous mail.
+    ///
+    /// The results are still returned in the order of the mails. As the
+    /// replies are read together, the durations reported to the
+    /// `command_observer` and recorded with `record_timings` are measured
+    /// from sending all pipelined commands to each reply.
+    ///
+    /// Pipelining is not used (and mails are send one after another) if:
+    ///
+    /// - mails are send using `send_batch_resilient`, as a mail is only
+    ///   retried once its result is known,
+    /// - the connection is cycled (`max_mails_per_connection` or
+    ///   `max_bytes_per_connection`), for the same reason,
+    /// - mails have a `per_mail_deadline`, as it would cover two mails,
+    /// - a mail needs more than one transaction (see `max_recipients_per_transaction`),
+    ///   this only affects the single mail.
+    pub pipelining: bool
+}
+
+impl SendConfig {
+
+    /// Returns true if `pipelining` is enabled and not disabled by another option.
+    ///
+    /// `send_batch_resilient` disables it, too, which isn't part of the config.
+    pub(crate) fn allows_pipelining(&self) -> bool {
+        self.pipelining
+            && self.max_mails_per_connection.is_none()
+            && self.max_bytes_per_connection.is_none()
+            && self.per_mail_deadline.is_none()
+    }
+}
+
+/// Restricts or orders the addresses of the server by address family.
+///
+/// This applies to the address of the `ConnectionConfig` and the
+/// `SendConfig::additional_addrs`. If no address is left after filtering,
+/// connecting fails with an I/O error of the kind `AddrNotAvailable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamilyPreference {
+    /// Use the addresses in the given order (the default).
+    Any,
+
+    /// Only connect to IPv4 addresses.
+    V4Only,
+
+    /// Only connect to IPv6 addresses.
+    V6Only,
+
+    /// Try IPv4 addresses before IPv6 addresses.
+    PreferV4,
+
+    /// Try IPv6 addresses before IPv4 addresses.
+    PreferV6
+}
+
+impl AddressFamilyPreference {
+
+    /// Filters/orders the addresses, keeping the order within a family.
+    pub(crate) fn apply(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<_>, Vec<_>) = addrs.iter().partition(|addr| addr.is_ipv6());
+        match self {
+            AddressFamilyPreference::Any => addrs,
+            AddressFamilyPreference::V4Only => v4,
+            AddressFamilyPreference::V6Only => v6,
+            AddressFamilyPreference::PreferV4 => v4.into_iter().chain(v6).collect(),
+            AddressFamilyPreference::PreferV6 => v6.into_iter().chain(v4).collect()
+        }
+    }
+}
+
+impl Default for AddressFamilyPreference {
+    fn default() -> Self {
+        AddressFamilyPreference::Any
+    }
+}
+
+impl fmt::Display for AddressFamilyPreference {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match *self {
+            AddressFamilyPreference::Any => "any",
+            AddressFamilyPreference::V4Only => "IPv4 only",
+            AddressFamilyPreference::V6Only => "IPv6 only",
+            AddressFamilyPreference::PreferV4 => "prefer IPv4",
+            AddressFamilyPreference::PreferV6 => "prefer IPv6"
+        };
+        fter.write_str(as_str)
+    }
+}
+
+/// The default limit of `Received` headers, see `SendConfig::max_received_headers`.
+pub const DEFAULT_MAX_RECEIVED_HEADERS: usize = 30;
+
+/// The kind of server mails are send to.
+///
+/// This crate is meant to be used with a Mail Submission Agent (MSA),
+/// which takes care of retries, bounces etc. Sending directly to the
+/// Mail Exchanger (MX) of the recipients domain is possible but requires
+/// the caller to handle all of this (e.g. retrying temporary failures
+/// over hours or days), which is why it has to be acknowledged explicitly:
+///
+/// ```
+/// # use mail_smtp::{SendConfig, SendTarget};
+/// let mut config = SendConfig::default();
+/// config.send_target = SendTarget::mx().acknowledge_limitations();
+/// assert!(config.send_target.is_mx());
+/// ```
+///
+/// Just using `SendTarget::mx()` is not enough:
+///
+/// ```compile_fail
+/// # use mail_smtp::{SendConfig, SendTarget};
+/// let mut config = SendConfig::default();
+/// config.send_target = SendTarget::mx();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SendTarget {
+    /// Send mails to a Mail Submission Agent (the default).
+    Msa,
+
+    /// Send mails to a Mail Exchanger.
+    ///
+    /// Compared to `Msa` this changes following defaults:
+    ///
+    /// - The auth command of the `ConnectionConfig` is not send, as
+    ///   a MX doesn't expect clients to authenticate.
+    /// - Mails marked as bounce (`MailRequest::set_bounce`) are send
+    ///   with the null reverse path (`MAIL FROM:<>`) as required by
+    ///   RFC 5321 (section 4.5.5), even if a reverse path was set.
+    Mx(MxAcknowledgment)
+}
+
+impl SendTarget {
+
+    /// Returns the first step of creating a `SendTarget::Mx`.
+    ///
+    /// Call `acknowledge_limitations` on the result to get the target.
+    pub fn mx() -> UnacknowledgedMx {
+        UnacknowledgedMx { _priv: () }
+    }
+
+    /// Returns true if this is `SendTarget::Mx`.
+    pub fn is_mx(&self) -> bool {
+        match *self {
+            SendTarget::Msa => false,
+            SendTarget::Mx(_) => true
+        }
+    }
+}
+
+impl Default for SendTarget {
+    fn default() -> Self {
+        SendTarget::Msa
+    }
+}
+
+/// A MX target whose limitations were not yet acknowledged, see `SendTarget`.
+#[derive(Debug)]
+#[must_use = "call acknowledge_limitations to get a SendTarget"]
+pub struct UnacknowledgedMx {
+    _priv: ()
+}
+
+impl UnacknowledgedMx {
+
+    /// Acknowledges that sending to a MX requires handling retries and bounces.
+    pub fn acknowledge_limitations(self) -> SendTarget {
+        SendTarget::Mx(MxAcknowledgment { _priv: () })
+    }
+}
+
+/// Proof that sending to a MX was acknowledged, see `SendTarget`.
+///
+/// This can only be created using `UnacknowledgedMx::acknowledge_limitations`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MxAcknowledgment {
+    _priv: ()
+}
+
+/// Decides what happens if the server rejects some recipients of a mail.
+///
+/// If the mail is not send to any recipient (i.e. the mail fails) `RSET` is
+/// send and the mail fails with an error, without sending the mail data.
+/// The connection can still be used for further mails in either case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecipientPolicy {
+    /// Any rejected recipient fails the whole mail (the default).
+    ///
+    /// No further recipients are send after the first rejected one
+    /// and the mail fails with `MailSendError::Smtp`.
+    RequireAll,
+
+    /// The mail is send to all accepted recipients.
+    ///
+    /// The reply codes of the rejected recipients can be found in the
+    /// `recipient_codes` of the `MailResponse`. If all recipients are
+    /// rejected the mail fails with `MailSendError::RecipientRejected`
+    /// for the first recipient.
+    AcceptPartial,
+
+    /// Like `AcceptPartial` but fails immediately if the first recipient is rejected.
+    ///
+    /// If the first recipient is rejected with a permanent error (`5xx`)
+    /// no further recipients are send and the mail fails with a
+    /// `MailSendError::RecipientRejected` identifying the first
+    /// recipient and the servers response. This is useful if the
+    /// first (`To`) recipient is the primary one and there is no
+    /// point in sending the mail only to the others (e.g. `Cc`).
+    FailFastOnFirstRecipient
+}
+
+impl Default for RecipientPolicy {
+    fn default() -> Self {
+        RecipientPolicy::RequireAll
+    }
+}
+
+/// Callback recording the progress of a batch.
+///
+/// It is called with the index (in the input `Vec`) of each mail of a
+/// batch once the mail was send successfully. Storing the indices allows
+/// a restarted job to skip the already send mails using `send_batch_resumable`.
+///
+/// The callback is called from within the stream returned by the send
+/// functions, so it should not block for long.
+#[derive(Clone)]
+pub struct Checkpoint {
+    on_sent: Arc<Fn(usize) + Send + Sync>
+}
+
+impl Checkpoint {
+
+    /// Creates a new `Checkpoint` calling the given function for each send mail.
+    pub fn new<F>(on_sent: F) -> Self
+        where F: Fn(usize) + Send + Sync + 'static
+    {
+        Checkpoint { on_sent: Arc::new(on_sent) }
+    }
+
+    /// Records that the mail with the given index was send successfully.
+    pub fn record(&self, index: usize) {
+        (self.on_sent)(index)
+    }
+}
+
+impl fmt::Debug for Checkpoint {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("Checkpoint { .. }")
+    }
+}
+
+/// Callback observing how long each SMTP command takes.
+///
+/// It is called once a command completed (successfully or not) with the
+/// command and the time between sending it and receiving the response,
+/// measured using the monotonic `Instant`. Each command is only send once
+/// the response to the previous one was received, so the duration is the
+/// full round trip of the command and no time is attributed to multiple
+/// commands. The exception are mails send using `SendConfig::pipelining`,
+/// for which the `MAIL` command is measured from sending all pipelined
+/// commands and each `RCPT` command from the reply to the previous command.
+///
+/// Like the `Checkpoint` it is called from within the send futures,
+/// so it should not block for long.
+#[derive(Clone)]
+pub struct CommandObserver {
+    on_command: Arc<Fn(SmtpCommand, Duration) + Send + Sync>
+}
+
+impl CommandObserver {
+
+    /// Creates a new `CommandObserver` calling the given function for each command.
+    pub fn new<F>(on_command: F) -> Self
+        where F: Fn(SmtpCommand, Duration) + Send + Sync + 'static
+    {
+        CommandObserver { on_command: Arc::new(on_command) }
+    }
+
+    /// Records that the command took the given duration.
+    pub fn record(&self, command: SmtpCommand, duration: Duration) {
+        (self.on_command)(command, duration)
+    }
+}
+
+impl fmt::Debug for CommandObserver {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("CommandObserver { .. }")
+    }
+}
+
+/// Builder combining multiple observers of each kind into one.
+///
+/// This allows using e.g. an observer for metrics and one for logging at
+/// the same time. It covers all observing hooks of the `SendConfig`: the
+/// `command_observer`, the `recipient_progress` and the `checkpoint`. The
+/// combined hooks call all observers of their kind in the order they were
+/// added. If one of them panics the panic is caught and the remaining ones
+/// are still called, so a faulty observer can't fail the send (the panic
+/// message is still printed by the panic hook).
+///
+/// ```
+/// # extern crate mail_smtp;
+/// use mail_smtp::{SendConfig, CommandObserver, CompositeObserver, RecipientProgress};
+///
+/// # fn main() {
+/// let mut config = SendConfig::default();
+/// CompositeObserver::new()
+///     .with(CommandObserver::new(|command, duration| println!("{:?} took {:?}", command, duration)))
+///     .with(CommandObserver::new(|_command, _duration| { /* record metrics */ }))
+///     .with_recipient_progress(RecipientProgress::new(|recipient, _outcome| println!("{} done", recipient.as_str())))
+///     .apply_to(&mut config);
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CompositeObserver {
+    command_observers: Vec<CommandObserver>,
+    recipient_progress: Vec<RecipientProgress>,
+    checkpoints: Vec<Checkpoint>
+}
+
+impl CompositeObserver {
+
+    /// Creates a new `CompositeObserver` without any observers.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a command observer, it's called after the previously added ones.
+    pub fn with(mut self, observer: CommandObserver) -> Self {
+        self.command_observers.push(observer);
+        self
+    }
+
+    /// Adds a recipient progress callback, it's called after the previously added ones.
+    pub fn with_recipient_progress(mut self, progress: RecipientProgress) -> Self {
+        self.recipient_progress.push(progress);
+        self
+    }
+
+    /// Adds a checkpoint, it's called after the previously added ones.
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoints.push(checkpoint);
+        self
+    }
+
+    /// Returns the `CommandObserver` calling all added command observers, if any.
+    pub fn command_observer(&self) -> Option<CommandObserver> {
+        if self.command_observers.is_empty() {
+            return None;
+        }
+        let observers = self.command_observers.clone();
+        Some(CommandObserver::new(move |command, duration| {
+            for observer in &observers {
+                // the panic was already reported by the panic hook
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| observer.record(command, duration)));
+            }
+        }))
+    }
+
+    /// Returns the `RecipientProgress` calling all added callbacks, if any.
+    pub fn recipient_progress(&self) -> Option<RecipientProgress> {
+        if self.recipient_progress.is_empty() {
+            return None;
+        }
+        let callbacks = self.recipient_progress.clone();
+        Some(RecipientProgress::new(move |recipient, outcome| {
+            for progress in &callbacks {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| progress.report(recipient, outcome)));
+            }
+        }))
+    }
+
+    /// Returns the `Checkpoint` calling all added checkpoints, if any.
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        if self.checkpoints.is_empty() {
+            return None;
+        }
+        let checkpoints = self.checkpoints.clone();
+        Some(Checkpoint::new(move |index| {
+            for checkpoint in &checkpoints {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| checkpoint.record(index)));
+            }
+        }))
+    }
+
+    /// Sets the hooks of the config for which observers were added.
+    ///
+    /// Hooks without added observers are kept as they are.
+    pub fn apply_to(&self, config: &mut SendConfig) {
+        if let Some(observer) = self.command_observer() {
+            config.command_observer = Some(observer);
+        }
+        if let Some(progress) = self.recipient_progress() {
+            config.recipient_progress = Some(progress);
+        }
+        if let Some(checkpoint) = self.checkpoint() {
+            config.checkpoint = Some(checkpoint);
+        }
+    }
+}
+
+/// Callback reporting the progress of a mail per recipient.
+///
+/// It is called once the server accepted or rejected the `RCPT` command of
+/// a recipient, with `Ok(())` or the error for the rejection. Whether a
+/// rejection fails the whole mail depends on the `RecipientPolicy`. This
+/// allows finer-grained progress reporting (e.g. a progress bar) than the
+/// results per mail.
+///
+/// Compared to the `CommandObserver` this is a plain closure which can
+/// keep mutable state. It is called on the task driving the send future,
+/// so it should be cheap and must not block. A panic of the closure isn't
+/// caught, but it's still called for later recipients (e.g. of other
+/// sends sharing the config).
+#[derive(Clone)]
+pub struct RecipientProgress {
+    on_recipient: Arc<Mutex<FnMut(&MailAddress, Result<(), &MailSendError>) + Send>>
+}
+
+impl RecipientProgress {
+
+    /// Creates a new `RecipientProgress` calling the given function for each recipient.
+    pub fn new<F>(on_recipient: F) -> Self
+        where F: FnMut(&MailAddress, Result<(), &MailSendError>) + Send + 'static
+    {
+        RecipientProgress { on_recipient: Arc::new(Mutex::new(on_recipient)) }
+    }
+
+    /// Reports the outcome for the given recipient.
+    pub fn report(&self, recipient: &MailAddress, outcome: Result<(), &MailSendError>) {
+        // a panic of the closure only poisons the lock, it's state is still usable
+        let mut on_recipient = self.on_recipient.lock().unwrap_or_else(|err| err.into_inner());
+        (&mut *on_recipient)(recipient, outcome)
+    }
+}
+
+impl fmt::Debug for RecipientProgress {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("RecipientProgress { .. }")
+    }
+}
+
+/// The SMTP commands observed by a `CommandObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SmtpCommand {
+    /// `EHLO`, send after the greeting and again after `STARTTLS`.
+    Ehlo,
+
+    /// `STARTTLS` including the TLS handshake.
+    StartTls,
+
+    /// The auth command of the `ConnectionConfig`.
+    Auth,
+
+    /// `MAIL FROM`.
+    Mail,
+
+    /// `RCPT TO`, once per recipient.
+    Rcpt,
+
+    /// `DATA` including sending the mail body, up to the final response.
+    Data
+}
+
+/// What to do if the server closes the connection using `421` during a batch.
+///
+/// Servers reply with `421 Service closing transmission channel` to any
+/// command if they are shutting down (or dropping long-lived connections)
+/// and then close the connection. The mail which got the reply always
+/// fails with the error (`MailSendError::is_service_closing` returns true)
+/// unless it is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceClosingPolicy {
+    /// Reconnect and send the mail which got the `421` again (the default).
+    ///
+    /// The mail is only retried once, and only if there is a token left
+    /// in the `retry_budget` (if one is set). The remaining mails are send
+    /// over the new connection.
+    RetryMail,
+
+    /// Reconnect and send the remaining mails, but don't retry the mail which got the `421`.
+    ///
+    /// This is useful if the caller wants to retry failed mails later
+    /// (e.g. using a retry queue) instead of right away.
+    ResumeRemaining,
+
+    /// Don't reconnect, all remaining mails fail with an I/O error of the kind `NotConnected`.
+    FailRemaining
+}
+
+impl Default for ServiceClosingPolicy {
+    fn default() -> Self {
+        ServiceClosingPolicy::RetryMail
+    }
+}
+
+/// A token bucket limiting the rate of retries.
+///
+/// Each retry (e.g. of a mail answered with `421` by `send_batch_resilient`)
+/// takes one token. If there is no token left, the mail is not retried but
+/// fails with the (transient) error, so it can be send again later. Tokens
+/// are refilled one at a time, once every `refill_every`, up to `max_tokens`.
+///
+/// This avoids retry storms, e.g. if a server hiccup makes many mails fail
+/// at once. Clones share the same tokens, so a budget can be shared by
+/// multiple batches by using clones of it in their `SendConfig`.
+#[derive(Clone)]
+pub struct RetryBudget {
+    max_tokens: u32,
+    refill_every: Duration,
+    state: Arc<Mutex<BudgetState>>
+}
+
+struct BudgetState {
+    tokens: u32,
+    last_refill: Instant
+}
+
+impl RetryBudget {
+
+    /// Creates a new budget starting with `max_tokens` tokens.
+    ///
+    /// A `refill_every` of zero refills tokens immediately, i.e.
+    /// only limits the number of retries to `max_tokens` at once.
+    pub fn new(max_tokens: u32, refill_every: Duration) -> Self {
+        let state = BudgetState { tokens: max_tokens, last_refill: Instant::now() };
+        RetryBudget { max_tokens, refill_every, state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Takes a token, returns false if there is none left.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().expect("[BUG] retry budget panicked");
+        self.refill(&mut state);
+        if state.tokens == 0 {
+            false
+        } else {
+            state.tokens -= 1;
+            true
+        }
+    }
+
+    /// The number of tokens currently available.
+    pub fn available(&self) -> u32 {
+        let mut state = self.state.lock().expect("[BUG] retry budget panicked");
+        self.refill(&mut state);
+        state.tokens
+    }
+
+    fn refill(&self, state: &mut BudgetState) {
+        let now = Instant::now();
+        let refill_every = as_nanos(self.refill_every);
+        if refill_every == 0 {
+            state.tokens = self.max_tokens;
+            state.last_refill = now;
+            return;
+        }
+
+        let refills = as_nanos(now - state.last_refill) / refill_every;
+        let missing = (self.max_tokens - state.tokens) as u64;
+        if refills >= missing {
+            state.tokens = self.max_tokens;
+            state.last_refill = now;
+        } else {
+            state.tokens += refills as u32;
+            state.last_refill += self.refill_every * refills as u32;
+        }
+    }
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    duration.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(duration.subsec_nanos() as u64)
+}
+
+impl fmt::Debug for RetryBudget {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.debug_struct("RetryBudget")
+            .field("max_tokens", &self.max_tokens)
+            .field("refill_every", &self.refill_every)
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+/// Custom commands run on each new connection right after `AUTH`.
+///
+/// This is meant for relays which require some non-standard command
+/// (e.g. `XCLIENT`) to be send before the first mail transaction.
+///
+/// As commands are consumed when they are send, this contains a function
+/// creating the commands, which is called once for each new connection.
+/// The commands are send in order and if any of them fails setting up
+/// the connection fails with `MailSendError::Connecting`, i.e. no mail
+/// is send over the connection.
+#[derive(Clone)]
+pub struct PostAuthCmds {
+    create_cmds: Arc<Fn() -> Vec<BoxedCmd> + Send + Sync>
+}
+
+impl PostAuthCmds {
+
+    /// Creates a new `PostAuthCmds` instance using the given function to create the commands.
+    ///
+    /// Commands can be turned into a `BoxedCmd` using `Cmd::boxed`.
+    pub fn new<F>(create_cmds: F) -> Self
+        where F: Fn() -> Vec<BoxedCmd> + Send + Sync + 'static
+    {
+        PostAuthCmds { create_cmds: Arc::new(create_cmds) }
+    }
+
+    /// Creates the commands to run on a new connection.
+    pub fn create_cmds(&self) -> Vec<BoxedCmd> {
+        (self.create_cmds)()
+    }
+}
+
+impl fmt::Debug for PostAuthCmds {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("PostAuthCmds { .. }")
+    }
+}
+
+/// Timeouts for the different phases of a smtp session.
+///
+/// A phase without a timeout (`None`) can take arbitrary long,
+/// which is the default for all phases.
+///
+/// If a timeout elapses sending fails with a `MailSendError::Timeout`
+/// reporting the phase in which it elapsed. As the state of the connection
+/// is unknown after a timeout it is not used for any further mails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Timeout for setting up the connection.
+    ///
+    /// This includes connecting with TCP, setting up TLS,
+    /// sending EHLO and authenticating.
+    pub connect: Option<Duration>,
+
+    /// Timeout for receiving the greeting of the server.
+    ///
+    /// Some servers delay the `220` greeting (e.g. tarpitting). This bounds
+    /// how long is waited for the greeting once the connection (including
+    /// direct TLS) is open. It is retried `SendConfig::greeting_retries`
+    /// times, see there for more details.
+    ///
+    /// This is independent of the `connect` timeout, which makes it possible
+    /// to quickly give up on tarpitting servers (e.g. when sending directly
+    /// to an MX) without limiting e.g. slow authentication.
+    pub greeting: Option<Duration>,
+
+    /// Timeout for the response to a single command.
+    ///
+    /// This applies to `MAIL`, each `RCPT` and `DATA` itself,
+    /// i.e. waiting for the intermediate `354` response.
+    pub command: Option<Duration>,
+
+    /// Timeout for sending the mail body.
+    ///
+    /// This starts after receiving the `354` response to `DATA`
+    /// and ends when the final response is received.
+    /// For a streamed body (see `send_streamed`) this includes
+    /// the time needed to produce the body.
+    pub data: Option<Duration>
+}
+
+/// Limits for the size of a single server response.
+///
+/// A server sending an excessively long (multi-line) response to `MAIL`,
+/// `RCPT`, `DATA` or `RSET` makes the mail fail with
+/// `MailSendError::ResponseTooLarge`. The connection isn't used anymore
+/// afterwards, so in a batch all later mails fail with an I/O error.
+/// An excessively long greeting or `EHLO` response makes setting up the
+/// connection fail with a `MailSendError::Connecting` error wrapping it.
+///
+/// The greeting and the responses to `MAIL`, `RCPT`, `DATA` and `RSET`
+/// are checked while they are read, i.e. reading stops as soon as the
+/// lines or bytes received so far exceed the limits. The responses to
+/// `EHLO`, `STARTTLS` and `AUTH` are read by `new-tokio-smtp` and only
+/// checked once they were received (and buffered) completely. How long
+/// receiving a response may take is bound by the `Timeouts`.
+///
+/// The defaults (1024 lines and 256KiB) are far above anything a well
+/// behaving server sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResponseLimits {
+    /// The maximal number of lines of a response.
+    pub max_lines: usize,
+
+    /// The maximal number of bytes of a response.
+    ///
+    /// This includes the reply code, separator and line break of each line.
+    pub max_bytes: usize
+}
+
+impl ResponseLimits {
+
+    /// Fails with `MailSendError::ResponseTooLarge` if the response exceeds the limits.
+    pub(crate) fn check(&self, response: &Response) -> Result<(), MailSendError> {
+        let lines = response.msg().len();
+        // each line has a reply code, a separator and a line break
+        let bytes = response.msg().iter().map(|line| line.len() + 6).sum();
+        if lines > self.max_lines || bytes > self.max_bytes {
+            Err(MailSendError::ResponseTooLarge { lines, bytes })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for ResponseLimits {
+    fn default() -> Self {
+        ResponseLimits {
+            max_lines: 1024,
+            max_bytes: 256 * 1024
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod retry_budget {
+        use std::time::Duration;
+        use super::super::RetryBudget;
+
+        #[test]
+        fn runs_out_of_tokens() {
+            let budget = RetryBudget::new(2, Duration::from_secs(3600));
+            assert!(budget.try_acquire());
+            assert!(budget.clone().try_acquire());
+            assert!(!budget.try_acquire());
+            assert_eq!(budget.available(), 0);
+        }
+
+        #[test]
+        fn refills_tokens() {
+            let budget = RetryBudget::new(1, Duration::from_millis(10));
+            assert!(budget.try_acquire());
+            ::std::thread::sleep(Duration::from_millis(25));
+            assert_eq!(budget.available(), 1);
+            assert!(budget.try_acquire());
+        }
+    }
+
+    mod composite_observer {
+        use std::{
+            sync::{Arc, Mutex},
+            time::Duration
+        };
+        use futures::Stream;
+        use new_tokio_smtp::send_mail::MailAddress;
+        use ::{
+            request::MailRequest,
+            send_mail::send_batch_with,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server, test_context}
+        };
+        use super::super::{SendConfig, CommandObserver, CompositeObserver, RecipientProgress, Checkpoint, SmtpCommand};
+
+        type Events = Arc<Mutex<Vec<(&'static str, String)>>>;
+
+        fn recording(composite: CompositeObserver, events: &Events, name: &'static str) -> CompositeObserver {
+            let (commands, recipients, checkpoints) = (events.clone(), events.clone(), events.clone());
+            composite
+                .with(CommandObserver::new(move |command, _duration| {
+                    commands.lock().unwrap().push((name, format!("{:?}", command)))
+                }))
+                .with_recipient_progress(RecipientProgress::new(move |recipient: &MailAddress, _outcome| {
+                    recipients.lock().unwrap().push((name, format!("progress {}", recipient.as_str())))
+                }))
+                .with_checkpoint(Checkpoint::new(move |index| {
+                    checkpoints.lock().unwrap().push((name, format!("checkpoint {}", index)))
+                }))
+        }
+
+        #[test]
+        fn forwards_every_event_to_all_observers_in_order() {
+            let events = Events::default();
+            let composite = recording(recording(CompositeObserver::new(), &events, "first"), &events, "second");
+            let mut config = SendConfig::default();
+            composite.apply_to(&mut config);
+
+            let mails = vec![MailRequest::new(simple_mail("to@example.com"))];
+            let results = run(send_batch_with(mails, con_config(spawn_smtp_server()), test_context(), config).collect()).unwrap();
+            assert_eq!(results.len(), 1);
+
+            let events = events.lock().unwrap();
+            let kinds = ["Ehlo", "Auth", "Mail", "Rcpt", "progress to@example.com", "Data", "checkpoint 0"];
+            for kind in kinds.iter() {
+                let received = events.iter()
+                    .filter(|&&(_, ref event)| event == kind)
+                    .map(|&(name, _)| name)
+                    .collect::<Vec<_>>();
+                assert_eq!(received, vec!["first", "second"], "{}: {:?}", kind, *events);
+            }
+        }
+
+        #[test]
+        fn a_panicking_observer_does_not_skip_the_others() {
+            let events = Events::default();
+            let composite = recording(
+                CompositeObserver::new()
+                    .with(CommandObserver::new(|_, _| panic!("observer failed")))
+                    .with_recipient_progress(RecipientProgress::new(|_, _| panic!("progress failed")))
+                    .with_checkpoint(Checkpoint::new(|_| panic!("checkpoint failed"))),
+                &events,
+                "second"
+            );
+
+            // the panics don't unwind through the combined observers
+            composite.command_observer().unwrap().record(SmtpCommand::Rcpt, Duration::from_millis(1));
+            let recipient = MailAddress::new_unchecked("a@test.test".to_owned(), false);
+            composite.recipient_progress().unwrap().report(&recipient, Ok(()));
+            composite.checkpoint().unwrap().record(3);
+
+            assert_eq!(*events.lock().unwrap(), vec![
+                ("second", "Rcpt".to_owned()),
+                ("second", "progress a@test.test".to_owned()),
+                ("second", "checkpoint 3".to_owned())
+            ]);
+        }
+
+        #[test]
+        fn keeps_hooks_without_observers() {
+            let mut config = SendConfig::default();
+            config.checkpoint = Some(Checkpoint::new(|_| {}));
+            CompositeObserver::new()
+                .with(CommandObserver::new(|_, _| {}))
+                .apply_to(&mut config);
+
+            assert!(config.command_observer.is_some());
+            assert!(config.recipient_progress.is_none());
+            assert!(config.checkpoint.is_some());
+        }
+    }
+
+    mod recipient_progress {
+        use std::{
+            panic::{self, AssertUnwindSafe},
+            sync::{Arc, Mutex}
+        };
+        use new_tokio_smtp::send_mail::MailAddress;
+        use super::super::RecipientProgress;
+
+        #[test]
+        fn is_still_called_after_the_closure_panicked() {
+            let reported = Arc::new(Mutex::new(Vec::new()));
+            let recorded = reported.clone();
+            let progress = RecipientProgress::new(move |recipient: &MailAddress, _outcome| {
+                if recipient.as_str() == "panic@test.test" {
+                    panic!("[test] progress callback panicked");
+                }
+                recorded.lock().unwrap().push(recipient.as_str().to_owned());
+            });
+
+            let panicking = MailAddress::new_unchecked("panic@test.test".to_owned(), false);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| progress.report(&panicking, Ok(()))));
+            assert!(result.is_err());
+
+            let recipient = MailAddress::new_unchecked("a@test.test".to_owned(), false);
+            progress.report(&recipient, Ok(()));
+            assert_eq!(*reported.lock().unwrap(), vec!["a@test.test".to_owned()]);
+        }
+    }
+
+    mod send_target {
+        use super::super::{SendConfig, SendTarget};
+
+        #[test]
+        fn defaults_to_msa() {
+            assert_eq!(SendConfig::default().send_target, SendTarget::Msa);
+            assert!(!SendTarget::Msa.is_mx());
+        }
+
+        #[test]
+        fn mx_requires_acknowledgment() {
+            let unacknowledged = SendTarget::mx();
+            let target = unacknowledged.acknowledge_limitations();
+            assert!(target.is_mx());
+            match target {
+                SendTarget::Mx(_) => {},
+                SendTarget::Msa => panic!("expected a MX target")
+            }
+        }
+    }
+}
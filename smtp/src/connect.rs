@@ -0,0 +1,1268 @@
+//! Module implementing setting up a connection to a smtp server.
+//!
+//! This does the same as `Connection::connect` from `new-tokio-smtp`
+//! but allows configuring how the underlying socket is opened (e.g.
+//! binding it to a local address).
+use std::{
+    io as std_io,
+    fmt, mem,
+    error::Error as StdError,
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant}
+};
+
+use failure::Fail;
+use futures::{
+    Poll, Async,
+    future::{self, Future, Either, Loop},
+    sync::oneshot
+};
+use native_tls::TlsConnector as NativeTlsConnector;
+use net2::TcpBuilder;
+use tokio::{
+    net::TcpStream,
+    reactor::Handle
+};
+use tokio_timer::{Delay, Timeout};
+use tokio_tls::TlsConnector;
+
+use new_tokio_smtp::{
+    Cmd, BoxedCmd, Connection, ConnectionConfig, Io, Socket, SetupTls,
+    Security, TlsConfig, ClientId, Response,
+    command::{Ehlo, StartTls},
+    error::{ConnectingFailed, LogicError}
+};
+
+use ::{
+    bounded::{read_response, response_too_large},
+    config::{SendConfig, PostAuthCmds, CommandObserver, SmtpCommand, AddressFamilyPreference, ResponseLimits},
+    credentials::ProviderAuth,
+    error::{EhloAfterStartTlsFailed, StartTlsDowngrade, logic_error_response},
+    observe::{observed, timed, TimingRecorder},
+    reply::reply_code,
+    tls_pin::TlsHostCache
+};
+
+/// Future returned by `connect`.
+pub(crate) type ConnectFuture = Box<Future<Item=Connection, Error=ConnectingFailed> + Send>;
+
+/// Future returned by `connect_recorded`, the flag tells if the connection was authenticated.
+pub(crate) type AuthConnectFuture = Box<Future<Item=(Connection, bool), Error=ConnectingFailed> + Send>;
+
+/// Opens a connection to the server and sets it up.
+///
+/// - Resolves `config.resolve_host` if given.
+/// - Opens the TCP connection, binding it to `config.local_addr` if given,
+///   trying all addresses of the server (see `ConnectAttempts`).
+/// - Sets up TLS if direct TLS is used.
+/// - Reads the greeting, waiting at most `config.timeouts.greeting`.
+///   If no greeting arrives in time the connection is closed and the
+///   steps up to here are retried up to `config.greeting_retries` times.
+/// - Sends EHLO.
+/// - Sets up TLS using STARTTLS if used (and re-sends EHLO), see
+///   `config.opportunistic_starttls` for servers not announcing it.
+/// - Authenticates using the auth command, or the credentials of
+///   `config.credential_provider` if set (unless sending to a MX).
+/// - Runs the `config.post_auth_cmds` if there are any.
+pub(crate) fn connect<A, S>(conconf: ConnectionConfig<A, S>, config: &SendConfig) -> ConnectFuture
+    where A: Cmd, S: SetupTls
+{
+    Box::new(connect_recorded(conconf, config, None).map(|(con, _authenticated)| con))
+}
+
+/// Like `connect` but records the durations of the phases if there is a recorder.
+///
+/// Also returns if the connection was authenticated, see `authenticate`.
+pub(crate) fn connect_recorded<A, S>(
+    conconf: ConnectionConfig<A, S>,
+    config: &SendConfig,
+    recorder: Option<TimingRecorder>
+) -> AuthConnectFuture
+    where A: Cmd, S: SetupTls
+{
+    let ConnectionConfig { addr, security, auth_cmd, client_id } = conconf;
+    let client_id = config.client_id.clone().unwrap_or(client_id);
+    let auth = select_auth(auth_cmd, config);
+    let post_auth_cmds = config.post_auth_cmds.as_ref()
+        .map(PostAuthCmds::create_cmds)
+        .unwrap_or_default();
+
+    let (direct_tls, starttls) = match security {
+        Security::DirectTls(tls_config) => match direct_tls_connector(tls_config) {
+            Ok(connector) => (Some(connector), None),
+            Err(err) => return Box::new(future::err::<(Connection, bool), _>(err))
+        },
+        Security::StartTls(tls_config) => (None, Some(tls_config)),
+        Security::None => (None, None)
+    };
+
+    let additional_addrs = config.additional_addrs.clone();
+    let address_family = config.address_family;
+    let interleave = config.happy_eyeballs.is_some();
+    let addrs = server_addrs(addr, config.resolve_host.clone())
+        .map_err(ConnectingFailed::Io)
+        .and_then(move |resolved| {
+            let mut resolved = resolved.into_iter();
+            let primary = resolved.next().expect("[BUG] resolved host without addresses");
+            let additional = resolved.chain(additional_addrs).collect::<Vec<_>>();
+            select_addrs(primary, &additional, address_family, interleave)
+        });
+
+    let local_addr = config.local_addr;
+    let happy_eyeballs = config.happy_eyeballs;
+    let greeting_timeout = config.timeouts.greeting;
+    let greeting_retries = config.greeting_retries;
+    let open_recorder = recorder.clone();
+
+    let limits = config.response_limits;
+    let ehlo_client_id = client_id.clone();
+    let ehlo_observer = config.command_observer.clone();
+    let tls_observer = config.command_observer.clone();
+    let auth_observer = config.command_observer.clone();
+    let ehlo_recorder = recorder.clone();
+    let tls_recorder = recorder.clone();
+    let opportunistic = if config.opportunistic_starttls {
+        Some(config.tls_host_cache.clone())
+    } else {
+        None
+    };
+    let fut = addrs
+        .and_then(move |addrs| {
+            let opener = Opener {
+                addrs,
+                local_addr,
+                happy_eyeballs,
+                direct_tls,
+                greeting_timeout,
+                limits,
+                recorder: open_recorder
+            };
+            future::loop_fn(greeting_retries, move |retries_left| {
+                opener.open().then(move |result| match result {
+                    Ok(con) => Ok(Loop::Break(con)),
+                    Err(ref err) if retries_left > 0 && is_greeting_timeout(err) => Ok(Loop::Continue(retries_left - 1)),
+                    Err(err) => Err(err)
+                })
+            })
+        })
+        .and_then(move |con| send_ehlo(con, ehlo_client_id, limits, ehlo_observer, ehlo_recorder))
+        .and_then(move |con| match (starttls, opportunistic) {
+            (Some(tls_config), Some(cache)) =>
+                Either::A(Either::A(setup_opportunistic_starttls(con, tls_config, client_id, limits, cache, tls_observer, tls_recorder))),
+            (Some(tls_config), None) =>
+                Either::A(Either::B(setup_starttls(con, tls_config, client_id, limits, tls_observer, tls_recorder))),
+            (None, _) => Either::B(future::ok(con))
+        })
+        .and_then(move |con| authenticate_selected(con, auth, auth_observer, recorder))
+        .and_then(move |(con, authenticated)| run_post_auth_cmds(con, post_auth_cmds)
+            .map(move |con| (con, authenticated)));
+
+    Box::new(fut)
+}
+
+/// Opens connections up to (and including) reading the greeting.
+///
+/// This can be used multiple times, e.g. to retry if there was no greeting.
+struct Opener {
+    addrs: Vec<SocketAddr>,
+    local_addr: Option<IpAddr>,
+    happy_eyeballs: Option<Duration>,
+    direct_tls: Option<(TlsConnector, String)>,
+    greeting_timeout: Option<Duration>,
+    limits: ResponseLimits,
+    recorder: Option<TimingRecorder>
+}
+
+impl Opener {
+    fn open(&self) -> impl Future<Item=Connection, Error=ConnectingFailed> {
+        let direct_tls = self.direct_tls.clone();
+        let greeting_timeout = self.greeting_timeout;
+        let limits = self.limits;
+        let tls_recorder = self.recorder.clone();
+        let greeting_recorder = self.recorder.clone();
+
+        let attempts = ConnectAttempts::new(self.addrs.clone(), self.local_addr, self.happy_eyeballs);
+        timed(attempts, self.recorder.as_ref(), |timings| &mut timings.tcp_connect)
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |stream| match direct_tls {
+                Some((connector, domain)) => {
+                    let fut = connector.connect(&domain, stream)
+                        .map(Socket::Secure)
+                        .map_err(|err| ConnectingFailed::Io(tls_error(err)));
+                    Either::A(timed(fut, tls_recorder.as_ref(), |timings| &mut timings.tls))
+                },
+                None => Either::B(future::ok(Socket::Insecure(stream)))
+            })
+            .and_then(move |socket| {
+                let fut = read_greeting(socket, greeting_timeout, limits);
+                timed(fut, greeting_recorder.as_ref(), |timings| &mut timings.greeting)
+            })
+    }
+}
+
+type AttemptFuture = Box<Future<Item=TcpStream, Error=std_io::Error> + Send>;
+
+/// Future connecting to the first reachable address of a list of addresses.
+///
+/// Without an attempt delay the addresses are tried one after another.
+/// With an attempt delay, a new attempt is started each time the delay
+/// elapses without any attempt succeeding, or immediately if an attempt
+/// fails (Happy Eyeballs, RFC 8305). The first attempt to succeed wins.
+///
+/// If all attempts fail the error of the last failed attempt is returned.
+struct ConnectAttempts {
+    pending: VecDeque<SocketAddr>,
+    running: Vec<AttemptFuture>,
+    open_attempt: Box<Fn(SocketAddr) -> AttemptFuture + Send>,
+    attempt_delay: Option<Duration>,
+    next_attempt: Option<Delay>,
+    last_error: Option<std_io::Error>
+}
+
+impl ConnectAttempts {
+    fn new(addrs: Vec<SocketAddr>, local_addr: Option<IpAddr>, attempt_delay: Option<Duration>) -> Self {
+        let open_attempt = move |addr| -> AttemptFuture { Box::new(open_tcp_stream(addr, local_addr)) };
+        ConnectAttempts::with_opener(addrs, attempt_delay, open_attempt)
+    }
+
+    /// Like `new` but uses the given function to open the connections.
+    fn with_opener<F>(addrs: Vec<SocketAddr>, attempt_delay: Option<Duration>, open_attempt: F) -> Self
+        where F: Fn(SocketAddr) -> AttemptFuture + Send + 'static
+    {
+        ConnectAttempts {
+            pending: addrs.into(),
+            running: Vec::new(),
+            open_attempt: Box::new(open_attempt),
+            attempt_delay,
+            next_attempt: None,
+            last_error: None
+        }
+    }
+
+    fn start_next_attempt(&mut self) -> bool {
+        let addr = match self.pending.pop_front() {
+            Some(addr) => addr,
+            None => return false
+        };
+        self.running.push((self.open_attempt)(addr));
+        self.next_attempt = match self.attempt_delay {
+            Some(delay) if !self.pending.is_empty() => Some(Delay::new(Instant::now() + delay)),
+            _ => None
+        };
+        true
+    }
+}
+
+impl Future for ConnectAttempts {
+    type Item = TcpStream;
+    type Error = std_io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut any_failed = false;
+            let mut idx = 0;
+            while idx < self.running.len() {
+                match self.running[idx].poll() {
+                    Ok(Async::Ready(stream)) => {
+                        // dropping the other attempts cancels them
+                        self.running.clear();
+                        return Ok(Async::Ready(stream));
+                    },
+                    Ok(Async::NotReady) => idx += 1,
+                    Err(err) => {
+                        self.last_error = Some(err);
+                        self.running.swap_remove(idx);
+                        any_failed = true;
+                    }
+                }
+            }
+
+            if self.running.is_empty() || (any_failed && self.attempt_delay.is_some()) {
+                if self.start_next_attempt() {
+                    continue;
+                }
+                if self.running.is_empty() {
+                    let err = mem::replace(&mut self.last_error, None).unwrap_or_else(|| {
+                        std_io::Error::new(std_io::ErrorKind::InvalidInput, "no address to connect to")
+                    });
+                    return Err(err);
+                }
+            }
+
+            let delay_elapsed = match self.next_attempt.as_mut().map(Future::poll) {
+                Some(Ok(Async::Ready(()))) => true,
+                Some(Ok(Async::NotReady)) | None => false,
+                Some(Err(err)) => return Err(std_io::Error::new(std_io::ErrorKind::Other, err))
+            };
+
+            if delay_elapsed && self.start_next_attempt() {
+                continue;
+            }
+
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+/// Returns the addresses of the server, resolving `host` if given.
+///
+/// Without a host only `addr` is returned, otherwise the resolved
+/// addresses using the port of `addr`.
+fn server_addrs(addr: SocketAddr, host: Option<String>) -> impl Future<Item=Vec<SocketAddr>, Error=std_io::Error> {
+    match host {
+        None => Either::A(future::ok(vec![addr])),
+        Some(host) => Either::B(resolve_host(host, addr.port()))
+    }
+}
+
+/// Resolves the host name on a new thread, as the lookup of the standard library blocks.
+fn resolve_host(host: String, port: u16) -> impl Future<Item=Vec<SocketAddr>, Error=std_io::Error> {
+    let (sender, receiver) = oneshot::channel();
+    let spawned = thread::Builder::new()
+        .name("smtp-resolve".to_owned())
+        .spawn(move || {
+            let resolved = (&*host, port).to_socket_addrs().map(Iterator::collect::<Vec<_>>);
+            let _ = sender.send(resolved);
+        });
+    if let Err(err) = spawned {
+        return Either::A(future::err(err));
+    }
+
+    Either::B(receiver.then(|result| match result {
+        Ok(Ok(addrs)) => if addrs.is_empty() {
+            Err(std_io::Error::new(std_io::ErrorKind::NotFound, "host name resolved to no address"))
+        } else {
+            Ok(addrs)
+        },
+        Ok(Err(err)) => Err(err),
+        Err(_canceled) => Err(std_io::Error::new(std_io::ErrorKind::Other, "resolving the host name was aborted"))
+    }))
+}
+
+/// Selects the addresses of the server for connection attempts.
+///
+/// Like `order_addrs` but applies the `AddressFamilyPreference` first, the
+/// addresses are interleaved starting with the first address left. Fails
+/// if no address of the required family is left.
+fn select_addrs(
+    primary: SocketAddr,
+    additional: &[SocketAddr],
+    preference: AddressFamilyPreference,
+    interleave: bool
+) -> Result<Vec<SocketAddr>, ConnectingFailed> {
+    let addrs = preference.apply(order_addrs(primary, additional, false));
+    if addrs.is_empty() {
+        let msg = format!("server has no address matching the address family preference ({})", preference);
+        return Err(ConnectingFailed::Io(std_io::Error::new(std_io::ErrorKind::AddrNotAvailable, msg)));
+    }
+
+    if interleave {
+        Ok(interleave_families(addrs))
+    } else {
+        Ok(addrs)
+    }
+}
+
+/// Orders the addresses of the server for connection attempts.
+///
+/// The primary address is always tried first, duplicates are removed. If
+/// `interleave` is true the remaining addresses are reordered so
+/// that IPv4 and IPv6 addresses alternate (keeping the relative order
+/// within a family).
+fn order_addrs(primary: SocketAddr, additional: &[SocketAddr], interleave: bool) -> Vec<SocketAddr> {
+    let mut addrs = vec![primary];
+    for addr in additional {
+        if !addrs.contains(addr) {
+            addrs.push(*addr);
+        }
+    }
+
+    if interleave {
+        interleave_families(addrs)
+    } else {
+        addrs
+    }
+}
+
+/// Reorders the addresses so that IPv4 and IPv6 addresses alternate, starting with the first one.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(first) => first.is_ipv6(),
+        None => return addrs
+    };
+    let (mut same, mut other): (VecDeque<_>, VecDeque<_>) = addrs.into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+
+    let mut ordered = Vec::with_capacity(same.len() + other.len());
+    loop {
+        match (same.pop_front(), other.pop_front()) {
+            (None, None) => break,
+            (first, second) => {
+                ordered.extend(first);
+                ordered.extend(second);
+            }
+        }
+    }
+    ordered
+}
+
+/// Opens a TCP connection to `addr`, binding it to `local_addr` if given.
+///
+/// If `local_addr` is given it has to be of the same address family as
+/// `addr` (i.e. both IPv4 or both IPv6), if not an I/O error of the kind
+/// `InvalidInput` is returned without trying to connect.
+fn open_tcp_stream(addr: SocketAddr, local_addr: Option<IpAddr>)
+    -> impl Future<Item=TcpStream, Error=std_io::Error>
+{
+    match local_addr {
+        None => Either::A(TcpStream::connect(&addr)),
+        Some(local_addr) => match bound_std_stream(&addr, local_addr) {
+            Ok(std_stream) => Either::B(Either::A(
+                TcpStream::connect_std(std_stream, &addr, &Handle::default())
+            )),
+            Err(err) => Either::B(Either::B(future::err(err)))
+        }
+    }
+}
+
+/// Creates a (not yet connected) std tcp stream bound to given local address.
+fn bound_std_stream(addr: &SocketAddr, local_addr: IpAddr) -> Result<::std::net::TcpStream, std_io::Error> {
+    let builder = match (addr, local_addr) {
+        (&SocketAddr::V4(_), IpAddr::V4(_)) => TcpBuilder::new_v4()?,
+        (&SocketAddr::V6(_), IpAddr::V6(_)) => TcpBuilder::new_v6()?,
+        _ => return Err(std_io::Error::new(
+            std_io::ErrorKind::InvalidInput,
+            "local address and server address have different address families"
+        ))
+    };
+    builder.bind(SocketAddr::new(local_addr, 0))?;
+    builder.to_tcp_stream()
+}
+
+/// Creates the connector used for direct TLS, together with the domain to connect to.
+fn direct_tls_connector<S>(tls_config: TlsConfig<S>) -> Result<(TlsConnector, String), ConnectingFailed>
+    where S: SetupTls
+{
+    let TlsConfig { domain, setup } = tls_config;
+    match setup.setup(NativeTlsConnector::builder()) {
+        Ok(connector) => Ok((TlsConnector::from(connector), domain.as_str().to_owned())),
+        Err(err) => Err(ConnectingFailed::Io(tls_error(err)))
+    }
+}
+
+fn tls_error(err: ::native_tls::Error) -> std_io::Error {
+    std_io::Error::new(std_io::ErrorKind::Other, err)
+}
+
+/// Reads the greeting, failing if it doesn't arrive within the timeout.
+///
+/// A greeting exceeding the `ResponseLimits` makes it fail, too.
+pub(crate) fn read_greeting(socket: Socket, timeout: Option<Duration>, limits: ResponseLimits)
+    -> impl Future<Item=Connection, Error=ConnectingFailed>
+{
+    let fut = read_response(Io::from(socket), limits);
+    let fut = match timeout {
+        None => Either::A(fut),
+        Some(timeout) => Either::B(Timeout::new(fut, timeout).map_err(move |err| {
+            if err.is_elapsed() {
+                std_io::Error::new(std_io::ErrorKind::TimedOut, NoGreeting { timeout })
+            } else if let Some(err) = err.into_inner() {
+                err
+            } else {
+                std_io::Error::new(std_io::ErrorKind::Other, "timer failed")
+            }
+        }))
+    };
+
+    fut.map_err(|err| match response_too_large(&err) {
+            Some(err) => ConnectingFailed::Setup(LogicError::Custom(Box::new(err.compat()))),
+            None => ConnectingFailed::Io(err)
+        })
+        .and_then(|(io, greeting)| {
+            if reply_code(&greeting) == 220 {
+                Ok(Connection::from(io))
+            } else if greeting.is_erroneous() {
+                Err(ConnectingFailed::Setup(LogicError::Code(greeting)))
+            } else {
+                Err(ConnectingFailed::Setup(LogicError::UnexpectedCode(greeting)))
+            }
+        })
+}
+
+/// Fails with `MailSendError::ResponseTooLarge` (wrapped in a setup error)
+/// if a response received while setting up the connection exceeds the limits.
+fn check_setup_response_size(response: &Response, limits: ResponseLimits) -> Result<(), ConnectingFailed> {
+    limits.check(response)
+        .map_err(|err| ConnectingFailed::Setup(LogicError::Custom(Box::new(err.compat()))))
+}
+
+/// Checks the size of the (successful or failed) response to `EHLO`.
+fn check_ehlo_response_size(result: &Result<Response, LogicError>, limits: ResponseLimits)
+    -> Result<(), ConnectingFailed>
+{
+    let response = match *result {
+        Ok(ref response) => Some(response),
+        Err(ref err) => logic_error_response(err)
+    };
+    match response {
+        Some(response) => check_setup_response_size(response, limits),
+        None => Ok(())
+    }
+}
+
+/// Error used (wrapped in an I/O error) if the server didn't send a greeting in time.
+#[derive(Debug)]
+struct NoGreeting {
+    timeout: Duration
+}
+
+impl fmt::Display for NoGreeting {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.timeout.as_secs();
+        let millis = self.timeout.subsec_millis();
+        if millis == 0 {
+            write!(fter, "no greeting within {}s", secs)
+        } else {
+            write!(fter, "no greeting within {}.{:03}s", secs, millis)
+        }
+    }
+}
+
+impl StdError for NoGreeting {
+    fn description(&self) -> &str {
+        "no greeting within the greeting timeout"
+    }
+}
+
+/// Returns true if the error is caused by not receiving the greeting in time.
+pub(crate) fn is_greeting_timeout(err: &ConnectingFailed) -> bool {
+    match *err {
+        ConnectingFailed::Io(ref err) => err.get_ref().map(|inner| inner.is::<NoGreeting>()).unwrap_or(false),
+        _ => false
+    }
+}
+
+pub(crate) fn send_ehlo(
+    con: Connection,
+    client_id: ClientId,
+    limits: ResponseLimits,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=Connection, Error=ConnectingFailed> {
+    let fut = observed(con.send(Ehlo::new(client_id)), observer.as_ref(), SmtpCommand::Ehlo);
+    timed(fut, recorder.as_ref(), |timings| &mut timings.ehlo)
+        .map_err(ConnectingFailed::Io)
+        .and_then(move |(con, result)| {
+            check_ehlo_response_size(&result, limits)?;
+            match result {
+                Ok(_) => Ok(con),
+                Err(err) => Err(ConnectingFailed::Setup(err))
+            }
+        })
+}
+
+fn setup_starttls<S>(
+    con: Connection,
+    tls_config: TlsConfig<S>,
+    client_id: ClientId,
+    limits: ResponseLimits,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=Connection, Error=ConnectingFailed>
+    where S: SetupTls
+{
+    let TlsConfig { domain, setup } = tls_config;
+    let cmd = StartTls { setup_tls: setup, sni_domain: domain };
+
+    let fut = observed(con.send(cmd), observer.as_ref(), SmtpCommand::StartTls);
+    timed(fut, recorder.as_ref(), |timings| &mut timings.tls)
+        .map_err(ConnectingFailed::Io)
+        .and_then(|(con, result)| match result {
+            Ok(_) => Ok(con),
+            Err(err) => Err(ConnectingFailed::Setup(err))
+        })
+        .and_then(move |con| send_ehlo_after_starttls(con, client_id, limits, observer, recorder))
+}
+
+/// Uses `STARTTLS` if the server announces it, see `SendConfig::opportunistic_starttls`.
+///
+/// If the server doesn't announce it the connection continues unencrypted,
+/// unless the host is pinned in the cache. Then `QUIT` is send and it fails
+/// with an error wrapping `StartTlsDowngrade` (which `MailSendError::from`
+/// turns into `MailSendError::StartTlsDowngrade`).
+fn setup_opportunistic_starttls<S>(
+    con: Connection,
+    tls_config: TlsConfig<S>,
+    client_id: ClientId,
+    limits: ResponseLimits,
+    cache: Option<Arc<TlsHostCache>>,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=Connection, Error=ConnectingFailed>
+    where S: SetupTls
+{
+    let host = tls_config.domain.as_str().to_owned();
+    let announced = con.ehlo_data()
+        .map(|ehlo_data| ehlo_data.has_capability("STARTTLS"))
+        .unwrap_or(false);
+
+    if announced {
+        let fut = setup_starttls(con, tls_config, client_id, limits, observer, recorder)
+            .map(move |con| {
+                if let Some(cache) = cache {
+                    cache.pin(&host);
+                }
+                con
+            });
+        Either::A(fut)
+    } else if cache.map(|cache| cache.is_pinned(&host)).unwrap_or(false) {
+        let err = LogicError::Custom(Box::new(StartTlsDowngrade::new(host).compat()));
+        Either::B(Either::A(con.quit().then(move |_| Err(ConnectingFailed::Setup(err)))))
+    } else {
+        Either::B(Either::B(future::ok(con)))
+    }
+}
+
+/// Sends `EHLO` again after `STARTTLS`.
+///
+/// If the server rejects it `QUIT` is send (ignoring any error) and it fails
+/// with an error wrapping `EhloAfterStartTlsFailed`, as this is otherwise
+/// easily mistaken for a failed TLS setup.
+fn send_ehlo_after_starttls(
+    con: Connection,
+    client_id: ClientId,
+    limits: ResponseLimits,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=Connection, Error=ConnectingFailed> {
+    let fut = observed(con.send(Ehlo::new(client_id)), observer.as_ref(), SmtpCommand::Ehlo);
+    timed(fut, recorder.as_ref(), |timings| &mut timings.ehlo)
+        .map_err(ConnectingFailed::Io)
+        .and_then(move |(con, result)| match (check_ehlo_response_size(&result, limits), result) {
+            (Err(err), _) => Either::A(future::err(err)),
+            (Ok(()), Ok(_)) => Either::A(future::ok(con)),
+            (Ok(()), Err(err)) => {
+                let err = LogicError::Custom(Box::new(EhloAfterStartTlsFailed::new(err).compat()));
+                Either::B(con.quit().then(move |_| Err(ConnectingFailed::Setup(err))))
+            }
+        })
+}
+
+/// Sends the auth command, returning if the server confirmed the authentication.
+///
+/// Only a `235` reply means the connection was authenticated, "auth commands"
+/// which don't authenticate (like `Noop`) are answered differently.
+pub(crate) fn authenticate<A>(
+    con: Connection,
+    auth_cmd: A,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=(Connection, bool), Error=ConnectingFailed>
+    where A: Cmd
+{
+    let fut = observed(con.send(auth_cmd), observer.as_ref(), SmtpCommand::Auth);
+    timed(fut, recorder.as_ref(), |timings| &mut timings.auth)
+        .map_err(ConnectingFailed::Io)
+        .and_then(|(con, result)| match result {
+            Ok(response) => Ok((con, reply_code(&response) == 235)),
+            Err(err) => Err(ConnectingFailed::Auth(err))
+        })
+}
+
+/// Selects how a new connection is authenticated.
+///
+/// Connections to a MX are not authenticated, otherwise the credentials of
+/// the `credential_provider` are used if set, and the auth command if not.
+pub(crate) fn select_auth<A>(auth_cmd: A, config: &SendConfig) -> Option<Either<A, ProviderAuth>> {
+    match (config.send_target.is_mx(), config.credential_provider.as_ref()) {
+        (true, _) => None,
+        (false, Some(provider)) => Some(Either::B(ProviderAuth::new(provider.clone()))),
+        (false, None) => Some(Either::A(auth_cmd))
+    }
+}
+
+/// Authenticates using the auth selected by `select_auth`, returning if the connection was authenticated.
+pub(crate) fn authenticate_selected<A>(
+    con: Connection,
+    auth: Option<Either<A, ProviderAuth>>,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=(Connection, bool), Error=ConnectingFailed>
+    where A: Cmd
+{
+    match auth {
+        Some(Either::A(auth_cmd)) => Either::A(authenticate(con, auth_cmd, observer, recorder)),
+        Some(Either::B(auth_cmd)) => Either::B(Either::A(authenticate(con, auth_cmd, observer, recorder))),
+        None => Either::B(Either::B(future::ok((con, false))))
+    }
+}
+
+/// Sends the commands in order, failing on the first failing command.
+pub(crate) fn run_post_auth_cmds(con: Connection, cmds: Vec<BoxedCmd>)
+    -> impl Future<Item=Connection, Error=ConnectingFailed>
+{
+    future::loop_fn((con, cmds.into_iter()), |(con, mut cmds)| match cmds.next() {
+        None => Either::A(future::ok(Loop::Break(con))),
+        Some(cmd) => Either::B(con.send(cmd)
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |(con, result)| match result {
+                Ok(_) => Ok(Loop::Continue((con, cmds))),
+                Err(err) => Err(ConnectingFailed::Setup(err))
+            }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    mod run_post_auth_cmds {
+        use futures::Future;
+        use new_tokio_smtp::{Cmd, command::Noop};
+        use ::{
+            test_utils::{FakeServer, Reply, mock_envelop, run},
+            transaction::send_envelop
+        };
+        use super::super::run_post_auth_cmds;
+
+        #[test]
+        fn sends_commands_before_mail_transaction() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n")
+            ]);
+
+            let fut = run_post_auth_cmds(server.connection(), vec![Noop.boxed()])
+                .map_err(|err| panic!("unexpected error: {}", err))
+                .and_then(|con| send_envelop(con, mock_envelop(&["a@test.test"]), Default::default()));
+
+            let (_con, result) = run(fut).unwrap();
+            result.unwrap();
+            assert!(server.written().starts_with("NOOP\r\nMAIL FROM:"));
+        }
+
+        #[test]
+        fn fails_if_a_command_fails() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("500 Unknown command\r\n")
+            ]);
+
+            let fut = run_post_auth_cmds(server.connection(), vec![Noop.boxed(), Noop.boxed()]);
+            run(fut).unwrap_err();
+            assert_eq!(server.written(), "NOOP\r\n");
+        }
+    }
+
+    mod send_ehlo_after_starttls {
+        use new_tokio_smtp::{
+            ClientId, Domain,
+            error::{ConnectingFailed, LogicError}
+        };
+        use ::{
+            config::ResponseLimits,
+            error::MailSendError,
+            test_utils::{FakeServer, Reply, run}
+        };
+        use super::super::send_ehlo_after_starttls;
+
+        #[test]
+        fn rejected_ehlo_is_reported_and_quits() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("554 5.7.1 no service for you\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+
+            let err = run(send_ehlo_after_starttls(server.connection(), client_id, ResponseLimits::default(), None, None)).unwrap_err();
+
+            match err {
+                ConnectingFailed::Setup(LogicError::Custom(ref err)) => {
+                    assert!(err.to_string().starts_with("EHLO after STARTTLS failed"), "{}", err);
+                },
+                ref other => panic!("unexpected error: {:?}", other)
+            }
+            let err = MailSendError::from(err);
+            assert_eq!(err.enhanced_status(), Some((5, 7, 1)));
+            assert!(!err.is_transient());
+            let written = server.written();
+            assert!(written.starts_with("EHLO me.test"), "{}", written);
+            assert!(written.ends_with("\r\nQUIT\r\n"), "{}", written);
+        }
+    }
+
+    mod setup_opportunistic_starttls {
+        use std::{
+            collections::HashSet,
+            sync::{Arc, Mutex}
+        };
+        use futures::Future;
+        use new_tokio_smtp::{
+            ClientId, Domain, TlsConfig, DefaultTlsSetup, Connection,
+            command::Ehlo,
+            error::ConnectingFailed
+        };
+        use ::{
+            config::ResponseLimits,
+            error::MailSendError,
+            tls_pin::TlsHostCache,
+            test_utils::{FakeServer, Reply, run}
+        };
+        use super::super::setup_opportunistic_starttls;
+
+        fn connect_without_starttls(cache: Option<Arc<TlsHostCache>>) -> (Result<Connection, ConnectingFailed>, FakeServer) {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-mx.test.test greets you\r\n250 8BITMIME\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let tls_config = TlsConfig {
+                domain: Domain::from_unchecked("mx.test.test".to_owned()),
+                setup: DefaultTlsSetup
+            };
+            let fut = server.connection()
+                .send(Ehlo::new(client_id.clone()))
+                .map_err(ConnectingFailed::Io)
+                .and_then(move |(con, result)| {
+                    result.unwrap();
+                    setup_opportunistic_starttls(con, tls_config, client_id, ResponseLimits::default(), cache, None, None)
+                });
+            (run(fut), server)
+        }
+
+        #[test]
+        fn continues_unencrypted_for_unknown_hosts() {
+            let cache: Arc<Mutex<HashSet<String>>> = Default::default();
+            let (result, server) = connect_without_starttls(Some(cache.clone()));
+
+            result.unwrap();
+            assert!(!cache.is_pinned("mx.test.test"));
+            assert!(!server.written().contains("STARTTLS"));
+        }
+
+        #[test]
+        fn fails_for_pinned_hosts() {
+            let cache: Arc<Mutex<HashSet<String>>> = Default::default();
+            cache.pin("mx.test.test");
+            let (result, server) = connect_without_starttls(Some(cache));
+
+            match MailSendError::from(result.unwrap_err()) {
+                MailSendError::StartTlsDowngrade(ref downgrade) => assert_eq!(downgrade.host(), "mx.test.test"),
+                other => panic!("unexpected error: {:?}", other)
+            }
+            let written = server.written();
+            assert!(written.ends_with("\r\nQUIT\r\n"), "{}", written);
+            assert!(!written.contains("STARTTLS"));
+        }
+    }
+
+    mod mandatory_starttls {
+        use std::{
+            thread,
+            collections::HashSet,
+            io::{BufRead, BufReader, Write},
+            net::TcpListener,
+            sync::{Arc, Mutex}
+        };
+        use new_tokio_smtp::{Security, TlsConfig, DefaultTlsSetup, Domain};
+        use ::{
+            config::SendConfig,
+            test_utils::{con_config, run}
+        };
+        use super::super::connect;
+
+        #[test]
+        fn fails_if_not_announced_without_opt_in() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"220 test.test ready\r\n").unwrap();
+                let mut received = String::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    stream.write_all(b"250 test.test\r\n").unwrap();
+                    received.push_str(&line);
+                    line.clear();
+                }
+                received
+            });
+
+            let mut conconf = con_config(addr);
+            conconf.security = Security::StartTls(TlsConfig {
+                domain: Domain::from_unchecked("test.test".to_owned()),
+                setup: DefaultTlsSetup
+            });
+            // a cache alone doesn't make STARTTLS opportunistic
+            let mut config = SendConfig::default();
+            let cache: Arc<Mutex<HashSet<String>>> = Default::default();
+            config.tls_host_cache = Some(cache);
+
+            assert!(run(connect(conconf, &config)).is_err());
+            let received = server.join().unwrap();
+            assert!(!received.contains("NOOP"), "{}", received);
+        }
+    }
+
+    mod greeting_timeout {
+        use futures::Future;
+        use std::{
+            thread,
+            io::{BufRead, BufReader, Read, Write},
+            net::{TcpListener, SocketAddr},
+            time::Duration
+        };
+        use new_tokio_smtp::error::ConnectingFailed;
+        use ::{
+            config::SendConfig,
+            error::{MailSendError, TimeoutPhase},
+            test_utils::{con_config, run}
+        };
+        use super::super::connect;
+
+        /// Starts a server handling one connection per given entry.
+        ///
+        /// `None` never sends a greeting, `Some(delay)` sends the greeting
+        /// after the delay and then accepts `EHLO` and `NOOP` (auth).
+        fn spawn_server(connections: Vec<Option<Duration>>) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                for greeting_delay in connections {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let delay = match greeting_delay {
+                        Some(delay) => delay,
+                        None => {
+                            // wait until the client gives up
+                            let _ = stream.read_to_end(&mut Vec::new());
+                            continue;
+                        }
+                    };
+                    thread::sleep(delay);
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    stream.write_all(b"220 test.test ready\r\n").unwrap();
+                    reader.read_line(&mut line).unwrap();
+                    stream.write_all(b"250 test.test\r\n").unwrap();
+                    reader.read_line(&mut line).unwrap();
+                    stream.write_all(b"250 Ok\r\n").unwrap();
+                }
+            });
+            addr
+        }
+
+        fn config(greeting_timeout: Duration, greeting_retries: u32) -> SendConfig {
+            let mut config = SendConfig::default();
+            config.timeouts.greeting = Some(greeting_timeout);
+            config.greeting_retries = greeting_retries;
+            config
+        }
+
+        #[test]
+        fn delayed_greeting_within_timeout_succeeds() {
+            let addr = spawn_server(vec![Some(Duration::from_millis(50))]);
+            let fut = connect(con_config(addr), &config(Duration::from_secs(5), 0));
+            run(fut).unwrap();
+        }
+
+        #[test]
+        fn missing_greeting_fails_with_message() {
+            let addr = spawn_server(vec![None]);
+            let fut = connect(con_config(addr), &config(Duration::from_millis(50), 0));
+            match run(fut) {
+                Err(ConnectingFailed::Io(err)) => assert_eq!(err.to_string(), "no greeting within 0.050s"),
+                Err(err) => panic!("unexpected error: {:?}", err),
+                Ok(_) => panic!("connecting should fail")
+            }
+        }
+
+        #[test]
+        fn missing_greeting_is_reported_as_greeting_timeout() {
+            let addr = spawn_server(vec![None]);
+            let fut = connect(con_config(addr), &config(Duration::from_millis(50), 0))
+                .map_err(MailSendError::from);
+            match run(fut) {
+                Err(MailSendError::Timeout { phase: TimeoutPhase::Greeting }) => {},
+                Err(err) => panic!("unexpected error: {:?}", err),
+                Ok(_) => panic!("connecting should fail")
+            }
+        }
+
+        #[test]
+        fn retries_if_there_is_no_greeting() {
+            let addr = spawn_server(vec![None, Some(Duration::from_millis(0))]);
+            let fut = connect(con_config(addr), &config(Duration::from_millis(100), 1));
+            run(fut).unwrap();
+        }
+    }
+
+    mod response_limits {
+        use std::{
+            thread,
+            io::{BufRead, BufReader, Write},
+            net::{TcpListener, SocketAddr}
+        };
+        use new_tokio_smtp::error::{ConnectingFailed, LogicError};
+        use ::{
+            config::SendConfig,
+            test_utils::{con_config, run}
+        };
+        use super::super::connect;
+
+        /// Starts a server sending the given greeting and `EHLO` response.
+        fn spawn_server(greeting: &'static str, ehlo: &'static str) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                stream.write_all(greeting.as_bytes()).unwrap();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                stream.write_all(ehlo.as_bytes()).unwrap();
+                let _ = reader.read_line(&mut line);
+                let _ = stream.write_all(b"250 Ok\r\n");
+            });
+            addr
+        }
+
+        fn connect_with_max_lines(addr: SocketAddr, max_lines: usize) -> Result<(), ConnectingFailed> {
+            let mut config = SendConfig::default();
+            config.response_limits.max_lines = max_lines;
+            run(connect(con_config(addr), &config)).map(|_| ())
+        }
+
+        fn assert_too_large(result: Result<(), ConnectingFailed>) {
+            match result {
+                Err(ConnectingFailed::Setup(LogicError::Custom(ref err))) => {
+                    assert!(err.to_string().starts_with("server response too large"), "{}", err);
+                },
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn applies_to_the_greeting() {
+            let addr = spawn_server(
+                "220-test.test\r\n220-one\r\n220-two\r\n220 ready\r\n",
+                "250 test.test\r\n"
+            );
+            assert_too_large(connect_with_max_lines(addr, 3));
+        }
+
+        #[test]
+        fn stops_reading_an_endless_greeting() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut lines_sent = 0;
+                // only stops once the client closed the connection
+                while stream.write_all(b"220-more to come\r\n").is_ok() {
+                    lines_sent += 1;
+                }
+                lines_sent
+            });
+
+            assert_too_large(connect_with_max_lines(addr, 3));
+            assert!(server.join().unwrap() > 3);
+        }
+
+        #[test]
+        fn applies_to_the_ehlo_response() {
+            let addr = spawn_server(
+                "220 test.test ready\r\n",
+                "250-test.test\r\n250-8BITMIME\r\n250-SIZE 1000\r\n250 PIPELINING\r\n"
+            );
+            assert_too_large(connect_with_max_lines(addr, 3));
+        }
+
+        #[test]
+        fn responses_within_the_limits_are_accepted() {
+            let addr = spawn_server(
+                "220 test.test ready\r\n",
+                "250-test.test\r\n250 8BITMIME\r\n"
+            );
+            connect_with_max_lines(addr, 3).unwrap();
+        }
+    }
+
+    mod bound_std_stream {
+        use std::{io as std_io, net::{IpAddr, SocketAddr}};
+        use super::super::bound_std_stream;
+
+        #[test]
+        fn binds_to_the_local_address() {
+            let server: SocketAddr = "127.0.0.1:25".parse().unwrap();
+            let local_addr: IpAddr = "127.0.0.1".parse().unwrap();
+
+            let stream = bound_std_stream(&server, local_addr).unwrap();
+            assert_eq!(stream.local_addr().unwrap().ip(), local_addr);
+        }
+
+        #[test]
+        fn fails_if_address_families_differ() {
+            let server: SocketAddr = "[::1]:25".parse().unwrap();
+            let local_addr: IpAddr = "127.0.0.1".parse().unwrap();
+
+            let err = bound_std_stream(&server, local_addr).unwrap_err();
+            assert_eq!(err.kind(), std_io::ErrorKind::InvalidInput);
+        }
+    }
+
+    mod connect_attempts {
+        use std::{
+            io as std_io,
+            net::{SocketAddr, TcpListener},
+            sync::{
+                Arc, Mutex,
+                atomic::{AtomicBool, Ordering}
+            },
+            time::{Duration, Instant}
+        };
+        use futures::{Async, Future, Poll};
+        use tokio::net::TcpStream;
+        use ::test_utils::run;
+        use super::super::{AttemptFuture, ConnectAttempts, open_tcp_stream};
+
+        /// An attempt which is never answered, recording if it was dropped.
+        struct Unanswered(Arc<AtomicBool>);
+
+        impl Future for Unanswered {
+            type Item = TcpStream;
+            type Error = std_io::Error;
+
+            fn poll(&mut self) -> Poll<TcpStream, std_io::Error> {
+                Ok(Async::NotReady)
+            }
+        }
+
+        impl Drop for Unanswered {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        #[test]
+        fn second_address_wins_after_the_attempt_delay() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let reachable = listener.local_addr().unwrap();
+            let unanswered: SocketAddr = "10.0.0.1:25".parse().unwrap();
+            let delay = Duration::from_millis(100);
+            let dropped = Arc::new(AtomicBool::new(false));
+            let started = Arc::new(Mutex::new(Vec::new()));
+
+            let attempts = {
+                let (dropped, started) = (dropped.clone(), started.clone());
+                ConnectAttempts::with_opener(vec![unanswered, reachable], Some(delay), move |addr| -> AttemptFuture {
+                    started.lock().unwrap().push((addr, Instant::now()));
+                    if addr == unanswered {
+                        Box::new(Unanswered(dropped.clone()))
+                    } else {
+                        Box::new(open_tcp_stream(addr, None))
+                    }
+                })
+            };
+            // checked before `attempts` is dropped, so only `ConnectAttempts` can have dropped it
+            let loser_dropped = dropped.clone();
+            let (stream, loser_dropped) = run(attempts.map(move |stream| (stream, loser_dropped.load(Ordering::SeqCst)))).unwrap();
+
+            assert_eq!(stream.peer_addr().unwrap(), reachable);
+            let started = started.lock().unwrap();
+            assert_eq!(started.iter().map(|&(addr, _)| addr).collect::<Vec<_>>(), vec![unanswered, reachable]);
+            assert!(started[1].1.duration_since(started[0].1) >= delay);
+            assert!(loser_dropped, "the unanswered attempt wasn't cancelled");
+        }
+    }
+
+    mod select_addrs {
+        use std::{io as std_io, net::SocketAddr};
+        use new_tokio_smtp::error::ConnectingFailed;
+        use ::config::AddressFamilyPreference;
+        use super::super::select_addrs;
+
+        fn addr(raw: &str) -> SocketAddr {
+            raw.parse().unwrap()
+        }
+
+        /// Addresses as resolved from mixed A/AAAA records.
+        fn resolved() -> (SocketAddr, Vec<SocketAddr>) {
+            (addr("10.0.0.1:25"), vec![addr("[::1]:25"), addr("10.0.0.2:25"), addr("[::2]:25")])
+        }
+
+        fn select(preference: AddressFamilyPreference, interleave: bool) -> Vec<SocketAddr> {
+            let (primary, additional) = resolved();
+            select_addrs(primary, &additional, preference, interleave).unwrap()
+        }
+
+        #[test]
+        fn filters_by_address_family() {
+            assert_eq!(select(AddressFamilyPreference::V4Only, false), vec![addr("10.0.0.1:25"), addr("10.0.0.2:25")]);
+            assert_eq!(select(AddressFamilyPreference::V6Only, false), vec![addr("[::1]:25"), addr("[::2]:25")]);
+        }
+
+        #[test]
+        fn orders_preferred_family_first() {
+            assert_eq!(select(AddressFamilyPreference::PreferV6, false), vec![
+                addr("[::1]:25"), addr("[::2]:25"), addr("10.0.0.1:25"), addr("10.0.0.2:25")
+            ]);
+            assert_eq!(select(AddressFamilyPreference::PreferV6, true), vec![
+                addr("[::1]:25"), addr("10.0.0.1:25"), addr("[::2]:25"), addr("10.0.0.2:25")
+            ]);
+        }
+
+        #[test]
+        fn keeps_order_by_default() {
+            assert_eq!(select(AddressFamilyPreference::Any, false), vec![
+                addr("10.0.0.1:25"), addr("[::1]:25"), addr("10.0.0.2:25"), addr("[::2]:25")
+            ]);
+        }
+
+        #[test]
+        fn fails_without_address_of_required_family() {
+            match select_addrs(addr("10.0.0.1:25"), &[], AddressFamilyPreference::V6Only, false) {
+                Err(ConnectingFailed::Io(ref err)) => assert_eq!(err.kind(), std_io::ErrorKind::AddrNotAvailable),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    mod order_addrs {
+        use std::net::SocketAddr;
+        use super::super::order_addrs;
+
+        fn addr(raw: &str) -> SocketAddr {
+            raw.parse().unwrap()
+        }
+
+        #[test]
+        fn primary_address_comes_first_and_duplicates_are_removed() {
+            let ordered = order_addrs(addr("10.0.0.1:25"), &[addr("10.0.0.2:25"), addr("10.0.0.1:25")], false);
+            assert_eq!(ordered, vec![addr("10.0.0.1:25"), addr("10.0.0.2:25")]);
+        }
+
+        #[test]
+        fn keeps_order_without_interleaving() {
+            let additional = [addr("10.0.0.2:25"), addr("[::1]:25")];
+            let ordered = order_addrs(addr("10.0.0.1:25"), &additional, false);
+            assert_eq!(ordered, vec![addr("10.0.0.1:25"), addr("10.0.0.2:25"), addr("[::1]:25")]);
+        }
+
+        #[test]
+        fn interleaves_address_families() {
+            let additional = [addr("[::2]:25"), addr("10.0.0.1:25"), addr("10.0.0.2:25")];
+            let ordered = order_addrs(addr("[::1]:25"), &additional, true);
+            assert_eq!(ordered, vec![
+                addr("[::1]:25"), addr("10.0.0.1:25"),
+                addr("[::2]:25"), addr("10.0.0.2:25")
+            ]);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Module containing functions working with (already established) connections.
+use std::{
+    collections::BTreeMap,
+    time::Duration
+};
+
+use futures::future::Future;
+
+use new_tokio_smtp::{
+    Cmd, Connection, ConnectionConfig, EhloData, SetupTls,
+    command::Reset
+};
+
+use ::{
+    config::SendConfig,
+    connect::connect,
+    error::{MailSendError, TimeoutPhase},
+    timeout::with_timeout
+};
+
+/// Checks if a server is reachable and accepts the connection config.
+///
+/// This connects to the server in the same way sending mails does
+/// (i.e. including STARTTLS and AUTH), collects the capabilities the
+/// server announced into a `ConnectionReport` and then closes the
+/// connection using `QUIT`. No mail is send.
+///
+/// This is useful to check a relay before using it, or as readiness
+/// probe. If setting up the connection fails, the error is returned.
+///
+/// This uses the default `SendConfig`, use `check_connection_with`
+/// to use a custom configuration (e.g. to set a connect timeout).
+pub fn check_connection<A, S>(conconf: ConnectionConfig<A, S>)
+    -> impl Future<Item=ConnectionReport, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    check_connection_with(conconf, SendConfig::default())
+}
+
+/// Checks if a server is reachable using the given `SendConfig`.
+///
+/// This works like `check_connection`, but connects using the
+/// given config, e.g. applying the connect timeout.
+pub fn check_connection_with<A, S>(conconf: ConnectionConfig<A, S>, config: SendConfig)
+    -> impl Future<Item=ConnectionReport, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    with_timeout(connect(conconf, &config), config.timeouts.connect, TimeoutPhase::Connect)
+        .and_then(|con| {
+            let report = ConnectionReport::from_connection(&con);
+            // the check already succeeded, errors on quit don't matter
+            con.quit().then(move |_| Ok(report))
+        })
+}
+
+/// Checks if the server is ready to accept mails, e.g. as readiness probe.
+///
+/// This works like `check_connection`, but setting up the connection
+/// (including AUTH) has to finish within the given timeout, which
+/// should be short for e.g. a load balancer or Kubernetes readiness
+/// probe. If it doesn't a `MailSendError::Timeout` is returned.
+///
+/// See `ConnectionPool::readiness_check` for a version reusing the
+/// idle connections of a pool.
+pub fn readiness_check<A, S>(conconf: ConnectionConfig<A, S>, timeout: Duration)
+    -> impl Future<Item=(), Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    let mut config = SendConfig::default();
+    config.timeouts.connect = Some(timeout);
+    check_connection_with(conconf, config).map(|_report| ())
+}
+
+/// Report about a server returned by `check_connection`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConnectionReport {
+    server_domain: Option<String>,
+    capabilities: BTreeMap<String, Vec<String>>
+}
+
+impl ConnectionReport {
+
+    /// Creates a report using the EHLO data of the connection.
+    ///
+    /// If no EHLO was send the report is empty.
+    pub fn from_connection(con: &Connection) -> Self {
+        con.ehlo_data()
+            .map(Self::from_ehlo_data)
+            .unwrap_or_default()
+    }
+
+    fn from_ehlo_data(ehlo_data: &EhloData) -> Self {
+        let capabilities = ehlo_data.capability_map()
+            .iter()
+            .map(|(capability, params)| {
+                let params = params.iter().map(|param| param.as_str().to_owned()).collect();
+                (capability.as_str().to_uppercase(), params)
+            })
+            .collect();
+
+        ConnectionReport {
+            server_domain: Some(ehlo_data.domain().as_str().to_owned()),
+            capabilities
+        }
+    }
+
+    /// The domain the server used to identify itself in the EHLO response.
+    pub fn server_domain(&self) -> Option<&str> {
+        self.server_domain.as_ref().map(|domain| &**domain)
+    }
+
+    /// The capabilities (EHLO keywords) announced by the server with their parameters.
+    ///
+    /// The keywords are in upper case.
+    pub fn capabilities(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.capabilities
+    }
+
+    /// Returns true if the server announced the given capability (case insensitive).
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains_key(&capability.to_uppercase())
+    }
+
+    /// Returns the parameters of the given capability (case insensitive).
+    ///
+    /// E.g. `["PLAIN", "LOGIN"]` for `AUTH` if the server announced `AUTH PLAIN LOGIN`.
+    pub fn capability_params(&self, capability: &str) -> Option<&[String]> {
+        self.capabilities.get(&capability.to_uppercase())
+            .map(|params| &**params)
+    }
+}
+
+/// Checks if the connection is still usable by sending `RSET`.
+///
+/// Like all commands in `new-tokio-smtp` this takes the connection
+/// by value, it is returned together with the result of the check.
+///
+/// - If the server responds to `RSET` with a success code, the
+///   connection is returned together with `true`.
+/// - If the server responds with an error code (e.g. it's about to
+///   close the connection), the connection is returned together with
+///   `false`. It should not be reused in this case.
+/// - If an I/O error happens, the connection is broken and the
+///   error is returned.
+///
+/// `RSET` aborts any ongoing mail transaction, which means this should
+/// only be used between sending mails, e.g. before checking out a
+/// connection from a connection pool.
+pub fn probe_connection(con: Connection)
+    -> impl Future<Item=(Connection, bool), Error=MailSendError>
+{
+    con.send(Reset)
+        .map(|(con, result)| (con, result.is_ok()))
+        .map_err(MailSendError::from)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod probe_connection {
+        use ::test_utils::{FakeServer, Reply, run};
+        use super::super::probe_connection;
+
+        #[test]
+        fn healthy_connection_returns_true() {
+            let server = FakeServer::new(vec![Reply::Lines("250 Ok\r\n")]);
+            let (_con, is_usable) = run(probe_connection(server.connection())).unwrap();
+            assert_eq!(is_usable, true);
+            assert_eq!(server.written(), "RSET\r\n");
+        }
+
+        #[test]
+        fn error_response_returns_false() {
+            let server = FakeServer::new(vec![Reply::Lines("421 Service not available\r\n")]);
+            let (_con, is_usable) = run(probe_connection(server.connection())).unwrap();
+            assert_eq!(is_usable, false);
+        }
+
+        #[test]
+        fn broken_connection_returns_error() {
+            let server = FakeServer::new(vec![]);
+            run(probe_connection(server.connection())).unwrap_err();
+        }
+    }
+
+    mod readiness_check {
+        use std::{
+            net::TcpListener,
+            time::Duration
+        };
+        use ::test_utils::{con_config, run, spawn_smtp_server};
+        use super::super::readiness_check;
+
+        #[test]
+        fn reachable_server_is_ready() {
+            let addr = spawn_smtp_server();
+            run(readiness_check(con_config(addr), Duration::from_secs(5))).unwrap();
+        }
+
+        #[test]
+        fn unreachable_server_is_not_ready() {
+            let addr = {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                listener.local_addr().unwrap()
+            };
+            run(readiness_check(con_config(addr), Duration::from_secs(5))).unwrap_err();
+        }
+    }
+
+    mod connection_report {
+        use futures::Future;
+        use new_tokio_smtp::{ClientId, Domain, command::Ehlo};
+        use ::test_utils::{FakeServer, Reply, run};
+        use super::super::ConnectionReport;
+
+        #[test]
+        fn collects_announced_capabilities() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-mx.test.test greets you\r\n250-SIZE 1000\r\n250-auth PLAIN LOGIN\r\n250 8BITMIME\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let fut = server.connection()
+                .send(Ehlo::new(client_id))
+                .map(|(con, result)| {
+                    result.unwrap();
+                    ConnectionReport::from_connection(&con)
+                });
+
+            let report = run(fut).unwrap();
+            assert_eq!(report.server_domain(), Some("mx.test.test"));
+            assert!(report.has_capability("8bitmime"));
+            assert!(!report.has_capability("SMTPUTF8"));
+            assert_eq!(report.capability_params("SIZE").unwrap(), &["1000".to_owned()]);
+            assert_eq!(report.capability_params("AUTH").unwrap(), &["PLAIN".to_owned(), "LOGIN".to_owned()]);
+        }
+
+        #[test]
+        fn is_empty_without_ehlo() {
+            let server = FakeServer::new(vec![]);
+            let report = ConnectionReport::from_connection(&server.connection());
+            assert_eq!(report, ConnectionReport::default());
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Module implementing authentication using credentials fetched when connecting.
+use std::{
+    fmt,
+    error::Error as StdError,
+    sync::{Arc, Mutex}
+};
+
+use futures::{
+    IntoFuture,
+    future::{self, Future}
+};
+
+use failure::Fail;
+use new_tokio_smtp::{
+    Cmd, Io, ExecFuture, EhloData,
+    error::{LogicError, MissingCapabilities}
+};
+
+use ::{
+    auto_auth::auto,
+    error::CredentialsUnavailable,
+    oauth::xoauth2
+};
+
+/// Credentials used to authenticate a connection.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Username and password, the mechanism is chosen like `auth::auto` does.
+    Password {
+        username: String,
+        password: String
+    },
+
+    /// Username and OAuth 2.0 bearer token, used with `AUTH XOAUTH2`.
+    OAuthToken {
+        username: String,
+        token: String
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        // never log secrets
+        match *self {
+            Credentials::Password { ref username, .. } => fter.debug_struct("Password")
+                .field("username", username)
+                .finish(),
+            Credentials::OAuthToken { ref username, .. } => fter.debug_struct("OAuthToken")
+                .field("username", username)
+                .finish()
+        }
+    }
+}
+
+impl Credentials {
+    fn exec(self, io: Io) -> ExecFuture {
+        match self {
+            Credentials::Password { username, password } => auto(username, password).exec(io),
+            Credentials::OAuthToken { username, token } =>
+                xoauth2(username, move || Ok(token.clone())).exec(io)
+        }
+    }
+}
+
+/// Future returned by a `CredentialProvider`.
+pub type CredentialsFuture = Box<Future<Item=Credentials, Error=Box<StdError + Send + Sync>> + Send>;
+type Fetch = Box<FnMut() -> CredentialsFuture + Send>;
+
+/// Provides the credentials used to authenticate each new connection.
+///
+/// Set it using `SendConfig::credential_provider` to authenticate connections
+/// with credentials fetched when connecting (e.g. from a secret manager like
+/// Vault) instead of the `auth_cmd` of the `ConnectionConfig`. This way rotated
+/// secrets are picked up without rebuilding the connection config.
+///
+/// If the returned future fails, setting up the connection fails with
+/// `MailSendError::Connecting` (an auth error wrapping `CredentialsUnavailable`)
+/// without sending any `AUTH` command.
+///
+/// It's implemented for all `Fn` closures which are `Send + Sync` and return
+/// something convertible into a future resolving to the `Credentials`.
+pub trait CredentialProvider: Send + Sync {
+    /// Fetches the credentials for a new connection.
+    fn credentials(&self) -> CredentialsFuture;
+}
+
+impl<F, R> CredentialProvider for F
+    where F: Fn() -> R + Send + Sync,
+          R: IntoFuture<Item=Credentials>,
+          R::Future: Send + 'static,
+          R::Error: Into<Box<StdError + Send + Sync>>
+{
+    fn credentials(&self) -> CredentialsFuture {
+        Box::new((self)().into_future().map_err(Into::into))
+    }
+}
+
+impl fmt::Debug for CredentialProvider {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("CredentialProvider { .. }")
+    }
+}
+
+/// Auth command using the credentials of a `CredentialProvider`.
+#[derive(Clone)]
+pub(crate) struct ProviderAuth {
+    provider: Arc<CredentialProvider>
+}
+
+impl ProviderAuth {
+    pub(crate) fn new(provider: Arc<CredentialProvider>) -> Self {
+        ProviderAuth { provider }
+    }
+}
+
+impl Cmd for ProviderAuth {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        // the mechanism is only known once the credentials are fetched
+        Ok(())
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        authenticate_with(self.provider.credentials(), io)
+    }
+}
+
+/// Authenticates using the credentials once they are fetched.
+fn authenticate_with(fetching: CredentialsFuture, io: Io) -> ExecFuture {
+    let fut = fetching.then(move |result| match result {
+        Ok(credentials) => credentials.exec(io),
+        Err(err) => {
+            let err = LogicError::Custom(Box::new(CredentialsUnavailable::new(err).compat()));
+            Box::new(future::ok((io, Err(err)))) as ExecFuture
+        }
+    });
+
+    Box::new(fut)
+}
+
+/// Creates an auth command fetching the credentials each time a connection is set up.
+///
+/// This is meant for credentials which rotate (e.g. short-lived tokens), the
+/// callback is called each time the command is executed and the connection is
+/// authenticated using the returned credentials. As reconnecting (e.g. in
+/// `send_batch_resilient`) uses a clone of the command, it fetches fresh
+/// credentials, too. If fetching the credentials fails, authenticating fails
+/// with the error (wrapped in a `CredentialsUnavailable` error).
+///
+/// To use fetched credentials independent of the `ConnectionConfig` see
+/// `CredentialProvider`.
+pub fn from_callback<F, R>(fetch: F) -> CallbackAuth
+    where F: FnMut() -> R + Send + 'static,
+          R: IntoFuture<Item=Credentials>,
+          R::Future: Send + 'static,
+          R::Error: Into<Box<StdError + Send + Sync>>
+{
+    let mut fetch = fetch;
+    let fetch: Fetch = Box::new(move || -> CredentialsFuture {
+        Box::new(fetch().into_future().map_err(Into::into))
+    });
+    CallbackAuth { fetch: Arc::new(Mutex::new(fetch)) }
+}
+
+/// Auth command using credentials fetched by a callback, see `from_callback`.
+#[derive(Clone)]
+pub struct CallbackAuth {
+    fetch: Arc<Mutex<Fetch>>
+}
+
+impl fmt::Debug for CallbackAuth {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("CallbackAuth { .. }")
+    }
+}
+
+impl Cmd for CallbackAuth {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        // the mechanism is only known once the credentials are fetched
+        Ok(())
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        let fetching = {
+            let mut fetch = self.fetch.lock().expect("[BUG] credentials callback panicked");
+            (fetch)()
+        };
+        authenticate_with(fetching, io)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod from_callback {
+        use std::{
+            io as std_io,
+            sync::{
+                Arc,
+                atomic::{AtomicUsize, Ordering}
+            }
+        };
+        use base64;
+        use ::test_utils::{FakeServer, Reply, run};
+        use super::super::{Credentials, from_callback};
+
+        #[test]
+        fn each_connection_uses_fresh_credentials() {
+            let fetched = Arc::new(AtomicUsize::new(0));
+            let auth = {
+                let fetched = fetched.clone();
+                from_callback(move || {
+                    let nr = fetched.fetch_add(1, Ordering::SeqCst) + 1;
+                    Ok::<_, std_io::Error>(Credentials::OAuthToken {
+                        username: "me@test.test".to_owned(),
+                        token: format!("token{}", nr)
+                    })
+                })
+            };
+
+            for nr in 1..3 {
+                let server = FakeServer::new(vec![Reply::Lines("235 2.7.0 Accepted\r\n")]);
+                let (_con, result) = run(server.connection().send(auth.clone())).unwrap();
+                result.unwrap();
+
+                let written = server.written();
+                let encoded = written.trim_end().trim_start_matches("AUTH XOAUTH2 ");
+                let decoded = String::from_utf8(base64::decode(encoded).unwrap()).unwrap();
+                assert_eq!(decoded, format!("user=me@test.test\x01auth=Bearer token{}\x01\x01", nr));
+            }
+            assert_eq!(fetched.load(Ordering::SeqCst), 2);
+        }
+
+        #[test]
+        fn fails_without_sending_anything_if_fetching_fails() {
+            let server = FakeServer::new(vec![]);
+            let auth = from_callback(|| Err::<Credentials, _>(std_io::Error::new(std_io::ErrorKind::Other, "vault down")));
+            let (_con, result) = run(server.connection().send(auth)).unwrap();
+
+            assert!(result.is_err());
+            assert_eq!(server.written(), "");
+        }
+    }
+
+    mod provider_auth {
+        use std::{io as std_io, sync::Arc};
+        use futures::Future;
+        use new_tokio_smtp::error::{ConnectingFailed, LogicError};
+        use ::{
+            connect::authenticate,
+            error::MailSendError,
+            test_utils::{FakeServer, Reply, run}
+        };
+        use super::super::{Credentials, CredentialProvider, ProviderAuth};
+
+        #[test]
+        fn authenticates_using_the_provided_credentials() {
+            let provider: Arc<CredentialProvider> = Arc::new(|| Ok::<_, std_io::Error>(Credentials::OAuthToken {
+                username: "me@test.test".to_owned(),
+                token: "rotated".to_owned()
+            }));
+            let server = FakeServer::new(vec![Reply::Lines("235 2.7.0 Accepted\r\n")]);
+            let (_con, authenticated) = run(authenticate(server.connection(), ProviderAuth::new(provider), None, None))
+                .unwrap();
+
+            assert!(authenticated);
+            assert!(server.written().starts_with("AUTH XOAUTH2 "));
+        }
+
+        #[test]
+        fn failing_provider_makes_connecting_fail() {
+            let provider: Arc<CredentialProvider> = Arc::new(|| {
+                Err::<Credentials, _>(std_io::Error::new(std_io::ErrorKind::Other, "vault sealed"))
+            });
+            let server = FakeServer::new(vec![]);
+            let fut = authenticate(server.connection(), ProviderAuth::new(provider), None, None)
+                .map_err(MailSendError::from);
+
+            match run(fut) {
+                Err(MailSendError::Connecting(ConnectingFailed::Auth(LogicError::Custom(err)))) => {
+                    assert_eq!(err.to_string(), "fetching the credentials failed: vault sealed");
+                },
+                Err(err) => panic!("unexpected error: {:?}", err),
+                Ok(_) => panic!("authenticating should fail")
+            }
+            assert_eq!(server.written(), "");
+        }
+    }
+}
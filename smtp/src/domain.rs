@@ -0,0 +1,170 @@
+//! Module containing a validating wrapper around `new-tokio-smtp`'s `Domain`.
+use std::{
+    fmt,
+    ops::Deref,
+    str::FromStr
+};
+
+use new_tokio_smtp::Domain;
+use headers::header_components::Domain as HeaderDomain;
+
+use ::error::InvalidDomain;
+
+/// The maximal length of a domain (RFC 1035 limits names to 255 octets
+/// in wire format, which leaves 253 characters in text form).
+const MAX_DOMAIN_LEN: usize = 253;
+/// The maximal length of a single label of a domain (RFC 1035).
+const MAX_LABEL_LEN: usize = 63;
+
+/// A syntactically valid domain which derefs to `new-tokio-smtp`'s `Domain`.
+///
+/// `Domain` can only be created using `Domain::from_unchecked`, which
+/// doesn't validate the domain at all. `DomainName` implements `FromStr`
+/// validating that the domain matches the `Domain` grammar of RFC 5321
+/// (labels of ASCII letters, digits and `-`, not starting or ending with
+/// a `-`, separated by `.`) and the length limits of RFC 1035.
+///
+/// Internationalized domain names have to be given in their ASCII
+/// (punycode) form.
+///
+/// ```
+/// # use mail_smtp::misc::{ClientId, Domain, DomainName};
+/// let domain: DomainName = "mail.example.com".parse().unwrap();
+/// let client_id = ClientId::Domain(domain.into());
+///
+/// assert!("not a domain".parse::<DomainName>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DomainName(Domain);
+
+impl DomainName {
+
+    /// Returns the validated domain.
+    pub fn into_inner(self) -> Domain {
+        self.0
+    }
+}
+
+impl FromStr for DomainName {
+    type Err = InvalidDomain;
+
+    fn from_str(domain: &str) -> Result<Self, InvalidDomain> {
+        validate_domain(domain)?;
+        Ok(DomainName(Domain::from_unchecked(domain.to_owned())))
+    }
+}
+
+impl Deref for DomainName {
+    type Target = Domain;
+
+    fn deref(&self) -> &Domain {
+        &self.0
+    }
+}
+
+impl AsRef<Domain> for DomainName {
+    fn as_ref(&self) -> &Domain {
+        &self.0
+    }
+}
+
+impl From<DomainName> for Domain {
+    fn from(domain: DomainName) -> Self {
+        domain.0
+    }
+}
+
+/// Allows using a parsed domain e.g. for the `simple_context` of `mail-core`.
+impl From<DomainName> for HeaderDomain {
+    fn from(domain: DomainName) -> Self {
+        HeaderDomain::from_unchecked(domain.0.as_str().to_owned())
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str(self.0.as_str())
+    }
+}
+
+fn validate_domain(domain: &str) -> Result<(), InvalidDomain> {
+    if domain.is_empty() {
+        return Err(InvalidDomain::Empty);
+    }
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err(InvalidDomain::TooLong(domain.to_owned()));
+    }
+    for label in domain.split('.') {
+        if !is_valid_label(label) {
+            return Err(InvalidDomain::InvalidLabel(label.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// sub-domain = Let-dig [Ldh-str]
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.bytes().all(|bch| bch.is_ascii_alphanumeric() || bch == b'-')
+}
+
+#[cfg(test)]
+mod test {
+
+    mod domain_name {
+        use ::error::InvalidDomain;
+        use super::super::DomainName;
+
+        #[test]
+        fn parses_valid_domains() {
+            for domain in &["example.com", "a.b-c.d", "localhost", "123.example", "xn--jx-yka.test"] {
+                let parsed: DomainName = domain.parse().unwrap();
+                assert_eq!(parsed.as_str(), *domain);
+            }
+        }
+
+        #[test]
+        fn rejects_empty_domain() {
+            assert_eq!("".parse::<DomainName>(), Err(InvalidDomain::Empty));
+        }
+
+        #[test]
+        fn rejects_invalid_labels() {
+            let cases = vec![
+                ("example..com", ""),
+                ("example.com.", ""),
+                ("-example.com", "-example"),
+                ("example-.com", "example-"),
+                ("exa mple.com", "exa mple"),
+                ("exa_mple.com", "exa_mple"),
+                ("jö.test", "jö")
+            ];
+            for (domain, label) in cases {
+                assert_eq!(
+                    domain.parse::<DomainName>(),
+                    Err(InvalidDomain::InvalidLabel(label.to_owned())),
+                    "domain: {}", domain
+                );
+            }
+        }
+
+        #[test]
+        fn rejects_too_long_domains_and_labels() {
+            let long_label = "a".repeat(64);
+            assert_eq!(
+                format!("{}.com", long_label).parse::<DomainName>(),
+                Err(InvalidDomain::InvalidLabel(long_label))
+            );
+
+            let long_domain = vec!["abcdefghi"; 26].join(".");
+            assert_eq!(long_domain.len(), 259);
+            assert_eq!(
+                long_domain.parse::<DomainName>(),
+                Err(InvalidDomain::TooLong(long_domain.clone()))
+            );
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Module containing helpers for creating and sending delivery status notifications (bounces).
+use futures::{future, Future};
+use vec1::Vec1;
+
+use mail::{
+    Context, Mail, Resource,
+    file_buffer::FileBuffer,
+    error::MailError
+};
+use headers::{
+    headers::{_From, _To, Subject},
+    header_components::MediaType
+};
+use new_tokio_smtp::{
+    Cmd, SetupTls, ConnectionConfig,
+    send_mail::{MailAddress, EnvelopData}
+};
+
+use ::{
+    config::SendConfig,
+    domain::DomainName,
+    error::{MailSendError, RecipientRejection},
+    reply::parse_enhanced_status,
+    request::{MailRequest, AutoSubmitted},
+    response::MailResponse,
+    send_mail::send_with
+};
+
+/// A delivery status notification (RFC 3464), i.e. a bounce message.
+///
+/// It's created as `multipart/report; report-type=delivery-status`
+/// (RFC 6522) mail containing:
+///
+/// 1. a human readable explanation (`text/plain`)
+/// 2. the machine readable status of each recipient (`message/delivery-status`)
+/// 3. optionally the returned mail (`message/rfc822`) or only its
+///    headers (`text/rfc822-headers`)
+///
+/// The mail is marked as `Auto-Submitted: auto-replied` (RFC 3834) and
+/// is send with a null reverse path (`MAIL FROM:<>`) so that it can
+/// not cause another bounce, see `to_request` and `send_dsn`.
+#[derive(Debug, Clone)]
+pub struct DeliveryStatusNotification {
+    reporting_mta: DomainName,
+    from: MailAddress,
+    to: MailAddress,
+    subject: String,
+    explanation: String,
+    recipients: Vec<RecipientStatus>,
+    returned: Option<Returned>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Returned {
+    Mail(Vec<u8>),
+    Headers(Vec<u8>)
+}
+
+impl DeliveryStatusNotification {
+
+    /// Creates a new notification send by `from` (e.g. `MAILER-DAEMON@example.com`) to `to`.
+    ///
+    /// `to` is normally the reverse path of the mail the notification is
+    /// about. `reporting_mta` is the MTA which attempted the delivery
+    /// and is used for the `Reporting-MTA` field.
+    pub fn new(reporting_mta: DomainName, from: MailAddress, to: MailAddress) -> Self {
+        DeliveryStatusNotification {
+            reporting_mta,
+            from,
+            to,
+            subject: "Undelivered Mail Returned to Sender".to_owned(),
+            explanation: "Your message could not be delivered to one or more recipients.".to_owned(),
+            recipients: Vec::new(),
+            returned: None
+        }
+    }
+
+    /// Sets the subject, line breaks are replaced by spaces.
+    ///
+    /// A non ASCII subject is encoded using encoded words (RFC 2047).
+    pub fn with_subject(mut self, subject: &str) -> Self {
+        self.subject = subject.lines().collect::<Vec<_>>().join(" ");
+        self
+    }
+
+    /// Sets the human readable explanation.
+    pub fn with_explanation(mut self, explanation: &str) -> Self {
+        self.explanation = explanation.to_owned();
+        self
+    }
+
+    /// Adds the status of a recipient.
+    pub fn add_recipient(&mut self, status: RecipientStatus) {
+        self.recipients.push(status);
+    }
+
+    /// Adds the status of a recipient.
+    pub fn with_recipient(mut self, status: RecipientStatus) -> Self {
+        self.add_recipient(status);
+        self
+    }
+
+    /// Returns the complete (already encoded) mail as part of the notification.
+    pub fn with_returned_mail(mut self, raw_mail: Vec<u8>) -> Self {
+        self.returned = Some(Returned::Mail(raw_mail));
+        self
+    }
+
+    /// Returns only the header section of the (already encoded) mail.
+    ///
+    /// The header section ends at the first empty line. If there is none
+    /// it ends before the first line which is neither a header field nor
+    /// the continuation of one, so the body is never returned.
+    pub fn with_returned_headers(mut self, raw_mail: &[u8]) -> Self {
+        let end = header_section_len(raw_mail);
+        self.returned = Some(Returned::Headers(raw_mail[..end].to_vec()));
+        self
+    }
+
+    /// The recipient statuses added so far.
+    pub fn recipients(&self) -> &[RecipientStatus] {
+        &self.recipients
+    }
+
+    /// The envelop to send the notification with.
+    ///
+    /// It has a null reverse path and `to` as only recipient.
+    pub fn envelop_data(&self) -> EnvelopData {
+        EnvelopData {
+            from: None,
+            to: Vec1::new(self.to.clone())
+        }
+    }
+
+    /// Creates the request for sending the notification.
+    ///
+    /// It uses the envelop returned by `envelop_data`, is marked as
+    /// `Auto-Submitted: auto-replied` and gets a `Date` and `Message-ID`
+    /// header (using the domain of the context) when it's encoded.
+    ///
+    /// Fails if the `From`, `To` or `Subject` header can't be created.
+    pub fn to_request(&self) -> Result<MailRequest, MailSendError> {
+        let mut parts = vec![
+            Mail::plain_text(normalize_text(&self.explanation).as_str()),
+            singlepart("message/delivery-status", self.encode_status().into_bytes())
+        ];
+        match self.returned {
+            Some(Returned::Mail(ref raw)) => parts.push(singlepart("message/rfc822", raw.clone())),
+            Some(Returned::Headers(ref raw)) => parts.push(singlepart("text/rfc822-headers", raw.clone())),
+            None => {}
+        }
+
+        let mut mail = Mail::new_multipart_mail(media_type("multipart/report; report-type=delivery-status"), parts);
+        let headers = headers! {
+            _From: [self.from.as_str()],
+            _To: [self.to.as_str()],
+            Subject: self.subject.as_str()
+        }.map_err(MailError::from)?;
+        mail.insert_headers(headers);
+
+        let mut request = MailRequest::new_with_envelop(mail, self.envelop_data());
+        request.mark_auto_submitted(AutoSubmitted::AutoReplied);
+        request.set_auto_date(true);
+        request.set_auto_message_id(true);
+        Ok(request)
+    }
+
+    fn encode_status(&self) -> String {
+        let mut status = format!("Reporting-MTA: dns; {}\r\n", self.reporting_mta);
+        for recipient in self.recipients.iter() {
+            status.push_str("\r\n");
+            recipient.encode(&mut status);
+        }
+        status
+    }
+}
+
+/// Sends the notification with a null reverse path (`MAIL FROM:<>`).
+///
+/// The notification is encoded using the context, see `to_request`.
+pub fn send_dsn<A, S>(
+    dsn: &DeliveryStatusNotification,
+    conconf: ConnectionConfig<A, S>,
+    ctx: impl Context,
+    config: SendConfig
+) -> impl Future<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    future::result(dsn.to_request())
+        .and_then(move |request| send_with(request, conconf, ctx, config))
+}
+
+/// The status of a single recipient in a `DeliveryStatusNotification`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientStatus {
+    recipient: MailAddress,
+    action: DsnAction,
+    status: (u8, u16, u16),
+    diagnostic: Option<String>
+}
+
+impl RecipientStatus {
+
+    /// Creates a status for the recipient given the action and the enhanced status code (e.g. `(5, 1, 1)`).
+    pub fn new(recipient: MailAddress, action: DsnAction, status: (u8, u16, u16)) -> Self {
+        RecipientStatus { recipient, action, status, diagnostic: None }
+    }
+
+    /// Creates a `Failed` status from the rejection of a recipient.
+    ///
+    /// The status code is taken from the enhanced status code of the
+    /// response if there is one and is `5.0.0`/`4.0.0` otherwise. The
+    /// response is used as diagnostic code.
+    pub fn from_rejection(rejection: &RecipientRejection) -> Self {
+        let code = rejection.code();
+        let lines = rejection.response().msg();
+        let status = parse_enhanced_status(lines)
+            .unwrap_or(((code / 100) as u8, 0, 0));
+        let diagnostic = format!("{} {}", code, lines.join(" "));
+        RecipientStatus::new(rejection.recipient().clone(), DsnAction::Failed, status)
+            .with_diagnostic(diagnostic.trim())
+    }
+
+    /// Sets the diagnostic code (send as `smtp` type), e.g. `550 5.1.1 unknown user`.
+    ///
+    /// Line breaks are replaced by spaces.
+    pub fn with_diagnostic(mut self, diagnostic: &str) -> Self {
+        self.diagnostic = Some(diagnostic.lines().collect::<Vec<_>>().join(" "));
+        self
+    }
+
+    /// The recipient the status is about.
+    pub fn recipient(&self) -> &MailAddress {
+        &self.recipient
+    }
+
+    /// The action performed for the recipient.
+    pub fn action(&self) -> DsnAction {
+        self.action
+    }
+
+    /// The enhanced status code, e.g. `(5, 1, 1)`.
+    pub fn status(&self) -> (u8, u16, u16) {
+        self.status
+    }
+
+    fn encode(&self, out: &mut String) {
+        let (class, subject, detail) = self.status;
+        out.push_str(&format!(
+            "Final-Recipient: rfc822; {}\r\nAction: {}\r\nStatus: {}.{}.{}\r\n",
+            self.recipient.as_str(), self.action.as_str(), class, subject, detail
+        ));
+        if let Some(diagnostic) = self.diagnostic.as_ref() {
+            out.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", diagnostic));
+        }
+    }
+}
+
+impl<'a> From<&'a RecipientRejection> for RecipientStatus {
+    fn from(rejection: &'a RecipientRejection) -> Self {
+        RecipientStatus::from_rejection(rejection)
+    }
+}
+
+/// The action performed for a recipient (RFC 3464, section 2.3.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DsnAction {
+    /// The mail could not be delivered.
+    Failed,
+    /// Delivery is delayed but will be retried.
+    Delayed,
+    /// The mail was delivered.
+    Delivered,
+    /// The mail was relayed to a system not supporting DSNs.
+    Relayed,
+    /// The mail was delivered and forwarded to multiple other recipients.
+    Expanded
+}
+
+impl DsnAction {
+
+    /// The value of the `Action` field, e.g. `failed`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DsnAction::Failed => "failed",
+            DsnAction::Delayed => "delayed",
+            DsnAction::Delivered => "delivered",
+            DsnAction::Relayed => "relayed",
+            DsnAction::Expanded => "expanded"
+        }
+    }
+}
+
+fn media_type(media_type: &str) -> MediaType {
+    MediaType::parse(media_type).expect("[BUG] static media type is invalid")
+}
+
+fn singlepart(content_type: &str, data: Vec<u8>) -> Mail {
+    let buffer = FileBuffer::new(media_type(content_type), data);
+    Mail::new_singlepart_mail(Resource::sourceless_from_buffer(buffer))
+}
+
+/// Normalizes line breaks to `\r\n` (including a trailing one).
+fn normalize_text(text: &str) -> String {
+    let mut normalized = text.lines()
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    normalized.push_str("\r\n");
+    normalized
+}
+
+/// Returns the length of the header section of the raw mail, including the line break of the last header.
+fn header_section_len(raw_mail: &[u8]) -> usize {
+    let mut len = 0;
+    for line in raw_mail.split(|byte| *byte == b'\n') {
+        let content = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+        let is_continuation = content.starts_with(b" ") || content.starts_with(b"\t");
+        let is_field = content.iter()
+            .position(|byte| *byte == b':')
+            .map(|colon| colon > 0 && !content[..colon].iter().any(|byte| byte.is_ascii_whitespace()))
+            .unwrap_or(false);
+        if content.is_empty() || !(is_field || (is_continuation && len > 0)) {
+            break;
+        }
+        len = (len + line.len() + 1).min(raw_mail.len());
+    }
+    len
+}
+
+#[cfg(test)]
+mod test {
+
+    mod delivery_status_notification {
+        use futures::Future;
+        use new_tokio_smtp::send_mail::MailAddress;
+        use ::{
+            config::SendConfig,
+            test_utils::{con_config, run, spawn_smtp_server_for, test_context}
+        };
+        use super::super::{DeliveryStatusNotification, RecipientStatus, DsnAction, Returned, send_dsn};
+
+        fn address(address: &str) -> MailAddress {
+            MailAddress::new_unchecked(address.to_owned(), false)
+        }
+
+        fn dsn() -> DeliveryStatusNotification {
+            DeliveryStatusNotification::new(
+                "mx.example.com".parse().unwrap(),
+                address("MAILER-DAEMON@example.com"),
+                address("sender@example.com")
+            )
+            .with_recipient(
+                RecipientStatus::new(address("nobody@example.org"), DsnAction::Failed, (5, 1, 1))
+                    .with_diagnostic("550 5.1.1 unknown user")
+            )
+        }
+
+        #[test]
+        fn is_send_with_null_reverse_path() {
+            let envelop = dsn().envelop_data();
+            assert!(envelop.from.is_none());
+            assert_eq!(envelop.to.len(), 1);
+            assert_eq!(envelop.to.first().as_str(), "sender@example.com");
+        }
+
+        #[test]
+        fn encodes_the_status_of_each_recipient() {
+            assert_eq!(
+                dsn().encode_status(),
+                "Reporting-MTA: dns; mx.example.com\r\n\
+                 \r\n\
+                 Final-Recipient: rfc822; nobody@example.org\r\n\
+                 Action: failed\r\n\
+                 Status: 5.1.1\r\n\
+                 Diagnostic-Code: smtp; 550 5.1.1 unknown user\r\n"
+            );
+        }
+
+        #[test]
+        fn returns_only_the_headers_if_requested() {
+            let raw = b"Subject: test\r\nFrom: <sender@example.com>\r\n\r\nsecret body\r\n";
+            let dsn = dsn().with_returned_headers(raw);
+            let expected = b"Subject: test\r\nFrom: <sender@example.com>\r\n".to_vec();
+            assert_eq!(dsn.returned, Some(Returned::Headers(expected)));
+        }
+
+        #[test]
+        fn returns_only_the_headers_without_an_empty_line() {
+            let raw = b"Subject: test\r\n folded\r\nsecret body: with colon\r\n";
+            let dsn = dsn().with_returned_headers(raw);
+            let expected = b"Subject: test\r\n folded\r\n".to_vec();
+            assert_eq!(dsn.returned, Some(Returned::Headers(expected)));
+
+            let raw = b"Subject: test\r\nno header\r\nFrom: <sender@example.com>\r\n";
+            let dsn = dsn.with_returned_headers(raw);
+            assert_eq!(dsn.returned, Some(Returned::Headers(b"Subject: test\r\n".to_vec())));
+        }
+
+        #[test]
+        fn returns_all_headers_of_a_mail_without_body() {
+            let raw = b"Subject: test\r\nFrom: <sender@example.com>\r\n";
+            let dsn = dsn().with_returned_headers(raw);
+            assert_eq!(dsn.returned, Some(Returned::Headers(raw.to_vec())));
+        }
+
+        #[test]
+        fn is_send_as_multipart_report_with_null_reverse_path() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let dsn = dsn()
+                .with_subject("Unzustellbar: Grüße")
+                .with_returned_headers(b"Subject: test\r\n\r\nbody\r\n");
+
+            let fut = send_dsn(&dsn, con_config(addr), test_context(), SendConfig::default());
+            run(fut.map(|_| ())).unwrap();
+            let written = server.join().unwrap();
+            let written = &written[0];
+
+            assert!(written.contains("\r\nMAIL FROM:<>\r\n"), "{}", written);
+            assert!(written.contains("\r\nRCPT TO:<sender@example.com>\r\n"), "{}", written);
+            assert!(written.contains("Auto-Submitted: auto-replied\r\n"), "{}", written);
+            assert!(written.contains("multipart/report"), "{}", written);
+            assert!(written.contains("report-type=delivery-status"), "{}", written);
+            assert!(written.contains("message/delivery-status"), "{}", written);
+            assert!(written.contains("text/rfc822-headers"), "{}", written);
+            assert!(written.contains("\r\nDate: "), "{}", written);
+            assert!(written.to_lowercase().contains("\r\nmessage-id: <"), "{}", written);
+            // the non ASCII subject is send as encoded word (RFC 2047)
+            assert!(written.contains("Subject: =?"), "{}", written);
+            assert!(!written.contains("Grüße"), "{}", written);
+        }
+    }
+}
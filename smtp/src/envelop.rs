@@ -0,0 +1,127 @@
+//! Module containing a serializable representation of the envelop of a mail.
+//!
+//! This module is only available with the `serde` feature.
+use serde::{Serialize, Deserialize};
+use vec1::{Vec1, Size0Error};
+
+use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+
+/// A serializable version of `EnvelopData`, e.g. to persist pending mails.
+///
+/// `EnvelopData` (from `new-tokio-smtp`) doesn't implement serde's traits,
+/// so this can be used to store the reverse path and the recipients of a
+/// mail (e.g. in a database) together with its encoded body and to turn
+/// them back into `EnvelopData` later on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StoredEnvelop {
+    /// The reverse path (`MAIL FROM`), `None` is the null reverse path (`<>`).
+    pub reverse_path: Option<StoredAddress>,
+
+    /// The recipients (`RCPT TO`).
+    pub recipients: Vec<StoredAddress>
+}
+
+impl StoredEnvelop {
+
+    /// Turns this into `EnvelopData`.
+    ///
+    /// Fails if there are no recipients, e.g. because the stored data was modified.
+    pub fn into_envelop_data(self) -> Result<EnvelopData, Size0Error> {
+        let StoredEnvelop { reverse_path, recipients } = self;
+        let to = Vec1::from_vec(recipients.into_iter().map(MailAddress::from).collect())
+            .map_err(|_| Size0Error)?;
+        Ok(EnvelopData { from: reverse_path.map(MailAddress::from), to })
+    }
+}
+
+impl<'a> From<&'a EnvelopData> for StoredEnvelop {
+    fn from(envelop: &'a EnvelopData) -> Self {
+        StoredEnvelop {
+            reverse_path: envelop.from.as_ref().map(StoredAddress::from),
+            recipients: envelop.to.iter().map(StoredAddress::from).collect()
+        }
+    }
+}
+
+/// A serializable version of a `MailAddress`.
+///
+/// Besides the address itself this stores if it needs `SMTPUTF8`,
+/// i.e. if it isn't an ascii only address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StoredAddress {
+    /// The address (without the surrounding `<>`).
+    pub address: String,
+
+    /// If the address needs the `SMTPUTF8` extension.
+    pub needs_smtputf8: bool
+}
+
+impl<'a> From<&'a MailAddress> for StoredAddress {
+    fn from(address: &'a MailAddress) -> Self {
+        StoredAddress {
+            address: address.as_str().to_owned(),
+            needs_smtputf8: address.needs_smtputf8()
+        }
+    }
+}
+
+impl From<StoredAddress> for MailAddress {
+    fn from(address: StoredAddress) -> Self {
+        MailAddress::new_unchecked(address.address, address.needs_smtputf8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod stored_envelop {
+        use serde_json;
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use super::super::StoredEnvelop;
+
+        fn round_trip(envelop: &EnvelopData) -> EnvelopData {
+            let json = serde_json::to_string(&StoredEnvelop::from(envelop)).unwrap();
+            let stored: StoredEnvelop = serde_json::from_str(&json).unwrap();
+            stored.into_envelop_data().unwrap()
+        }
+
+        #[test]
+        fn round_trips_with_utf8_recipient() {
+            let envelop = EnvelopData {
+                from: Some(MailAddress::new_unchecked("sender@test.test".to_owned(), false)),
+                to: Vec1::from_vec(vec![
+                    MailAddress::new_unchecked("a@test.test".to_owned(), false),
+                    MailAddress::new_unchecked("jö@test.test".to_owned(), true)
+                ]).unwrap()
+            };
+
+            let restored = round_trip(&envelop);
+
+            assert_eq!(restored.from.as_ref().unwrap().as_str(), "sender@test.test");
+            let recipients = restored.to.iter()
+                .map(|address| (address.as_str(), address.needs_smtputf8()))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec![("a@test.test", false), ("jö@test.test", true)]);
+        }
+
+        #[test]
+        fn round_trips_null_reverse_path() {
+            let envelop = EnvelopData {
+                from: None,
+                to: Vec1::new(MailAddress::new_unchecked("a@test.test".to_owned(), false))
+            };
+
+            let restored = round_trip(&envelop);
+
+            assert!(restored.from.is_none());
+            assert_eq!(restored.to.len(), 1);
+        }
+
+        #[test]
+        fn fails_without_recipients() {
+            let stored: StoredEnvelop = serde_json::from_str(r#"{"reverse_path":null,"recipients":[]}"#).unwrap();
+            assert!(stored.into_envelop_data().is_err());
+        }
+    }
+}
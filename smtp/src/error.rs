@@ -1,14 +1,28 @@
 //! Module containing all custom errors.
-use std::{io as std_io};
+use std::{io as std_io, fmt, error::Error as StdError};
 
-use new_tokio_smtp::error::{
-    ConnectingFailed,
-    LogicError, GeneralError
+use failure::{Fail, Compat};
+
+use native_tls;
+use new_tokio_smtp::{
+    Response,
+    send_mail::MailAddress,
+    error::{
+        ConnectingFailed,
+        LogicError, GeneralError
+    }
 };
 
 use mail::error::MailError;
 use headers::error::HeaderValidationError;
 
+use ::{
+    bounded::response_too_large,
+    connect::is_greeting_timeout,
+    reply::{self, reply_code},
+    response::MailResponse
+};
+
 /// Error used when sending a mail fails.
 ///
 /// Failing to encode a mail before sending
@@ -56,7 +70,663 @@ pub enum MailSendError {
     /// was successful, which normally includes sending Ehlo and Auth
     /// commands.
     #[fail(display = "{}", _0)]
-    Io(std_io::Error)
+    Io(std_io::Error),
+
+    /// A phase of the smtp session didn't complete in time.
+    ///
+    /// The connection is not used anymore after a timeout, so in
+    /// a batch all later mails fail with an I/O error.
+    #[fail(display = "timeout while {}", phase)]
+    Timeout { phase: TimeoutPhase },
+
+    /// The server rejected a recipient, which made the mail fail.
+    ///
+    /// This is only returned if the `RecipientPolicy` isn't `RequireAll`
+    /// (which reports rejected recipients as `MailSendError::Smtp`).
+    #[fail(display = "{}", _0)]
+    RecipientRejected(RecipientRejection),
+
+    /// The server send a response exceeding the `ResponseLimits`.
+    ///
+    /// The connection is not used anymore afterwards. If the greeting or
+    /// the response to `EHLO` is too large this is returned wrapped in a
+    /// `MailSendError::Connecting` error.
+    #[fail(display = "server response too large ({} lines, {} bytes)", lines, bytes)]
+    ResponseTooLarge { lines: usize, bytes: usize },
+
+    /// The server demands authentication (`530`) although the connection was authenticated.
+    ///
+    /// This is only used if the server confirmed the authentication of the
+    /// connection, a `530` over a connection which wasn't authenticated
+    /// (e.g. using `Noop` as auth command) is a `MailSendError::Smtp` error.
+    ///
+    /// This happens e.g. if the OAuth token used to authenticate a long-lived
+    /// connection expired. The mail can be send again over a new connection
+    /// authenticated using fresh credentials (see `auth::TokenProvider`),
+    /// which `send_batch_resilient` does automatically.
+    #[fail(display = "server requires authentication again")]
+    AuthExpired(Response),
+
+    /// The mail has more `Received` headers than allowed, so it's probably looping.
+    ///
+    /// The mail was not send, see `SendConfig::max_received_headers`.
+    #[fail(display = "probable mail loop: {} Received headers exceed the limit of {}", received, limit)]
+    LoopDetected { received: usize, limit: usize },
+
+    /// The `RecipientRewriter` dropped all recipients of the mail.
+    ///
+    /// The mail was not send.
+    #[fail(display = "all recipients were dropped by the recipient rewriter")]
+    NoRecipients,
+
+    /// The domain of the reverse path doesn't align with the domain of the `From` header.
+    ///
+    /// The mail was not send, see `SendConfig::domain_alignment`.
+    #[fail(display = "envelop from domain {} is not aligned with From header domain {}", envelop_from, header_from)]
+    DomainMisaligned { header_from: String, envelop_from: String },
+
+    /// Sending the mail was cancelled by the caller using a `CancelToken`.
+    #[fail(display = "sending the mail was cancelled")]
+    Cancelled,
+
+    /// Sending the mail was cancelled using a `CancelToken` before
+    /// anything of it was send to the server.
+    ///
+    /// This is the case if the token was cancelled before the mail was
+    /// taken up, while waiting for a `Limiter` permit or while setting
+    /// up the connection.
+    #[fail(display = "sending the mail was cancelled before it was send")]
+    CancelledBeforeSending,
+
+    /// A host which supported `STARTTLS` before doesn't announce it anymore.
+    ///
+    /// The connection was closed without sending anything, see
+    /// `SendConfig::opportunistic_starttls` and `StartTlsDowngrade`.
+    #[fail(display = "{}", _0)]
+    StartTlsDowngrade(StartTlsDowngrade)
+}
+
+impl MailSendError {
+
+    /// Returns true if the server greylisted the mail.
+    ///
+    /// Greylisting servers reject mails from unknown senders with
+    /// a temporary error on first contact and accept them if they
+    /// are send again after some time (normally a few minutes).
+    ///
+    /// The detection is based on the enhanced status code (`4.7.1`)
+    /// if the server provides one, and only falls back to looking
+    /// for "greylisted" in the reply text if not.
+    pub fn is_greylisted(&self) -> bool {
+        self.smtp_response()
+            .map(|response| reply::is_greylisting(reply_code(response), response.msg()))
+            .unwrap_or(false)
+    }
+
+    /// Returns the enhanced status code (RFC 3463) of the server response.
+    ///
+    /// The code is returned as a tuple of `(class, subject, detail)`,
+    /// e.g. `(5, 1, 1)` for `550 5.1.1 unknown user`.
+    ///
+    /// `None` is returned if the error wasn't caused by a server
+    /// response or the server didn't include an enhanced status code.
+    pub fn enhanced_status(&self) -> Option<(u8, u16, u16)> {
+        self.smtp_response()
+            .and_then(|response| reply::parse_enhanced_status(response.msg()))
+    }
+
+    /// Classifies why the server rejected the mail.
+    ///
+    /// This is a best-effort heuristic based on the enhanced status code,
+    /// the reply text (e.g. "Relay access denied") and the reply code.
+    /// As servers word their replies very differently the result can be
+    /// wrong or `RejectionReason::Unknown`, so it should only be used to
+    /// e.g. pick a message for the user and not for anything critical.
+    ///
+    /// `None` is returned if the error wasn't caused by a `4xx` or `5xx`
+    /// server response.
+    pub fn rejection_reason(&self) -> Option<RejectionReason> {
+        let response = self.smtp_response()?;
+        let code = reply_code(response);
+        if code / 100 == 4 || code / 100 == 5 {
+            Some(reply::rejection_reason(code, response.msg()))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if sending the mail was cancelled using a `CancelToken`.
+    ///
+    /// This includes mails cancelled before they were send (`CancelledBeforeSending`).
+    ///
+    /// Cancelled mails are neither transient nor permanent failures,
+    /// so `is_transient` returns false for them.
+    pub fn is_cancelled(&self) -> bool {
+        match *self {
+            MailSendError::Cancelled | MailSendError::CancelledBeforeSending => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if the connection was refused because of a possible `STARTTLS` downgrade.
+    ///
+    /// See `MailSendError::StartTlsDowngrade`.
+    pub fn is_starttls_downgrade(&self) -> bool {
+        match *self {
+            MailSendError::StartTlsDowngrade(_) => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if the authentication of the connection expired.
+    ///
+    /// See `MailSendError::AuthExpired`.
+    pub fn is_auth_expired(&self) -> bool {
+        match *self {
+            MailSendError::AuthExpired(_) => true,
+            _ => false
+        }
+    }
+
+    /// Turns a `530` (authentication required) reply into `AuthExpired`.
+    ///
+    /// This must only be used for errors of connections which were
+    /// authenticated, for other connections a `530` just means that
+    /// the server requires authentication.
+    pub(crate) fn auth_expired_if_required(self) -> Self {
+        match self {
+            MailSendError::Smtp(LogicError::Code(response)) => if reply_code(&response) == 530 {
+                MailSendError::AuthExpired(response)
+            } else {
+                MailSendError::Smtp(LogicError::Code(response))
+            },
+            err => err
+        }
+    }
+
+    /// Returns true if the server closed the connection using `421`.
+    ///
+    /// Servers reply with `421 Service closing transmission channel` to any
+    /// command when they are shutting down or overloaded and then close the
+    /// connection. The mail can be send again over a new connection, see
+    /// `send_batch_resilient`.
+    pub fn is_service_closing(&self) -> bool {
+        self.reply_code() == Some(421)
+    }
+
+    /// Returns true if sending the mail again later might succeed.
+    ///
+    /// This is the case for:
+    ///
+    /// - Temporary errors of the server (`4xx`, including `421`), including
+    ///   temporary errors while setting up the connection.
+    /// - I/O errors and timeouts (including ones while connecting).
+    ///
+    /// Errors with the mail itself (`MailSendError::Mail`) and permanent
+    /// errors of the server (`5xx`) are not transient.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            MailSendError::Mail(_) => false,
+            MailSendError::Io(_) | MailSendError::Timeout { .. } => true,
+            MailSendError::Connecting(ConnectingFailed::Io(_)) => true,
+            _ => self.reply_code().map(|code| code / 100 == 4).unwrap_or(false)
+        }
+    }
+
+    /// Returns true if sending the mail again won't succeed without changing it or the setup.
+    ///
+    /// This is the case for:
+    ///
+    /// - Errors with the mail itself (`MailSendError::Mail`).
+    /// - Mails refused by this crate (`LoopDetected`, `NoRecipients`, `DomainMisaligned`).
+    /// - Permanent errors of the server (`5xx`), including permanent
+    ///   errors while setting up the connection.
+    ///
+    /// Errors which are neither transient nor permanent are e.g. cancelled
+    /// sends or responses exceeding the `ResponseLimits`.
+    pub fn is_permanent(&self) -> bool {
+        match *self {
+            MailSendError::Mail(_)
+            | MailSendError::LoopDetected { .. }
+            | MailSendError::NoRecipients
+            | MailSendError::DomainMisaligned { .. } => true,
+            _ => self.reply_code().map(|code| code / 100 == 5).unwrap_or(false)
+        }
+    }
+
+    /// Returns a HTTP status code matching this error.
+    ///
+    /// This is meant for services offering an (HTTP) API for sending mails
+    /// which need to translate send failures into a HTTP status:
+    ///
+    /// - `400` (Bad Request) for errors with the mail itself (`Mail`).
+    /// - `422` (Unprocessable Entity) for mails refused by this crate because
+    ///   of their recipients or sender (`NoRecipients`, `DomainMisaligned`).
+    /// - `508` (Loop Detected) for `LoopDetected`.
+    /// - `504` (Gateway Timeout) for timeouts.
+    /// - `503` (Service Unavailable) for other transient errors (see `is_transient`)
+    ///   and cancelled sends.
+    /// - `502` (Bad Gateway) for everything else, including permanent
+    ///   errors of the server (`5xx`) and failed authentication.
+    pub fn http_status_hint(&self) -> u16 {
+        match *self {
+            MailSendError::Mail(_) => 400,
+            MailSendError::NoRecipients | MailSendError::DomainMisaligned { .. } => 422,
+            MailSendError::LoopDetected { .. } => 508,
+            MailSendError::Timeout { .. } => 504,
+            MailSendError::Cancelled | MailSendError::CancelledBeforeSending => 503,
+            _ if self.is_transient() => 503,
+            _ => 502
+        }
+    }
+
+    /// Returns the reply code of the server response which caused this error.
+    fn reply_code(&self) -> Option<u16> {
+        self.smtp_response().map(reply_code)
+    }
+
+    /// Returns in which phase setting up the connection failed.
+    ///
+    /// This covers `MailSendError::Connecting` errors, a timeout waiting
+    /// for the greeting (`ConnectPhase::Tcp`) and a `STARTTLS` downgrade
+    /// (`ConnectPhase::Tls`). Other timeouts, including the overall
+    /// `TimeoutPhase::Connect` one, can't be attributed to a phase, so
+    /// `None` is returned for them like for all other errors.
+    pub fn connect_phase(&self) -> Option<ConnectPhase> {
+        match *self {
+            MailSendError::Connecting(ref err) => Some(ConnectPhase::of(err)),
+            MailSendError::Timeout { phase: TimeoutPhase::Greeting } => Some(ConnectPhase::Tcp),
+            MailSendError::StartTlsDowngrade(_) => Some(ConnectPhase::Tls),
+            _ => None
+        }
+    }
+
+    /// Returns the server response which caused this error, if there is one.
+    fn smtp_response(&self) -> Option<&Response> {
+        match *self {
+            MailSendError::Smtp(LogicError::Code(ref response)) => Some(response),
+            MailSendError::Smtp(LogicError::UnexpectedCode(ref response)) => Some(response),
+            MailSendError::RecipientRejected(ref rejection) => Some(rejection.response()),
+            MailSendError::AuthExpired(ref response) => Some(response),
+            MailSendError::Connecting(ConnectingFailed::Setup(ref err)) => logic_error_response(err),
+            MailSendError::Connecting(ConnectingFailed::Auth(ref err)) => logic_error_response(err),
+            _ => None
+        }
+    }
+}
+
+pub(crate) fn logic_error_response(err: &LogicError) -> Option<&Response> {
+    match *err {
+        LogicError::Code(ref response) => Some(response),
+        LogicError::UnexpectedCode(ref response) => Some(response),
+        LogicError::Custom(ref err) => err.downcast_ref::<Compat<EhloAfterStartTlsFailed>>()
+            .and_then(|err| logic_error_response(err.get_ref().error())),
+        _ => None
+    }
+}
+
+/// Returns the reply code (e.g. `550`) of the server response which caused the error.
+///
+/// `None` is returned if the error wasn't caused by a server response.
+pub fn logic_error_code(err: &LogicError) -> Option<u16> {
+    logic_error_response(err).map(reply_code)
+}
+
+/// Error of `send_batch_with_error_mapper`.
+///
+/// Either the error returned by the error mapper or the
+/// `MailSendError` if the mapper didn't map the error.
+#[derive(Debug)]
+pub enum MappedError<E> {
+    /// The error returned by the error mapper.
+    Custom(E),
+
+    /// The error wasn't mapped.
+    Default(MailSendError)
+}
+
+impl<E> MappedError<E> {
+
+    /// Maps the error using the mapper if it was caused by a server response.
+    ///
+    /// Rejected recipients and expired authentications are passed to the
+    /// mapper as `LogicError::Code` of the servers response.
+    pub(crate) fn map<F>(err: MailSendError, mapper: F) -> Self
+        where F: FnOnce(&LogicError) -> Option<E>
+    {
+        let mapped = match err {
+            MailSendError::Smtp(ref err) => mapper(err),
+            MailSendError::RecipientRejected(ref rejection) => mapper(&LogicError::Code(rejection.response().clone())),
+            MailSendError::AuthExpired(ref response) => mapper(&LogicError::Code(response.clone())),
+            MailSendError::Connecting(ConnectingFailed::Setup(ref err)) => mapper(err),
+            MailSendError::Connecting(ConnectingFailed::Auth(ref err)) => mapper(err),
+            _ => None
+        };
+        match mapped {
+            Some(custom) => MappedError::Custom(custom),
+            None => MappedError::Default(err)
+        }
+    }
+}
+
+impl<E> fmt::Display for MappedError<E>
+    where E: fmt::Display
+{
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MappedError::Custom(ref err) => fmt::Display::fmt(err, fter),
+            MappedError::Default(ref err) => fmt::Display::fmt(err, fter)
+        }
+    }
+}
+
+impl<E> Fail for MappedError<E>
+    where E: Fail
+{
+    fn cause(&self) -> Option<&Fail> {
+        match *self {
+            MappedError::Custom(ref err) => err.cause(),
+            MappedError::Default(ref err) => err.cause()
+        }
+    }
+}
+
+/// Error of `send_batch_collected` if the connection couldn't be set up.
+///
+/// No mail was send if this is returned.
+#[derive(Debug, Fail)]
+#[fail(display = "setting up the connection failed: {}", _0)]
+pub struct TransportError(MailSendError);
+
+impl TransportError {
+
+    /// The error which made setting up the connection fail.
+    pub fn error(&self) -> &MailSendError {
+        &self.0
+    }
+
+    /// Turns this error into the error which made setting up the connection fail.
+    pub fn into_error(self) -> MailSendError {
+        self.0
+    }
+
+    /// Fails with the error of the given result if setting up the connection failed for it.
+    ///
+    /// The index is the one recorded by the session (see `session::SetupFailure`),
+    /// `None` means that setting up the first connection didn't fail.
+    pub(crate) fn check_batch(
+        mut results: Vec<Result<MailResponse, MailSendError>>,
+        setup_failure: Option<usize>
+    ) -> Result<Vec<Result<MailResponse, MailSendError>>, TransportError> {
+        match setup_failure {
+            Some(idx) if results.get(idx).map(Result::is_err).unwrap_or(false) =>
+                Err(TransportError(results.swap_remove(idx).unwrap_err())),
+            _ => Ok(results)
+        }
+    }
+}
+
+/// Error returned by `util::combine_results` if any result of a batch failed.
+///
+/// It contains all failures together with the index of the failed
+/// result in the batch.
+#[derive(Debug)]
+pub struct BatchError {
+    total: usize,
+    failures: Vec<(usize, MailSendError)>
+}
+
+impl BatchError {
+
+    pub(crate) fn new(total: usize, failures: Vec<(usize, MailSendError)>) -> Self {
+        BatchError { total, failures }
+    }
+
+    /// The number of results in the batch (including the successful ones).
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// The failures with the index of the failed result, in the order of the batch.
+    pub fn failures(&self) -> &[(usize, MailSendError)] {
+        &self.failures
+    }
+
+    /// Turns this error into the failures with the index of the failed result.
+    pub fn into_failures(self) -> Vec<(usize, MailSendError)> {
+        self.failures
+    }
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "{} of {} failed", self.failures.len(), self.total)?;
+        if let Some(&(idx, ref err)) = self.failures.first() {
+            write!(fter, ", first failure (#{}): {}", idx, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for BatchError {}
+
+/// Error used (as auth error) if fetching the credentials for a connection failed.
+///
+/// See `auth::CredentialProvider` and `auth::from_callback`.
+#[derive(Debug, Fail)]
+#[fail(display = "fetching the credentials failed: {}", _0)]
+pub struct CredentialsUnavailable(Box<StdError + Send + Sync>);
+
+impl CredentialsUnavailable {
+
+    pub(crate) fn new(err: Box<StdError + Send + Sync>) -> Self {
+        CredentialsUnavailable(err)
+    }
+
+    /// The error returned when fetching the credentials.
+    pub fn error(&self) -> &(StdError + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+/// Error used if the server rejected the `EHLO` send after `STARTTLS`.
+///
+/// Some relays accept `STARTTLS` and complete the TLS handshake but then
+/// reject the `EHLO` which has to be send again afterwards. In that case
+/// `QUIT` is send and setting up the connection fails with a
+/// `MailSendError::Connecting` setup error wrapping this error. The
+/// response of the server is still used by e.g. `is_transient`.
+#[derive(Debug, Fail)]
+#[fail(display = "EHLO after STARTTLS failed: {}", _0)]
+pub struct EhloAfterStartTlsFailed(LogicError);
+
+impl EhloAfterStartTlsFailed {
+
+    pub(crate) fn new(err: LogicError) -> Self {
+        EhloAfterStartTlsFailed(err)
+    }
+
+    /// The error returned for the `EHLO` command.
+    pub fn error(&self) -> &LogicError {
+        &self.0
+    }
+}
+
+/// Error used if a host with which `STARTTLS` was used before doesn't announce it.
+///
+/// See `SendConfig::tls_host_cache`. This can be caused by an attacker
+/// stripping `STARTTLS` from the `EHLO` response (a downgrade attack), but
+/// also by a server whose TLS setup was removed. `QUIT` is send and sending
+/// fails with `MailSendError::StartTlsDowngrade`.
+#[derive(Debug, Fail)]
+#[fail(display = "possible downgrade attack: {} doesn't announce STARTTLS anymore", host)]
+pub struct StartTlsDowngrade {
+    host: String
+}
+
+impl StartTlsDowngrade {
+
+    pub(crate) fn new(host: String) -> Self {
+        StartTlsDowngrade { host }
+    }
+
+    /// The host which doesn't announce `STARTTLS` anymore.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+/// Error returned by `auth::auto` if no auth mechanism can be used.
+#[derive(Debug, Fail)]
+pub enum AuthSelectionError {
+    /// The server didn't announce any of the supported mechanisms.
+    #[fail(display = "server announced no supported auth mechanism")]
+    NoSupportedMechanism,
+
+    /// The only supported mechanism would send the password over an unencrypted connection.
+    #[fail(display = "refusing to use AUTH {} over an unencrypted connection", _0)]
+    Unencrypted(&'static str),
+
+    /// The username or password contains a `\0`, which `AUTH PLAIN` can't represent.
+    #[fail(display = "credentials contain a \\0 which can not be send using AUTH PLAIN")]
+    InvalidCredentials,
+
+    /// The server send a `CRAM-MD5` challenge which isn't valid base64.
+    #[fail(display = "server send an invalid CRAM-MD5 challenge")]
+    InvalidChallenge
+}
+
+/// A recipient rejected by the server together with the servers response.
+#[derive(Debug)]
+pub struct RecipientRejection {
+    recipient: MailAddress,
+    response: Response
+}
+
+impl RecipientRejection {
+
+    pub(crate) fn new(recipient: MailAddress, response: Response) -> Self {
+        RecipientRejection { recipient, response }
+    }
+
+    /// The rejected recipient.
+    pub fn recipient(&self) -> &MailAddress {
+        &self.recipient
+    }
+
+    /// The response the server send for the recipient.
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    /// The reply code the server send for the recipient (e.g. `550`).
+    pub fn code(&self) -> u16 {
+        reply_code(&self.response)
+    }
+}
+
+impl fmt::Display for RecipientRejection {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "recipient <{}> was rejected with {}", self.recipient.as_str(), self.code())?;
+        for line in self.response.msg() {
+            write!(fter, " {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why the server rejected a mail, see `MailSendError::rejection_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectionReason {
+    /// The mailbox doesn't exist or can't receive mails (e.g. `5.1.1 User unknown`).
+    MailboxUnavailable,
+    /// The server doesn't relay the mail to the recipient (e.g. `Relay access denied`).
+    RelayDenied,
+    /// The mail was rejected because of a policy of the server (e.g. SPF, DMARC or a blocklist).
+    PolicyRejection,
+    /// The mail was considered to be spam.
+    SpamSuspected,
+    /// The mailbox is full or over quota.
+    Quota,
+    /// The reason couldn't be determined.
+    Unknown
+}
+
+/// The phase in which setting up a connection failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectPhase {
+    /// Opening the TCP connection or an I/O error on it (outside of TLS).
+    ///
+    /// This includes not receiving a greeting in time.
+    Tcp,
+    /// Setting up TLS (direct TLS or after `STARTTLS`) failed.
+    Tls,
+    /// The server refused the session.
+    ///
+    /// This includes an error greeting, a failed `EHLO`, a rejected `STARTTLS`
+    /// command and failed `SendConfig::post_auth_cmds`.
+    Ehlo,
+    /// The server rejected the authentication.
+    Auth
+}
+
+impl ConnectPhase {
+
+    fn of(err: &ConnectingFailed) -> Self {
+        match *err {
+            ConnectingFailed::Io(ref err) if is_tls_error(err) => ConnectPhase::Tls,
+            ConnectingFailed::Io(_) => ConnectPhase::Tcp,
+            ConnectingFailed::Setup(_) => ConnectPhase::Ehlo,
+            ConnectingFailed::Auth(_) => ConnectPhase::Auth
+        }
+    }
+}
+
+impl fmt::Display for ConnectPhase {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match *self {
+            ConnectPhase::Tcp => "tcp",
+            ConnectPhase::Tls => "tls",
+            ConnectPhase::Ehlo => "ehlo",
+            ConnectPhase::Auth => "auth"
+        };
+        fter.write_str(as_str)
+    }
+}
+
+/// Returns true if the I/O error wraps an error of the TLS implementation.
+fn is_tls_error(err: &std_io::Error) -> bool {
+    err.get_ref()
+        .map(|inner| inner.is::<native_tls::Error>())
+        .unwrap_or(false)
+}
+
+/// The phase of the smtp session in which a timeout elapsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeoutPhase {
+    /// Setting up the connection (including TLS, EHLO and AUTH).
+    Connect,
+    /// Waiting for the `220` greeting of the server (see `Timeouts::greeting`).
+    Greeting,
+    /// Waiting for the response to a command (including the `354` response to `DATA`).
+    Command,
+    /// Sending the mail body and waiting for the final response.
+    Data,
+    /// Sending the whole mail (see `SendConfig::per_mail_deadline`).
+    Mail
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match *self {
+            TimeoutPhase::Connect => "connecting",
+            TimeoutPhase::Greeting => "waiting for the greeting",
+            TimeoutPhase::Command => "waiting for a command response",
+            TimeoutPhase::Data => "sending the mail body",
+            TimeoutPhase::Mail => "sending the mail"
+        };
+        fter.write_str(as_str)
+    }
 }
 
 impl From<MailError> for MailSendError {
@@ -73,13 +743,26 @@ impl From<LogicError> for MailSendError {
 
 impl From<std_io::Error> for MailSendError {
     fn from(err: std_io::Error) -> Self {
-        MailSendError::Io(err)
+        response_too_large(&err).unwrap_or(MailSendError::Io(err))
     }
 }
 
 impl From<ConnectingFailed> for MailSendError {
     fn from(err: ConnectingFailed) -> Self {
-        MailSendError::Connecting(err)
+        if is_greeting_timeout(&err) {
+            return MailSendError::Timeout { phase: TimeoutPhase::Greeting };
+        }
+        match err {
+            // the downgrade is detected while setting up the connection,
+            // where errors have to be `ConnectingFailed`
+            ConnectingFailed::Setup(LogicError::Custom(err)) => {
+                match err.downcast::<Compat<StartTlsDowngrade>>() {
+                    Ok(downgrade) => MailSendError::StartTlsDowngrade(downgrade.into_inner()),
+                    Err(err) => MailSendError::Connecting(ConnectingFailed::Setup(LogicError::Custom(err)))
+                }
+            },
+            err => MailSendError::Connecting(err)
+        }
     }
 }
 
@@ -95,11 +778,65 @@ impl From<GeneralError> for MailSendError {
 }
 
 
+/// Error returned when adding a syntactically invalid ESMTP parameter.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum InvalidEsmtpParam {
+    /// The keyword is not a valid ESMTP keyword.
+    #[fail(display = "invalid esmtp parameter keyword: {:?}", _0)]
+    Keyword(String),
+
+    /// The value is not a valid ESMTP value.
+    #[fail(display = "invalid esmtp parameter value: {:?}", _0)]
+    Value(String)
+}
+
+/// Error returned when creating a `ReceivedHeader` with an invalid clause.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum InvalidReceivedHeader {
+    /// The `from` clause is empty or contains invalid characters.
+    #[fail(display = "invalid from clause for Received header: {:?}", _0)]
+    From(String),
+
+    /// The `by` clause is empty or contains invalid characters.
+    #[fail(display = "invalid by clause for Received header: {:?}", _0)]
+    By(String),
+
+    /// The `with` clause is not a valid protocol name.
+    #[fail(display = "invalid with clause for Received header: {:?}", _0)]
+    Protocol(String),
+
+    /// The `id` clause is not a valid id.
+    #[fail(display = "invalid id clause for Received header: {:?}", _0)]
+    Id(String)
+}
+
+/// Error returned when parsing an invalid domain into a `misc::DomainName`.
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum InvalidDomain {
+    /// The domain is empty.
+    #[fail(display = "domain is empty")]
+    Empty,
+
+    /// The domain is longer than 253 characters.
+    #[fail(display = "domain is too long: {:?}", _0)]
+    TooLong(String),
+
+    /// The domain contains an empty, too long or syntactically invalid label.
+    #[fail(display = "invalid label in domain: {:?}", _0)]
+    InvalidLabel(String)
+}
+
 #[derive(Debug, Fail)]
 pub enum OtherValidationError {
 
     #[fail(display = "no To header was present")]
-    NoTo
+    NoTo,
+
+    /// The recipient headers of the mail don't match the recipients derived from it.
+    ///
+    /// E.g. because the mail was changed after deriving the envelop data.
+    #[fail(display = "recipient headers of the mail don't match the derived envelop data")]
+    InconsistentRecipients
 }
 
 impl From<OtherValidationError> for HeaderValidationError {
@@ -113,4 +850,80 @@ impl From<OtherValidationError> for MailError {
     fn from(ove: OtherValidationError) -> Self {
         MailError::from(HeaderValidationError::from(ove))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+
+    mod connect_phase {
+        use std::io as std_io;
+        use new_tokio_smtp::error::ConnectingFailed;
+        use super::super::{MailSendError, ConnectPhase, StartTlsDowngrade, TimeoutPhase};
+
+        #[test]
+        fn io_errors_without_tls_error_are_tcp_phase() {
+            let io_err = std_io::Error::new(std_io::ErrorKind::ConnectionRefused, "refused");
+            let err = MailSendError::Connecting(ConnectingFailed::Io(io_err));
+            assert_eq!(err.connect_phase(), Some(ConnectPhase::Tcp));
+        }
+
+        #[test]
+        fn greeting_timeouts_are_tcp_phase() {
+            let err = MailSendError::Timeout { phase: TimeoutPhase::Greeting };
+            assert_eq!(err.connect_phase(), Some(ConnectPhase::Tcp));
+        }
+
+        #[test]
+        fn starttls_downgrades_are_tls_phase() {
+            let err = MailSendError::StartTlsDowngrade(StartTlsDowngrade::new("mx.test.test".to_owned()));
+            assert_eq!(err.connect_phase(), Some(ConnectPhase::Tls));
+        }
+
+        #[test]
+        fn is_none_for_other_errors() {
+            let err = MailSendError::Io(std_io::Error::new(std_io::ErrorKind::Other, "broken"));
+            assert_eq!(err.connect_phase(), None);
+            let err = MailSendError::Timeout { phase: TimeoutPhase::Connect };
+            assert_eq!(err.connect_phase(), None);
+        }
+    }
+
+    mod http_status_hint {
+        use std::io as std_io;
+        use ::{
+            test_utils::{FakeServer, Reply, mock_envelop, run},
+            transaction::send_envelop
+        };
+        use super::super::{MailSendError, TimeoutPhase};
+
+        fn rejected_mail(reply: &'static str) -> MailSendError {
+            let server = FakeServer::new(vec![Reply::Lines(reply), Reply::Lines("250 Ok\r\n")]);
+            let fut = send_envelop(server.connection(), mock_envelop(&["a@test.test"]), Default::default());
+            let (_con, result) = run(fut).unwrap();
+            result.unwrap_err()
+        }
+
+        #[test]
+        fn permanent_server_errors_are_bad_gateway() {
+            let err = rejected_mail("550 5.7.1 rejected\r\n");
+            assert!(err.is_permanent());
+            assert_eq!(err.http_status_hint(), 502);
+        }
+
+        #[test]
+        fn transient_errors_are_service_unavailable() {
+            let err = rejected_mail("451 4.3.0 try again later\r\n");
+            assert!(!err.is_permanent());
+            assert_eq!(err.http_status_hint(), 503);
+
+            let err = MailSendError::Io(std_io::Error::new(std_io::ErrorKind::ConnectionReset, "reset"));
+            assert_eq!(err.http_status_hint(), 503);
+        }
+
+        #[test]
+        fn refused_mails_and_timeouts_have_their_own_status() {
+            assert_eq!(MailSendError::NoRecipients.http_status_hint(), 422);
+            assert_eq!(MailSendError::LoopDetected { received: 31, limit: 30 }.http_status_hint(), 508);
+            assert_eq!(MailSendError::Timeout { phase: TimeoutPhase::Data }.http_status_hint(), 504);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Module implementing requesting the delivery of queued mails using `ETRN` (RFC 1985).
+use std::time::Duration;
+
+use futures::future::Future;
+
+use new_tokio_smtp::{
+    Cmd, Connection, ConnectionConfig, EhloData, ExecFuture, Io, SetupTls, Domain,
+    error::{LogicError, MissingCapabilities}
+};
+
+use ::{
+    config::SendConfig,
+    connect::connect,
+    error::{MailSendError, TimeoutPhase},
+    reply::reply_code,
+    timeout::with_timeout
+};
+
+/// The outcome of a `ETRN` command, see `request_etrn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EtrnOutcome {
+    /// `250`: The server started delivering the queued mails.
+    Started,
+
+    /// `251`: There are no mails waiting for the domain.
+    NoMessages,
+
+    /// `252`/`253`: The server started delivering the pending mails.
+    ///
+    /// With `253` the server includes the number of pending mails.
+    PendingStarted { count: Option<usize> },
+
+    /// `458`: The server is unable to start delivering the mails right now.
+    UnableToQueue,
+
+    /// `459`: The server doesn't allow requesting the mails of the domain.
+    NotAllowed
+}
+
+impl EtrnOutcome {
+
+    /// Returns true if the server started delivering mails or there are none.
+    pub fn is_success(&self) -> bool {
+        match *self {
+            EtrnOutcome::UnableToQueue | EtrnOutcome::NotAllowed => false,
+            _ => true
+        }
+    }
+
+    fn from_reply(code: u16, lines: &[String]) -> Option<Self> {
+        let outcome = match code {
+            250 => EtrnOutcome::Started,
+            251 => EtrnOutcome::NoMessages,
+            252 => EtrnOutcome::PendingStarted { count: None },
+            253 => EtrnOutcome::PendingStarted { count: pending_count(lines) },
+            458 => EtrnOutcome::UnableToQueue,
+            459 => EtrnOutcome::NotAllowed,
+            _ => return None
+        };
+        Some(outcome)
+    }
+}
+
+/// Returns the number of pending mails of a `253` reply, e.g. `OK 12 pending messages for node x started`.
+fn pending_count(lines: &[String]) -> Option<usize> {
+    lines.first()?
+        .split_whitespace()
+        .filter_map(|word| word.parse().ok())
+        .next()
+}
+
+/// Asks the server to start delivering the mails queued for a domain (`ETRN`).
+///
+/// This connects to the server (including STARTTLS and AUTH), sends
+/// `ETRN <domain>` and closes the connection using `QUIT`. It's meant for
+/// hosts which are only connected from time to time and receive their
+/// mails from a relay queuing them in the meantime. The mails are then
+/// delivered by the server over a new connection, as with any other mail.
+///
+/// All replies defined by RFC 1985 are returned as `EtrnOutcome`, other
+/// replies (e.g. `502` if the server doesn't support `ETRN`) fail with
+/// `MailSendError::Smtp`.
+///
+/// This uses the default `SendConfig`, use `request_etrn_with` to use a
+/// custom configuration (e.g. to set timeouts).
+pub fn request_etrn<A, S>(conconf: ConnectionConfig<A, S>, domain: Domain)
+    -> impl Future<Item=EtrnOutcome, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    request_etrn_with(conconf, domain, SendConfig::default())
+}
+
+/// Asks the server to start delivering the mails queued for a domain using the given `SendConfig`.
+///
+/// See `request_etrn`.
+pub fn request_etrn_with<A, S>(conconf: ConnectionConfig<A, S>, domain: Domain, config: SendConfig)
+    -> impl Future<Item=EtrnOutcome, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    let command_timeout = config.timeouts.command;
+    with_timeout(connect(conconf, &config), config.timeouts.connect, TimeoutPhase::Connect)
+        .and_then(move |con| send_etrn(con, domain, command_timeout))
+        .and_then(|(con, result)| {
+            // the server already answered, errors on quit don't matter
+            con.quit().then(move |_| result)
+        })
+}
+
+/// Sends `ETRN` over the connection.
+fn send_etrn(con: Connection, domain: Domain, timeout: Option<Duration>)
+    -> impl Future<Item=(Connection, Result<EtrnOutcome, MailSendError>), Error=MailSendError>
+{
+    with_timeout(con.send(Etrn { domain }), timeout, TimeoutPhase::Command)
+        .map(|(con, result)| {
+            let result = result
+                .map(|response| {
+                    EtrnOutcome::from_reply(reply_code(&response), response.msg())
+                        .expect("[BUG] Etrn only accepts RFC 1985 replies")
+                })
+                .map_err(MailSendError::from);
+            (con, result)
+        })
+}
+
+/// The `ETRN` command.
+struct Etrn {
+    domain: Domain
+}
+
+impl Cmd for Etrn {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        // some servers support ETRN without announcing it
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        io.write_line_from_parts(&["ETRN ", self.domain.as_str()]);
+        let fut = io.flush()
+            .and_then(Io::parse_response)
+            .map(|(io, response)| {
+                let result = if EtrnOutcome::from_reply(reply_code(&response), response.msg()).is_some() {
+                    Ok(response)
+                } else if response.is_erroneous() {
+                    Err(LogicError::Code(response))
+                } else {
+                    Err(LogicError::UnexpectedCode(response))
+                };
+                (io, result)
+            });
+
+        Box::new(fut)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod send_etrn {
+        use new_tokio_smtp::Domain;
+        use ::{
+            error::MailSendError,
+            test_utils::{FakeServer, Reply, run}
+        };
+        use super::super::{send_etrn, EtrnOutcome};
+
+        fn etrn(reply: &'static str) -> (Result<EtrnOutcome, MailSendError>, String) {
+            let server = FakeServer::new(vec![Reply::Lines(reply)]);
+            let domain = Domain::from_unchecked("client.test".to_owned());
+            let (_con, result) = run(send_etrn(server.connection(), domain, None)).unwrap();
+            (result, server.written())
+        }
+
+        #[test]
+        fn started() {
+            let (result, written) = etrn("250 OK, queuing for node client.test started\r\n");
+            assert_eq!(result.unwrap(), EtrnOutcome::Started);
+            assert_eq!(written, "ETRN client.test\r\n");
+        }
+
+        #[test]
+        fn no_messages() {
+            let (result, _) = etrn("251 OK, no messages waiting for node client.test\r\n");
+            assert_eq!(result.unwrap(), EtrnOutcome::NoMessages);
+        }
+
+        #[test]
+        fn pending_messages_with_count() {
+            let (result, _) = etrn("253 OK, 14 pending messages for node client.test started\r\n");
+            assert_eq!(result.unwrap(), EtrnOutcome::PendingStarted { count: Some(14) });
+        }
+
+        #[test]
+        fn refusals_are_outcomes() {
+            let (result, _) = etrn("458 Unable to queue messages for node client.test\r\n");
+            assert_eq!(result.unwrap(), EtrnOutcome::UnableToQueue);
+            let (result, _) = etrn("459 Node client.test not allowed: unknown\r\n");
+            let outcome = result.unwrap();
+            assert_eq!(outcome, EtrnOutcome::NotAllowed);
+            assert!(!outcome.is_success());
+        }
+
+        #[test]
+        fn other_replies_are_errors() {
+            let (result, _) = etrn("502 Command not implemented\r\n");
+            match result {
+                Err(MailSendError::Smtp(_)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! Module defining the headers added by this crate which `mail-headers` doesn't have.
+//!
+//! They are inserted into the `Mail` before it's encoded (see
+//! `MailRequest::into_mail_for_encoding`), like any other header.
+
+mod components {
+    pub use ::request::AutoSubmitted;
+    pub use ::trace::ReceivedHeader;
+}
+
+def_headers! {
+    test_name: validate_header_names,
+    scope: components,
+    /// (rfc5321) A trace header documenting a hop of the mail
+    Received, unchecked { "Received" }, ReceivedHeader, multi, None,
+    /// (rfc3834) Marks the mail as automatically submitted
+    AutoSubmitted, unchecked { "Auto-Submitted" }, AutoSubmitted, maxOne, None
+}
@@ -27,18 +27,15 @@
 //! #[macro_use] extern crate mail_headers;
 //!
 //! use futures::Future;
-//! use mail_headers::{
-//!     headers::*,
-//!     header_components::Domain
-//! };
+//! use mail_headers::headers::*;
 //! use mail_core::{Mail, default_impl::simple_context};
 //! use mail_smtp::{self as smtp, ConnectionConfig};
 //!
 //! # fn main() {
 //! // this is normally done _once per application instance_
-//! // and then stored in e.g. a lazy_static. Also `Domain`
-//! // will implement `FromStr` in the future.
-//! let ctx = simple_context::new(Domain::from_unchecked("example.com".to_owned()), "asdkds".parse().unwrap())
+//! // and then stored in e.g. a lazy_static.
+//! let domain: smtp::misc::DomainName = "example.com".parse().unwrap();
+//! let ctx = simple_context::new(domain.into(), "asdkds".parse().unwrap())
 //!     .unwrap();
 //!
 //! let mut mail = Mail::plain_text("Some body");
@@ -50,34 +47,141 @@
 //!
 //! // don't use unencrypted con for anything but testing and
 //! // simplified examples
-//! let con_config = ConnectionConfig::build_local_unencrypted().build();
+//! let mut con_config = ConnectionConfig::build_local_unencrypted().build();
+//! // the domain send with EHLO, parsed (and validated) using `misc::DomainName`
+//! let client_domain: smtp::misc::DomainName = "example.com".parse().unwrap();
+//! con_config.client_id = smtp::misc::ClientId::Domain(client_domain.into());
 //!
 //! let fut = smtp::send(mail.into(), con_config, ctx);
 //! let results = fut.wait();
 //! # }
 //! ```
 //!
+//! # Runtime
+//!
+//! All futures and streams returned by this crate are `futures` 0.1
+//! futures which use tokio's (0.1) reactor for their I/O, as the
+//! underlying `new-tokio-smtp` connection is build on tokio's TCP
+//! stream. This means they have to be polled from within a tokio
+//! runtime (e.g. through `tokio::run` or a `tokio::runtime::Runtime`).
+//!
+//! The connection can't be driven by another runtime (e.g. async-std)
+//! directly, as `new-tokio-smtp` only has socket variants for tokio's
+//! TCP stream (the `unix-socket` feature works around this for Unix
+//! domain sockets using its mock support, see `connect_unix` for the
+//! risks). Instead the sends can run on a tokio runtime on background
+//! threads, with the results being forwarded through channels which
+//! can be awaited from any executor.
+//!
+//! With the `futures03` feature the `compat` module provides versions
+//! of the send functions returning `std::future::Future`s which can be
+//! used with `.await`. They still need tokio's (0.1) reactor, but
+//! `compat::BackgroundRuntime` runs them (and any other future of this
+//! crate) on a background tokio runtime as described above.
 //!
 extern crate futures;
+extern crate tokio;
+extern crate tokio_timer;
+extern crate tokio_tls;
+extern crate native_tls;
+extern crate net2;
+extern crate vec1;
+extern crate base64;
+extern crate md5;
+extern crate chrono;
 extern crate new_tokio_smtp;
 extern crate mail_core as mail;
 extern crate mail_internals;
-#[cfg_attr(test, macro_use)]
+#[macro_use]
 extern crate mail_headers as headers;
 #[macro_use]
 extern crate failure;
+#[cfg(feature="futures03")]
+extern crate futures03;
+#[cfg(feature="serde")]
+extern crate serde;
+#[cfg(all(test, feature="serde"))]
+extern crate serde_json;
 
 mod resolve_all;
+mod reply;
+mod timeout;
+mod cancel;
+mod budget;
+mod bounded;
+mod observe;
+mod connect;
+mod transaction;
+mod machine;
+mod params;
+mod session;
+#[cfg(test)]
+mod test_utils;
 
 pub mod error;
+mod config;
 mod request;
+mod response;
 mod send_mail;
+mod connection;
+mod url;
+mod domain;
+mod pool;
+mod trace;
+mod fields;
+mod prepared;
+mod auto_auth;
+mod oauth;
+mod credentials;
+mod dsn;
+mod rewrite;
+mod etrn;
+mod limiter;
+mod align;
+mod tls_pin;
+#[cfg(all(unix, feature="unix-socket"))]
+mod unix;
+#[cfg(feature="serde")]
+mod envelop;
+pub mod util;
+#[cfg(feature="testing")]
+pub mod testing;
+#[cfg(feature="futures03")]
+pub mod compat;
 
-pub use self::request::MailRequest;
+pub use self::request::{MailRequest, BccHandling, DowngradeReport, AutoSubmitted};
+pub use self::params::AuthSubmitter;
+pub use self::cancel::CancelToken;
+pub use self::trace::ReceivedHeader;
+pub use self::rewrite::{RecipientRewriter, RedirectPolicy};
+pub use self::align::DomainAlignment;
+pub use self::tls_pin::TlsHostCache;
+pub use self::prepared::PreparedMail;
+pub use self::dsn::{send_dsn, DeliveryStatusNotification, RecipientStatus, DsnAction};
+pub use self::response::{MailResponse, BatchOutcome, SendOutcome, SendTimings, TransferMode};
 #[cfg(feature="extended-api")]
 pub use self::request::derive_envelop_data_from_mail;
 
-pub use self::send_mail::{send, send_batch};
+pub use self::config::{
+    SendConfig, Timeouts, PostAuthCmds, Checkpoint, RecipientPolicy,
+    SendTarget, UnacknowledgedMx, MxAcknowledgment, CommandObserver, CompositeObserver, RecipientProgress, SmtpCommand,
+    ResponseLimits, RetryBudget, ServiceClosingPolicy, AddressFamilyPreference, DEFAULT_MAX_RECEIVED_HEADERS
+};
+pub use self::send_mail::{
+    send, send_with, send_batch, send_batch_with, send_batch_cycling, send_batch_resumable,
+    send_batch_resilient, send_batch_with_error_mapper, send_batch_collected, send_batch_collected_with,
+    send_stream, send_stream_with, send_stream_cycling, send_over, send_over_with, send_streamed
+};
+pub use self::machine::{SendTransaction, TransactionCommand, TransactionFailed};
+pub use self::etrn::{request_etrn, request_etrn_with, EtrnOutcome};
+pub use self::limiter::{Limiter, Permit};
+pub use self::connection::{probe_connection, check_connection, readiness_check, check_connection_with, ConnectionReport};
+pub use self::pool::{warm_pool, warm_pool_with, ConnectionPool, PoolSendFuture};
+pub use self::url::{parse_smtp_url, SmtpUrl, UrlScheme, UrlAuth, UrlError};
+#[cfg(all(unix, feature="unix-socket"))]
+pub use self::unix::{UnixConnectionConfig, connect_unix, send_unix};
+#[cfg(feature="serde")]
+pub use self::envelop::{StoredEnvelop, StoredAddress};
 #[cfg(feature="extended-api")]
 pub use self::send_mail::encode;
 
@@ -90,6 +194,9 @@ pub mod auth {
     //! ease of use.
 
     pub use new_tokio_smtp::command::auth::*;
+    pub use ::auto_auth::{auto, AutoAuth, AuthMechanism};
+    pub use ::oauth::{xoauth2, XOAuth2, TokenProvider};
+    pub use ::credentials::{from_callback, CallbackAuth, Credentials, CredentialProvider, CredentialsFuture};
 
     /// Auth command for not doing anything on auth.
     //FIXME: this currently still sends the noop cmd,
@@ -99,6 +206,10 @@ pub mod auth {
 
 pub mod misc {
     //! A small collection of usefull types re-exported from `new-tokio-smtp`.
+    //!
+    //! Use `DomainName` to parse and validate a `Domain` instead
+    //! of using `Domain::from_unchecked`.
+    pub use ::domain::DomainName;
     pub use new_tokio_smtp::{
         ClientId,
         Domain,
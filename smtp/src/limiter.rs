@@ -0,0 +1,302 @@
+//! Module implementing limiting the number of concurrent smtp sessions.
+use std::{
+    fmt, mem,
+    sync::{Arc, Mutex, MutexGuard}
+};
+
+use futures::{
+    Future, Stream, Poll, Async,
+    future::Either,
+    stream,
+    task::{self, Task}
+};
+
+use mail::Context;
+use new_tokio_smtp::{ConnectionConfig, Cmd, SetupTls};
+
+use ::{
+    cancel::{CancelToken, cancellable},
+    config::SendConfig,
+    error::MailSendError,
+    request::MailRequest,
+    response::MailResponse,
+    send_mail::{send_with, send_batch_with}
+};
+
+/// Limits the number of smtp sessions running at the same time.
+///
+/// Each send started through the limiter first waits for a permit, and
+/// only connects to the server once it got one. The permit is released
+/// once the send is done (or its future/stream is dropped). This bounds
+/// the number of connections to the server across all sends using the
+/// limiter (e.g. from many request handlers), which is different from
+/// the parallelism of a single batch.
+///
+/// Waiting for a permit respects the `cancel_token` of the `SendConfig`,
+/// a send cancelled while waiting fails with `MailSendError::CancelledBeforeSending`
+/// without connecting.
+///
+/// Clones share the same permits, so it can be stored e.g. in a
+/// `lazy_static` and used process-wide.
+#[derive(Clone)]
+pub struct Limiter {
+    max_sessions: usize,
+    state: Arc<Mutex<LimiterState>>
+}
+
+#[derive(Default)]
+struct LimiterState {
+    in_use: usize,
+    /// Tasks waiting for a permit, all are notified once one is released.
+    waiting: Vec<Task>
+}
+
+impl Limiter {
+
+    /// Creates a limiter allowing at most `max_sessions` concurrent sessions.
+    ///
+    /// # Panics
+    ///
+    /// If `max_sessions` is zero, as no send could ever start.
+    pub fn new(max_sessions: usize) -> Self {
+        assert!(max_sessions > 0, "a limiter needs to allow at least one session");
+        Limiter { max_sessions, state: Default::default() }
+    }
+
+    /// The maximal number of concurrent sessions.
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    /// The number of permits currently in use, i.e. the number of running sessions.
+    pub fn in_use(&self) -> usize {
+        self.lock().in_use
+    }
+
+    /// Waits for a permit, the permit is released once it's dropped.
+    ///
+    /// This allows limiting sends which are not started through the
+    /// limiter, e.g. using `send_over` with a custom connection.
+    pub fn acquire(&self, cancel_token: Option<CancelToken>) -> impl Future<Item=Permit, Error=MailSendError> {
+        cancellable(Acquire { limiter: self.clone() }, cancel_token)
+            .map_err(|err| if err.is_cancelled() { MailSendError::CancelledBeforeSending } else { err })
+    }
+
+    /// Sends a mail like `send` once a permit is available.
+    pub fn send<A, S>(&self, mail: MailRequest, conconf: ConnectionConfig<A, S>, ctx: impl Context)
+        -> impl Future<Item=MailResponse, Error=MailSendError>
+        where A: Cmd, S: SetupTls
+    {
+        self.send_with(mail, conconf, ctx, SendConfig::default())
+    }
+
+    /// Sends a mail like `send_with` once a permit is available.
+    pub fn send_with<A, S>(
+        &self,
+        mail: MailRequest,
+        conconf: ConnectionConfig<A, S>,
+        ctx: impl Context,
+        config: SendConfig
+    ) -> impl Future<Item=MailResponse, Error=MailSendError>
+        where A: Cmd, S: SetupTls
+    {
+        self.acquire(config.cancel_token.clone())
+            .and_then(move |permit| send_with(mail, conconf, ctx, config)
+                .then(move |result| {
+                    drop(permit);
+                    result
+                }))
+    }
+
+    /// Sends a batch of mails like `send_batch` once a permit is available.
+    pub fn send_batch<A, S, C>(&self, mails: Vec<MailRequest>, conconf: ConnectionConfig<A, S>, ctx: C)
+        -> impl Stream<Item=MailResponse, Error=MailSendError>
+        where A: Cmd, S: SetupTls, C: Context
+    {
+        self.send_batch_with(mails, conconf, ctx, SendConfig::default())
+    }
+
+    /// Sends a batch of mails like `send_batch_with` once a permit is available.
+    ///
+    /// The whole batch uses one permit, which is released once the
+    /// stream is done. If the batch is cancelled while waiting for the
+    /// permit, each mail fails with `MailSendError::CancelledBeforeSending`.
+    pub fn send_batch_with<A, S, C>(
+        &self,
+        mails: Vec<MailRequest>,
+        conconf: ConnectionConfig<A, S>,
+        ctx: C,
+        config: SendConfig
+    ) -> impl Stream<Item=MailResponse, Error=MailSendError>
+        where A: Cmd, S: SetupTls, C: Context
+    {
+        let count = mails.len();
+        self.acquire(config.cancel_token.clone())
+            .then(move |result| -> Result<_, MailSendError> {
+                let stream = match result {
+                    Ok(permit) => Either::A(WithPermit {
+                        stream: send_batch_with(mails, conconf, ctx, config),
+                        permit: Some(permit)
+                    }),
+                    Err(err) => {
+                        // one result per mail, like any other batch
+                        debug_assert!(err.is_cancelled());
+                        Either::B(stream::iter_result((0..count).map(|_| Err(MailSendError::CancelledBeforeSending))))
+                    }
+                };
+                Ok(stream)
+            })
+            .flatten_stream()
+    }
+
+    fn try_acquire(&self) -> Option<Permit> {
+        let mut state = self.lock();
+        if state.in_use < self.max_sessions {
+            state.in_use += 1;
+            Some(Permit { limiter: self.clone() })
+        } else {
+            state.waiting.push(task::current());
+            None
+        }
+    }
+
+    fn release(&self) {
+        let waiting = {
+            let mut state = self.lock();
+            state.in_use -= 1;
+            mem::replace(&mut state.waiting, Vec::new())
+        };
+        for task in waiting {
+            task.notify();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<LimiterState> {
+        self.state.lock().expect("[BUG] limiter panicked")
+    }
+}
+
+impl fmt::Debug for Limiter {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.debug_struct("Limiter")
+            .field("max_sessions", &self.max_sessions)
+            .field("in_use", &self.in_use())
+            .finish()
+    }
+}
+
+/// A permit of a `Limiter`, released once dropped.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Limiter
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+/// Future waiting for a permit.
+struct Acquire {
+    limiter: Limiter
+}
+
+impl Future for Acquire {
+    type Item = Permit;
+    type Error = MailSendError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.limiter.try_acquire() {
+            Some(permit) => Ok(Async::Ready(permit)),
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Stream holding a permit until it's done.
+struct WithPermit<S> {
+    stream: S,
+    permit: Option<Permit>
+}
+
+impl<S> Stream for WithPermit<S>
+    where S: Stream
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.stream.poll()? {
+            Async::Ready(item) => {
+                if item.is_none() {
+                    self.permit.take();
+                }
+                Ok(Async::Ready(item))
+            },
+            Async::NotReady => Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod limiter {
+        use std::sync::Arc;
+        use futures::{
+            Async,
+            executor::{self, Notify, NotifyHandle}
+        };
+        use ::{
+            cancel::CancelToken,
+            error::MailSendError
+        };
+        use super::super::Limiter;
+
+        struct NoopNotify;
+
+        impl Notify for NoopNotify {
+            fn notify(&self, _id: usize) {}
+        }
+
+        #[test]
+        fn waits_until_a_permit_is_released() {
+            let limiter = Limiter::new(1);
+            let notify = NotifyHandle::from(Arc::new(NoopNotify));
+
+            let first = executor::spawn(limiter.acquire(None)).wait_future().unwrap();
+            assert_eq!(limiter.in_use(), 1);
+
+            let mut second = executor::spawn(limiter.acquire(None));
+            assert!(second.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+            drop(first);
+            assert_eq!(limiter.in_use(), 0);
+            match second.poll_future_notify(&notify, 0).unwrap() {
+                Async::Ready(_permit) => assert_eq!(limiter.in_use(), 1),
+                Async::NotReady => panic!("permit was released")
+            }
+            assert_eq!(limiter.in_use(), 0);
+        }
+
+        #[test]
+        fn waiting_can_be_cancelled() {
+            let limiter = Limiter::new(1);
+            let token = CancelToken::new();
+            let notify = NotifyHandle::from(Arc::new(NoopNotify));
+
+            let _first = executor::spawn(limiter.acquire(None)).wait_future().unwrap();
+            let mut second = executor::spawn(limiter.acquire(Some(token.clone())));
+            assert!(second.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+            token.cancel();
+            let err = second.poll_future_notify(&notify, 0).unwrap_err();
+            match err {
+                MailSendError::CancelledBeforeSending => {},
+                other => panic!("unexpected error: {:?}", other)
+            }
+            assert_eq!(limiter.in_use(), 1);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Module implementing the mail transaction as a state machine independent of any I/O.
+use std::fmt;
+
+use failure::Fail;
+
+use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+
+use ::{
+    config::{RecipientPolicy, SmtpCommand},
+    response::MailResponse
+};
+
+/// A command to send to the server, returned by `SendTransaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionCommand {
+    /// `MAIL FROM`, with `SMTPUTF8` if any address of the envelop needs it.
+    Mail { from: Option<MailAddress>, smtputf8: bool },
+
+    /// `RCPT TO`.
+    Rcpt { to: MailAddress },
+
+    /// `DATA`.
+    Data,
+
+    /// The (dot-stuffed) mail data, terminated by `<CRLF>.<CRLF>`.
+    MailData,
+
+    /// `RSET`, send to reset the server after the transaction failed.
+    Rset
+}
+
+impl TransactionCommand {
+
+    /// Returns the command line to send, without the trailing `CRLF`.
+    ///
+    /// Returns `None` for `TransactionCommand::MailData` as the mail data is
+    /// send by the caller.
+    pub fn line(&self) -> Option<String> {
+        let line = match *self {
+            TransactionCommand::Mail { ref from, smtputf8 } => {
+                let from = from.as_ref().map(|from| from.as_str()).unwrap_or("");
+                let param = if smtputf8 { " SMTPUTF8" } else { "" };
+                format!("MAIL FROM:<{}>{}", from, param)
+            },
+            TransactionCommand::Rcpt { ref to } => format!("RCPT TO:<{}>", to.as_str()),
+            TransactionCommand::Data => "DATA".to_owned(),
+            TransactionCommand::MailData => return None,
+            TransactionCommand::Rset => "RSET".to_owned()
+        };
+        Some(line)
+    }
+}
+
+/// The server rejected a command of a `SendTransaction`.
+#[derive(Debug, Clone)]
+pub struct TransactionFailed {
+    command: SmtpCommand,
+    recipient: Option<MailAddress>,
+    response: MailResponse
+}
+
+impl TransactionFailed {
+
+    fn new(command: SmtpCommand, recipient: Option<MailAddress>, response: MailResponse) -> Self {
+        TransactionFailed { command, recipient, response }
+    }
+
+    /// The rejected command, `SmtpCommand::Data` includes the mail data.
+    pub fn command(&self) -> SmtpCommand {
+        self.command
+    }
+
+    /// The rejected recipient if the command is `SmtpCommand::Rcpt`.
+    pub fn recipient(&self) -> Option<&MailAddress> {
+        self.recipient.as_ref()
+    }
+
+    /// The response of the server.
+    pub fn response(&self) -> &MailResponse {
+        &self.response
+    }
+}
+
+impl fmt::Display for TransactionFailed {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "server rejected {:?} with {}", self.command, self.response.code())?;
+        for line in self.response.lines() {
+            write!(fter, " {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for TransactionFailed {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Mail,
+    Rcpt(usize),
+    Data,
+    MailData,
+    Rset,
+    Done
+}
+
+/// The mail transaction (`MAIL`, `RCPT`, `DATA`) as a state machine without I/O.
+///
+/// This leaves sending the commands and parsing the responses to the
+/// caller, which makes it usable with other runtimes (or blocking I/O)
+/// and for testing. It's not used by the connection based sending of
+/// this crate and only covers the command sequence: pipelining, timeouts,
+/// `ResponseLimits` and checking the servers capabilities (e.g. for
+/// `SMTPUTF8`) are left to the caller.
+///
+/// Call `start` to get the first command, send it and pass the parsed
+/// response to `on_response`, which returns the next command to send.
+/// Once it returns `None` the transaction is done and `into_result`
+/// returns the outcome. If the transaction fails before the mail data
+/// is send, `RSET` is send so that the connection can be used for
+/// further mails. Rejected recipients are handled according to the
+/// `RecipientPolicy`.
+#[derive(Debug)]
+pub struct SendTransaction {
+    envelop_data: EnvelopData,
+    policy: RecipientPolicy,
+    state: State,
+    recipient_codes: Vec<u16>,
+    rejected: Vec<(MailAddress, u16)>,
+    first_rejection: Option<TransactionFailed>,
+    result: Option<Result<MailResponse, TransactionFailed>>
+}
+
+impl SendTransaction {
+
+    /// Creates a new transaction sending a mail to the recipients of the envelop.
+    pub fn new(envelop_data: EnvelopData, policy: RecipientPolicy) -> Self {
+        SendTransaction {
+            envelop_data, policy,
+            state: State::Start,
+            recipient_codes: Vec::new(),
+            rejected: Vec::new(),
+            first_rejection: None,
+            result: None
+        }
+    }
+
+    /// Returns the first command of the transaction (`MAIL FROM`).
+    ///
+    /// # Panics
+    ///
+    /// If the transaction was already started.
+    pub fn start(&mut self) -> TransactionCommand {
+        assert_eq!(self.state, State::Start, "transaction already started");
+        self.state = State::Mail;
+        let smtputf8 = self.envelop_data.from.iter()
+            .chain(self.envelop_data.to.iter())
+            .any(|address| address.needs_smtputf8());
+        TransactionCommand::Mail { from: self.envelop_data.from.clone(), smtputf8 }
+    }
+
+    /// Handles the response to the last command, returning the next command to send.
+    ///
+    /// Returns `None` once the transaction is done (or if it wasn't started).
+    pub fn on_response(&mut self, response: MailResponse) -> Option<TransactionCommand> {
+        let class = response.code() / 100;
+        match self.state {
+            State::Start | State::Done => None,
+            State::Mail => {
+                if class == 2 {
+                    self.rcpt(0)
+                } else {
+                    self.fail(TransactionFailed::new(SmtpCommand::Mail, None, response))
+                }
+            },
+            State::Rcpt(idx) => self.on_rcpt_response(idx, response),
+            State::Data => {
+                if class == 3 {
+                    self.state = State::MailData;
+                    Some(TransactionCommand::MailData)
+                } else {
+                    self.fail(TransactionFailed::new(SmtpCommand::Data, None, response))
+                }
+            },
+            State::MailData => {
+                self.state = State::Done;
+                self.result = Some(if class == 2 {
+                    Ok(response
+                        .with_recipient_codes(self.recipient_codes.clone())
+                        .with_rejected(self.rejected.clone()))
+                } else {
+                    // the transaction is over either way, no RSET needed
+                    Err(TransactionFailed::new(SmtpCommand::Data, None, response))
+                });
+                None
+            },
+            State::Rset => {
+                // the transaction failed already, the RSET response doesn't change that
+                self.state = State::Done;
+                None
+            }
+        }
+    }
+
+    /// Returns true if the transaction is done.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Returns the result of the transaction, or `None` if it's not done yet.
+    pub fn into_result(self) -> Option<Result<MailResponse, TransactionFailed>> {
+        if self.is_done() {
+            self.result
+        } else {
+            None
+        }
+    }
+
+    fn on_rcpt_response(&mut self, idx: usize, response: MailResponse) -> Option<TransactionCommand> {
+        let code = response.code();
+        self.recipient_codes.push(code);
+        if code / 100 != 2 {
+            let recipient = self.envelop_data.to[idx].clone();
+            self.rejected.push((recipient.clone(), code));
+            let rejection = TransactionFailed::new(SmtpCommand::Rcpt, Some(recipient), response);
+            let fail_now = match self.policy {
+                RecipientPolicy::RequireAll => true,
+                RecipientPolicy::FailFastOnFirstRecipient => idx == 0 && code / 100 == 5,
+                RecipientPolicy::AcceptPartial => false
+            };
+            if fail_now {
+                return self.fail(rejection);
+            }
+            if self.first_rejection.is_none() {
+                self.first_rejection = Some(rejection);
+            }
+        }
+
+        if idx + 1 < self.envelop_data.to.len() {
+            self.rcpt(idx + 1)
+        } else if self.rejected.len() < self.envelop_data.to.len() {
+            self.state = State::Data;
+            Some(TransactionCommand::Data)
+        } else {
+            let rejection = self.first_rejection.take()
+                .expect("[BUG] all recipients rejected without a rejection");
+            self.fail(rejection)
+        }
+    }
+
+    fn rcpt(&mut self, idx: usize) -> Option<TransactionCommand> {
+        self.state = State::Rcpt(idx);
+        Some(TransactionCommand::Rcpt { to: self.envelop_data.to[idx].clone() })
+    }
+
+    fn fail(&mut self, failure: TransactionFailed) -> Option<TransactionCommand> {
+        self.result = Some(Err(failure));
+        self.state = State::Rset;
+        Some(TransactionCommand::Rset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod send_transaction {
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use ::{
+            config::{RecipientPolicy, SmtpCommand},
+            response::MailResponse
+        };
+        use super::super::{SendTransaction, TransactionCommand};
+
+        fn address(address: &str) -> MailAddress {
+            MailAddress::new_unchecked(address.to_owned(), false)
+        }
+
+        fn transaction(policy: RecipientPolicy) -> SendTransaction {
+            let envelop_data = EnvelopData {
+                from: Some(address("sender@test.test")),
+                to: Vec1::from_vec(vec![address("a@test.test"), address("b@test.test")]).unwrap()
+            };
+            SendTransaction::new(envelop_data, policy)
+        }
+
+        fn reply(code: u16) -> MailResponse {
+            MailResponse::new(code, vec!["text".to_owned()])
+        }
+
+        #[test]
+        fn successful_transaction() {
+            let mut transaction = transaction(RecipientPolicy::RequireAll);
+
+            let mail = transaction.start();
+            assert_eq!(mail.line().unwrap(), "MAIL FROM:<sender@test.test>");
+            assert_eq!(transaction.on_response(reply(250)), Some(TransactionCommand::Rcpt { to: address("a@test.test") }));
+            assert_eq!(transaction.on_response(reply(250)), Some(TransactionCommand::Rcpt { to: address("b@test.test") }));
+            assert_eq!(transaction.on_response(reply(251)), Some(TransactionCommand::Data));
+            assert_eq!(transaction.on_response(reply(354)), Some(TransactionCommand::MailData));
+            assert!(!transaction.is_done());
+            assert_eq!(transaction.on_response(reply(250)), None);
+            assert!(transaction.is_done());
+
+            let response = transaction.into_result().unwrap().unwrap();
+            assert_eq!(response.code(), 250);
+            assert_eq!(response.recipient_codes(), &[250, 251]);
+        }
+
+        #[test]
+        fn rejected_recipient_resets_the_transaction() {
+            let mut transaction = transaction(RecipientPolicy::RequireAll);
+
+            transaction.start();
+            transaction.on_response(reply(250));
+            assert_eq!(transaction.on_response(reply(550)), Some(TransactionCommand::Rset));
+            assert!(!transaction.is_done());
+            assert_eq!(transaction.on_response(reply(250)), None);
+
+            let failure = transaction.into_result().unwrap().unwrap_err();
+            assert_eq!(failure.command(), SmtpCommand::Rcpt);
+            assert_eq!(failure.recipient(), Some(&address("a@test.test")));
+            assert_eq!(failure.response().code(), 550);
+        }
+
+        #[test]
+        fn accept_partial_sends_to_the_accepted_recipients() {
+            let mut transaction = transaction(RecipientPolicy::AcceptPartial);
+
+            transaction.start();
+            transaction.on_response(reply(250));
+            assert_eq!(transaction.on_response(reply(550)), Some(TransactionCommand::Rcpt { to: address("b@test.test") }));
+            assert_eq!(transaction.on_response(reply(250)), Some(TransactionCommand::Data));
+            transaction.on_response(reply(354));
+            transaction.on_response(reply(250));
+
+            let response = transaction.into_result().unwrap().unwrap();
+            assert_eq!(response.rejected(), &[(address("a@test.test"), 550)]);
+        }
+
+        #[test]
+        fn rejected_mail_from_resets_without_recipients() {
+            let mut transaction = transaction(RecipientPolicy::AcceptPartial);
+
+            transaction.start();
+            let rset = transaction.on_response(reply(451)).unwrap();
+            assert_eq!(rset.line().unwrap(), "RSET");
+            transaction.on_response(reply(250));
+
+            let failure = transaction.into_result().unwrap().unwrap_err();
+            assert_eq!(failure.command(), SmtpCommand::Mail);
+            assert_eq!(failure.recipient(), None);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Module implementing `AUTH XOAUTH2` using tokens provided on demand.
+use std::{
+    fmt,
+    error::Error as StdError,
+    sync::Arc
+};
+
+use futures::future::{self, Future};
+use base64;
+
+use new_tokio_smtp::{
+    Cmd, Io, ExecFuture, EhloData,
+    error::{LogicError, MissingCapabilities}
+};
+
+use ::reply::{reply_code, check_response};
+
+/// Provides (fresh) OAuth 2.0 bearer tokens.
+///
+/// The provider is asked for a token each time a connection authenticates,
+/// including when a batch reconnects because the token of the previous
+/// connection expired (see `MailSendError::AuthExpired`). It's up to the
+/// provider to cache tokens and refresh them once they expire.
+///
+/// It's implemented for closures returning a token.
+pub trait TokenProvider: Send + Sync + 'static {
+    /// Returns a token valid for at least the next few seconds.
+    fn token(&self) -> Result<String, Box<StdError + Send + Sync>>;
+}
+
+impl<F> TokenProvider for F
+    where F: Fn() -> Result<String, Box<StdError + Send + Sync>> + Send + Sync + 'static
+{
+    fn token(&self) -> Result<String, Box<StdError + Send + Sync>> {
+        (self)()
+    }
+}
+
+/// Creates an `AUTH XOAUTH2` command getting the token from the provider.
+pub fn xoauth2<U, P>(username: U, provider: P) -> XOAuth2
+    where U: Into<String>, P: TokenProvider
+{
+    XOAuth2 { username: username.into(), provider: Arc::new(provider) }
+}
+
+/// `AUTH XOAUTH2` command as used by e.g. Gmail and Outlook.
+///
+/// The token is requested from the `TokenProvider` each time the command
+/// is executed, so cloning it (e.g. to reconnect) always uses a current
+/// token. If the provider fails, the command fails with the error without
+/// sending anything.
+#[derive(Clone)]
+pub struct XOAuth2 {
+    username: String,
+    provider: Arc<TokenProvider>
+}
+
+impl fmt::Debug for XOAuth2 {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.debug_struct("XOAuth2")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl Cmd for XOAuth2 {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        // not all servers announce XOAUTH2, they reject it if it's not supported
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        let token = match self.provider.token() {
+            Ok(token) => token,
+            Err(err) => return Box::new(future::ok((io, Err(LogicError::Custom(err)))))
+        };
+
+        let initial_response = initial_response(&self.username, &token);
+        io.write_line_from_parts(&["AUTH XOAUTH2 ", initial_response.as_str()]);
+        let fut = io.flush()
+            .and_then(Io::parse_response)
+            .and_then(|(mut io, response)| -> ExecFuture {
+                if reply_code(&response) != 334 {
+                    return Box::new(future::ok((io, check_response(response, 2))));
+                }
+                // the server send an error description as challenge,
+                // the actual error reply follows the (empty) answer
+                io.write_line_from_parts(&[""]);
+                let fut = io.flush()
+                    .and_then(Io::parse_response)
+                    .map(|(io, response)| (io, check_response(response, 2)));
+                Box::new(fut)
+            });
+
+        Box::new(fut)
+    }
+}
+
+/// Returns the (base64 encoded) initial client response of `XOAUTH2`.
+fn initial_response(username: &str, token: &str) -> String {
+    base64::encode(&format!("user={}\x01auth=Bearer {}\x01\x01", username, token))
+}
+
+#[cfg(test)]
+mod test {
+
+    mod xoauth2 {
+        use std::error::Error as StdError;
+        use new_tokio_smtp::Connection;
+        use ::test_utils::{FakeServer, Reply, run};
+        use super::super::{xoauth2, initial_response};
+
+        fn fixed_token() -> Result<String, Box<StdError + Send + Sync>> {
+            Ok("ya29.token".to_owned())
+        }
+
+        #[test]
+        fn encodes_the_initial_response() {
+            assert_eq!(
+                initial_response("someuser@example.com", "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg"),
+                "dXNlcj1zb21ldXNlckBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB5YTI5LnZGOWRmdDRxbVRjMk52\
+                 YjNSbGNrQmhkSFJoZG1semRHRXVZMjl0Q2cBAQ=="
+            );
+        }
+
+        #[test]
+        fn answers_error_challenge_and_fails() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("334 eyJzdGF0dXMiOiI0MDEifQ==\r\n"),
+                Reply::Lines("535 5.7.8 Username and Password not accepted\r\n")
+            ]);
+            let con: Connection = server.connection();
+            let (_con, result) = run(con.send(xoauth2("me@test.test", fixed_token))).unwrap();
+
+            assert!(result.is_err());
+            assert!(server.written().ends_with("\r\n\r\n"));
+        }
+
+        #[test]
+        fn does_not_send_anything_if_the_provider_fails() {
+            let server = FakeServer::new(vec![]);
+            let failing = || -> Result<String, Box<StdError + Send + Sync>> { Err("refresh failed".into()) };
+            let (_con, result) = run(server.connection().send(xoauth2("me@test.test", failing))).unwrap();
+
+            assert!(result.is_err());
+            assert_eq!(server.written(), "");
+        }
+    }
+}
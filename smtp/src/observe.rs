@@ -0,0 +1,74 @@
+//! Module implementing measuring commands and send phases.
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+use futures::future::{Future, Either};
+
+use ::{
+    config::{CommandObserver, SmtpCommand},
+    response::SendTimings
+};
+
+/// Reports the time until the future completes to the observer, if there is one.
+///
+/// The time is measured from calling this function, so it should be called
+/// right before the future is polled the first time (e.g. in `and_then`).
+pub(crate) fn observed<F>(fut: F, observer: Option<&CommandObserver>, command: SmtpCommand)
+    -> impl Future<Item=F::Item, Error=F::Error>
+    where F: Future
+{
+    match observer.cloned() {
+        None => Either::A(fut),
+        Some(observer) => {
+            let start = Instant::now();
+            Either::B(fut.then(move |result| {
+                observer.record(command, start.elapsed());
+                result
+            }))
+        }
+    }
+}
+
+/// Collects the `SendTimings` of a mail, shared by the phases of sending it.
+#[derive(Clone, Default)]
+pub(crate) struct TimingRecorder {
+    timings: Arc<Mutex<SendTimings>>
+}
+
+impl TimingRecorder {
+
+    /// Adds the duration to the phase selected by `phase`.
+    pub(crate) fn add<P>(&self, phase: P, duration: Duration)
+        where P: FnOnce(&mut SendTimings) -> &mut Option<Duration>
+    {
+        let mut timings = self.timings.lock().expect("[BUG] recording timings panicked");
+        let slot = phase(&mut timings);
+        *slot = Some(slot.unwrap_or_default() + duration);
+    }
+
+    /// Returns the timings recorded so far.
+    pub(crate) fn timings(&self) -> SendTimings {
+        *self.timings.lock().expect("[BUG] recording timings panicked")
+    }
+}
+
+/// Adds the time until the future completes to a phase, if there is a recorder.
+///
+/// Like with `observed` the time is measured from calling this function.
+pub(crate) fn timed<F, P>(fut: F, recorder: Option<&TimingRecorder>, phase: P)
+    -> impl Future<Item=F::Item, Error=F::Error>
+    where F: Future, P: FnOnce(&mut SendTimings) -> &mut Option<Duration>
+{
+    match recorder.cloned() {
+        None => Either::A(fut),
+        Some(recorder) => {
+            let start = Instant::now();
+            Either::B(fut.then(move |result| {
+                recorder.add(phase, start.elapsed());
+                result
+            }))
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Module containing additional ESMTP parameters for the `MAIL` and `RCPT` commands.
+use std::fmt::Write;
+
+use new_tokio_smtp::send_mail::MailAddress;
+
+use ::error::InvalidEsmtpParam;
+
+/// The authenticated submitter of a mail, send as `AUTH` parameter of `MAIL` (RFC 4954).
+///
+/// This tells a relay on whose behalf the mail is submitted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthSubmitter {
+    /// The submitter is unknown or not authenticated (`AUTH=<>`).
+    Unknown,
+
+    /// The mailbox of the authenticated submitter.
+    ///
+    /// The address is xtext encoded when it is send (e.g. `e=mc2@example.com`
+    /// is send as `AUTH=e+3Dmc2@example.com`).
+    Mailbox(MailAddress)
+}
+
+impl AuthSubmitter {
+
+    /// Returns the value of the `AUTH` parameter.
+    fn param_value(&self) -> String {
+        match *self {
+            AuthSubmitter::Unknown => "<>".to_owned(),
+            AuthSubmitter::Mailbox(ref address) => xtext_encode(address.as_str())
+        }
+    }
+}
+
+/// Encodes the input as xtext (RFC 3461, section 4).
+///
+/// All printable ASCII characters except `+` and `=` are kept as is,
+/// all other bytes (including the bytes of non ASCII characters) are
+/// encoded as `+` followed by two upper case hex digits.
+pub(crate) fn xtext_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for bch in input.bytes() {
+        if bch >= 33 && bch <= 126 && bch != b'+' && bch != b'=' {
+            out.push(bch as char);
+        } else {
+            write!(out, "+{:02X}", bch).expect("writing to a String can not fail");
+        }
+    }
+    out
+}
+
+/// Additional parameters send with the `MAIL` command and each `RCPT` command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct EsmtpParams {
+    auth_submitter: Option<AuthSubmitter>,
+    mail: Vec<EsmtpParam>,
+    rcpt: Vec<EsmtpParam>
+}
+
+impl EsmtpParams {
+
+    pub(crate) fn set_auth_submitter(&mut self, submitter: Option<AuthSubmitter>) -> Option<AuthSubmitter> {
+        ::std::mem::replace(&mut self.auth_submitter, submitter)
+    }
+
+    pub(crate) fn push_mail_param(&mut self, param: EsmtpParam) {
+        self.mail.push(param);
+    }
+
+    pub(crate) fn push_rcpt_param(&mut self, param: EsmtpParam) {
+        self.rcpt.push(param);
+    }
+
+    /// Returns the `MAIL` parameters as keyword/value pairs.
+    ///
+    /// Raw parameters come last, so they override typed ones with the same keyword.
+    pub(crate) fn mail_params(&self) -> Vec<(String, Option<String>)> {
+        let mut params = Vec::new();
+        if let Some(submitter) = self.auth_submitter.as_ref() {
+            params.push(("AUTH".to_owned(), Some(submitter.param_value())));
+        }
+        params.extend(self.mail.iter().map(EsmtpParam::to_pair));
+        params
+    }
+
+    /// Returns the `RCPT` parameters as keyword/value pairs.
+    pub(crate) fn rcpt_params(&self) -> Vec<(String, Option<String>)> {
+        self.rcpt.iter().map(EsmtpParam::to_pair).collect()
+    }
+}
+
+/// A syntactically valid ESMTP parameter (RFC 5321, section 4.1.2).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EsmtpParam {
+    keyword: String,
+    value: Option<String>
+}
+
+impl EsmtpParam {
+
+    /// Creates a new parameter, failing if the keyword or value is not valid.
+    ///
+    /// - The keyword has to consist of ASCII letters, digits and `-` and must
+    ///   not start with a `-`.
+    /// - The value has to be non empty and consist of printable ASCII
+    ///   characters excluding `=` (control characters and space are not
+    ///   allowed either).
+    pub(crate) fn new(keyword: &str, value: Option<&str>) -> Result<Self, InvalidEsmtpParam> {
+        if !is_esmtp_keyword(keyword) {
+            return Err(InvalidEsmtpParam::Keyword(keyword.to_owned()));
+        }
+        if let Some(value) = value {
+            if !is_esmtp_value(value) {
+                return Err(InvalidEsmtpParam::Value(value.to_owned()));
+            }
+        }
+        Ok(EsmtpParam {
+            keyword: keyword.to_owned(),
+            value: value.map(ToOwned::to_owned)
+        })
+    }
+
+    fn to_pair(&self) -> (String, Option<String>) {
+        (self.keyword.clone(), self.value.clone())
+    }
+}
+
+// esmtp-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+fn is_esmtp_keyword(keyword: &str) -> bool {
+    let mut bytes = keyword.bytes();
+    match bytes.next() {
+        Some(bch) if bch.is_ascii_alphanumeric() => {},
+        _ => return false
+    }
+    bytes.all(|bch| bch.is_ascii_alphanumeric() || bch == b'-')
+}
+
+// esmtp-value = 1*(%d33-60 / %d62-126)
+fn is_esmtp_value(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|bch| bch >= 33 && bch <= 126 && bch != b'=')
+}
+
+#[cfg(test)]
+mod test {
+
+    mod xtext_encode {
+        use super::super::xtext_encode;
+
+        #[test]
+        fn keeps_plain_addresses() {
+            assert_eq!(xtext_encode("joe@example.com"), "joe@example.com");
+        }
+
+        #[test]
+        fn encodes_plus_equals_and_non_printable_chars() {
+            assert_eq!(xtext_encode("e=mc2@example.com"), "e+3Dmc2@example.com");
+            assert_eq!(xtext_encode("a+b@example.com"), "a+2Bb@example.com");
+            assert_eq!(xtext_encode("\"a b\"@example.com"), "\"a+20b\"@example.com");
+        }
+
+        #[test]
+        fn encodes_each_byte_of_non_ascii_chars() {
+            assert_eq!(xtext_encode("jö@example.com"), "j+C3+B6@example.com");
+        }
+    }
+
+    mod auth_submitter {
+        use new_tokio_smtp::send_mail::MailAddress;
+        use super::super::AuthSubmitter;
+
+        #[test]
+        fn unknown_submitter_is_empty_path() {
+            assert_eq!(AuthSubmitter::Unknown.param_value(), "<>");
+        }
+
+        #[test]
+        fn mailbox_is_xtext_encoded() {
+            let address = MailAddress::new_unchecked("e=mc2@example.com".to_owned(), false);
+            assert_eq!(AuthSubmitter::Mailbox(address).param_value(), "e+3Dmc2@example.com");
+        }
+    }
+
+    mod esmtp_param {
+        use ::error::InvalidEsmtpParam;
+        use super::super::EsmtpParam;
+
+        #[test]
+        fn accepts_valid_params() {
+            EsmtpParam::new("X-VENDOR-1", Some("some+value")).unwrap();
+            EsmtpParam::new("SMTPUTF8", None).unwrap();
+        }
+
+        #[test]
+        fn rejects_invalid_keywords() {
+            for keyword in &["", "-X", "X_Y", "X Y", "Ä"] {
+                match EsmtpParam::new(keyword, None) {
+                    Err(InvalidEsmtpParam::Keyword(ref got)) => assert_eq!(got, keyword),
+                    other => panic!("unexpected result for {:?}: {:?}", keyword, other)
+                }
+            }
+        }
+
+        #[test]
+        fn rejects_invalid_values() {
+            for value in &["", "a=b", "a b", "a\r\nRSET"] {
+                match EsmtpParam::new("X-KEY", Some(value)) {
+                    Err(InvalidEsmtpParam::Value(ref got)) => assert_eq!(got, value),
+                    other => panic!("unexpected result for {:?}: {:?}", value, other)
+                }
+            }
+        }
+    }
+}
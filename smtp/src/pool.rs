@@ -0,0 +1,311 @@
+//! Module containing a pool of pre-established connections.
+use std::{
+    fmt,
+    io as std_io,
+    time::Duration
+};
+
+use futures::future::{self, Future, Either, Loop};
+
+use new_tokio_smtp::{Cmd, ConnectionConfig, SetupTls};
+use mail::Context;
+
+use ::{
+    config::SendConfig,
+    connect::connect,
+    connection::{probe_connection, readiness_check},
+    error::{MailSendError, TimeoutPhase},
+    request::MailRequest,
+    response::MailResponse,
+    send_mail::encode_outgoing,
+    session::QuitOnDrop,
+    timeout::with_timeout,
+    transaction::{OutgoingMail, send_envelop_with}
+};
+
+/// Future returned by `ConnectionPool::send`.
+pub type PoolSendFuture<C> =
+    Box<Future<Item=(ConnectionPool<C>, Result<MailResponse, MailSendError>), Error=()> + Send>;
+
+/// Opens `count` connections in parallel and returns a pool containing them.
+///
+/// This uses the default `SendConfig`, use `warm_pool_with` to
+/// use a custom configuration (e.g. to set timeouts).
+pub fn warm_pool<A, S, C>(conconf: ConnectionConfig<A, S>, ctx: C, count: usize)
+    -> impl Future<Item=ConnectionPool<C>, Error=MailSendError>
+    where A: Cmd + Clone, S: SetupTls + Clone, C: Context
+{
+    warm_pool_with(conconf, ctx, count, SendConfig::default())
+}
+
+/// Opens `count` connections in parallel using the given `SendConfig`.
+///
+/// Each connection is set up completely (including authentication and
+/// the `post_auth_cmds`) before it is placed in the pool, so that the
+/// first mail send through the pool doesn't pay the cost of connecting.
+///
+/// Connections which fail to be set up are left out, i.e. the pool
+/// starts smaller than `count`. Only if all connections fail (and
+/// `count` isn't `0`) the warm-up fails with the error of the first one.
+pub fn warm_pool_with<A, S, C>(
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    count: usize,
+    config: SendConfig
+) -> impl Future<Item=ConnectionPool<C>, Error=MailSendError>
+    where A: Cmd + Clone, S: SetupTls + Clone, C: Context
+{
+    let timeout = config.timeouts.connect;
+    let attempts = (0..count)
+        .map(|_| {
+            with_timeout(connect(conconf.clone(), &config), timeout, TimeoutPhase::Connect)
+                .then(|result| Ok::<_, MailSendError>(result))
+        })
+        .collect::<Vec<_>>();
+
+    future::join_all(attempts)
+        .and_then(move |results| {
+            let mut connections = Vec::with_capacity(results.len());
+            let mut first_error = None;
+            for result in results {
+                match result {
+                    Ok(con) => connections.push(QuitOnDrop::new(con)),
+                    Err(err) => if first_error.is_none() { first_error = Some(err) }
+                }
+            }
+
+            match first_error {
+                Some(err) if connections.is_empty() => Err(err),
+                _ => Ok(ConnectionPool { connections, ctx, config })
+            }
+        })
+}
+
+/// A pool of open connections to the same server.
+///
+/// Mails are send using `send`, which takes an idle connection from the
+/// pool and puts it back once the mail is send. Connections which break
+/// (I/O error or timeout) are dropped, the pool never opens new ones.
+///
+/// Dropping the pool closes all idle connections using `QUIT` (on a
+/// best-effort basis, like dropping the stream of `send_batch`).
+pub struct ConnectionPool<C> {
+    connections: Vec<QuitOnDrop>,
+    ctx: C,
+    config: SendConfig
+}
+
+impl<C> ConnectionPool<C>
+    where C: Context
+{
+    /// The number of idle connections in the pool.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns true if there are no idle connections in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Sends a mail using one of the connections of the pool.
+    ///
+    /// The future resolves to the pool and the result of sending the
+    /// mail, it never fails. If the pool is empty the mail fails with a
+    /// I/O error of the kind `NotConnected`.
+    pub fn send(self, mail: MailRequest) -> PoolSendFuture<C> {
+        let fut = encode_outgoing(mail, self.ctx.clone(), self.config.send_target)
+            .then(move |result| match result {
+                Ok(mail) => Either::A(self.send_outgoing(mail)),
+                Err(err) => Either::B(future::ok((self, Err(err))))
+            });
+
+        Box::new(fut)
+    }
+
+    /// Checks if mails can be send, e.g. as readiness probe of a load balancer.
+    ///
+    /// This reuses an idle connection of the pool if possible by probing
+    /// it with `RSET` (see `probe_connection`). Idle connections which
+    /// are broken or not usable anymore are dropped. If there is no usable
+    /// idle connection, a new connection is set up and closed again (see
+    /// `readiness_check`) without adding it to the pool.
+    ///
+    /// Probing a connection and setting up a new one each have to finish
+    /// within the given timeout. Like `send` the future resolves to the
+    /// pool and the result of the check, it never fails.
+    pub fn readiness_check<A, S>(self, conconf: ConnectionConfig<A, S>, timeout: Duration)
+        -> impl Future<Item=(Self, Result<(), MailSendError>), Error=()>
+        where A: Cmd, S: SetupTls
+    {
+        future::loop_fn((self, conconf), move |(mut pool, conconf)| {
+            match pool.connections.pop() {
+                Some(con) => {
+                    let probe = with_timeout(probe_connection(con.into_inner()), Some(timeout), TimeoutPhase::Command)
+                        .then(move |result| {
+                            match result {
+                                Ok((con, true)) => {
+                                    pool.connections.push(QuitOnDrop::new(con));
+                                    return Ok(Loop::Break((pool, Ok(()))));
+                                },
+                                // e.g. the server is about to close the connection
+                                Ok((con, false)) => drop(QuitOnDrop::new(con)),
+                                Err(_) => {}
+                            }
+                            Ok(Loop::Continue((pool, conconf)))
+                        });
+                    Either::A(probe)
+                },
+                None => {
+                    let check = readiness_check(conconf, timeout)
+                        .then(move |result| Ok(Loop::Break((pool, result))));
+                    Either::B(check)
+                }
+            }
+        })
+    }
+
+    fn send_outgoing(mut self, mail: OutgoingMail)
+        -> impl Future<Item=(Self, Result<MailResponse, MailSendError>), Error=()>
+    {
+        let con = match self.connections.pop() {
+            Some(con) => con.into_inner(),
+            None => return Either::B(future::ok((self, Err(pool_empty()))))
+        };
+
+        let fut = send_envelop_with(con, mail, &self.config)
+            .then(move |result| {
+                let result = match result {
+                    Ok((con, result)) => {
+                        self.connections.push(QuitOnDrop::new(con));
+                        result
+                    },
+                    Err(err) => Err(err)
+                };
+                Ok((self, result))
+            });
+
+        Either::A(fut)
+    }
+}
+
+impl<C> fmt::Debug for ConnectionPool<C> {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.debug_struct("ConnectionPool")
+            .field("idle_connections", &self.connections.len())
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+fn pool_empty() -> MailSendError {
+    MailSendError::Io(std_io::Error::new(
+        std_io::ErrorKind::NotConnected,
+        "connection pool has no idle connection"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+
+    mod connection_pool {
+        use std::{
+            io as std_io,
+            net::{SocketAddr, TcpListener},
+            time::Duration
+        };
+        use mail::default_impl::simple_context;
+        use ::{
+            config::SendConfig,
+            error::MailSendError,
+            session::QuitOnDrop,
+            test_utils::{FakeServer, Reply, con_config, mock_envelop, run, spawn_smtp_server, test_context}
+        };
+        use super::super::ConnectionPool;
+
+        fn unreachable_addr() -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        }
+
+        fn pool(servers: &[&FakeServer]) -> ConnectionPool<simple_context::Context> {
+            let ctx = test_context();
+            ConnectionPool {
+                connections: servers.iter().map(|server| QuitOnDrop::new(server.connection())).collect(),
+                ctx,
+                config: SendConfig::default()
+            }
+        }
+
+        #[test]
+        fn returns_connection_to_pool_after_sending() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n")
+            ]);
+            let pool = pool(&[&server]);
+
+            let (pool, result) = run(pool.send_outgoing(mock_envelop(&["a@test.test"]).into())).unwrap();
+            result.unwrap();
+            assert_eq!(pool.len(), 1);
+        }
+
+        #[test]
+        fn drops_broken_connections() {
+            let server = FakeServer::new(vec![]);
+            let pool = pool(&[&server]);
+
+            let (pool, result) = run(pool.send_outgoing(mock_envelop(&["a@test.test"]).into())).unwrap();
+            assert!(result.is_err());
+            assert!(pool.is_empty());
+        }
+
+        #[test]
+        fn fails_mails_if_pool_is_empty() {
+            let pool = pool(&[]);
+
+            let (pool, result) = run(pool.send_outgoing(mock_envelop(&["a@test.test"]).into())).unwrap();
+            match result {
+                Err(MailSendError::Io(ref err)) => assert_eq!(err.kind(), std_io::ErrorKind::NotConnected),
+                other => panic!("unexpected result: {:?}", other)
+            }
+            assert!(pool.is_empty());
+        }
+
+        #[test]
+        fn readiness_check_reuses_idle_connection() {
+            let server = FakeServer::new(vec![Reply::Lines("250 Ok\r\n")]);
+            let pool = pool(&[&server]);
+
+            let fut = pool.readiness_check(con_config(unreachable_addr()), Duration::from_secs(5));
+            let (pool, result) = run(fut).unwrap();
+            result.unwrap();
+            assert_eq!(pool.len(), 1);
+            assert_eq!(server.written(), "RSET\r\n");
+        }
+
+        #[test]
+        fn readiness_check_connects_if_no_idle_connection_is_usable() {
+            let broken = FakeServer::new(vec![]);
+            let pool = pool(&[&broken]);
+
+            let fut = pool.readiness_check(con_config(spawn_smtp_server()), Duration::from_secs(5));
+            let (pool, result) = run(fut).unwrap();
+            result.unwrap();
+            assert!(pool.is_empty());
+        }
+
+        #[test]
+        fn readiness_check_fails_if_server_is_unreachable() {
+            let broken = FakeServer::new(vec![]);
+            let pool = pool(&[&broken]);
+
+            let fut = pool.readiness_check(con_config(unreachable_addr()), Duration::from_secs(5));
+            let (pool, result) = run(fut).unwrap();
+            result.unwrap_err();
+            assert!(pool.is_empty());
+        }
+    }
+}
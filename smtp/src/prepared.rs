@@ -0,0 +1,261 @@
+//! Module containing mails which are encoded once and send to different recipients.
+use futures::{
+    Future, Stream,
+    future::{self, Either}
+};
+use vec1::Vec1;
+
+use mail::Context;
+use new_tokio_smtp::{
+    Cmd, ConnectionConfig, SetupTls,
+    send_mail::{self as smtp, MailAddress, MailEnvelop, EnvelopData}
+};
+
+use ::{
+    config::{SendConfig, SendTarget},
+    error::MailSendError,
+    params::EsmtpParams,
+    request::MailRequest,
+    response::MailResponse,
+    send_mail::encode_outgoing,
+    session::{connect_send_quit, source_from_vec},
+    transaction::OutgoingMail
+};
+
+/// A mail which was encoded once and can be send to different recipients.
+///
+/// Created using `MailRequest::pre_encode`. When sending the identical
+/// mail to many recipients over multiple calls, this avoids encoding the
+/// mail (including loading its resources) again each time.
+///
+/// The reverse path and the ESMTP parameters are the ones of the request,
+/// the recipients are given each time the mail is send. Note that the mail
+/// itself is not changed, e.g. the `To` header still contains the recipients
+/// of the original mail.
+#[derive(Debug, Clone)]
+pub struct PreparedMail {
+    mail: smtp::Mail,
+    reverse_path: Option<MailAddress>,
+    params: EsmtpParams,
+    bounce: bool
+}
+
+impl PreparedMail {
+
+    pub(crate) fn encode<C>(request: MailRequest, ctx: C) -> impl Future<Item=Self, Error=MailSendError>
+        where C: Context
+    {
+        let bounce = request.is_bounce();
+        encode_outgoing(request, ctx, SendTarget::Msa)
+            .map(move |outgoing| {
+                let OutgoingMail { envelop, params, .. } = outgoing;
+                let (mail, envelop_data): (smtp::Mail, EnvelopData) = envelop.into();
+                PreparedMail { mail, reverse_path: envelop_data.from, params, bounce }
+            })
+    }
+
+    /// The reverse path (smtp from) used when sending the mail.
+    ///
+    /// `None` is the null reverse path (`MAIL FROM:<>`).
+    pub fn reverse_path(&self) -> Option<&MailAddress> {
+        self.reverse_path.as_ref()
+    }
+
+    /// The encoded mail.
+    pub fn raw_data(&self) -> &[u8] {
+        self.mail.raw_data()
+    }
+
+    /// Sends the mail to the given recipients.
+    ///
+    /// Like `send` this opens a connection, sends the mail and then closes
+    /// the connection again, but without encoding the mail.
+    ///
+    /// This uses the default `SendConfig`, use `send_to_with` to
+    /// use a custom configuration (e.g. to set timeouts).
+    pub fn send_to<A, S>(&self, recipients: Vec1<MailAddress>, conconf: ConnectionConfig<A, S>)
+        -> impl Future<Item=MailResponse, Error=MailSendError>
+        where A: Cmd, S: SetupTls
+    {
+        self.send_to_with(recipients, conconf, SendConfig::default())
+    }
+
+    /// Sends the mail to the given recipients using the given `SendConfig`.
+    ///
+    /// When sending to a MX bounces are send with the null reverse path,
+    /// as it is done by `send_with`.
+    pub fn send_to_with<A, S>(
+        &self,
+        recipients: Vec1<MailAddress>,
+        conconf: ConnectionConfig<A, S>,
+        config: SendConfig
+    ) -> impl Future<Item=MailResponse, Error=MailSendError>
+        where A: Cmd, S: SetupTls
+    {
+        let mail = self.outgoing(recipients, config.send_target);
+        connect_send_quit(conconf, source_from_vec(vec![Ok(mail)]), config, None)
+            .collect()
+            .map(|mut results| results.pop().expect("[BUG] sending one mail expects one result"))
+    }
+
+    /// Sends the mail again to the recipients which the server rejected transiently.
+    ///
+    /// The response is the response of a previous send of this mail. If the
+    /// server rejected some of the recipients with a transient error (`4xx`,
+    /// e.g. `450` for a temporarily unavailable mailbox) the identical mail
+    /// is send to only these recipients, instead of to all recipients again.
+    /// If there are none nothing is send and `None` is returned.
+    ///
+    /// Recipients are only rejected individually (instead of failing the
+    /// whole mail) with a `RecipientPolicy` accepting partial delivery.
+    /// The returned response can be used to retry again.
+    ///
+    /// This uses the default `SendConfig`, use `retry_transient_with` to
+    /// use a custom configuration.
+    pub fn retry_transient<A, S>(&self, response: &MailResponse, conconf: ConnectionConfig<A, S>)
+        -> impl Future<Item=Option<MailResponse>, Error=MailSendError>
+        where A: Cmd, S: SetupTls
+    {
+        self.retry_transient_with(response, conconf, SendConfig::default())
+    }
+
+    /// Sends the mail again to the transiently rejected recipients using the given `SendConfig`.
+    ///
+    /// See `retry_transient`.
+    pub fn retry_transient_with<A, S>(
+        &self,
+        response: &MailResponse,
+        conconf: ConnectionConfig<A, S>,
+        config: SendConfig
+    ) -> impl Future<Item=Option<MailResponse>, Error=MailSendError>
+        where A: Cmd, S: SetupTls
+    {
+        match self.retry_outgoing(response, config.send_target) {
+            Some(mail) => {
+                let fut = connect_send_quit(conconf, source_from_vec(vec![Ok(mail)]), config, None)
+                    .collect()
+                    .map(|mut results| Some(results.pop().expect("[BUG] sending one mail expects one result")));
+                Either::A(fut)
+            },
+            None => Either::B(future::ok(None))
+        }
+    }
+
+    /// Creates the mail to send to the transiently rejected recipients of the response, if there are any.
+    pub(crate) fn retry_outgoing(&self, response: &MailResponse, send_target: SendTarget) -> Option<OutgoingMail> {
+        let recipients = Vec1::from_vec(response.transiently_rejected()).ok()?;
+        Some(self.outgoing(recipients, send_target))
+    }
+
+    /// Creates the mail to send to the given recipients, reusing the encoded mail.
+    pub(crate) fn outgoing(&self, recipients: Vec1<MailAddress>, send_target: SendTarget) -> OutgoingMail {
+        let from = if send_target.is_mx() && self.bounce {
+            None
+        } else {
+            self.reverse_path.clone()
+        };
+        let envelop = MailEnvelop::from((self.mail.clone(), EnvelopData { from, to: recipients }));
+        OutgoingMail { envelop, params: self.params.clone(), encode_time: None, in_flight: None }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    mod prepared_mail {
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::MailAddress;
+        use ::{
+            config::{SendConfig, SendTarget, RecipientPolicy},
+            request::MailRequest,
+            test_utils::{FakeServer, Reply, run, simple_mail, test_context},
+            transaction::send_envelop_with
+        };
+        use super::super::PreparedMail;
+
+        fn recipients(address: &str) -> Vec1<MailAddress> {
+            Vec1::new(MailAddress::new_unchecked(address.to_owned(), false))
+        }
+
+        fn data_section(written: &str) -> String {
+            let start = written.find("DATA\r\n").expect("DATA was send");
+            written[start..].to_owned()
+        }
+
+        #[test]
+        fn is_encoded_once_for_multiple_sends() {
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+
+            let prepared = run(PreparedMail::encode(MailRequest::new(mail), ctx)).unwrap();
+
+            let mut data_sections = Vec::new();
+            for recipient in &["a@test.test", "b@test.test", "c@test.test"] {
+                let server = FakeServer::new(vec![
+                    Reply::Lines("250 Ok\r\n"),
+                    Reply::Lines("250 Ok\r\n"),
+                    Reply::Lines("354 Go ahead\r\n"),
+                    Reply::Lines("250 Ok: queued\r\n")
+                ]);
+                let outgoing = prepared.outgoing(recipients(recipient), SendTarget::Msa);
+                let fut = send_envelop_with(server.connection(), outgoing, &SendConfig::default());
+                let (_con, result) = run(fut).unwrap();
+                result.unwrap();
+
+                let written = server.written();
+                assert!(written.starts_with("MAIL FROM:<from@example.com>\r\n"));
+                assert!(written.contains(&format!("RCPT TO:<{}>\r\n", recipient)));
+                data_sections.push(data_section(&written));
+            }
+
+            // encoding again would e.g. generate a new Message-Id
+            let expected = format!("DATA\r\n{}", String::from_utf8(prepared.raw_data().to_owned()).unwrap());
+            for data in &data_sections {
+                assert!(data.starts_with(&expected));
+            }
+        }
+
+        #[test]
+        fn retries_only_the_transiently_rejected_recipients() {
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+            let prepared = run(PreparedMail::encode(MailRequest::new(mail), ctx)).unwrap();
+            let mut config = SendConfig::default();
+            config.recipient_policy = RecipientPolicy::AcceptPartial;
+
+            let server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("450 4.2.1 Mailbox busy\r\n"),
+                Reply::Lines("450 4.2.1 Mailbox busy\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n")
+            ]);
+            let all = Vec1::from_vec(["a@test.test", "b@test.test", "c@test.test"].iter()
+                .map(|address| MailAddress::new_unchecked((*address).to_owned(), false))
+                .collect()).unwrap();
+            let fut = send_envelop_with(server.connection(), prepared.outgoing(all, SendTarget::Msa), &config);
+            let (_con, result) = run(fut).unwrap();
+            let response = result.unwrap();
+            let first_data = data_section(&server.written());
+
+            let retry_server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n")
+            ]);
+            let retry = prepared.retry_outgoing(&response, SendTarget::Msa).expect("recipients to retry");
+            let (_con, result) = run(send_envelop_with(retry_server.connection(), retry, &config)).unwrap();
+            assert!(result.unwrap().transiently_rejected().is_empty());
+
+            let written = retry_server.written();
+            let recipients = written.lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<b@test.test>", "RCPT TO:<c@test.test>"]);
+            assert_eq!(data_section(&written), first_data);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Module containing helpers for interpreting smtp replies.
+use new_tokio_smtp::{Response, error::LogicError};
+
+use ::error::RejectionReason;
+
+/// Returns the reply code of the response as a number (e.g. `250`).
+pub(crate) fn reply_code(response: &Response) -> u16 {
+    response.code()
+        .as_byte_string()
+        .iter()
+        .fold(0, |code, digit| code * 10 + (digit - b'0') as u16)
+}
+
+/// Checks that the reply code is of the expected class (e.g. `3` for `354`).
+pub(crate) fn check_response(response: Response, expected_class: u16) -> Result<Response, LogicError> {
+    if reply_code(&response) / 100 == expected_class {
+        Ok(response)
+    } else if response.is_erroneous() {
+        Err(LogicError::Code(response))
+    } else {
+        Err(LogicError::UnexpectedCode(response))
+    }
+}
+
+/// Parses an enhanced status code (RFC 3463) from the reply text.
+///
+/// Enhanced status codes are placed at the start of the reply
+/// text (RFC 2034), e.g. `5.1.1` in `550 5.1.1 unknown user`.
+pub(crate) fn parse_enhanced_status(lines: &[String]) -> Option<(u8, u16, u16)> {
+    let first_word = lines.first()?.split_whitespace().next()?;
+    let mut parts = first_word.split('.');
+
+    let class = parse_number_part(parts.next()?, 1)?;
+    let subject = parse_number_part(parts.next()?, 3)?;
+    let detail = parse_number_part(parts.next()?, 3)?;
+
+    if parts.next().is_some() || !(class == 2 || class == 4 || class == 5) {
+        return None;
+    }
+
+    Some((class as u8, subject, detail))
+}
+
+fn parse_number_part(part: &str, max_len: usize) -> Option<u16> {
+    if part.is_empty() || part.len() > max_len || !part.bytes().all(|bch| bch.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+/// Checks if the reply indicates that the mail was greylisted.
+///
+/// If the reply contains an enhanced status code it's used for
+/// the detection, i.e. `4.7.1` is always treated as greylisting
+/// while other codes are only treated as greylisting if the text
+/// explicitly mentions greylisting. Without an enhanced status
+/// code only `450`/`451` replies which mention greylisting are
+/// treated as such.
+pub(crate) fn is_greylisting(code: u16, lines: &[String]) -> bool {
+    if code / 100 != 4 {
+        return false;
+    }
+
+    match parse_enhanced_status(lines) {
+        Some((4, 7, 1)) => true,
+        Some(_) => mentions_greylisting(lines),
+        None => (code == 450 || code == 451) && mentions_greylisting(lines)
+    }
+}
+
+fn mentions_greylisting(lines: &[String]) -> bool {
+    lines.iter().any(|line| {
+        let line = line.to_lowercase();
+        line.contains("greylist") || line.contains("graylist")
+    })
+}
+
+/// Classifies why the server rejected something based on the reply.
+///
+/// The mailbox and quota related enhanced status codes are used first.
+/// Then phrases denying relaying or calling the mail spam are checked,
+/// as such replies are often send with the generic `5.7.1`. Otherwise
+/// the remaining enhanced status codes are used, then further text
+/// heuristics and finally the reply code.
+///
+/// Only specific phrases are matched, e.g. Postfix's `User unknown in
+/// relay recipient table` is about an unknown mailbox, not relaying, and
+/// a blocklist like `zen.spamhaus.org` is a policy rejection.
+pub(crate) fn rejection_reason(code: u16, lines: &[String]) -> RejectionReason {
+    let text = lines.join(" ").to_lowercase();
+    let mentions = |words: &[&str]| words.iter().any(|word| text.contains(word));
+    let enhanced_status = parse_enhanced_status(lines);
+
+    match enhanced_status {
+        Some((_, 1, 1)) | Some((_, 1, 2)) | Some((_, 1, 6)) | Some((_, 1, 10)) | Some((_, 2, 1)) => {
+            return RejectionReason::MailboxUnavailable;
+        },
+        Some((_, 2, 2)) => return RejectionReason::Quota,
+        _ => {}
+    }
+
+    if mentions(&[
+        "relay access denied", "relaying denied", "relay denied", "relaying not allowed",
+        "relay not permitted", "relaying not permitted", "unable to relay", "we do not relay"
+    ]) {
+        return RejectionReason::RelayDenied;
+    }
+    if mentions(&[
+        "as spam", "is spam", "spam detected", "spam message", "looks like spam",
+        "junk mail", "bulk mail"
+    ]) {
+        return RejectionReason::SpamSuspected;
+    }
+    if let Some((_, 7, _)) = enhanced_status {
+        return RejectionReason::PolicyRejection;
+    }
+
+    if mentions(&["quota", "mailbox full", "mailbox is full", "insufficient storage"]) {
+        RejectionReason::Quota
+    } else if mentions(&[
+        "user unknown", "unknown user", "no such user", "does not exist", "mailbox unavailable",
+        "mailbox not found", "invalid recipient", "unknown recipient", "no mailbox"
+    ]) {
+        RejectionReason::MailboxUnavailable
+    } else if mentions(&["policy", "blocked", "blacklist", "blocklist", "spf", "dkim", "dmarc", "not authorized"]) {
+        RejectionReason::PolicyRejection
+    } else {
+        match code {
+            552 => RejectionReason::Quota,
+            553 => RejectionReason::MailboxUnavailable,
+            _ => RejectionReason::Unknown
+        }
+    }
+}
+
+/// Extracts the queue id from the final response to the mail data.
+///
+/// Recognized are the formats used by:
+///
+/// - Postfix: `250 2.0.0 Ok: queued as 4Bq5Xk1XyZz`
+/// - Exim: `250 OK id=1tAbCd-000Ef1-Gh`
+/// - Sendmail: `250 2.0.0 x9GAbC12345678 Message accepted for delivery`
+pub(crate) fn parse_queue_id(lines: &[String]) -> Option<String> {
+    lines.iter()
+        .filter_map(|line| parse_queue_id_from_line(line))
+        .next()
+}
+
+fn parse_queue_id_from_line(line: &str) -> Option<String> {
+    let words = line.split_whitespace()
+        .skip_while(|word| is_enhanced_status(word))
+        .collect::<Vec<_>>();
+
+    let postfix = words.windows(3)
+        .find(|window| window[0].eq_ignore_ascii_case("queued") && window[1].eq_ignore_ascii_case("as"))
+        .map(|window| window[2]);
+    let exim = || words.iter()
+        .find(|word| word.len() > 3 && word.get(..3).map_or(false, |prefix| prefix.eq_ignore_ascii_case("id=")))
+        .map(|word| &word[3..]);
+    let sendmail = || {
+        let rest = words.get(1..4)?;
+        let accepted = rest.iter().map(|word| word.to_lowercase()).collect::<Vec<_>>();
+        if accepted == ["message", "accepted", "for"] { Some(words[0]) } else { None }
+    };
+
+    let id = postfix.or_else(exim).or_else(sendmail)?
+        .trim_matches(|ch: char| !ch.is_ascii_alphanumeric());
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_owned())
+    }
+}
+
+fn is_enhanced_status(word: &str) -> bool {
+    parse_enhanced_status(&[word.to_owned()]).is_some()
+}
+
+#[cfg(test)]
+mod test {
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(ToOwned::to_owned).collect()
+    }
+
+    mod parse_enhanced_status {
+        use super::lines;
+        use super::super::parse_enhanced_status;
+
+        #[test]
+        fn parses_enhanced_status() {
+            assert_eq!(parse_enhanced_status(&lines("5.1.1 unknown user")), Some((5, 1, 1)));
+            assert_eq!(parse_enhanced_status(&lines("4.7.1 try later")), Some((4, 7, 1)));
+            assert_eq!(parse_enhanced_status(&lines("2.0.0 Ok: queued as 12AB")), Some((2, 0, 0)));
+            assert_eq!(parse_enhanced_status(&lines("5.7.133 blocked")), Some((5, 7, 133)));
+        }
+
+        #[test]
+        fn returns_none_without_enhanced_status() {
+            assert_eq!(parse_enhanced_status(&lines("unknown user")), None);
+            assert_eq!(parse_enhanced_status(&lines("")), None);
+            assert_eq!(parse_enhanced_status(&[]), None);
+            assert_eq!(parse_enhanced_status(&lines("1.2.3 no valid class")), None);
+            assert_eq!(parse_enhanced_status(&lines("5.1 to short")), None);
+            assert_eq!(parse_enhanced_status(&lines("5.1.1.1 to long")), None);
+            assert_eq!(parse_enhanced_status(&lines("5.1234.1 to long subject")), None);
+        }
+    }
+
+    mod is_greylisting {
+        use super::lines;
+        use super::super::is_greylisting;
+
+        #[test]
+        fn enhanced_status_4_7_1_is_greylisting() {
+            assert!(is_greylisting(451, &lines("4.7.1 Please try again later")));
+        }
+
+        #[test]
+        fn other_enhanced_status_needs_greylist_text() {
+            assert!(is_greylisting(450, &lines("4.2.0 Recipient address rejected: Greylisted")));
+            assert!(!is_greylisting(452, &lines("4.2.2 Mailbox full")));
+        }
+
+        #[test]
+        fn without_enhanced_status_only_450_451_with_text() {
+            assert!(is_greylisting(451, &lines("Greylisted, please try again in 300 seconds")));
+            assert!(!is_greylisting(421, &lines("greylisted")));
+            assert!(!is_greylisting(451, &lines("Local error in processing")));
+        }
+
+        #[test]
+        fn permanent_errors_are_never_greylisting() {
+            assert!(!is_greylisting(550, &lines("5.7.1 greylisted forever")));
+        }
+    }
+
+    mod rejection_reason {
+        use ::error::RejectionReason;
+        use super::lines;
+        use super::super::rejection_reason;
+
+        #[test]
+        fn classifies_unknown_mailboxes() {
+            assert_eq!(
+                rejection_reason(550, &lines("5.1.1 <a@test.test>: Recipient address rejected: User unknown")),
+                RejectionReason::MailboxUnavailable
+            );
+            assert_eq!(
+                rejection_reason(550, &lines("Requested action not taken: mailbox unavailable")),
+                RejectionReason::MailboxUnavailable
+            );
+        }
+
+        #[test]
+        fn unknown_mailbox_in_relay_recipient_table_is_not_relay_denied() {
+            assert_eq!(
+                rejection_reason(550, &lines("5.1.1 <a@test.test>: Recipient address rejected: User unknown in relay recipient table")),
+                RejectionReason::MailboxUnavailable
+            );
+        }
+
+        #[test]
+        fn blocklists_are_policy_rejections() {
+            assert_eq!(
+                rejection_reason(554, &lines("5.7.1 Service unavailable; Client host [192.0.2.1] blocked using zen.spamhaus.org")),
+                RejectionReason::PolicyRejection
+            );
+        }
+
+        #[test]
+        fn classifies_relay_denied() {
+            assert_eq!(rejection_reason(554, &lines("5.7.1 <a@test.test>: Relay access denied")), RejectionReason::RelayDenied);
+            assert_eq!(rejection_reason(550, &lines("relaying denied")), RejectionReason::RelayDenied);
+        }
+
+        #[test]
+        fn classifies_spam() {
+            assert_eq!(
+                rejection_reason(554, &lines("5.7.1 Message rejected as spam by Content Filtering")),
+                RejectionReason::SpamSuspected
+            );
+        }
+
+        #[test]
+        fn classifies_policy_rejections() {
+            assert_eq!(
+                rejection_reason(550, &lines("5.7.26 Unauthenticated email is not accepted")),
+                RejectionReason::PolicyRejection
+            );
+            assert_eq!(
+                rejection_reason(554, &lines("Service unavailable; Client host [192.0.2.1] blocked using bl.example.org")),
+                RejectionReason::PolicyRejection
+            );
+            assert_eq!(rejection_reason(550, &lines("rejected due to local policy")), RejectionReason::PolicyRejection);
+        }
+
+        #[test]
+        fn classifies_quota() {
+            assert_eq!(rejection_reason(552, &lines("5.2.2 Mailbox full")), RejectionReason::Quota);
+            assert_eq!(rejection_reason(552, &lines("Requested mail action aborted")), RejectionReason::Quota);
+            assert_eq!(rejection_reason(550, &lines("user is over quota")), RejectionReason::Quota);
+        }
+
+        #[test]
+        fn falls_back_to_unknown() {
+            assert_eq!(rejection_reason(554, &lines("Transaction failed")), RejectionReason::Unknown);
+            assert_eq!(rejection_reason(550, &[]), RejectionReason::Unknown);
+        }
+    }
+
+    mod parse_queue_id {
+        use super::lines;
+        use super::super::parse_queue_id;
+
+        #[test]
+        fn parses_postfix_queue_id() {
+            assert_eq!(parse_queue_id(&lines("2.0.0 Ok: queued as 4Bq5Xk1XyZz")), Some("4Bq5Xk1XyZz".to_owned()));
+            assert_eq!(parse_queue_id(&lines("Ok: queued as 9C2F1A3B")), Some("9C2F1A3B".to_owned()));
+        }
+
+        #[test]
+        fn parses_exim_queue_id() {
+            assert_eq!(parse_queue_id(&lines("OK id=1tAbCd-000Ef1-Gh")), Some("1tAbCd-000Ef1-Gh".to_owned()));
+        }
+
+        #[test]
+        fn parses_sendmail_queue_id() {
+            assert_eq!(
+                parse_queue_id(&lines("2.0.0 x9GAbC12345678 Message accepted for delivery")),
+                Some("x9GAbC12345678".to_owned())
+            );
+        }
+
+        #[test]
+        fn returns_none_for_unknown_formats() {
+            assert_eq!(parse_queue_id(&lines("2.0.0 Ok")), None);
+            assert_eq!(parse_queue_id(&lines("Ok: queued as")), None);
+            assert_eq!(parse_queue_id(&[]), None);
+        }
+
+        #[test]
+        fn does_not_panic_on_non_ascii_replies() {
+            assert_eq!(parse_queue_id(&lines("OK id✓x")), None);
+            assert_eq!(parse_queue_id(&lines("OK ✓✓ Message accepted for delivery")), None);
+        }
+    }
+}
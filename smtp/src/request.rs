@@ -1,5 +1,8 @@
 use std::mem;
 
+use futures::Future;
+use vec1::Vec1;
+
 use new_tokio_smtp::send_mail::{
     self as smtp,
     MailAddress,
@@ -8,20 +11,27 @@ use new_tokio_smtp::send_mail::{
 
 use mail_internals::{
     MailType,
-    encoder::{EncodingBuffer, EncodableInHeader},
+    encoder::{EncodingBuffer, EncodingWriter, EncodableInHeader},
     error::EncodingError
 };
 use headers::{
-    headers::{Sender, _From, _To},
-    header_components::Mailbox,
+    HeaderKind, HeaderMap,
+    headers::{Sender, _From, _To, Cc, Bcc, Date, MessageId},
+    header_components::{Mailbox, DateTime},
     error::{BuildInValidationError}
 };
 use mail::{
-    Mail,
+    Mail, Context,
     error::{MailError, OtherValidationError}
 };
 
-use ::error::{ OtherValidationError as AnotherOtherValidationError };
+use ::{
+    error::{ OtherValidationError as AnotherOtherValidationError, InvalidEsmtpParam, MailSendError },
+    fields,
+    params::{EsmtpParams, EsmtpParam, AuthSubmitter},
+    prepared::PreparedMail,
+    trace::ReceivedHeader
+};
 
 /// This type contains a mail and potentially some envelop data.
 ///
@@ -35,7 +45,87 @@ use ::error::{ OtherValidationError as AnotherOtherValidationError };
 #[derive(Clone, Debug)]
 pub struct MailRequest {
     mail: Mail,
-    envelop_data: Option<EnvelopData>
+    envelop_data: Option<EnvelopData>,
+    reverse_path: Option<MailAddress>,
+    bcc_handling: BccHandling,
+    params: EsmtpParams,
+    bounce: bool,
+    null_reverse_path: bool,
+    skip_punycode: bool,
+    sender: Option<Mailbox>,
+    received: Vec<ReceivedHeader>,
+    auto_submitted: Option<AutoSubmitted>,
+    auto_date: bool,
+    auto_message_id: bool,
+    force_smtputf8: Option<bool>
+}
+
+/// Controls if the `Bcc` header is removed from the mail before it is encoded.
+///
+/// This only affects the transmitted headers, it never affects who
+/// receives the mail: If the envelop data is derived from the mail,
+/// all `Bcc` recipients are always included in the smtp recipients
+/// (as are all `To` and `Cc` recipients, see `derive_envelop_data_from_mail`).
+/// If the envelop data was set explicitly, exactly the recipients in it
+/// receive the mail.
+///
+/// As all recipients of a mail transaction receive the same data,
+/// keeping the `Bcc` header when sending to the `Bcc` recipients
+/// themselves reveals them to all other recipients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BccHandling {
+    /// Always remove the `Bcc` header (the default).
+    StripAlways,
+
+    /// Keep the `Bcc` header only if the envelop data was set explicitly.
+    ///
+    /// This is meant for sending a copy of the mail to an archive
+    /// address (using `new_with_envelop` or `override_envelop`), while
+    /// the same mail send with derived recipients has the header removed.
+    KeepForArchive,
+
+    /// Never remove the `Bcc` header.
+    Keep
+}
+
+impl Default for BccHandling {
+    fn default() -> Self {
+        BccHandling::StripAlways
+    }
+}
+
+/// The kind of automatically submitted mail (RFC 3834).
+///
+/// See `MailRequest::mark_auto_submitted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AutoSubmitted {
+    /// The mail was generated automatically (e.g. a notification or a bounce).
+    AutoGenerated,
+
+    /// The mail is an automatic reply to another mail (e.g. a vacation notice).
+    AutoReplied
+}
+
+impl AutoSubmitted {
+
+    /// The value of the `Auto-Submitted` header, e.g. `auto-generated`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AutoSubmitted::AutoGenerated => "auto-generated",
+            AutoSubmitted::AutoReplied => "auto-replied"
+        }
+    }
+
+}
+
+impl EncodableInHeader for AutoSubmitted {
+    fn encode(&self, handle: &mut EncodingWriter) -> Result<(), EncodingError> {
+        handle.write_str_unchecked(self.as_str())
+    }
+
+    fn boxed_clone(&self) -> Box<EncodableInHeader> {
+        Box::new(*self)
+    }
 }
 
 impl From<Mail> for MailRequest {
@@ -44,13 +134,32 @@ impl From<Mail> for MailRequest {
     }
 }
 
-
+impl From<(Mail, EnvelopData)> for MailRequest {
+    fn from((mail, envelop): (Mail, EnvelopData)) -> Self {
+        MailRequest::new_with_envelop(mail, envelop)
+    }
+}
 
 impl MailRequest {
 
     /// creates a new `MailRequest` from a `Mail` instance
     pub fn new(mail: Mail) -> Self {
-        MailRequest { mail, envelop_data: None }
+        MailRequest {
+            mail,
+            envelop_data: None,
+            reverse_path: None,
+            bcc_handling: Default::default(),
+            params: Default::default(),
+            bounce: false,
+            null_reverse_path: false,
+            skip_punycode: false,
+            sender: None,
+            received: Vec::new(),
+            auto_submitted: None,
+            auto_date: false,
+            auto_message_id: false,
+            force_smtputf8: None
+        }
     }
 
     /// create a new `MailRequest` and use custom smtp `EnvelopData`
@@ -60,7 +169,9 @@ impl MailRequest {
     /// cases where you need to set it manually just import it from
     /// `new-tokio-smtp`.
     pub fn new_with_envelop(mail: Mail, envelop: EnvelopData) -> Self {
-        MailRequest { mail, envelop_data: Some(envelop) }
+        let mut request = MailRequest::new(mail);
+        request.envelop_data = Some(envelop);
+        request
     }
 
     /// replace the smtp `EnvelopData`
@@ -68,12 +179,390 @@ impl MailRequest {
         mem::replace(&mut self.envelop_data, Some(envelop))
     }
 
+    /// override only the smtp reverse path (smtp from)
+    ///
+    /// The recipients are still derived from the `Mail` headers
+    /// and the `Mail` itself (including its `From`/`Sender` headers)
+    /// is not changed.
+    ///
+    /// If a full envelop was set (using `new_with_envelop` or `override_envelop`)
+    /// the reverse path set with this method still takes precedence over the
+    /// one in the envelop, i.e. it always is the reverse path used to send the mail.
+    ///
+    /// Returns the previously set reverse path override, if there was any.
+    pub fn set_reverse_path_only(&mut self, reverse_path: MailAddress) -> Option<MailAddress> {
+        mem::replace(&mut self.reverse_path, Some(reverse_path))
+    }
+
+    /// use the given mailbox as sender if the mail has multiple `From` mailboxes
+    ///
+    /// A mail with multiple mailboxes in `From` needs a `Sender` header,
+    /// which is used as reverse path (smtp from). If the mail has no `Sender`
+    /// header the sender given here is used to derive the reverse path
+    /// instead of failing with `MultiMailboxFromWithoutSender`.
+    ///
+    /// As such a mail can't be encoded without `Sender` header, the sender
+    /// is also added as `Sender` header to the mail when it's encoded.
+    /// The sender is only used in this case, i.e. it doesn't override an
+    /// existing `Sender` header or a single `From` mailbox.
+    pub fn with_sender(mut self, sender: Mailbox) -> Self {
+        self.sender = Some(sender);
+        self
+    }
+
+    /// prepend a `Received` trace header to the mail when it is encoded
+    ///
+    /// This is meant for relays documenting the hop before sending the
+    /// mail onward. The header is placed in front of all other headers
+    /// of the mail. If this is called multiple times the header
+    /// added last is placed first, as each hop prepends it's own header.
+    pub fn prepend_received(&mut self, header: ReceivedHeader) {
+        self.received.push(header);
+    }
+
+    /// mark the mail as automatically submitted (RFC 3834)
+    ///
+    /// This adds an `Auto-Submitted` header with the given kind when the
+    /// mail is encoded, which prevents e.g. vacation responders from
+    /// replying to it (and causing mail loops). If the mail already has
+    /// an `Auto-Submitted` header it's kept as is and none is added.
+    ///
+    /// Returns the previously set kind.
+    pub fn mark_auto_submitted(&mut self, kind: AutoSubmitted) -> Option<AutoSubmitted> {
+        mem::replace(&mut self.auto_submitted, Some(kind))
+    }
+
+    /// returns the kind the mail was marked as automatically submitted with
+    pub fn auto_submitted(&self) -> Option<AutoSubmitted> {
+        self.auto_submitted
+    }
+
+    /// add a `Date` header set to the current time if the mail has none
+    ///
+    /// Some servers reject mails without a `Date` header (or add one
+    /// themselves). If enabled a `Date` header is added when the mail is
+    /// encoded, unless the mail already has one, which is kept as is.
+    /// This is disabled by default.
+    ///
+    /// Returns the previous setting.
+    pub fn set_auto_date(&mut self, auto_date: bool) -> bool {
+        mem::replace(&mut self.auto_date, auto_date)
+    }
+
+    /// returns true if a `Date` header is added if the mail has none
+    pub fn auto_date(&self) -> bool {
+        self.auto_date
+    }
+
+    /// add a generated `Message-ID` header if the mail has none
+    ///
+    /// The id is generated using `Context::generate_message_id`, i.e. it
+    /// uses the domain of the context as right hand side. An existing
+    /// `Message-ID` header of the mail is kept as is. Either way
+    /// the id is returned by `MailResponse::message_id` for correlating
+    /// the mail later on. This is disabled by default.
+    ///
+    /// Returns the previous setting.
+    pub fn set_auto_message_id(&mut self, auto_message_id: bool) -> bool {
+        mem::replace(&mut self.auto_message_id, auto_message_id)
+    }
+
+    /// returns true if a `Message-ID` header is added if the mail has none
+    pub fn auto_message_id(&self) -> bool {
+        self.auto_message_id
+    }
+
+    /// override if the mail is send using `SMTPUTF8`
+    ///
+    /// By default `SMTPUTF8` is used if any address of the envelop needs
+    /// it. `Some(true)` always uses it (e.g. for an internationalized
+    /// `Subject` which should be send as UTF-8), the mail then fails with
+    /// `MailSendError::Smtp` if the server doesn't support `SMTPUTF8`, like
+    /// a mail with internationalized addresses does. `Some(false)` encodes
+    /// the mail as ASCII and only uses `SMTPUTF8` for addresses which can
+    /// not be send without it (i.e. with a non ASCII local part). `None`
+    /// uses the automatic detection again.
+    ///
+    /// Returns the previous setting.
+    pub fn set_force_smtputf8(&mut self, force_smtputf8: Option<bool>) -> Option<bool> {
+        mem::replace(&mut self.force_smtputf8, force_smtputf8)
+    }
+
+    /// returns the override of the `SMTPUTF8` detection, if any
+    pub fn force_smtputf8(&self) -> Option<bool> {
+        self.force_smtputf8
+    }
+
+    /// encode the mail once so that it can be send to different recipients
+    ///
+    /// See `PreparedMail` for more details.
+    pub fn pre_encode<C>(self, ctx: C) -> impl Future<Item=PreparedMail, Error=MailSendError>
+        where C: Context
+    {
+        PreparedMail::encode(self, ctx)
+    }
+
+    /// set how the `Bcc` header is handled, see `BccHandling`
+    ///
+    /// Returns the previously set handling.
+    pub fn set_bcc_handling(&mut self, handling: BccHandling) -> BccHandling {
+        mem::replace(&mut self.bcc_handling, handling)
+    }
+
+    /// returns how the `Bcc` header is handled
+    pub fn bcc_handling(&self) -> BccHandling {
+        self.bcc_handling
+    }
+
+    /// mark the mail as bounce (delivery status notification)
+    ///
+    /// When sending to a MX (`SendTarget::Mx`) bounces are send with the
+    /// null reverse path (`MAIL FROM:<>`), overriding any reverse path set
+    /// or derived, so that they can not cause further bounces. When sending
+    /// to a MSA this has no effect.
+    ///
+    /// Returns if the mail was marked as bounce before.
+    pub fn set_bounce(&mut self, bounce: bool) -> bool {
+        mem::replace(&mut self.bounce, bounce)
+    }
+
+    /// returns if the mail is marked as bounce
+    pub fn is_bounce(&self) -> bool {
+        self.bounce
+    }
+
+    /// keep internationalized domains of derived addresses as is
+    ///
+    /// By default the domains of the addresses derived from the mail are
+    /// punycode encoded (e.g. `tüst.test` becomes `xn--tst-hoa.test`) if
+    /// the address doesn't need `SMTPUTF8` anyway (i.e. its local part is
+    /// ASCII). If this is set the domain is kept as is (as U-label), even if
+    /// `SMTPUTF8` isn't used for the mail.
+    ///
+    /// This is meant for test/debug relays. Sending non ASCII addresses
+    /// without `SMTPUTF8` violates RFC 5321, most servers will reject such
+    /// addresses (or worse, mangle them), so this should not be used with
+    /// real servers. It has no effect on explicitly set envelop data.
+    ///
+    /// Returns the previous setting.
+    pub fn set_skip_punycode(&mut self, skip_punycode: bool) -> bool {
+        mem::replace(&mut self.skip_punycode, skip_punycode)
+    }
+
+    /// Makes the mail be send with the null reverse path.
+    pub(crate) fn use_null_reverse_path(&mut self) {
+        self.null_reverse_path = true;
+    }
+
+    /// set the authenticated submitter send as `AUTH` parameter of `MAIL` (RFC 4954)
+    ///
+    /// This is used when submitting mails on behalf of another user through
+    /// a relay. It should only be set if the server supports `AUTH`, passing
+    /// in `None` removes it again.
+    ///
+    /// Returns the previously set submitter.
+    pub fn set_auth_submitter(&mut self, submitter: Option<AuthSubmitter>) -> Option<AuthSubmitter> {
+        self.params.set_auth_submitter(submitter)
+    }
+
+    /// add a raw ESMTP parameter to the `MAIL` command
+    ///
+    /// This is an escape hatch for (e.g. vendor specific) extensions this
+    /// crate doesn't support natively. The parameter is send as is, i.e.
+    /// as `keyword` or `keyword=value`, it's up to the caller to make sure
+    /// the server supports it. Adding a parameter with the same keyword as
+    /// a parameter set by this crate (e.g. `SMTPUTF8`) overrides it.
+    ///
+    /// # Error
+    ///
+    /// Fails if the keyword or value is syntactically invalid (RFC 5321):
+    /// the keyword has to consist of ASCII letters, digits and `-` (not
+    /// at the beginning), the value of printable ASCII characters other
+    /// than `=`.
+    pub fn add_mail_param(&mut self, keyword: &str, value: Option<&str>) -> Result<(), InvalidEsmtpParam> {
+        self.params.push_mail_param(EsmtpParam::new(keyword, value)?);
+        Ok(())
+    }
+
+    /// add a raw ESMTP parameter to each `RCPT` command
+    ///
+    /// This works like `add_mail_param` but the parameter is
+    /// added to the `RCPT` command of every recipient.
+    pub fn add_rcpt_param(&mut self, keyword: &str, value: Option<&str>) -> Result<(), InvalidEsmtpParam> {
+        self.params.push_rcpt_param(EsmtpParam::new(keyword, value)?);
+        Ok(())
+    }
+
+    /// returns the mail and the explicitly set envelop data
+    ///
+    /// Unlike `into_mail_with_envelop` this doesn't derive the envelop
+    /// data from the mail (so it never fails), i.e. the envelop data is
+    /// `None` if it wasn't set using `new_with_envelop` or `override_envelop`.
+    /// A reverse path override (`set_reverse_path_only`) is not applied.
+    pub fn parts(&self) -> (&Mail, Option<&EnvelopData>) {
+        (&self.mail, self.envelop_data.as_ref())
+    }
+
+    /// turns this request into the mail and the explicitly set envelop data
+    ///
+    /// Like `parts` this doesn't derive the envelop data. All other
+    /// settings of the request (e.g. the reverse path override or
+    /// the `BccHandling`) are discarded, the mail is returned as is.
+    pub fn into_parts(self) -> (Mail, Option<EnvelopData>) {
+        (self.mail, self.envelop_data)
+    }
+
+    pub(crate) fn params(&self) -> &EsmtpParams {
+        &self.params
+    }
+
     pub fn _into_mail_with_envelop(self) -> Result<(Mail, EnvelopData), MailError> {
-        let envelop =
-            if let Some(envelop) = self.envelop_data { envelop }
-            else { derive_envelop_data_from_mail(&self.mail)? };
+        let envelop = self.resolve_envelop()?;
+        let strip_bcc = self.strips_bcc();
+        let sender = self.sender;
+        // the envelop is derived from and the Bcc header stripped on the
+        // same (owned) mail, `into_mail_for_encoding` checks that the
+        // headers added afterwards didn't change the recipients
+        let mut mail = self.mail;
+        if strip_bcc {
+            mail.headers_mut().remove(Bcc);
+        }
+        if let Some(sender) = sender {
+            add_fallback_sender(&mut mail, sender)?;
+        }
+        Ok((mail, envelop))
+    }
+
+    /// Like `into_mail_with_envelop` but also inserts the headers added by this request.
+    ///
+    /// The `Received` headers are put in front of all other headers (the one
+    /// prepended last first). The `Auto-Submitted`, `Date` and `Message-ID`
+    /// headers are only inserted if enabled and the mail has none, the
+    /// `Message-ID` is generated using the context.
+    pub(crate) fn into_mail_for_encoding<C>(mut self, ctx: &C) -> Result<(Mail, EnvelopData), MailError>
+        where C: Context
+    {
+        let received = mem::replace(&mut self.received, Vec::new());
+        let auto_submitted = self.auto_submitted;
+        let auto_date = self.auto_date;
+        let auto_message_id = self.auto_message_id;
+        let check_snapshot = self.strips_bcc() && self.envelop_data.is_none();
+        let skip_punycode = self.skip_punycode;
+        let (mut mail, envelop) = self._into_mail_with_envelop()?;
+
+        {
+            let headers = mail.headers_mut();
+            if let Some(kind) = auto_submitted {
+                if !headers.contains(fields::AutoSubmitted) {
+                    headers.insert(fields::AutoSubmitted::body(kind));
+                }
+            }
+            if auto_date && !headers.contains(Date) {
+                headers.insert(Date::body(DateTime::now()));
+            }
+            if auto_message_id && !headers.contains(MessageId) {
+                headers.insert(MessageId::body(ctx.generate_message_id()));
+            }
+        }
+
+        if !received.is_empty() {
+            let mut trace = HeaderMap::new();
+            for header in received.into_iter().rev() {
+                trace.insert(fields::Received::body(header));
+            }
+            let headers = mem::replace(mail.headers_mut(), trace);
+            mail.headers_mut().insert_all(headers);
+        }
+
+        // this is the mail which is encoded, so its headers have to match the derived recipients
+        if check_snapshot {
+            check_recipient_snapshot(&mail, &envelop, skip_punycode)?;
+        }
+        Ok((mail, envelop))
+    }
+
+    fn strips_bcc(&self) -> bool {
+        match self.bcc_handling {
+            BccHandling::StripAlways => true,
+            BccHandling::KeepForArchive => self.envelop_data.is_none(),
+            BccHandling::Keep => false
+        }
+    }
+
+    /// Returns the envelop data which will be used to send the mail.
+    ///
+    /// This is the explicitly set envelop data or the envelop data
+    /// derived from the mail, with the reverse path override applied.
+    pub(crate) fn resolve_envelop(&self) -> Result<EnvelopData, MailError> {
+        let mut envelop =
+            if let Some(envelop) = self.envelop_data.as_ref() {
+                let mut envelop = envelop.clone();
+                if let Some(reverse_path) = self.reverse_path.as_ref() {
+                    envelop.from = Some(reverse_path.clone());
+                }
+                envelop
+            } else if let Some(reverse_path) = self.reverse_path.as_ref() {
+                EnvelopData {
+                    from: Some(reverse_path.clone()),
+                    to: derive_smtp_to_from_mail(&self.mail, self.skip_punycode)?
+                }
+            } else if self.null_reverse_path {
+                EnvelopData {
+                    from: None,
+                    to: derive_smtp_to_from_mail(&self.mail, self.skip_punycode)?
+                }
+            } else {
+                EnvelopData {
+                    from: Some(derive_smtp_from_from_mail(&self.mail, self.sender.as_ref(), self.skip_punycode)?),
+                    to: derive_smtp_to_from_mail(&self.mail, self.skip_punycode)?
+                }
+            };
+
+        if self.null_reverse_path {
+            envelop.from = None;
+        }
+
+        Ok(envelop)
+    }
+
+    /// returns which recipients are changed or can not be send if `SMTPUTF8` isn't used
+    ///
+    /// If a mail is send to a server without `SMTPUTF8` support, internationalized
+    /// addresses have to be downgraded: Addresses with an ASCII local part are
+    /// send with a punycode encoded domain (e.g. `tast@xn--tst-hoa.test`) while
+    /// addresses with a non ASCII local part can not be represented at all and
+    /// make sending the mail fail. This report lists both kinds of recipients,
+    /// so that e.g. the user can be notified about the unrepresentable addresses
+    /// before the mail is send (and fails).
+    ///
+    /// If the envelop data was set explicitly it is send as is, i.e. no
+    /// addresses are punycoded and all non ASCII addresses are unrepresentable.
+    /// The same is true for non ASCII domains if `set_skip_punycode` is used.
+    ///
+    /// # Error
+    ///
+    /// Fails if the envelop data has to be derived from the mail,
+    /// but deriving it fails (see `derive_envelop_data_from_mail`).
+    pub fn downgrade_report(&self) -> Result<DowngradeReport, MailError> {
+        let mut report = DowngradeReport::default();
+        if let Some(envelop) = self.envelop_data.as_ref() {
+            report.unrepresentable = envelop.to.iter()
+                .filter(|address| !address.as_str().is_ascii())
+                .cloned()
+                .collect();
+            return Ok(report);
+        }
 
-        Ok((self.mail, envelop))
+        let originals = derive_smtp_to_from_mail(&self.mail, true)?;
+        let send = derive_smtp_to_from_mail(&self.mail, self.skip_punycode)?;
+        for (original, send) in originals.into_iter().zip(send) {
+            if !send.as_str().is_ascii() {
+                report.unrepresentable.push(send);
+            } else if original.as_str() != send.as_str() {
+                report.punycoded.push((original, send));
+            }
+        }
+        Ok(report)
     }
 
     #[cfg(not(feature="extended-api"))]
@@ -94,10 +583,43 @@ impl MailRequest {
     }
 }
 
-fn mailaddress_from_mailbox(mailbox: &Mailbox) -> Result<MailAddress, EncodingError> {
+/// Recipients which are changed or can not be send without `SMTPUTF8`.
+///
+/// Returned by `MailRequest::downgrade_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DowngradeReport {
+    punycoded: Vec<(MailAddress, MailAddress)>,
+    unrepresentable: Vec<MailAddress>
+}
+
+impl DowngradeReport {
+
+    /// Recipients whose domain is punycode encoded, as `(original, as send)`.
+    pub fn punycoded(&self) -> &[(MailAddress, MailAddress)] {
+        &self.punycoded
+    }
+
+    /// Recipients which can not be send without `SMTPUTF8` (e.g. non ASCII local part).
+    pub fn unrepresentable(&self) -> &[MailAddress] {
+        &self.unrepresentable
+    }
+
+    /// Returns true if all recipients can be send without `SMTPUTF8`.
+    ///
+    /// Punycoded recipients still reach the same mailbox, so they don't count.
+    pub fn is_lossless(&self) -> bool {
+        self.unrepresentable.is_empty()
+    }
+}
+
+/// Turns the mailbox into a `MailAddress`.
+///
+/// Unless `skip_punycode` is set the domain is punycode encoded
+/// if the address doesn't need `SMTPUTF8`.
+fn mailaddress_from_mailbox(mailbox: &Mailbox, skip_punycode: bool) -> Result<MailAddress, EncodingError> {
     let email = &mailbox.email;
     let needs_smtputf8 = email.check_if_internationalized();
-    let mt = if needs_smtputf8 { MailType::Internationalized } else { MailType::Ascii };
+    let mt = if needs_smtputf8 || skip_punycode { MailType::Internationalized } else { MailType::Ascii };
     let mut buffer = EncodingBuffer::new(mt);
      {
         let mut writer = buffer.writer();
@@ -115,10 +637,14 @@ fn mailaddress_from_mailbox(mailbox: &Mailbox) -> Result<MailAddress, EncodingEr
 /// as smtp from else the single mailbox in from
 /// is used as smtp from.
 ///
-/// All `To`'s are used as smtp recipients.
+/// All `To`'s, `Cc`'s and `Bcc`'s are used as smtp recipients (in
+/// that order). Whether or not the `Bcc` header is transmitted is
+/// controlled by `MailRequest::set_bcc_handling`.
 ///
-/// **`Cc`/`Bcc` is currently no supported/has no
-/// special handling**
+/// **Breaking change:** Previously only the `To`'s were used as smtp
+/// recipients, i.e. mails with `Cc` or `Bcc` headers are now also send
+/// to these recipients. To keep sending only to the `To`'s, set the
+/// envelop data explicitly (`MailRequest::new_with_envelop`).
 ///
 /// # Error
 ///
@@ -130,43 +656,461 @@ fn mailaddress_from_mailbox(mailbox: &Mailbox) -> Result<MailAddress, EncodingEr
 ///
 pub fn derive_envelop_data_from_mail(mail: &Mail)
     -> Result<smtp::EnvelopData, MailError>
+{
+    Ok(EnvelopData {
+        from: Some(derive_smtp_from_from_mail(mail, None, false)?),
+        to: derive_smtp_to_from_mail(mail, false)?
+    })
+}
+
+/// Derives the reverse path from the `Sender` or `From` header.
+///
+/// If there is no `Sender` header but multiple `From` mailboxes
+/// `fallback_sender` is used if given.
+fn derive_smtp_from_from_mail(mail: &Mail, fallback_sender: Option<&Mailbox>, skip_punycode: bool)
+    -> Result<MailAddress, MailError>
 {
     let headers = mail.headers();
     let smtp_from =
         if let Some(sender) = headers.get_single(Sender) {
             let sender = sender?;
             //TODO double check with from field
-            mailaddress_from_mailbox(sender)?
+            mailaddress_from_mailbox(sender, skip_punycode)?
         } else {
             let from = headers.get_single(_From)
                 .ok_or(OtherValidationError::NoFrom)??;
 
             if from.len() > 1 {
-                return Err(BuildInValidationError::MultiMailboxFromWithoutSender.into());
+                let sender = fallback_sender
+                    .ok_or(BuildInValidationError::MultiMailboxFromWithoutSender)?;
+                return Ok(mailaddress_from_mailbox(sender, skip_punycode)?);
             }
 
-            mailaddress_from_mailbox(from.first())?
+            mailaddress_from_mailbox(from.first(), skip_punycode)?
         };
 
-    let smtp_to =
+    Ok(smtp_from)
+}
+
+fn derive_smtp_to_from_mail(mail: &Mail, skip_punycode: bool) -> Result<Vec1<MailAddress>, MailError> {
+    let mut smtp_to = derive_visible_smtp_to_from_mail(mail, skip_punycode)?;
+
+    if let Some(bcc) = mail.headers().get_single(Bcc) {
+        for mailbox in bcc?.iter() {
+            smtp_to.push(mailaddress_from_mailbox(mailbox, skip_punycode)?);
+        }
+    }
+
+    Ok(smtp_to)
+}
+
+/// Derives the recipients from the `To` and `Cc` headers (i.e. without `Bcc`).
+fn derive_visible_smtp_to_from_mail(mail: &Mail, skip_punycode: bool) -> Result<Vec1<MailAddress>, MailError> {
+    let headers = mail.headers();
+    let mut smtp_to =
         if let Some(to) = headers.get_single(_To) {
             let to = to?;
-            to.try_mapped_ref(mailaddress_from_mailbox)?
+            to.try_mapped_ref(|mailbox| mailaddress_from_mailbox(mailbox, skip_punycode))?
         } else {
             return Err(AnotherOtherValidationError::NoTo.into());
         };
 
-    //TODO Cc, Bcc
+    if let Some(cc) = headers.get_single(Cc) {
+        for mailbox in cc?.iter() {
+            smtp_to.push(mailaddress_from_mailbox(mailbox, skip_punycode)?);
+        }
+    }
 
-    Ok(EnvelopData {
-        from: Some(smtp_from),
-        to: smtp_to
-    })
+    Ok(smtp_to)
+}
+
+/// Adds the sender as `Sender` header if the mail has multiple `From` mailboxes but no `Sender`.
+fn add_fallback_sender(mail: &mut Mail, sender: Mailbox) -> Result<(), MailError> {
+    let needs_sender = {
+        let headers = mail.headers();
+        if headers.contains(Sender) {
+            false
+        } else {
+            match headers.get_single(_From) {
+                Some(from) => from?.len() > 1,
+                None => false
+            }
+        }
+    };
+    if needs_sender {
+        mail.headers_mut().insert(Sender::body(sender));
+    }
+    Ok(())
+}
+
+/// Checks that the envelop still matches the mail which is encoded.
+///
+/// Called after stripping `Bcc` and inserting the headers added by the
+/// request (e.g. `Received`), which rebuilds the header map.
+///
+/// The derived recipients start with the `To` and `Cc` recipients followed
+/// by the `Bcc` recipients. After stripping `Bcc` the `To` and `Cc` headers
+/// of the mail have to still produce the same leading recipients, otherwise
+/// the mail was changed in between and the transmitted headers wouldn't match
+/// the recipients the mail is send to.
+fn check_recipient_snapshot(mail: &Mail, envelop: &EnvelopData, skip_punycode: bool) -> Result<(), MailError> {
+    let visible = derive_visible_smtp_to_from_mail(mail, skip_punycode)?;
+    let consistent = !mail.headers().contains(Bcc)
+        && visible.len() <= envelop.to.len()
+        && visible.iter().zip(envelop.to.iter()).all(|(header, envelop)| header == envelop);
+
+    if consistent {
+        Ok(())
+    } else {
+        Err(AnotherOtherValidationError::InconsistentRecipients.into())
+    }
 }
 
 #[cfg(test)]
 mod test {
 
+    mod mail_request {
+        use new_tokio_smtp::send_mail::MailAddress;
+        use mail::{
+            Mail,
+            Resource,
+            file_buffer::FileBuffer
+        };
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::EnvelopData;
+        use headers::{
+            headers::{_From, _To, Bcc, Sender},
+            header_components::{MediaType, Mailbox, Email}
+        };
+        use std::time::UNIX_EPOCH;
+        use test_utils::test_context;
+        use trace::ReceivedHeader;
+        use super::super::{MailRequest, BccHandling, check_recipient_snapshot};
+
+        fn mock_resource() -> Resource {
+            let mt = MediaType::parse("text/plain; charset=utf-8").unwrap();
+            let fb = FileBuffer::new(mt, "abcd↓efg".to_owned().into());
+            Resource::sourceless_from_buffer(fb)
+        }
+
+        #[test]
+        fn can_be_created_from_a_mail() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test"]
+            }.unwrap());
+
+            let request: MailRequest = mail.into();
+
+            let (_mail, envelop_data) = request.parts();
+            assert!(envelop_data.is_none());
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            assert_eq!(envelop_data.from.as_ref().unwrap().as_str(), "ape@caffe.test");
+            assert_eq!(envelop_data.to.first().as_str(), "das@ding.test");
+        }
+
+        #[test]
+        fn can_be_created_from_a_mail_and_envelop_data() {
+            let mail = Mail::new_singlepart_mail(mock_resource());
+            let envelop = EnvelopData {
+                from: None,
+                to: Vec1::new(MailAddress::new_unchecked("das@ding.test".to_owned(), false))
+            };
+
+            let request: MailRequest = (mail, envelop).into();
+
+            let (_mail, envelop_data) = request.parts();
+            assert_eq!(envelop_data.unwrap().to.first().as_str(), "das@ding.test");
+        }
+
+        #[test]
+        fn set_reverse_path_only_overrides_from_but_derives_recipients() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test", "dies@ding.test"]
+            }.unwrap());
+
+            let mut request = MailRequest::new(mail);
+            request.set_reverse_path_only(
+                MailAddress::new_unchecked("bounce@caffe.test".to_owned(), false));
+
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+
+            assert_eq!(
+                envelop_data.from.as_ref().unwrap().as_str(),
+                "bounce@caffe.test"
+            );
+            let recipients = envelop_data.to.iter()
+                .map(|address| address.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["das@ding.test", "dies@ding.test"]);
+        }
+
+        #[test]
+        fn set_reverse_path_only_does_not_need_from_header() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _To: ["das@ding.test"]
+            }.unwrap());
+
+            let mut request = MailRequest::new(mail);
+            request.set_reverse_path_only(
+                MailAddress::new_unchecked("bounce@caffe.test".to_owned(), false));
+
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            assert_eq!(envelop_data.to.first().as_str(), "das@ding.test");
+        }
+
+        #[test]
+        fn null_reverse_path_overrides_reverse_path_and_needs_no_from() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _To: ["das@ding.test"]
+            }.unwrap());
+
+            let mut request = MailRequest::new(mail);
+            request.set_reverse_path_only(
+                MailAddress::new_unchecked("bounce@caffe.test".to_owned(), false));
+            assert!(!request.set_bounce(true));
+            assert!(request.is_bounce());
+            request.use_null_reverse_path();
+
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            assert!(envelop_data.from.is_none());
+            assert_eq!(envelop_data.to.first().as_str(), "das@ding.test");
+        }
+
+        #[test]
+        fn bounce_alone_keeps_reverse_path() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test"]
+            }.unwrap());
+
+            let mut request = MailRequest::new(mail);
+            request.set_bounce(true);
+
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            assert_eq!(envelop_data.from.as_ref().unwrap().as_str(), "ape@caffe.test");
+        }
+
+        #[test]
+        fn with_sender_is_used_for_multi_mailbox_from() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test", "affe@caffe.test"],
+                _To: ["das@ding.test"]
+            }.unwrap());
+
+            let sender = Mailbox::from(Email::new("editor@caffe.test").unwrap());
+            let request = MailRequest::new(mail).with_sender(sender);
+
+            let (mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            assert_eq!(envelop_data.from.as_ref().unwrap().as_str(), "editor@caffe.test");
+            // without it the mail can't be encoded
+            assert!(mail.headers().contains(Sender));
+        }
+
+        #[test]
+        fn with_sender_does_not_override_single_from() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test"]
+            }.unwrap());
+
+            let sender = Mailbox::from(Email::new("editor@caffe.test").unwrap());
+            let request = MailRequest::new(mail).with_sender(sender);
+
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            assert_eq!(envelop_data.from.as_ref().unwrap().as_str(), "ape@caffe.test");
+        }
+
+        #[test]
+        fn into_parts_does_not_derive_envelop() {
+            let mail = Mail::new_singlepart_mail(mock_resource());
+            let request = MailRequest::new(mail);
+
+            let (mail, envelop) = request.parts();
+            assert!(envelop.is_none());
+            assert!(!mail.headers().contains(_To));
+
+            let (_mail, envelop) = request.into_parts();
+            assert!(envelop.is_none());
+        }
+
+        #[test]
+        fn into_parts_returns_explicit_envelop() {
+            let request = MailRequest::new_with_envelop(mail_with_bcc(), archive_envelop());
+            let (mail, envelop) = request.into_parts();
+            assert_eq!(envelop.unwrap().to.first().as_str(), "archive@caffe.test");
+            // the mail is returned as is, i.e. Bcc is not stripped
+            assert!(mail.headers().contains(Bcc));
+        }
+
+        fn mail_with_bcc() -> Mail {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test"],
+                Bcc: ["archive@caffe.test"]
+            }.unwrap());
+            mail
+        }
+
+        fn archive_envelop() -> EnvelopData {
+            EnvelopData {
+                from: Some(MailAddress::new_unchecked("ape@caffe.test".to_owned(), false)),
+                to: Vec1::new(MailAddress::new_unchecked("archive@caffe.test".to_owned(), false))
+            }
+        }
+
+        fn has_bcc(request: MailRequest) -> bool {
+            let (mail, _envelop) = request._into_mail_with_envelop().unwrap();
+            mail.headers().contains(Bcc)
+        }
+
+        #[test]
+        fn strips_bcc_by_default() {
+            let request = MailRequest::new(mail_with_bcc());
+            assert_eq!(request.bcc_handling(), BccHandling::StripAlways);
+            assert!(!has_bcc(request));
+
+            let request = MailRequest::new_with_envelop(mail_with_bcc(), archive_envelop());
+            assert!(!has_bcc(request));
+        }
+
+        #[test]
+        fn keep_for_archive_only_keeps_bcc_with_explicit_envelop() {
+            let mut request = MailRequest::new(mail_with_bcc());
+            request.set_bcc_handling(BccHandling::KeepForArchive);
+            assert!(!has_bcc(request));
+
+            let mut request = MailRequest::new_with_envelop(mail_with_bcc(), archive_envelop());
+            request.set_bcc_handling(BccHandling::KeepForArchive);
+            assert!(has_bcc(request));
+        }
+
+        #[test]
+        fn keep_always_keeps_bcc() {
+            let mut request = MailRequest::new(mail_with_bcc());
+            request.set_bcc_handling(BccHandling::Keep);
+            assert!(has_bcc(request));
+        }
+
+        #[test]
+        fn detects_mail_changed_between_deriving_the_envelop_and_stripping_bcc() {
+            let request = MailRequest::new(mail_with_bcc());
+            let envelop = request.resolve_envelop().unwrap();
+            // simulates the mail being changed after the envelop was derived
+            let (mut mail, _envelop) = request.into_parts();
+            mail.headers_mut().remove(Bcc);
+            check_recipient_snapshot(&mail, &envelop, false).unwrap();
+
+            mail.insert_headers(headers! {
+                _To: ["other@ding.test"]
+            }.unwrap());
+            check_recipient_snapshot(&mail, &envelop, false).unwrap_err();
+        }
+
+        #[test]
+        fn encoding_a_mail_with_bcc_keeps_the_recipient_snapshot() {
+            let mut request = MailRequest::new(mail_with_bcc());
+            request.prepend_received(ReceivedHeader::new("a.test", "b.test", UNIX_EPOCH).unwrap());
+            let (mail, envelop) = request.into_mail_for_encoding(&test_context()).unwrap();
+            assert!(!mail.headers().contains(Bcc));
+            assert_eq!(envelop.to.len(), 2);
+        }
+
+        #[test]
+        fn bcc_recipients_receive_the_mail_even_if_the_header_is_stripped() {
+            let request = MailRequest::new(mail_with_bcc());
+            let (_mail, envelop_data) = request._into_mail_with_envelop().unwrap();
+            let recipients = envelop_data.to.iter()
+                .map(|address| address.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["das@ding.test", "archive@caffe.test"]);
+        }
+    }
+
+    mod downgrade_report {
+        use vec1::Vec1;
+        use mail::Mail;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use headers::headers::{_From, _To};
+        use super::super::MailRequest;
+
+        #[test]
+        fn lists_punycoded_and_unrepresentable_recipients() {
+            let mut mail = Mail::plain_text("body");
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test", "tast@tüst.test", "töst@tost.test"]
+            }.unwrap());
+            let report = MailRequest::new(mail).downgrade_report().unwrap();
+
+            assert_eq!(report.punycoded().len(), 1);
+            let (ref original, ref send) = report.punycoded()[0];
+            assert_eq!(original.as_str(), "tast@tüst.test");
+            assert_eq!(send.as_str(), "tast@xn--tst-hoa.test");
+
+            let unrepresentable = report.unrepresentable().iter()
+                .map(|address| address.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(unrepresentable, vec!["töst@tost.test"]);
+            assert!(!report.is_lossless());
+        }
+
+        #[test]
+        fn ascii_recipients_are_lossless() {
+            let mut mail = Mail::plain_text("body");
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test", "dies@ding.test"]
+            }.unwrap());
+            let report = MailRequest::new(mail).downgrade_report().unwrap();
+
+            assert!(report.punycoded().is_empty());
+            assert!(report.is_lossless());
+        }
+
+        #[test]
+        fn unicode_domains_are_unrepresentable_if_punycode_is_skipped() {
+            let mut mail = Mail::plain_text("body");
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["tast@tüst.test"]
+            }.unwrap());
+            let mut request = MailRequest::new(mail);
+            request.set_skip_punycode(true);
+            let report = request.downgrade_report().unwrap();
+
+            assert!(report.punycoded().is_empty());
+            assert_eq!(report.unrepresentable()[0].as_str(), "tast@tüst.test");
+        }
+
+        #[test]
+        fn explicit_envelop_data_is_never_punycoded() {
+            let envelop = EnvelopData {
+                from: None,
+                to: Vec1::from_vec(vec![
+                    MailAddress::new_unchecked("tast@tüst.test".to_owned(), false),
+                    MailAddress::new_unchecked("das@ding.test".to_owned(), false)
+                ]).unwrap()
+            };
+            let mail = Mail::plain_text("body");
+            let request = MailRequest::new_with_envelop(mail, envelop);
+            let report = request.downgrade_report().unwrap();
+
+            assert!(report.punycoded().is_empty());
+            assert_eq!(report.unrepresentable().len(), 1);
+            assert_eq!(report.unrepresentable()[0].as_str(), "tast@tüst.test");
+        }
+    }
+
     mod derive_envelop_data_from_mail {
         use super::super::derive_envelop_data_from_mail;
         use mail::{
@@ -175,7 +1119,7 @@ mod test {
             file_buffer::FileBuffer
         };
         use headers::{
-            headers::{_From, _To, Sender},
+            headers::{_From, _To, Cc, Bcc, Sender},
             header_components::MediaType
         };
 
@@ -248,6 +1192,35 @@ mod test {
                 "das@ding.test"
             );
         }
+
+        #[test]
+        fn use_to_cc_and_bcc_in_order() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                _To: ["das@ding.test"],
+                Cc: ["dies@ding.test", "jenes@ding.test"],
+                Bcc: ["archive@caffe.test"]
+            }.unwrap());
+
+            let envelop_data = derive_envelop_data_from_mail(&mail).unwrap();
+
+            let recipients = envelop_data.to.iter()
+                .map(|address| address.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["das@ding.test", "dies@ding.test", "jenes@ding.test", "archive@caffe.test"]);
+        }
+
+        #[test]
+        fn still_fails_without_to_even_with_cc() {
+            let mut mail = Mail::new_singlepart_mail(mock_resource());
+            mail.insert_headers(headers! {
+                _From: ["ape@caffe.test"],
+                Cc: ["dies@ding.test"]
+            }.unwrap());
+
+            derive_envelop_data_from_mail(&mail).unwrap_err();
+        }
     }
 
     mod mailaddress_from_mailbox {
@@ -261,13 +1234,13 @@ mod test {
         #[cfg_attr(not(feature="test-with-traceing"), ignore)]
         fn does_not_panic_with_tracing_enabled() {
             let mb = Mailbox::try_from("hy@b").unwrap();
-            mailaddress_from_mailbox(&mb).unwrap();
+            mailaddress_from_mailbox(&mb, false).unwrap();
         }
 
         #[test]
         fn correctly_converts_mailbox() {
             let mb = Mailbox::from(Email::new("tast@tost.test").unwrap());
-            let address = mailaddress_from_mailbox(&mb).unwrap();
+            let address = mailaddress_from_mailbox(&mb, false).unwrap();
             assert_eq!(address.as_str(), "tast@tost.test");
             assert_eq!(address.needs_smtputf8(), false);
         }
@@ -275,7 +1248,7 @@ mod test {
         #[test]
         fn tracks_if_smtputf8_is_needed() {
             let mb = Mailbox::from(Email::new("tüst@tost.test").unwrap());
-            let address = mailaddress_from_mailbox(&mb).unwrap();
+            let address = mailaddress_from_mailbox(&mb, false).unwrap();
             assert_eq!(address.as_str(), "tüst@tost.test");
             assert_eq!(address.needs_smtputf8(), true);
         }
@@ -283,15 +1256,23 @@ mod test {
         #[test]
         fn puny_encodes_domain_if_smtputf8_is_not_needed() {
             let mb = Mailbox::from(Email::new("tast@tüst.test").unwrap());
-            let address = mailaddress_from_mailbox(&mb).unwrap();
+            let address = mailaddress_from_mailbox(&mb, false).unwrap();
             assert_eq!(address.as_str(), "tast@xn--tst-hoa.test");
             assert_eq!(address.needs_smtputf8(), false);
         }
 
+        #[test]
+        fn keeps_unicode_domain_if_punycode_is_skipped() {
+            let mb = Mailbox::from(Email::new("tast@tüst.test").unwrap());
+            let address = mailaddress_from_mailbox(&mb, true).unwrap();
+            assert_eq!(address.as_str(), "tast@tüst.test");
+            assert_eq!(address.needs_smtputf8(), false);
+        }
+
         #[test]
         fn does_not_puny_encodes_domain_if_smtputf8_is_needed() {
             let mb = Mailbox::from(Email::new("töst@tüst.test").unwrap());
-            let address = mailaddress_from_mailbox(&mb).unwrap();
+            let address = mailaddress_from_mailbox(&mb, false).unwrap();
             assert_eq!(address.as_str(), "töst@tüst.test");
             assert_eq!(address.needs_smtputf8(), true);
         }
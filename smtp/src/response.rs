@@ -0,0 +1,335 @@
+//! Module containing the response returned for successfully send mails.
+use std::time::Duration;
+
+use new_tokio_smtp::send_mail::MailAddress;
+
+use ::{
+    error::{MailSendError, TimeoutPhase},
+    reply::parse_queue_id
+};
+
+/// The outcome of sending one mail of a batch using `send_batch_resumable`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOutcome {
+    /// The mail was send, containing the servers response.
+    Sent(MailResponse),
+
+    /// The mail was skipped (not send) as it is before the resume point.
+    Skipped
+}
+
+impl BatchOutcome {
+
+    /// Returns the response if the mail was send.
+    pub fn response(&self) -> Option<&MailResponse> {
+        match *self {
+            BatchOutcome::Sent(ref response) => Some(response),
+            BatchOutcome::Skipped => None
+        }
+    }
+
+    /// Returns true if the mail was skipped.
+    pub fn is_skipped(&self) -> bool {
+        *self == BatchOutcome::Skipped
+    }
+}
+
+/// The outcome of sending one mail, telling mails which never reached the server apart.
+///
+/// Created from the results of e.g. `send_batch_resilient` using `From`.
+/// Mails which failed before anything of them was send to the server
+/// (because they failed to encode, were refused by this crate, the
+/// connection couldn't be set up or the send was cancelled before they
+/// were send) are `Skipped`, mails which were send but failed (e.g.
+/// because the server rejected them or the connection broke) are `Failed`.
+/// Whether sending a skipped mail again helps depends on the reason, a
+/// mail which failed to encode won't be send without changing it (or the
+/// config), while a connection failure might be temporary.
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate mail_smtp;
+/// use futures::Stream;
+/// use mail_smtp::{MailResponse, SendOutcome, error::MailSendError};
+///
+/// fn outcomes<S>(results: S) -> impl Stream<Item=SendOutcome, Error=()>
+///     where S: Stream<Item=MailResponse, Error=MailSendError>
+/// {
+///     results.then(|result| Ok(SendOutcome::from(result)))
+/// }
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub enum SendOutcome {
+    /// The mail was send, containing the servers response.
+    Sent(MailResponse),
+
+    /// The mail was not send to the server, e.g. because it failed to encode,
+    /// the connection couldn't be set up or the send was cancelled before
+    /// the mail was send.
+    Skipped { reason: MailSendError },
+
+    /// Sending the mail was attempted but failed, e.g. because the server rejected it.
+    Failed(MailSendError)
+}
+
+impl SendOutcome {
+
+    /// Returns the response if the mail was send.
+    pub fn response(&self) -> Option<&MailResponse> {
+        match *self {
+            SendOutcome::Sent(ref response) => Some(response),
+            _ => None
+        }
+    }
+
+    /// Returns the error if the mail was skipped or failed.
+    pub fn error(&self) -> Option<&MailSendError> {
+        match *self {
+            SendOutcome::Sent(_) => None,
+            SendOutcome::Skipped { ref reason } => Some(reason),
+            SendOutcome::Failed(ref err) => Some(err)
+        }
+    }
+
+    /// Returns true if the mail was not send to the server.
+    pub fn is_skipped(&self) -> bool {
+        match *self {
+            SendOutcome::Skipped { .. } => true,
+            _ => false
+        }
+    }
+}
+
+impl From<Result<MailResponse, MailSendError>> for SendOutcome {
+    fn from(result: Result<MailResponse, MailSendError>) -> Self {
+        match result {
+            Ok(response) => SendOutcome::Sent(response),
+            Err(err) => match err {
+                MailSendError::Mail(_)
+                | MailSendError::LoopDetected { .. }
+                | MailSendError::NoRecipients
+                | MailSendError::DomainMisaligned { .. }
+                | MailSendError::Connecting(_)
+                | MailSendError::StartTlsDowngrade(_)
+                | MailSendError::Timeout { phase: TimeoutPhase::Connect }
+                | MailSendError::Timeout { phase: TimeoutPhase::Greeting }
+                | MailSendError::CancelledBeforeSending => SendOutcome::Skipped { reason: err },
+                err => SendOutcome::Failed(err)
+            }
+        }
+    }
+}
+
+/// The server response for a successfully send mail.
+///
+/// Besides the final response to the mail data (normally `250`)
+/// this also contains the reply codes for each recipient (`RCPT`).
+/// While all of them are success codes (`2xx`) some carry additional
+/// information, e.g. `251 User not local; will forward`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailResponse {
+    code: u16,
+    lines: Vec<String>,
+    recipient_codes: Vec<u16>,
+    rejected: Vec<(MailAddress, u16)>,
+    transfer_mode: TransferMode,
+    timings: Option<SendTimings>,
+    message_id: Option<String>
+}
+
+impl MailResponse {
+
+    /// Creates a new `MailResponse` with the given final reply code and text.
+    ///
+    /// This is mainly useful for testing, e.g. to create responses
+    /// returned by a mock transport.
+    pub fn new(code: u16, lines: Vec<String>) -> Self {
+        MailResponse {
+            code, lines,
+            recipient_codes: Vec::new(),
+            rejected: Vec::new(),
+            transfer_mode: TransferMode::SevenBit,
+            timings: None,
+            message_id: None
+        }
+    }
+
+    /// Sets the reply codes received for the recipients (`RCPT`) of the mail.
+    pub fn with_recipient_codes(mut self, recipient_codes: Vec<u16>) -> Self {
+        self.recipient_codes = recipient_codes;
+        self
+    }
+
+    /// Sets the recipients rejected by the server together with their reply codes.
+    pub fn with_rejected(mut self, rejected: Vec<(MailAddress, u16)>) -> Self {
+        self.rejected = rejected;
+        self
+    }
+
+    /// Sets the transfer mode the mail body was send with.
+    pub fn with_transfer_mode(mut self, transfer_mode: TransferMode) -> Self {
+        self.transfer_mode = transfer_mode;
+        self
+    }
+
+    /// Sets the time spend in the different phases of sending the mail.
+    pub fn with_timings(mut self, timings: SendTimings) -> Self {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Sets the `Message-ID` of the send mail.
+    pub fn with_message_id(mut self, message_id: String) -> Self {
+        self.message_id = Some(message_id);
+        self
+    }
+
+    /// The reply code of the final response to the mail data (e.g. `250`).
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    /// The text lines of the final response to the mail data.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// The queue id the server assigned to the mail.
+    ///
+    /// Most servers include the id under which the mail was queued in the
+    /// final response, which allows correlating later bounces with the mail.
+    /// The formats of Postfix (`Ok: queued as <id>`), Exim (`OK id=<id>`)
+    /// and Sendmail (`<id> Message accepted for delivery`) are recognized.
+    /// For other formats `None` is returned, but the full text is still
+    /// available through `lines`.
+    pub fn queue_id(&self) -> Option<String> {
+        parse_queue_id(&self.lines)
+    }
+
+    /// The reply codes to each recipient (`RCPT`) in the order of the recipients.
+    ///
+    /// With a `RecipientPolicy` accepting partial delivery this includes
+    /// the (error) codes of the recipients rejected by the server.
+    pub fn recipient_codes(&self) -> &[u16] {
+        &self.recipient_codes
+    }
+
+    /// The number of recipients the server accepted (i.e. replied with `2xx`).
+    pub fn accepted(&self) -> usize {
+        self.recipient_codes.iter()
+            .filter(|&&code| code / 100 == 2)
+            .count()
+    }
+
+    /// The recipients rejected by the server together with their reply codes.
+    ///
+    /// This can only be non-empty with a `RecipientPolicy` accepting
+    /// partial delivery. If all recipients are rejected there is no
+    /// `MailResponse`, instead the mail fails with
+    /// `MailSendError::RecipientRejected` for the first rejected recipient.
+    pub fn rejected(&self) -> &[(MailAddress, u16)] {
+        &self.rejected
+    }
+
+    /// The recipients rejected by the server with a transient error (`4xx`).
+    ///
+    /// Sending the mail again to them later might succeed, see
+    /// `PreparedMail::retry_transient`.
+    pub fn transiently_rejected(&self) -> Vec<MailAddress> {
+        self.rejected.iter()
+            .filter(|&&(_, code)| code / 100 == 4)
+            .map(|&(ref address, _)| address.clone())
+            .collect()
+    }
+
+    /// Returns true if the server will forward the mail for any recipient.
+    ///
+    /// This is the case if the server replied with `251` (User not local;
+    /// will forward) to any recipient or to the mail data.
+    ///
+    /// Note that a `252` (Cannot verify user, but will attempt delivery)
+    /// is not forwarding, but can be detected through `code`/`recipient_codes`.
+    pub fn is_forwarded(&self) -> bool {
+        self.code == 251 || self.recipient_codes.iter().any(|&code| code == 251)
+    }
+
+    /// The `Message-ID` of the send mail including the angle brackets (e.g. `<abc@example.com>`).
+    ///
+    /// This is the existing `Message-ID` header of the mail or the one
+    /// generated using `MailRequest::set_auto_message_id`. It's `None`
+    /// if the mail has no `Message-ID` or was send using `send_streamed`.
+    pub fn message_id(&self) -> Option<&str> {
+        self.message_id.as_ref().map(|message_id| message_id.as_str())
+    }
+
+    /// The transfer mode the mail body was send with, see `TransferMode`.
+    pub fn transfer_mode(&self) -> TransferMode {
+        self.transfer_mode
+    }
+
+    /// The time spend in the different phases of sending the mail.
+    ///
+    /// This is only set if `SendConfig::record_timings` is enabled.
+    pub fn timings(&self) -> Option<&SendTimings> {
+        self.timings.as_ref()
+    }
+}
+
+/// The content transfer mode negotiated with the server.
+///
+/// If the server announces `8BITMIME` (RFC 6152) the mail body is send
+/// as 8bit data, otherwise it is send as 7bit data, i.e. 8bit content
+/// has to be downgraded (transfer encoded) before it is send. Mails
+/// containing 8bit data are send with `BODY=8BITMIME` in the first case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferMode {
+    /// The body was send as 7bit data.
+    SevenBit,
+
+    /// The body was send as 8bit data (`8BITMIME`).
+    EightBit
+}
+
+/// The time spend in the different phases of sending a mail.
+///
+/// Recorded if `SendConfig::record_timings` is enabled, using the
+/// monotonic `Instant`. A phase is `None` if it didn't happen for the
+/// mail, e.g. the connection phases are only set for the mail for which
+/// the connection was opened (the first mail of a batch) and `tls` is only
+/// set if TLS is used. If a phase happened multiple times for the mail
+/// (e.g. because of reconnecting, or multiple transactions because of too
+/// many recipients) the durations are added up.
+///
+/// Resolving the server address is not included, as the `ConnectionConfig`
+/// already contains the resolved address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendTimings {
+    /// Encoding the mail, from starting to encode it until it is encoded.
+    pub encode: Option<Duration>,
+
+    /// Opening the TCP connection.
+    pub tcp_connect: Option<Duration>,
+
+    /// Setting up TLS, either the direct TLS handshake or `STARTTLS`
+    /// including the handshake (but not the following `EHLO`).
+    pub tls: Option<Duration>,
+
+    /// Waiting for the greeting of the server.
+    pub greeting: Option<Duration>,
+
+    /// `EHLO`, including the one send after `STARTTLS`.
+    pub ehlo: Option<Duration>,
+
+    /// The auth command.
+    pub auth: Option<Duration>,
+
+    /// `MAIL`, all `RCPT`s and `DATA` up to the `354` response.
+    pub envelope: Option<Duration>,
+
+    /// Sending the mail body.
+    pub data_upload: Option<Duration>,
+
+    /// Waiting for the final response after the mail body was send.
+    pub server_processing: Option<Duration>
+}
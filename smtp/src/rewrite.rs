@@ -0,0 +1,326 @@
+//! Module implementing rewriting the recipients of the envelop at send time.
+use std::fmt;
+
+use vec1::Vec1;
+
+use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+
+use ::{
+    config::SendConfig,
+    error::MailSendError
+};
+
+/// Rewrites the recipients of each mail just before they are send with `RCPT TO`.
+///
+/// This allows e.g. expanding aliases (`support@` to multiple real
+/// inboxes) without changing the mail. Only the envelop is rewritten,
+/// the mail (including its `To` header) is send unchanged.
+///
+/// Each recipient of the envelop is passed to `rewrite`, which returns
+/// the recipients to use instead of it:
+///
+/// - returning the address itself keeps it unchanged
+/// - returning no address drops the recipient
+/// - returning multiple addresses expands it to all of them
+///
+/// The rewritten recipients keep the order of the envelop, with the
+/// recipients an address expanded to in the returned order. Afterwards
+/// duplicates are removed, keeping the first occurrence, so that
+/// expanding to an address which is already a recipient doesn't send
+/// the mail to it twice. If all recipients are dropped the mail fails
+/// with `MailSendError::NoRecipients` without being send.
+///
+/// It's implemented for all `Fn(&MailAddress) -> Vec<MailAddress>` closures
+/// which are `Send + Sync`, set it using `SendConfig::recipient_rewriter`.
+pub trait RecipientRewriter: Send + Sync {
+    /// Returns the recipients to use instead of the given one.
+    fn rewrite(&self, addr: &MailAddress) -> Vec<MailAddress>;
+}
+
+impl<F> RecipientRewriter for F
+    where F: Fn(&MailAddress) -> Vec<MailAddress> + Send + Sync
+{
+    fn rewrite(&self, addr: &MailAddress) -> Vec<MailAddress> {
+        (self)(addr)
+    }
+}
+
+impl fmt::Debug for RecipientRewriter {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("RecipientRewriter { .. }")
+    }
+}
+
+/// Redirects all mails to a single recipient, e.g. a test inbox in a staging environment.
+///
+/// The recipients of the envelop are replaced with `redirect_to`, so no mail
+/// reaches its real recipients. The mail itself is send unchanged, except
+/// that a `X-Original-To` header listing the replaced recipients (including
+/// `Bcc` recipients) is added in front of the other headers if `annotate`
+/// is true.
+///
+/// Set it using `SendConfig::redirect`. It's applied after the
+/// `RecipientRewriter`, i.e. the rewritten recipients are replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectPolicy {
+    /// The address all mails are send to instead of their recipients.
+    pub redirect_to: MailAddress,
+
+    /// Adds a `X-Original-To` header with the replaced recipients.
+    pub annotate: bool
+}
+
+impl RedirectPolicy {
+
+    /// Replaces the recipients, returning the `X-Original-To` header if `annotate` is set.
+    pub(crate) fn apply(&self, envelop_data: EnvelopData) -> (EnvelopData, Option<Vec<u8>>) {
+        let EnvelopData { from, to } = envelop_data;
+        let header = if self.annotate { Some(original_to_header(&to)) } else { None };
+        (EnvelopData { from, to: Vec1::new(self.redirect_to.clone()) }, header)
+    }
+}
+
+/// Encodes a `X-Original-To` header listing the recipients, folded after a `,` if needed.
+fn original_to_header(recipients: &Vec1<MailAddress>) -> Vec<u8> {
+    const MAX_LINE_LEN: usize = 78;
+    let mut header = String::from("X-Original-To:");
+    let mut line_len = header.len();
+    for (idx, recipient) in recipients.iter().enumerate() {
+        if idx > 0 {
+            header.push(',');
+            line_len += 1;
+        }
+        let recipient = recipient.as_str();
+        if idx > 0 && line_len + 1 + recipient.len() > MAX_LINE_LEN {
+            header.push_str("\r\n");
+            line_len = 0;
+        }
+        header.push(' ');
+        header.push_str(recipient);
+        line_len += 1 + recipient.len();
+    }
+    header.push_str("\r\n");
+    header.into_bytes()
+}
+
+/// Applies the recipient options of the config to the envelop data.
+///
+/// The recipient domains are lowercased first (if enabled), then the
+/// `RecipientRewriter` and finally the `RedirectPolicy` are applied.
+/// The `X-Original-To` header to add to the mail is returned, too, if
+/// the redirect policy annotates the mail.
+pub(crate) fn apply_recipient_options(envelop_data: EnvelopData, config: &SendConfig)
+    -> Result<(EnvelopData, Option<Vec<u8>>), MailSendError>
+{
+    let mut envelop_data = envelop_data;
+    if config.lowercase_recipient_domains {
+        envelop_data = rewrite_recipients(envelop_data, &lowercase_domain)?;
+    }
+    if let Some(rewriter) = config.recipient_rewriter.as_ref() {
+        envelop_data = rewrite_recipients(envelop_data, &**rewriter)?;
+    }
+    match config.redirect.as_ref() {
+        Some(policy) => Ok(policy.apply(envelop_data)),
+        None => Ok((envelop_data, None))
+    }
+}
+
+/// Returns the address with its domain in (ASCII) lower case.
+///
+/// The local part is kept as it is, as it can be case sensitive.
+/// Address literals (e.g. `[IPv6:2001:DB8::1]`) are kept unchanged.
+fn lowercase_domain(addr: &MailAddress) -> Vec<MailAddress> {
+    let address = addr.as_str();
+    let lowercased = match address.rfind('@') {
+        Some(at) if !address[at + 1..].starts_with('[') => {
+            let (local_part, domain) = address.split_at(at);
+            MailAddress::new_unchecked(format!("{}{}", local_part, domain.to_ascii_lowercase()), addr.needs_smtputf8())
+        },
+        _ => addr.clone()
+    };
+    vec![lowercased]
+}
+
+/// Rewrites the recipients of the envelop data, see `RecipientRewriter`.
+pub(crate) fn rewrite_recipients(envelop_data: EnvelopData, rewriter: &RecipientRewriter)
+    -> Result<EnvelopData, MailSendError>
+{
+    let EnvelopData { from, to } = envelop_data;
+    let mut rewritten: Vec<MailAddress> = Vec::new();
+    for recipient in to.iter() {
+        for address in rewriter.rewrite(recipient) {
+            if !rewritten.iter().any(|existing| existing.as_str() == address.as_str()) {
+                rewritten.push(address);
+            }
+        }
+    }
+
+    let to = Vec1::from_vec(rewritten).map_err(|_| MailSendError::NoRecipients)?;
+    Ok(EnvelopData { from, to })
+}
+
+#[cfg(test)]
+mod test {
+
+    mod rewrite_recipients {
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use ::error::MailSendError;
+        use super::super::rewrite_recipients;
+
+        fn address(address: &str) -> MailAddress {
+            MailAddress::new_unchecked(address.to_owned(), false)
+        }
+
+        fn envelop_data(recipients: &[&str]) -> EnvelopData {
+            let to = recipients.iter().map(|recipient| address(recipient)).collect();
+            EnvelopData { from: Some(address("sender@test.test")), to: Vec1::from_vec(to).unwrap() }
+        }
+
+        fn recipients(envelop_data: &EnvelopData) -> Vec<&str> {
+            envelop_data.to.iter().map(|address| address.as_str()).collect()
+        }
+
+        fn expand_support(addr: &MailAddress) -> Vec<MailAddress> {
+            match addr.as_str() {
+                "support@test.test" => vec![address("alice@test.test"), address("bob@test.test")],
+                "noreply@test.test" => vec![],
+                _ => vec![addr.clone()]
+            }
+        }
+
+        #[test]
+        fn expands_in_place_keeping_the_order() {
+            let rewritten = rewrite_recipients(
+                envelop_data(&["a@test.test", "support@test.test", "z@test.test"]),
+                &expand_support
+            ).unwrap();
+
+            assert_eq!(
+                recipients(&rewritten),
+                vec!["a@test.test", "alice@test.test", "bob@test.test", "z@test.test"]
+            );
+            assert_eq!(rewritten.from.unwrap().as_str(), "sender@test.test");
+        }
+
+        #[test]
+        fn removes_duplicates_keeping_the_first_occurrence() {
+            let rewritten = rewrite_recipients(
+                envelop_data(&["bob@test.test", "support@test.test"]),
+                &expand_support
+            ).unwrap();
+
+            assert_eq!(recipients(&rewritten), vec!["bob@test.test", "alice@test.test"]);
+        }
+
+        #[test]
+        fn fails_if_all_recipients_are_dropped() {
+            match rewrite_recipients(envelop_data(&["noreply@test.test"]), &expand_support) {
+                Err(MailSendError::NoRecipients) => {},
+                Err(err) => panic!("unexpected error: {:?}", err),
+                Ok(_) => panic!("rewriting should fail")
+            }
+        }
+    }
+
+    mod apply_recipient_options {
+        use std::sync::Arc;
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use ::config::SendConfig;
+        use super::super::apply_recipient_options;
+
+        fn envelop_data(recipients: &[&str]) -> EnvelopData {
+            let to = recipients.iter()
+                .map(|recipient| MailAddress::new_unchecked((*recipient).to_owned(), false))
+                .collect();
+            EnvelopData { from: None, to: Vec1::from_vec(to).unwrap() }
+        }
+
+        fn recipients(envelop_data: &EnvelopData) -> Vec<&str> {
+            envelop_data.to.iter().map(|address| address.as_str()).collect()
+        }
+
+        #[test]
+        fn lowercases_domains_only_if_enabled() {
+            let mut config = SendConfig::default();
+            let data = || envelop_data(&["Alice@Example.COM", "Bob@[IPv6:2001:DB8::1]", "alice@example.com"]);
+
+            let (unchanged, _) = apply_recipient_options(data(), &config).unwrap();
+            assert_eq!(recipients(&unchanged), vec!["Alice@Example.COM", "Bob@[IPv6:2001:DB8::1]", "alice@example.com"]);
+
+            config.lowercase_recipient_domains = true;
+            let (lowercased, _) = apply_recipient_options(data(), &config).unwrap();
+            assert_eq!(recipients(&lowercased), vec!["Alice@example.com", "Bob@[IPv6:2001:DB8::1]", "alice@example.com"]);
+        }
+
+        #[test]
+        fn removes_recipients_which_are_duplicates_after_lowercasing() {
+            let mut config = SendConfig::default();
+            config.lowercase_recipient_domains = true;
+
+            let (envelop_data, _) = apply_recipient_options(envelop_data(&["a@Test.Test", "a@test.test"]), &config)
+                .unwrap();
+            assert_eq!(recipients(&envelop_data), vec!["a@test.test"]);
+        }
+
+        #[test]
+        fn rewriter_sees_lowercased_domains() {
+            let mut config = SendConfig::default();
+            config.lowercase_recipient_domains = true;
+            config.recipient_rewriter = Some(Arc::new(|addr: &MailAddress| {
+                assert_eq!(addr.as_str(), "x@test.test");
+                vec![addr.clone()]
+            }));
+
+            apply_recipient_options(envelop_data(&["x@TEST.test"]), &config).unwrap();
+        }
+    }
+
+    mod redirect_policy {
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use super::super::RedirectPolicy;
+
+        fn address(address: &str) -> MailAddress {
+            MailAddress::new_unchecked(address.to_owned(), false)
+        }
+
+        fn policy(annotate: bool) -> RedirectPolicy {
+            RedirectPolicy { redirect_to: address("test@staging.test"), annotate }
+        }
+
+        fn envelop_data(recipients: &[&str]) -> EnvelopData {
+            let to = recipients.iter().map(|recipient| address(recipient)).collect();
+            EnvelopData { from: Some(address("sender@test.test")), to: Vec1::from_vec(to).unwrap() }
+        }
+
+        #[test]
+        fn replaces_all_recipients() {
+            let (envelop_data, header) = policy(false).apply(envelop_data(&["a@test.test", "b@test.test"]));
+
+            let recipients = envelop_data.to.iter().map(|address| address.as_str()).collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["test@staging.test"]);
+            assert_eq!(envelop_data.from.unwrap().as_str(), "sender@test.test");
+            assert_eq!(header, None);
+        }
+
+        #[test]
+        fn folds_long_original_to_headers() {
+            let recipients = (0..6)
+                .map(|idx| format!("some.long.recipient.name.{}@test.test", idx))
+                .collect::<Vec<_>>();
+            let recipients = recipients.iter().map(|recipient| recipient.as_str()).collect::<Vec<_>>();
+
+            let (_, header) = policy(true).apply(envelop_data(&recipients));
+
+            let header = String::from_utf8(header.unwrap()).unwrap();
+            assert!(header.starts_with("X-Original-To: some.long.recipient.name.0@test.test,"));
+            assert!(header.ends_with("some.long.recipient.name.5@test.test\r\n"));
+            for line in header.split("\r\n") {
+                assert!(line.len() <= 78, "{:?}", line);
+            }
+            assert_eq!(header.matches("@test.test").count(), 6);
+        }
+    }
+}
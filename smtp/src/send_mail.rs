@@ -1,11 +1,18 @@
 //! Module implementing mail sending using `new-tokio-smtp::send_mail`.
 
-use std::iter::{once as one};
+use std::{
+    cmp,
+    io as std_io,
+    sync::Arc,
+    time::Instant
+};
 
 use futures::{
     stream::{self, Stream},
-    future::{self, Future, Either}
+    future::{self, Future, Either},
+    sync::oneshot
 };
+use tokio::executor::{Executor, DefaultExecutor};
 
 use mail_internals::{
     MailType,
@@ -14,17 +21,29 @@ use mail_internals::{
 use mail::Context;
 
 use new_tokio_smtp::{
+    Connection,
     ConnectionConfig,
     Cmd,
     SetupTls,
-    send_mail::MailEnvelop,
-    Connection,
+    error::LogicError,
+    send_mail::{MailEnvelop, EnvelopData},
     send_mail as smtp
 };
 
 use ::{
-    error::MailSendError,
-    request::MailRequest
+    budget::{ByteBudget, gated},
+    cancel::cancellable,
+    config::{SendConfig, Checkpoint, SendTarget},
+    connect::connect,
+    error::{MailSendError, MappedError, TransportError, TimeoutPhase},
+    request::MailRequest,
+    response::{MailResponse, BatchOutcome},
+    session::{
+        MailSource, source_from_vec,
+        SetupFailure, connect_send_quit, connect_send_quit_reconnecting, connect_send_quit_resilient
+    },
+    timeout::with_timeout,
+    transaction::{OutgoingMail, BodyStream, send_envelop_with, send_streamed_envelop}
 };
 
 /// Sends a given mail (request).
@@ -38,19 +57,120 @@ use ::{
 /// You can use `MailRequest: From<Mail>` (i.e. `mail.into()`) to pass in
 /// a mail and derive the envelop data (from, to) from it or create your own
 /// mail request if different smtp envelop data is needed.
+///
+/// The domain send with `EHLO` is the `client_id` of the connection config,
+/// unless it's overridden by `SendConfig::client_id`.
+///
+/// This uses the default `SendConfig`, use `send_with` to
+/// use a custom configuration (e.g. to set timeouts).
 pub fn send<A, S>(mail: MailRequest, conconf: ConnectionConfig<A, S>, ctx: impl Context)
-    -> impl Future<Item=(), Error=MailSendError>
+    -> impl Future<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    send_with(mail, conconf, ctx, SendConfig::default())
+}
+
+/// Sends a given mail (request) using the given `SendConfig`.
+///
+/// This works like `send` but allows configuring the
+/// send path, e.g. setting timeouts for the different
+/// phases of sending the mail.
+pub fn send_with<A, S>(
+    mail: MailRequest,
+    conconf: ConnectionConfig<A, S>,
+    ctx: impl Context,
+    config: SendConfig
+) -> impl Future<Item=MailResponse, Error=MailSendError>
     where A: Cmd, S: SetupTls
 {
-    let fut = encode(mail, ctx)
-        .then(move |envelop_res| Connection
-            ::connect_send_quit(conconf, one(envelop_res))
+    let fut = encode_outgoing(mail, ctx, config.send_target)
+        .then(move |mail_res| connect_send_quit(conconf, source_from_vec(vec![mail_res]), config, None)
             .collect())
         .map(|mut results| results.pop().expect("[BUG] sending one mail expects one result"));
 
     fut
 }
 
+/// Sends a mail over an existing connection, leaving it open.
+///
+/// This encodes the mail and then sends it using `MAIL`, `RCPT` and
+/// `DATA` over the given connection, without doing any of the connection
+/// handling `send` does (connecting, `STARTTLS`, `AUTH` and `QUIT`).
+/// This allows using a connection which was set up by another component
+/// (e.g. a connection broker), as long as it is ready for a mail transaction.
+///
+/// The caller owns the lifecycle of the connection: It is returned together
+/// with the result of sending the mail and is never closed by this function.
+/// If sending the mail fails in a way which leaves the connection usable
+/// (e.g. the mail can't be encoded or the server rejected it) the error is
+/// part of the item and `RSET` was send if needed. If the connection breaks
+/// (I/O error or timeout) the future fails and the connection is dropped.
+///
+/// This uses the default `SendConfig`, use `send_over_with` to
+/// use a custom configuration (e.g. to set timeouts).
+pub fn send_over(con: Connection, mail: MailRequest, ctx: impl Context)
+    -> impl Future<Item=(Connection, Result<MailResponse, MailSendError>), Error=MailSendError>
+{
+    send_over_with(con, mail, ctx, SendConfig::default())
+}
+
+/// Sends a mail over an existing connection using the given `SendConfig`.
+///
+/// This works like `send_over`. Options of the config which only affect
+/// setting up connections (e.g. `local_addr` or the connect timeout) are ignored.
+///
+/// If the send is cancelled using the `cancel_token` the future fails with
+/// `MailSendError::Cancelled` and the connection is dropped.
+pub fn send_over_with(con: Connection, mail: MailRequest, ctx: impl Context, config: SendConfig)
+    -> impl Future<Item=(Connection, Result<MailResponse, MailSendError>), Error=MailSendError>
+{
+    encode_outgoing(mail, ctx, config.send_target)
+        .then(move |result| match result {
+            Ok(mail) => {
+                let sending = send_envelop_with(con, mail, &config);
+                Either::A(cancellable(sending, config.cancel_token.clone()))
+            },
+            Err(err) => Either::B(future::ok((con, Err(err))))
+        })
+}
+
+/// Sends a mail whose body is produced while it is send.
+///
+/// This is meant for huge bodies generated on the fly (e.g. a large
+/// export) which shouldn't be buffered in memory as a whole, as it is
+/// done by `encode`. The body is given as stream of chunks of the already
+/// encoded mail (i.e. including the header), each chunk is dot-stashed and
+/// written to the server once it is produced. A `AsyncRead` can be turned
+/// into such a stream using e.g. `tokio::codec::FramedRead` with `BytesCodec`.
+///
+/// As the mail is not encoded by this crate, the envelop data can not be
+/// derived from the mail headers and has to be given explicitly. `SMTPUTF8`
+/// is used if any address in the envelop data needs it, anything else
+/// about the body (like it containing 8bit data) is up to the caller.
+///
+/// Like `send` this opens a connection, sends the mail and then closes the
+/// connection again. As the body can only be produced once, the transaction
+/// is never split into multiple transactions, i.e. `max_recipients_per_transaction`
+/// is not applied. If the body stream fails while sending the mail, the error
+/// is returned and the connection is dropped without `QUIT`. Note that the
+/// `data` timeout covers the whole body, so it should be set accordingly.
+pub fn send_streamed<A, S, B>(
+    envelop_data: EnvelopData,
+    body: B,
+    conconf: ConnectionConfig<A, S>,
+    config: SendConfig
+) -> impl Future<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls, B: Stream<Error=std_io::Error> + Send + 'static, B::Item: AsRef<[u8]>
+{
+    let body: BodyStream = Box::new(body.map(|chunk| chunk.as_ref().to_vec()));
+    let cancel_token = config.cancel_token.clone();
+    let connecting = with_timeout(connect(conconf, &config), config.timeouts.connect, TimeoutPhase::Connect);
+    cancellable(connecting, cancel_token.clone())
+        .map_err(|err| if err.is_cancelled() { MailSendError::CancelledBeforeSending } else { err })
+        .and_then(move |con| cancellable(send_streamed_envelop(con, envelop_data, body, &config), cancel_token))
+        .and_then(|(con, result)| con.quit().then(move |_| result))
+}
+
 /// Sends a batch of mails to a server.
 ///
 /// - This will use the given context to encode all mails.
@@ -63,7 +183,7 @@ pub fn send<A, S>(mail: MailRequest, conconf: ConnectionConfig<A, S>, ctx: impl
 ///   - If the connection is broken because setting it up failed or it was
 ///     interrupted, then the mail at which place it was noticed will return
 ///     the given error and all later mails will return a I/0-Error with the
-///     `ErrorKind::NoConnection`
+///     `ErrorKind::NotConnected`
 /// - It will return a `Stream` which when polled will send the mails
 ///   and return results _in the order the mails had been supplied_. So
 ///   for each mail there will be exactly one result.
@@ -71,27 +191,467 @@ pub fn send<A, S>(mail: MailRequest, conconf: ConnectionConfig<A, S>, ctx: impl
 ///   closed (even if the stream is not yet dropped, it closes it the
 ///   moment it notices that there are no more mails to send!)
 ///
+/// This uses the default `SendConfig`, use `send_batch_with` to
+/// use a custom configuration (e.g. to set timeouts).
 pub fn send_batch<A, S, C>(
     mails: Vec<MailRequest>,
     conconf: ConnectionConfig<A, S>,
     ctx: C
-) -> impl Stream<Item=(), Error=MailSendError>
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
     where A: Cmd, S: SetupTls, C: Context
 {
-    let iter = mails.into_iter().map(move |mail| encode(mail, ctx.clone()));
+    send_batch_with(mails, conconf, ctx, SendConfig::default())
+}
 
-    let fut = collect_res(stream::futures_ordered(iter))
-        .map(move |vec_of_res| Connection::connect_send_quit(conconf, vec_of_res))
-        .flatten_stream();
+/// Sends a batch of mails to a server using the given `SendConfig`.
+///
+/// This works like `send_batch` but allows configuring the
+/// send path, e.g. setting timeouts for the different
+/// phases of sending the mails.
+///
+/// All mails are send over a single connection, so the
+/// `max_mails_per_connection` and `max_bytes_per_connection` of the
+/// config are not used and the remaining mails fail once a mail exceeded
+/// the `per_mail_deadline`. Use `send_batch_cycling` to open new connections
+/// in these cases.
+pub fn send_batch_with<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    send_batch_from(mails, 0, conconf, ctx, config, None)
+}
 
-    fut
+/// Sends a batch of mails, cycling the connection as configured by the `SendConfig`.
+///
+/// This works like `send_batch_with`, except that the connection is closed
+/// using `QUIT` and a new one is opened once the `max_mails_per_connection`
+/// or `max_bytes_per_connection` of the config is reached, and after a mail
+/// exceeded the `per_mail_deadline`. As a new connection has to be set up
+/// (including auth) using the same configuration, this requires the auth
+/// command and TLS setup to be `Clone`.
+pub fn send_batch_cycling<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd + Clone, S: SetupTls + Clone, C: Context
+{
+    let checkpoint = config.checkpoint.clone();
+    let source = encode_batch(mails, ctx, &config);
+    let stream = connect_send_quit_reconnecting(conconf, source, config, None);
+
+    with_checkpoint(stream, 0, checkpoint)
+}
+
+/// Sends a batch of mails, returning all results once the whole batch is done.
+///
+/// This works like `send_batch` but drives the stream to completion,
+/// returning one result per mail _in the order the mails had been supplied_.
+///
+/// If the connection can't be set up (including a failed authentication
+/// or a connect/greeting timeout) nothing was send and the future fails
+/// with a `TransportError` instead. Mails which failed before the
+/// connection was needed (e.g. because they failed to encode) are not
+/// reported in that case. All other failures, including mails refused
+/// by this crate (e.g. `MailSendError::NoRecipients`), cancelled mails
+/// and the connection breaking after some mails were send, are reported
+/// per mail.
+pub fn send_batch_collected<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C
+) -> impl Future<Item=Vec<Result<MailResponse, MailSendError>>, Error=TransportError>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    send_batch_collected_with(mails, conconf, ctx, SendConfig::default())
+}
+
+/// Sends a batch of mails using the given `SendConfig`, returning all results once the whole batch is done.
+///
+/// See `send_batch_collected` and `send_batch_with`.
+pub fn send_batch_collected_with<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Future<Item=Vec<Result<MailResponse, MailSendError>>, Error=TransportError>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    let setup_failure = SetupFailure::default();
+    send_batch_from(mails, 0, conconf, ctx, config, Some(setup_failure.clone()))
+        .then(|result| Ok::<_, TransportError>(result))
+        .collect()
+        .and_then(move |results| TransportError::check_batch(results, setup_failure.index()))
+}
+
+/// Sends a batch of mails, mapping errors caused by server responses using the mapper.
+///
+/// This works like `send_batch_with`, but each failure caused by a server
+/// response (e.g. a rejected mail or a failed authentication) is passed to
+/// the mapper, which can turn it into a domain specific error. Errors which
+/// carry the response instead of a `LogicError` (`RecipientRejected` and
+/// `AuthExpired`) are passed as `LogicError::Code`. If the mapper returns
+/// `None` (or the error wasn't caused by a server response, e.g. an I/O
+/// error) the default `MailSendError` is returned.
+///
+/// ```
+/// # extern crate mail_smtp;
+/// # extern crate new_tokio_smtp;
+/// use new_tokio_smtp::error::LogicError;
+/// use mail_smtp::error::logic_error_code;
+///
+/// #[derive(Debug)]
+/// enum AppError {
+///     SpamBlocked
+/// }
+///
+/// fn map_blocked(err: &LogicError) -> Option<AppError> {
+///     let mentions_blocked = match *err {
+///         LogicError::Code(ref response) => response.msg().iter().any(|line| line.contains("blocked")),
+///         _ => false
+///     };
+///     if logic_error_code(err) == Some(550) && mentions_blocked {
+///         Some(AppError::SpamBlocked)
+///     } else {
+///         None
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub fn send_batch_with_error_mapper<A, S, C, E, F>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig,
+    mapper: F
+) -> impl Stream<Item=MailResponse, Error=MappedError<E>>
+    where A: Cmd, S: SetupTls, C: Context, F: Fn(&LogicError) -> Option<E>
+{
+    send_batch_with(mails, conconf, ctx, config)
+        .map_err(move |err| MappedError::map(err, &mapper))
+}
+
+/// Sends a batch of mails skipping the first `skip_first` mails.
+///
+/// This is meant to resume a batch which was interrupted (e.g. by a
+/// process restart) using the indices recorded by the `Checkpoint`
+/// of the `SendConfig`: If the mails up to index `n` were send, the
+/// batch can be resumed by calling this with the same mails and
+/// `skip_first = n + 1`.
+///
+/// The skipped mails are neither encoded nor send, but still produce
+/// a `BatchOutcome::Skipped` entry so that the n-th result still belongs
+/// to the n-th mail. Indices passed to the `Checkpoint` are indices into
+/// the full `mails` vector, too. Besides this it works like `send_batch_with`.
+pub fn send_batch_resumable<A, S, C>(
+    mut mails: Vec<MailRequest>,
+    skip_first: usize,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream<Item=BatchOutcome, Error=MailSendError>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    let skip_first = cmp::min(skip_first, mails.len());
+    let mails = mails.split_off(skip_first);
+
+    let skipped = stream::iter_ok((0..skip_first).map(|_| BatchOutcome::Skipped));
+    let sent = send_batch_from(mails, skip_first, conconf, ctx, config, None)
+        .map(BatchOutcome::Sent);
+
+    skipped.chain(sent)
+}
+
+fn send_batch_from<A, S, C>(
+    mails: Vec<MailRequest>,
+    first_index: usize,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig,
+    setup_failure: Option<SetupFailure>
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls, C: Context
+{
+    let checkpoint = config.checkpoint.clone();
+    let source = encode_batch(mails, ctx, &config);
+    let stream = connect_send_quit(conconf, source, config, setup_failure);
+
+    with_checkpoint(stream, first_index, checkpoint)
+}
+
+/// Sends a batch of mails, reconnecting if the server closes the connection using `421`.
+///
+/// This works like `send_batch_with`, except if the server replies with
+/// `421 Service closing transmission channel` (which servers under load
+/// routinely do to drop long-lived connections). In that case a new
+/// connection is opened and the mail which got the `421` is send again
+/// over it (once), followed by the remaining mails. Without this all
+/// remaining mails would fail with a `NotConnected` I/O error.
+///
+/// If the retried mail is answered with `421` again it fails with the error
+/// (`MailSendError::is_service_closing` returns true for it), the remaining
+/// mails are still send using another new connection.
+///
+/// The `service_closing` policy of the config allows to not retry the mail
+/// (while still sending the remaining mails) or to not reconnect at all.
+///
+/// If a `retry_budget` is set in the config, each retry takes a token from
+/// it. A mail for which no token is left is not retried but fails with the
+/// `421` error, the remaining mails are still send over a new connection.
+///
+/// The same is done if the authentication of the connection expired (see
+/// `MailSendError::AuthExpired`), as the new connection authenticates again.
+/// Combined with `auth::xoauth2` this uses a fresh OAuth token.
+///
+/// Connections are cycled (`max_mails_per_connection`, `max_bytes_per_connection`
+/// and `per_mail_deadline`) like it's done by `send_batch_cycling`. Mails are
+/// never send pipelined, see `SendConfig::pipelining`.
+///
+/// The results can be turned into `SendOutcome`s to tell mails which
+/// were never send (e.g. because they failed to encode) apart from
+/// mails which were send and failed.
+pub fn send_batch_resilient<A, S, C>(
+    mails: Vec<MailRequest>,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd + Clone, S: SetupTls + Clone, C: Context
+{
+    let checkpoint = config.checkpoint.clone();
+    let source = encode_batch(mails, ctx, &config);
+    let stream = connect_send_quit_resilient(conconf, source, config);
+
+    with_checkpoint(stream, 0, checkpoint)
+}
+
+/// Sends the mails produced by a stream over a persistent connection.
+///
+/// This works like `send_batch` but the mails are taken from the stream
+/// as they are needed instead of being collected up front, which makes it
+/// usable for an always-on sender consuming e.g. a queue. The returned
+/// stream has exactly one result per mail, in the order of the input
+/// stream. If the input stream fails, the error is returned as the result
+/// for that position and the input stream is polled again afterwards.
+///
+/// Mails are encoded while the previous ones are send, with at most
+/// `config.pipelined_encoding` mails (by default one) being taken from
+/// the input stream and encoded ahead. No further mails are taken from
+/// the input stream while this limit is reached, so a slow server
+/// applies backpressure to the source. The encodings are spawned on the
+/// default executor, without one the mails are only encoded once the
+/// previous mail was send.
+///
+/// This uses the default `SendConfig`, use `send_stream_with` to
+/// use a custom configuration (e.g. to set timeouts).
+pub fn send_stream<M, A, S, C>(
+    mails: M,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where M: Stream<Item=MailRequest> + Send + 'static,
+          M::Error: Into<MailSendError>,
+          A: Cmd, S: SetupTls, C: Context
+{
+    send_stream_with(mails, conconf, ctx, SendConfig::default())
+}
+
+/// Sends the mails produced by a stream using the given `SendConfig`.
+///
+/// This works like `send_stream` but allows configuring the send path.
+/// The `Checkpoint` of the config is called with the index of each mail
+/// in the input stream which was send successfully. Like with
+/// `send_batch_with` all mails are send over a single connection, use
+/// `send_stream_cycling` to cycle it.
+pub fn send_stream_with<M, A, S, C>(
+    mails: M,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where M: Stream<Item=MailRequest> + Send + 'static,
+          M::Error: Into<MailSendError>,
+          A: Cmd, S: SetupTls, C: Context
+{
+    let checkpoint = config.checkpoint.clone();
+    let source = encode_stream(mails, ctx, &config);
+    let stream = connect_send_quit(conconf, source, config, None);
+
+    with_checkpoint(stream, 0, checkpoint)
+}
+
+/// Sends the mails produced by a stream, cycling the connection as configured by the `SendConfig`.
+///
+/// This works like `send_stream_with` but cycles the connection (e.g. after
+/// `max_bytes_per_connection`) like `send_batch_cycling`, which is why it
+/// requires the auth command and TLS setup to be `Clone`.
+pub fn send_stream_cycling<M, A, S, C>(
+    mails: M,
+    conconf: ConnectionConfig<A, S>,
+    ctx: C,
+    config: SendConfig
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where M: Stream<Item=MailRequest> + Send + 'static,
+          M::Error: Into<MailSendError>,
+          A: Cmd + Clone, S: SetupTls + Clone, C: Context
+{
+    let checkpoint = config.checkpoint.clone();
+    let source = encode_stream(mails, ctx, &config);
+    let stream = connect_send_quit_reconnecting(conconf, source, config, None);
+
+    with_checkpoint(stream, 0, checkpoint)
 }
 
-//FIXME[futures/v>=0.2] use Error=Never
-fn collect_res<S, E>(stream: S) -> impl Future<Item=Vec<Result<S::Item, S::Error>>, Error=E>
+/// Creates the source of the encoded mails of a stream, with one result per mail (in order).
+///
+/// At most `config.pipelined_encoding` (at least one) mails are encoded ahead,
+/// limited by `config.max_in_flight_bytes`.
+fn encode_stream<M, C>(mails: M, ctx: C, config: &SendConfig) -> MailSource
+    where M: Stream<Item=MailRequest> + Send + 'static, M::Error: Into<MailSendError>, C: Context
+{
+    let send_target = config.send_target;
+    let max_ahead = config.pipelined_encoding.unwrap_or(1).max(1);
+    let encodings = mails
+        .then(|result| Ok::<_, ()>(result))
+        .map(move |result| {
+            let encoding = match result {
+                Ok(mail) => Either::A(encode_outgoing(mail, ctx.clone(), send_target)),
+                Err(err) => Either::B(future::err(err.into()))
+            };
+            encoding.then(|result| Ok::<_, ()>(result))
+        });
+
+    encode_ahead(encodings, max_ahead, config.max_in_flight_bytes)
+}
+
+/// Runs the encodings with at most `max_ahead` mails being encoded ahead.
+///
+/// The encodings are spawned (see `spawn_encoding`), so they progress
+/// while the session sends the previous mail and doesn't poll the source.
+///
+/// If there is a limit of in flight bytes it's applied using a `ByteBudget`.
+fn encode_ahead<S, F>(encodings: S, max_ahead: usize, max_in_flight_bytes: Option<usize>) -> MailSource
+    where S: Stream<Item=F, Error=()> + Send + 'static,
+          F: Future<Item=Result<OutgoingMail, MailSendError>, Error=()> + Send + 'static
+{
+    let budget = match max_in_flight_bytes {
+        Some(limit) => ByteBudget::new(limit),
+        None => return Box::new(encodings.map(spawn_encoding).buffered(max_ahead))
+    };
+
+    let source = gated(encodings, budget)
+        .map(|(started, encoding)| encoding.map(move |result| match result {
+            // the size is released once the mail is send, if the encoding failed
+            // (or is dropped) dropping `started` releases it
+            Ok(mut mail) => {
+                mail.in_flight = Some(Arc::new(started.encoded(mail.size())));
+                Ok(mail)
+            },
+            Err(err) => Err(err)
+        }))
+        .map(spawn_encoding)
+        .buffered(max_ahead);
+
+    Box::new(source)
+}
+
+/// Runs the encoding on the default executor, returning a future resolving to its result.
+///
+/// Without a default executor the encoding only runs when the returned
+/// future is polled. If the executor drops the encoding (e.g. because
+/// it's shut down) the result is an I/O error.
+fn spawn_encoding<F>(encoding: F) -> impl Future<Item=Result<OutgoingMail, MailSendError>, Error=()>
+    where F: Future<Item=Result<OutgoingMail, MailSendError>, Error=()> + Send + 'static
+{
+    let mut executor = DefaultExecutor::current();
+    if executor.status().is_err() {
+        return Either::A(encoding);
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let task = encoding.map(move |result| {
+        let _ = sender.send(result);
+    });
+    let _ = executor.spawn(Box::new(task));
+
+    Either::B(receiver.then(|result| match result {
+        Ok(result) => Ok(result),
+        Err(_canceled) => {
+            let err = std_io::Error::new(std_io::ErrorKind::Other, "encoding the mail was aborted");
+            Ok(Err(MailSendError::Io(err)))
+        }
+    }))
+}
+
+/// Creates the source of the encoded mails of a batch, with one result per mail (in order).
+///
+/// Without `config.pipelined_encoding` and `config.max_in_flight_bytes` all
+/// mails are encoded before the first one is provided, otherwise they are
+/// encoded ahead while sending, see there.
+fn encode_batch<C>(mails: Vec<MailRequest>, ctx: C, config: &SendConfig) -> MailSource
+    where C: Context
+{
+    let send_target = config.send_target;
+    let mail_count = mails.len();
+    let iter = mails.into_iter()
+        .map(move |mail| {
+            encode_outgoing(mail, ctx.clone(), send_target)
+                .then(|result| Ok::<_, ()>(result))
+        });
+
+    match (config.pipelined_encoding, config.max_in_flight_bytes) {
+        (Some(max_ahead), max_in_flight_bytes) => {
+            encode_ahead(stream::iter_ok(iter), max_ahead.max(1), max_in_flight_bytes)
+        },
+        (None, Some(max_in_flight_bytes)) => {
+            encode_ahead(stream::iter_ok(iter), mail_count.max(1), Some(max_in_flight_bytes))
+        },
+        (None, None) => {
+            let all_encoded = stream::futures_ordered(iter)
+                .collect()
+                .map(stream::iter_ok)
+                .flatten_stream();
+            Box::new(all_encoded)
+        }
+    }
+}
+
+/// Calls the checkpoint with the index of each successful result of the stream.
+fn with_checkpoint<S>(stream: S, first_index: usize, checkpoint: Option<Checkpoint>)
+    -> impl Stream<Item=S::Item, Error=S::Error>
     where S: Stream
 {
-    stream.then(|res| Ok(res)).collect()
+    let mut index = first_index;
+    stream.then(move |result| {
+        if let (Ok(_), Some(checkpoint)) = (&result, checkpoint.as_ref()) {
+            checkpoint.record(index);
+        }
+        index += 1;
+        result
+    })
+}
+
+/// Encodes the mail keeping the additional parameters of the request.
+///
+/// When sending to a MX bounces are send with the null reverse path.
+pub(crate) fn encode_outgoing<C>(mut request: MailRequest, ctx: C, send_target: SendTarget)
+    -> impl Future<Item=OutgoingMail, Error=MailSendError>
+    where C: Context
+{
+    if send_target.is_mx() && request.is_bounce() {
+        request.use_null_reverse_path();
+    }
+    let params = request.params().clone();
+    future::lazy(move || {
+        let start = Instant::now();
+        encode(request, ctx)
+            .map(move |envelop| OutgoingMail { envelop, params, encode_time: Some(start.elapsed()), in_flight: None })
+    })
 }
 
 /// Turns a `MailRequest` into a future resolving to a `MailEnvelop`.
@@ -109,8 +669,9 @@ pub fn encode<C>(request: MailRequest, ctx: C)
     -> impl Future<Item=MailEnvelop, Error=MailSendError>
     where C: Context
 {
+    let force_smtputf8 = request.force_smtputf8();
     let (mail, envelop_data) =
-        match request.into_mail_with_envelop() {
+        match request.into_mail_for_encoding(&ctx) {
             Ok(pair) => pair,
             Err(e) => return Either::A(future::err(e.into()))
         };
@@ -118,8 +679,9 @@ pub fn encode<C>(request: MailRequest, ctx: C)
     let fut = mail
         .into_encodeable_mail(ctx.clone())
         .and_then(move |enc_mail| ctx.offload_fn(move || {
+            let smtputf8 = force_smtputf8.unwrap_or_else(|| envelop_data.needs_smtputf8());
             let (mail_type, requirement) =
-                if envelop_data.needs_smtputf8() {
+                if smtputf8 {
                     (MailType::Internationalized, smtp::EncodingRequirement::Smtputf8)
                 } else {
                     (MailType::Ascii, smtp::EncodingRequirement::None)
@@ -136,4 +698,1171 @@ pub fn encode<C>(request: MailRequest, ctx: C)
         .map_err(MailSendError::from);
 
     Either::B(fut)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+
+    mod send_batch_resumable {
+        use std::sync::{Arc, Mutex};
+        use futures::Stream;
+        use ::{
+            config::{SendConfig, Checkpoint},
+            request::MailRequest,
+            response::BatchOutcome,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server_for, test_context}
+        };
+        use super::super::send_batch_resumable;
+
+        #[test]
+        fn skips_the_mails_before_the_checkpoint() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let checkpoints = Arc::new(Mutex::new(Vec::new()));
+            let mut config = SendConfig::default();
+            config.checkpoint = Some({
+                let checkpoints = checkpoints.clone();
+                Checkpoint::new(move |index| checkpoints.lock().unwrap().push(index))
+            });
+            // the mails up to index 1 were send before the batch was interrupted
+            let mails = ["a@test.test", "b@test.test", "c@test.test", "d@test.test"].iter()
+                .map(|recipient| MailRequest::new(simple_mail(recipient)))
+                .collect::<Vec<_>>();
+
+            let stream = send_batch_resumable(mails, 2, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 4);
+            assert_eq!(results[0], BatchOutcome::Skipped);
+            assert_eq!(results[1], BatchOutcome::Skipped);
+            for result in &results[2..] {
+                match *result {
+                    BatchOutcome::Sent(_) => {},
+                    ref other => panic!("unexpected result: {:?}", other)
+                }
+            }
+            let recipients = written[0].lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<c@test.test>", "RCPT TO:<d@test.test>"]);
+            // the indices are indices into all mails, so they can be used to resume again
+            assert_eq!(*checkpoints.lock().unwrap(), vec![2, 3]);
+        }
+    }
+
+    mod send_outcome {
+        use std::net::TcpListener;
+        use futures::Stream;
+        use mail::Mail;
+        use ::{
+            config::SendConfig,
+            error::MailSendError,
+            request::MailRequest,
+            response::SendOutcome,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server_for, test_context}
+        };
+        use super::super::send_batch_resilient;
+
+        #[test]
+        fn mails_failing_to_encode_are_skipped() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mails = vec![
+                MailRequest::new(simple_mail("a@test.test")),
+                // a mail without any headers fails to encode
+                MailRequest::new(Mail::plain_text("body")),
+                MailRequest::new(simple_mail("c@test.test"))
+            ];
+
+            let stream = send_batch_resilient(mails, con_config(addr), test_context(), SendConfig::default());
+            let outcomes = run(stream.then(|result| Ok::<_, ()>(SendOutcome::from(result))).collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(outcomes.len(), 3);
+            match (&outcomes[0], &outcomes[1], &outcomes[2]) {
+                (&SendOutcome::Sent(_), &SendOutcome::Skipped { reason: MailSendError::Mail(_) }, &SendOutcome::Sent(_)) => {},
+                other => panic!("unexpected outcomes: {:?}", other)
+            }
+            // the skipped mail didn't interrupt the connection
+            let recipients = written[0].lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<a@test.test>", "RCPT TO:<c@test.test>"]);
+        }
+
+        #[test]
+        fn mail_is_skipped_if_the_connection_is_refused() {
+            let mails = vec![MailRequest::new(simple_mail("to@example.com"))];
+            // bind and drop a listener to get a port nothing listens on
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+            let stream = send_batch_resilient(mails, con_config(addr), test_context(), SendConfig::default());
+            let outcomes = run(stream.then(|result| Ok::<_, ()>(SendOutcome::from(result))).collect()).unwrap();
+
+            assert_eq!(outcomes.len(), 1);
+            match outcomes[0] {
+                SendOutcome::Skipped { reason: MailSendError::Connecting(_) } => {},
+                ref other => panic!("unexpected outcome: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn only_mails_cancelled_before_sending_are_skipped() {
+            let outcome = SendOutcome::from(Err(MailSendError::Cancelled));
+            assert!(!outcome.is_skipped());
+            assert!(outcome.error().unwrap().is_cancelled());
+            let outcome = SendOutcome::from(Err(MailSendError::CancelledBeforeSending));
+            assert!(outcome.is_skipped());
+            assert!(outcome.error().unwrap().is_cancelled());
+            let outcome = SendOutcome::from(Err(MailSendError::NoRecipients));
+            assert!(outcome.is_skipped());
+        }
+    }
+
+    mod client_id {
+        use std::{
+            thread,
+            net::{SocketAddr, TcpListener}
+        };
+        use new_tokio_smtp::{ClientId, Domain as SmtpDomain};
+        use ::{
+            config::SendConfig,
+            request::MailRequest,
+            test_utils::{con_config, run, serve_smtp, simple_mail, test_context}
+        };
+        use super::super::send_with;
+
+        fn send_with_client_ids(con_client_id: ClientId, config_client_id: Option<ClientId>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr: SocketAddr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                serve_smtp(stream.try_clone().unwrap(), stream)
+            });
+
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+            let mut conconf = con_config(addr);
+            conconf.client_id = con_client_id;
+            let mut config = SendConfig::default();
+            config.client_id = config_client_id;
+
+            run(send_with(MailRequest::new(mail), conconf, ctx, config)).unwrap();
+            server.join().unwrap()
+        }
+
+        fn domain(domain: &str) -> ClientId {
+            ClientId::Domain(SmtpDomain::from_unchecked(domain.to_owned()))
+        }
+
+        #[test]
+        fn uses_the_client_id_of_the_connection_config_by_default() {
+            let written = send_with_client_ids(domain("me.test"), None);
+            assert!(written.starts_with("EHLO me.test\r\n"), "{}", written);
+        }
+
+        #[test]
+        fn client_id_of_the_send_config_takes_precedence() {
+            let written = send_with_client_ids(domain("localhost"), Some(domain("example.com")));
+            assert!(written.starts_with("EHLO example.com\r\n"), "{}", written);
+        }
+    }
+
+    mod connection_cycling {
+        use futures::{Stream, stream};
+        use new_tokio_smtp::{
+            Cmd, ConnectionConfig, EhloData, ExecFuture, Io,
+            command::Noop,
+            error::MissingCapabilities
+        };
+        use ::{
+            config::SendConfig,
+            error::MailSendError,
+            request::MailRequest,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server_for, test_context}
+        };
+        use super::super::{send_batch_with, send_batch_cycling, send_stream_cycling};
+
+        fn mails(recipients: &[&str]) -> Vec<MailRequest> {
+            recipients.iter()
+                .map(|recipient| MailRequest::new(simple_mail(recipient)))
+                .collect()
+        }
+
+        fn recipients(written: &str) -> Vec<&str> {
+            written.lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect()
+        }
+
+        #[test]
+        fn send_batch_cycling_reconnects_after_max_mails_per_connection() {
+            let (addr, server) = spawn_smtp_server_for(2);
+            let mut config = SendConfig::default();
+            config.max_mails_per_connection = Some(2);
+
+            let mails = mails(&["a@test.test", "b@test.test", "c@test.test"]);
+            let stream = send_batch_cycling(mails, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 3);
+            assert_eq!(recipients(&written[0]), vec!["RCPT TO:<a@test.test>", "RCPT TO:<b@test.test>"]);
+            assert!(written[0].ends_with("QUIT\r\n"));
+            assert!(written[1].starts_with("EHLO me.test\r\n"));
+            assert_eq!(recipients(&written[1]), vec!["RCPT TO:<c@test.test>"]);
+        }
+
+        #[test]
+        fn send_batch_cycling_reconnects_before_exceeding_max_bytes_per_connection() {
+            let (addr, server) = spawn_smtp_server_for(3);
+            let mut config = SendConfig::default();
+            // each mail is larger than the limit, so each is send over a new connection
+            config.max_bytes_per_connection = Some(1);
+
+            let mails = mails(&["a@test.test", "b@test.test", "c@test.test"]);
+            let stream = send_batch_cycling(mails, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 3);
+            let per_connection = written.iter().map(|written| recipients(written)).collect::<Vec<_>>();
+            assert_eq!(per_connection, vec![
+                vec!["RCPT TO:<a@test.test>"],
+                vec!["RCPT TO:<b@test.test>"],
+                vec!["RCPT TO:<c@test.test>"]
+            ]);
+        }
+
+        #[test]
+        fn send_batch_with_uses_a_single_connection() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mut config = SendConfig::default();
+            config.max_mails_per_connection = Some(1);
+
+            let mails = mails(&["a@test.test", "b@test.test"]);
+            let stream = send_batch_with(mails, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 2);
+            assert_eq!(recipients(&written[0]), vec!["RCPT TO:<a@test.test>", "RCPT TO:<b@test.test>"]);
+        }
+
+        #[test]
+        fn send_batch_with_does_not_require_a_clone_auth_command() {
+            /// Auth command which can only be used once.
+            struct NoopOnce;
+
+            impl Cmd for NoopOnce {
+                fn check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+                    Noop.check_cmd_availability(caps)
+                }
+
+                fn exec(self, io: Io) -> ExecFuture {
+                    Noop.exec(io)
+                }
+            }
+
+            let (addr, server) = spawn_smtp_server_for(1);
+            let con_config = con_config(addr);
+            let con_config = ConnectionConfig {
+                addr: con_config.addr,
+                security: con_config.security,
+                auth_cmd: NoopOnce,
+                client_id: con_config.client_id
+            };
+
+            let mails = mails(&["a@test.test"]);
+            let stream = send_batch_with(mails, con_config, test_context(), SendConfig::default());
+            let results = run(stream.collect()).unwrap();
+            server.join().unwrap();
+
+            assert_eq!(results.len(), 1);
+        }
+
+        #[test]
+        fn send_stream_cycling_cycles_on_bytes_and_mails() {
+            let (addr, server) = spawn_smtp_server_for(3);
+            let mut config = SendConfig::default();
+            config.max_mails_per_connection = Some(2);
+            // the byte limit allows more than two mails, so the mail limit is reached first
+            config.max_bytes_per_connection = Some(100_000);
+
+            let recipients_in = ["a@test.test", "b@test.test", "c@test.test", "d@test.test", "e@test.test"];
+            let source = stream::iter_ok::<_, MailSendError>(mails(&recipients_in));
+            let stream = send_stream_cycling(source, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 5);
+            let per_connection = written.iter().map(|written| recipients(written)).collect::<Vec<_>>();
+            assert_eq!(per_connection, vec![
+                vec!["RCPT TO:<a@test.test>", "RCPT TO:<b@test.test>"],
+                vec!["RCPT TO:<c@test.test>", "RCPT TO:<d@test.test>"],
+                vec!["RCPT TO:<e@test.test>"]
+            ]);
+        }
+
+        #[test]
+        fn send_stream_cycling_reconnects_before_exceeding_max_bytes_per_connection() {
+            let (addr, server) = spawn_smtp_server_for(2);
+            let mut config = SendConfig::default();
+            config.max_bytes_per_connection = Some(1);
+
+            let source = stream::iter_ok::<_, MailSendError>(mails(&["a@test.test", "b@test.test"]));
+            let stream = send_stream_cycling(source, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 2);
+            let per_connection = written.iter().map(|written| recipients(written)).collect::<Vec<_>>();
+            assert_eq!(per_connection, vec![vec!["RCPT TO:<a@test.test>"], vec!["RCPT TO:<b@test.test>"]]);
+        }
+    }
+
+    mod per_mail_deadline {
+        use std::{
+            thread,
+            io::{BufRead, BufReader, Write},
+            net::{SocketAddr, TcpListener},
+            time::Duration
+        };
+        use futures::Stream;
+        use ::{
+            config::SendConfig,
+            error::{MailSendError, TimeoutPhase},
+            request::MailRequest,
+            test_utils::{con_config, run, serve_smtp, simple_mail, test_context}
+        };
+        use super::super::send_batch_cycling;
+
+        /// Starts a server never answering the data of the first mail, the second connection works.
+        fn spawn_stalling_server() -> (SocketAddr, thread::JoinHandle<String>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let handle = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"220 test.test ready\r\n").unwrap();
+                let mut in_data = false;
+                loop {
+                    let mut line = String::new();
+                    // the client drops the connection once the deadline elapsed
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    if in_data {
+                        continue;
+                    }
+                    in_data = line.starts_with("DATA");
+                    let reply: &[u8] = if in_data { b"354 Go ahead\r\n" } else { b"250 Ok\r\n" };
+                    stream.write_all(reply).unwrap();
+                }
+
+                let (stream, _) = listener.accept().unwrap();
+                serve_smtp(stream.try_clone().unwrap(), stream)
+            });
+            (addr, handle)
+        }
+
+        #[test]
+        fn send_batch_cycling_continues_over_new_connection() {
+            let (addr, server) = spawn_stalling_server();
+            let mut config = SendConfig::default();
+            config.per_mail_deadline = Some(Duration::from_millis(100));
+            let mails = vec![
+                MailRequest::new(simple_mail("a@test.test")),
+                MailRequest::new(simple_mail("b@test.test"))
+            ];
+
+            let stream = send_batch_cycling(mails, con_config(addr), test_context(), config);
+            let mut results = run(stream.then(|result| Ok::<_, ()>(result)).collect())
+                .unwrap()
+                .into_iter();
+            let second_connection = server.join().unwrap();
+
+            match results.next().unwrap() {
+                Err(MailSendError::Timeout { phase: TimeoutPhase::Mail }) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            results.next().unwrap().unwrap();
+            assert!(second_connection.starts_with("EHLO me.test\r\n"));
+            assert!(second_connection.contains("RCPT TO:<b@test.test>\r\n"));
+        }
+    }
+
+    mod pipelined_encoding {
+        use std::{
+            cmp,
+            sync::{
+                Arc, Mutex,
+                atomic::{AtomicUsize, Ordering}
+            },
+            time::{Duration, Instant}
+        };
+        use futures::{Future, Stream, stream};
+        use tokio_timer::Delay;
+        use mail::Mail;
+        use ::{
+            config::{SendConfig, SendTarget},
+            error::MailSendError,
+            request::MailRequest,
+            transaction::OutgoingMail,
+            test_utils::{con_config, run, simple_mail, simple_mail_with_body, spawn_smtp_server_for, test_context}
+        };
+        use super::super::{send_batch_with, encode_ahead, encode_outgoing};
+
+        #[test]
+        fn encodes_ahead_while_the_source_isnt_polled() {
+            let encoded = Arc::new(AtomicUsize::new(0));
+            let encodings = {
+                let encoded = encoded.clone();
+                stream::iter_ok::<_, ()>(0..3)
+                    .map(move |_| {
+                        let encoded = encoded.clone();
+                        Delay::new(Instant::now() + Duration::from_millis(10)).then(move |_| {
+                            encoded.fetch_add(1, Ordering::SeqCst);
+                            Ok::<_, ()>(Err::<OutgoingMail, _>(MailSendError::Cancelled))
+                        })
+                    })
+            };
+
+            let fut = encode_ahead(encodings, 2, None)
+                .into_future()
+                .map_err(|(err, _)| err)
+                .and_then(|(first, rest)| {
+                    assert!(first.is_some());
+                    // the source isn't polled while "sending" the first mail
+                    Delay::new(Instant::now() + Duration::from_millis(100)).then(move |_| Ok(rest))
+                });
+            let _rest = run(fut).unwrap();
+
+            assert_eq!(encoded.load(Ordering::SeqCst), 2);
+        }
+
+        #[test]
+        fn sends_all_mails_in_order() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mails = ["a@test.test", "b@test.test", "c@test.test"].iter()
+                .map(|recipient| MailRequest::new(simple_mail(recipient)))
+                .collect::<Vec<_>>();
+            let mut config = SendConfig::default();
+            config.pipelined_encoding = Some(2);
+            config.max_in_flight_bytes = Some(1024);
+
+            let stream = send_batch_with(mails, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 3);
+            let recipients = written[0].lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<a@test.test>", "RCPT TO:<b@test.test>", "RCPT TO:<c@test.test>"]);
+        }
+
+        #[test]
+        fn keeps_the_position_of_mails_failing_to_encode() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mails = vec![
+                MailRequest::new(simple_mail("a@test.test")),
+                // a mail without any headers fails to encode
+                MailRequest::new(Mail::plain_text("body")),
+                MailRequest::new(simple_mail("c@test.test")),
+                MailRequest::new(simple_mail("d@test.test"))
+            ];
+            let mut config = SendConfig::default();
+            config.pipelined_encoding = Some(2);
+
+            let stream = send_batch_with(mails, con_config(addr), test_context(), config);
+            let results = run(stream.then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 4);
+            assert!(results[0].is_ok());
+            match results[1] {
+                Err(MailSendError::Mail(_)) => {},
+                ref other => panic!("unexpected result: {:?}", other)
+            }
+            assert!(results[2].is_ok());
+            assert!(results[3].is_ok());
+            let recipients = written[0].lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<a@test.test>", "RCPT TO:<c@test.test>", "RCPT TO:<d@test.test>"]);
+        }
+
+        #[test]
+        fn bounds_the_bytes_held_at_once() {
+            const LIMIT: usize = 35_000;
+            let body = "a line of the body\n".repeat(500);
+            // the bytes held, the maximum of them and the size of the largest mail
+            let held = Arc::new(Mutex::new((0, 0, 0)));
+            let encodings = {
+                let held = held.clone();
+                let ctx = test_context();
+                stream::iter_ok::<_, ()>(0..10)
+                    .map(move |_| {
+                        let held = held.clone();
+                        let request = MailRequest::new(simple_mail_with_body("to@test.test", &body));
+                        encode_outgoing(request, ctx.clone(), SendTarget::Msa).then(move |result| {
+                            let mail = result.unwrap();
+                            let mut held = held.lock().unwrap();
+                            held.0 += mail.size();
+                            held.1 = cmp::max(held.1, held.0);
+                            held.2 = cmp::max(held.2, mail.size());
+                            Ok::<_, ()>(Ok(mail))
+                        })
+                    })
+            };
+
+            let sending = {
+                let held = held.clone();
+                encode_ahead(encodings, 10, Some(LIMIT))
+                    .and_then(move |result| {
+                        let mail = result.unwrap();
+                        let held = held.clone();
+                        // the mail is held while it's "send"
+                        Delay::new(Instant::now() + Duration::from_millis(20)).then(move |_| {
+                            held.lock().unwrap().0 -= mail.size();
+                            drop(mail);
+                            Ok::<_, ()>(())
+                        })
+                    })
+                    .collect()
+            };
+            let sent = run(sending).unwrap();
+
+            assert_eq!(sent.len(), 10);
+            let (_, max_held, max_size) = *held.lock().unwrap();
+            assert!(max_held <= LIMIT, "{} bytes were held at once", max_held);
+            // more than one mail was encoded ahead
+            assert!(max_held > max_size);
+        }
+    }
+
+    mod send_stream {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering}
+        };
+        use futures::{Stream, stream};
+        use ::{
+            config::SendConfig,
+            error::MailSendError,
+            request::MailRequest,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server, spawn_smtp_server_for, test_context}
+        };
+        use super::super::{send_stream, send_stream_with};
+
+        #[test]
+        fn sends_all_mails_of_the_stream_over_one_connection() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mails = stream::iter_ok::<_, MailSendError>(vec!["a@test.test", "b@test.test", "c@test.test"])
+                .map(|recipient| MailRequest::new(simple_mail(recipient)));
+            let mut config = SendConfig::default();
+            config.pipelined_encoding = Some(2);
+
+            let stream = send_stream_with(mails, con_config(addr), test_context(), config);
+            let results = run(stream.collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 3);
+            let recipients = written[0].lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<a@test.test>", "RCPT TO:<b@test.test>", "RCPT TO:<c@test.test>"]);
+            assert!(written[0].ends_with("QUIT\r\n"));
+        }
+
+        #[test]
+        fn returns_one_result_per_mail_and_source_error() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mails = stream::iter_result(vec![
+                Ok(MailRequest::new(simple_mail("a@test.test"))),
+                Err(MailSendError::Cancelled),
+                Ok(MailRequest::new(simple_mail("c@test.test")))
+            ]);
+
+            let stream = send_stream(mails, con_config(addr), test_context());
+            let results = run(stream.then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 3);
+            match (&results[0], &results[1], &results[2]) {
+                (&Ok(_), &Err(MailSendError::Cancelled), &Ok(_)) => {},
+                other => panic!("unexpected results: {:?}", other)
+            }
+            // the source error doesn't affect the connection
+            let recipients = written[0].lines()
+                .filter(|line| line.starts_with("RCPT TO:"))
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, vec!["RCPT TO:<a@test.test>", "RCPT TO:<c@test.test>"]);
+        }
+
+        #[test]
+        fn takes_mails_from_the_source_lazily() {
+            let taken = Arc::new(AtomicUsize::new(0));
+            let mails = {
+                let taken = taken.clone();
+                stream::iter_ok::<_, MailSendError>(0..100)
+                    .map(move |_| {
+                        taken.fetch_add(1, Ordering::SeqCst);
+                        MailRequest::new(simple_mail("to@test.test"))
+                    })
+            };
+            let mut config = SendConfig::default();
+            config.pipelined_encoding = Some(2);
+
+            let stream = send_stream_with(mails, con_config(spawn_smtp_server()), test_context(), config);
+            let (first, _rest) = run(stream.into_future())
+                .map_err(|_| ())
+                .unwrap();
+
+            first.unwrap();
+            // the first mail and the ones encoded ahead
+            assert!(taken.load(Ordering::SeqCst) <= 3);
+        }
+    }
+
+    mod send_batch_collected {
+        use std::{net::TcpListener, sync::Arc};
+        use mail::Mail;
+        use new_tokio_smtp::send_mail::MailAddress;
+        use ::{
+            cancel::CancelToken,
+            config::SendConfig,
+            error::MailSendError,
+            request::MailRequest,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server, spawn_smtp_server_for, test_context}
+        };
+        use super::super::{send_batch_collected, send_batch_collected_with};
+
+        #[test]
+        fn collects_one_result_per_mail() {
+            let (addr, server) = spawn_smtp_server_for(1);
+            let mails = vec![
+                MailRequest::new(simple_mail("a@test.test")),
+                // a mail without any headers fails to encode
+                MailRequest::new(Mail::plain_text("body")),
+                MailRequest::new(simple_mail("c@test.test"))
+            ];
+
+            let results = run(send_batch_collected(mails, con_config(addr), test_context())).unwrap();
+            let written = server.join().unwrap();
+
+            assert_eq!(results.len(), 3);
+            match (&results[0], &results[1], &results[2]) {
+                (&Ok(_), &Err(MailSendError::Mail(_)), &Ok(_)) => {},
+                other => panic!("unexpected results: {:?}", other)
+            }
+            assert!(written[0].contains("RCPT TO:<a@test.test>\r\n"));
+            assert!(written[0].contains("RCPT TO:<c@test.test>\r\n"));
+            assert!(written[0].ends_with("QUIT\r\n"));
+        }
+
+        #[test]
+        fn fails_with_transport_error_if_connecting_fails() {
+            let ctx = test_context();
+            let mails = (0..2)
+                .map(|_| MailRequest::new(simple_mail("to@example.com")))
+                .collect::<Vec<_>>();
+            // bind and drop a listener to get a port nothing listens on
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+            let err = run(send_batch_collected(mails, con_config(addr), ctx)).unwrap_err();
+
+            match *err.error() {
+                MailSendError::Connecting(_) => {},
+                ref other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn fails_with_transport_error_after_mails_failing_to_encode() {
+            let ctx = test_context();
+            let mails = vec![
+                MailRequest::new(Mail::plain_text("body")),
+                MailRequest::new(simple_mail("to@example.com"))
+            ];
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+            let err = run(send_batch_collected(mails, con_config(addr), ctx)).unwrap_err();
+
+            match *err.error() {
+                MailSendError::Connecting(_) => {},
+                ref other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn reports_refused_mails_in_a_mixed_batch_per_mail() {
+            let ctx = test_context();
+            let mails = vec![
+                MailRequest::new(Mail::plain_text("body")),
+                MailRequest::new(simple_mail("dropped@example.com")),
+                MailRequest::new(simple_mail("to@example.com"))
+            ];
+            let mut config = SendConfig::default();
+            config.recipient_rewriter = Some(Arc::new(|addr: &MailAddress| {
+                if addr.as_str() == "dropped@example.com" { Vec::new() } else { vec![addr.clone()] }
+            }));
+
+            let results = run(send_batch_collected_with(mails, con_config(spawn_smtp_server()), ctx, config)).unwrap();
+
+            assert_eq!(results.len(), 3);
+            match (&results[0], &results[1], &results[2]) {
+                (&Err(MailSendError::Mail(_)), &Err(MailSendError::NoRecipients), &Ok(_)) => {},
+                other => panic!("unexpected results: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn reports_mails_cancelled_before_connecting_per_mail() {
+            let ctx = test_context();
+            let mails = (0..2)
+                .map(|_| MailRequest::new(simple_mail("to@example.com")))
+                .collect::<Vec<_>>();
+            let token = CancelToken::new();
+            token.cancel();
+            let mut config = SendConfig::default();
+            config.cancel_token = Some(token);
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+            let results = run(send_batch_collected_with(mails, con_config(addr), ctx, config)).unwrap();
+
+            assert_eq!(results.len(), 2);
+            for result in results {
+                match result {
+                    Err(MailSendError::CancelledBeforeSending) => {},
+                    other => panic!("unexpected result: {:?}", other)
+                }
+            }
+        }
+    }
+
+    mod error_mapper {
+        use std::{
+            thread,
+            io::{BufRead, BufReader, Write},
+            net::{TcpListener, SocketAddr}
+        };
+        use futures::Stream;
+        use new_tokio_smtp::error::LogicError;
+        use ::{
+            config::{SendConfig, RecipientPolicy},
+            error::{MailSendError, MappedError, logic_error_code},
+            request::MailRequest,
+            test_utils::{FakeServer, Reply, con_config, mock_envelop, run, simple_mail, test_context},
+            transaction::send_envelop_with
+        };
+        use super::super::send_batch_with_error_mapper;
+
+        #[derive(Debug, PartialEq)]
+        enum AppError {
+            SpamBlocked
+        }
+
+        fn map_blocked(err: &LogicError) -> Option<AppError> {
+            let mentions_blocked = match *err {
+                LogicError::Code(ref response) => response.msg().iter().any(|line| line.contains("blocked")),
+                _ => false
+            };
+            if logic_error_code(err) == Some(550) && mentions_blocked {
+                Some(AppError::SpamBlocked)
+            } else {
+                None
+            }
+        }
+
+        fn rejected_with(reply: &'static str) -> MailSendError {
+            let server = FakeServer::new(vec![Reply::Lines(reply), Reply::Lines("250 Ok\r\n")]);
+            let fut = send_envelop_with(server.connection(), mock_envelop(&["a@test.test"]).into(), &SendConfig::default());
+            let (_con, result) = run(fut).unwrap();
+            result.unwrap_err()
+        }
+
+        #[test]
+        fn mapped_error_replaces_default_error() {
+            let err = rejected_with("550 5.7.1 Message blocked as spam\r\n");
+            match MappedError::map(err, map_blocked) {
+                MappedError::Custom(AppError::SpamBlocked) => {},
+                other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn unmapped_errors_are_kept() {
+            let err = rejected_with("550 5.1.1 Unknown user\r\n");
+            match MappedError::map(err, map_blocked) {
+                MappedError::Default(MailSendError::Smtp(_)) => {},
+                other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn expired_authentication_is_mapped() {
+            let err = rejected_with("530 5.7.0 blocked until you authenticate\r\n").auth_expired_if_required();
+            let mapped = MappedError::map(err, |err: &LogicError| match logic_error_code(err) {
+                Some(530) => Some(AppError::SpamBlocked),
+                _ => None
+            });
+            match mapped {
+                MappedError::Custom(AppError::SpamBlocked) => {},
+                other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        /// Starts a server rejecting all recipients with the given reply.
+        fn spawn_rejecting_server(rcpt_reply: &'static str) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"220 test.test ready\r\n").unwrap();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    if line.starts_with("QUIT") {
+                        stream.write_all(b"221 Bye\r\n").unwrap();
+                        break;
+                    }
+                    let reply = if line.starts_with("RCPT") { rcpt_reply } else { "250 Ok\r\n" };
+                    stream.write_all(reply.as_bytes()).unwrap();
+                }
+            });
+            addr
+        }
+
+        #[test]
+        fn rejected_recipients_are_mapped_in_a_batch() {
+            let addr = spawn_rejecting_server("550 5.7.1 Recipient blocked\r\n");
+            let mut config = SendConfig::default();
+            config.recipient_policy = RecipientPolicy::AcceptPartial;
+            let mails = vec![MailRequest::new(simple_mail("a@test.test"))];
+
+            let stream = send_batch_with_error_mapper(mails, con_config(addr), test_context(), config, map_blocked);
+            let results = run(stream.then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+
+            assert_eq!(results.len(), 1);
+            match results[0] {
+                Err(MappedError::Custom(AppError::SpamBlocked)) => {},
+                ref other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    mod send_over {
+        use mail::Mail;
+        use ::{
+            error::MailSendError,
+            request::MailRequest,
+            test_utils::{FakeServer, run, test_context}
+        };
+        use super::super::send_over;
+
+        #[test]
+        fn returns_the_connection_if_the_mail_fails_to_encode() {
+            let ctx = test_context();
+            let server = FakeServer::new(vec![]);
+            // a mail without any headers fails to encode
+            let mail = MailRequest::new(Mail::plain_text("body"));
+
+            let (_con, result) = run(send_over(server.connection(), mail, ctx)).unwrap();
+
+            match result {
+                Err(MailSendError::Mail(_)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            assert_eq!(server.written(), "");
+        }
+    }
+
+    mod send_streamed {
+        use std::{
+            io as std_io,
+            net::TcpListener,
+            time::Duration
+        };
+        use futures::stream;
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use ::{
+            config::SendConfig,
+            error::{MailSendError, TimeoutPhase},
+            test_utils::{con_config, run}
+        };
+        use super::super::send_streamed;
+
+        #[test]
+        fn applies_the_connect_timeout() {
+            // the connection is accepted by the OS but the server never sends a greeting
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let conconf = con_config(listener.local_addr().unwrap());
+            let mut config = SendConfig::default();
+            config.timeouts.connect = Some(Duration::from_millis(50));
+            let envelop_data = EnvelopData {
+                from: None,
+                to: Vec1::new(MailAddress::new_unchecked("a@test.test".to_owned(), false))
+            };
+            let body = stream::iter_ok::<_, std_io::Error>(vec![b"Subject: x\r\n\r\nbody\r\n".to_vec()]);
+
+            match run(send_streamed(envelop_data, body, conconf, config)) {
+                Err(MailSendError::Timeout { phase: TimeoutPhase::Connect }) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    mod encode {
+        use std::time::UNIX_EPOCH;
+        use headers::{
+            headers::{_From, _To, Subject},
+            header_components::{Mailbox, Email}
+        };
+        use mail::Mail;
+        use new_tokio_smtp::send_mail as smtp;
+        use ::{
+            request::MailRequest,
+            trace::ReceivedHeader,
+            test_utils::{run, simple_mail, test_context}
+        };
+        use super::super::encode;
+
+        #[test]
+        fn prepended_received_header_is_the_first_header() {
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+            let mut request = MailRequest::new(mail);
+            request.prepend_received(ReceivedHeader::new("a.test", "b.test", UNIX_EPOCH).unwrap());
+            request.prepend_received(ReceivedHeader::new("b.test", "c.test", UNIX_EPOCH).unwrap());
+
+            let envelop = run(encode(request, ctx)).unwrap();
+            let (mail, _): (smtp::Mail, smtp::EnvelopData) = envelop.into();
+            let raw = String::from_utf8(mail.raw_data().to_owned()).unwrap();
+
+            let expected_start = concat!(
+                "Received: from b.test by c.test; Thu, 1 Jan 1970 00:00:00 +0000\r\n",
+                "Received: from a.test by b.test; Thu, 1 Jan 1970 00:00:00 +0000\r\n"
+            );
+            assert!(raw.starts_with(expected_start), "unexpected start of mail: {:?}", raw);
+            assert_eq!(raw.matches("Received:").count(), 2);
+        }
+
+        fn encoding_requirement(force_smtputf8: Option<bool>, to: &str) -> smtp::EncodingRequirement {
+            let ctx = test_context();
+            let mail = simple_mail(to);
+            let mut request = MailRequest::new(mail);
+            request.set_force_smtputf8(force_smtputf8);
+
+            let envelop = run(encode(request, ctx)).unwrap();
+            let (mail, _): (smtp::Mail, smtp::EnvelopData) = envelop.into();
+            mail.encoding_requirement()
+        }
+
+        #[test]
+        fn with_sender_makes_multi_mailbox_from_encodable() {
+            let ctx = test_context();
+            let mut mail = Mail::plain_text("body");
+            mail.insert_headers(headers! {
+                _From: ["ape@example.com", "affe@example.com"],
+                _To: ["to@example.com"],
+                Subject: "test"
+            }.unwrap());
+            let sender = Mailbox::from(Email::new("editor@example.com").unwrap());
+            let request = MailRequest::new(mail).with_sender(sender);
+
+            let envelop = run(encode(request, ctx)).unwrap();
+            let (mail, envelop_data): (smtp::Mail, smtp::EnvelopData) = envelop.into();
+            let raw = String::from_utf8(mail.raw_data().to_owned()).unwrap();
+
+            assert_eq!(envelop_data.from.as_ref().unwrap().as_str(), "editor@example.com");
+            let header_section = &raw[..raw.find("\r\n\r\n").unwrap()];
+            assert!(
+                header_section.lines().any(|line| line.starts_with("Sender:") && line.contains("editor@example.com")),
+                "no Sender header in: {:?}", header_section
+            );
+        }
+
+        #[test]
+        fn smtputf8_is_detected_from_the_addresses() {
+            assert_eq!(encoding_requirement(None, "to@example.com"), smtp::EncodingRequirement::None);
+            assert_eq!(encoding_requirement(None, "tö@example.com"), smtp::EncodingRequirement::Smtputf8);
+        }
+
+        #[test]
+        fn forced_smtputf8_overrides_the_detection() {
+            assert_eq!(encoding_requirement(Some(true), "to@example.com"), smtp::EncodingRequirement::Smtputf8);
+            assert_eq!(encoding_requirement(Some(false), "tö@example.com"), smtp::EncodingRequirement::None);
+        }
+    }
+
+    mod auto_submitted {
+        use new_tokio_smtp::send_mail as smtp;
+        use ::{
+            request::{MailRequest, AutoSubmitted},
+            test_utils::{run, simple_mail, test_context}
+        };
+        use super::super::encode;
+
+        #[test]
+        fn adds_auto_submitted_header() {
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+            let mut request = MailRequest::new(mail);
+            request.mark_auto_submitted(AutoSubmitted::AutoGenerated);
+
+            let envelop = run(encode(request, ctx)).unwrap();
+            let (mail, _): (smtp::Mail, smtp::EnvelopData) = envelop.into();
+            let raw = String::from_utf8(mail.raw_data().to_owned()).unwrap();
+
+            let header_section = &raw[..raw.find("\r\n\r\n").unwrap()];
+            assert!(header_section.lines().any(|line| line == "Auto-Submitted: auto-generated"), "{}", header_section);
+            assert_eq!(raw.matches("Auto-Submitted:").count(), 1);
+        }
+    }
+
+    mod auto_date {
+        use headers::{
+            headers::{_From, _To, Subject, Date},
+            header_components::DateTime
+        };
+        use mail::Mail;
+        use new_tokio_smtp::send_mail as smtp;
+        use ::{
+            request::MailRequest,
+            test_utils::{run, test_context}
+        };
+        use super::super::encode;
+
+        fn encoded_header_section(request: MailRequest) -> String {
+            let envelop = run(encode(request, test_context())).unwrap();
+            let (mail, _): (smtp::Mail, smtp::EnvelopData) = envelop.into();
+            let raw = String::from_utf8(mail.raw_data().to_owned()).unwrap();
+            raw[..raw.find("\r\n\r\n").unwrap()].to_owned()
+        }
+
+        #[test]
+        fn adds_date_if_missing() {
+            let mut mail = Mail::plain_text("body");
+            mail.insert_headers(headers! {
+                _From: ["from@example.com"],
+                _To: ["to@example.com"],
+                Subject: "test"
+            }.unwrap());
+            let mut request = MailRequest::new(mail);
+            request.set_auto_date(true);
+
+            let header_section = encoded_header_section(request);
+            assert_eq!(header_section.matches("Date: ").count(), 1, "{}", header_section);
+        }
+
+        #[test]
+        fn keeps_existing_date() {
+            let mut mail = Mail::plain_text("body");
+            mail.insert_headers(headers! {
+                _From: ["from@example.com"],
+                _To: ["to@example.com"],
+                Subject: "test"
+            }.unwrap());
+            mail.headers_mut().insert(Date::body(DateTime::now()));
+            let mut request = MailRequest::new(mail);
+            request.set_auto_date(true);
+
+            // no second `Date` header is added
+            let header_section = encoded_header_section(request);
+            assert_eq!(header_section.matches("Date: ").count(), 1, "{}", header_section);
+        }
+    }
+
+    mod auto_message_id {
+        use ::{
+            request::MailRequest,
+            test_utils::{FakeServer, Reply, run, simple_mail, test_context}
+        };
+        use super::super::send_over;
+
+        #[test]
+        fn generates_message_id_with_context_domain() {
+            let ctx = test_context();
+            let server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n")
+            ]);
+            let mail = simple_mail("to@example.com");
+            let mut request = MailRequest::new(mail);
+            request.set_auto_message_id(true);
+
+            let (_con, result) = run(send_over(server.connection(), request, ctx)).unwrap();
+            let response = result.unwrap();
+
+            let message_id = response.message_id().expect("message id is set").to_owned();
+            assert!(message_id.starts_with('<') && message_id.ends_with("@example.com>"), "{}", message_id);
+            // the header name is encoded as `Message-Id` by mail-headers
+            let written = server.written().to_lowercase();
+            assert!(written.contains(&format!("message-id: {}\r\n", message_id.to_lowercase())), "{}", written);
+            assert_eq!(written.matches("message-id:").count(), 1);
+        }
+    }
+
+    mod record_timings {
+        use ::{
+            config::SendConfig,
+            request::MailRequest,
+            test_utils::{con_config, run, simple_mail, spawn_smtp_server, test_context}
+        };
+        use super::super::send_with;
+
+        #[test]
+        fn records_all_phases_of_a_successful_send() {
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+            let mut config = SendConfig::default();
+            config.record_timings = true;
+
+            let response = run(send_with(MailRequest::new(mail), con_config(spawn_smtp_server()), ctx, config)).unwrap();
+
+            let timings = response.timings().expect("timings are recorded");
+            assert!(timings.encode.is_some());
+            assert!(timings.tcp_connect.is_some());
+            assert!(timings.greeting.is_some());
+            assert!(timings.ehlo.is_some());
+            assert!(timings.auth.is_some());
+            assert!(timings.envelope.is_some());
+            assert!(timings.data_upload.is_some());
+            assert!(timings.server_processing.is_some());
+            // the connection doesn't use TLS
+            assert!(timings.tls.is_none());
+        }
+    }
+
+    mod with_checkpoint {
+        use std::sync::{Arc, Mutex};
+        use futures::{Stream, stream};
+        use ::{
+            config::Checkpoint,
+            test_utils::run
+        };
+        use super::super::with_checkpoint;
+
+        #[test]
+        fn records_indices_of_successful_results() {
+            let recorded = Arc::new(Mutex::new(Vec::new()));
+            let checkpoint = {
+                let recorded = recorded.clone();
+                Checkpoint::new(move |index| recorded.lock().unwrap().push(index))
+            };
+
+            let input = stream::iter_result(vec![Ok(()), Err(()), Ok(()), Ok(())]);
+            let stream = with_checkpoint(input, 2, Some(checkpoint));
+            run(stream.then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+
+            assert_eq!(*recorded.lock().unwrap(), vec![2, 4, 5]);
+        }
+    }
+}
@@ -0,0 +1,1239 @@
+//! Module implementing the connect -> send -> quit session used by `send`/`send_batch`.
+use std::{
+    io as std_io,
+    mem,
+    sync::{Arc, Mutex}
+};
+
+use futures::{
+    stream::{self, Stream},
+    future::{self, Future, Either}
+};
+use tokio::executor::{Executor, DefaultExecutor};
+
+use new_tokio_smtp::{Cmd, Connection, ConnectionConfig, SetupTls};
+
+use ::{
+    cancel::{CancelToken, cancellable},
+    config::{SendConfig, RetryBudget, ServiceClosingPolicy},
+    connect::connect_recorded,
+    error::{MailSendError, TimeoutPhase},
+    response::MailResponse,
+    timeout::with_timeout,
+    observe::TimingRecorder,
+    transaction::{
+        OutgoingMail, PendingMail, Pipelined, PipelinedFuture,
+        send_envelop_recorded, send_envelop_pipelined, finish_pipelined, can_pipeline
+    }
+};
+
+/// The (encoded) mails send in a session, one entry per input mail.
+///
+/// Mails which failed to encode are passed in as errors, so
+/// that there is still exactly one result per input.
+pub(crate) type MailSource =
+    Box<Stream<Item=Result<OutgoingMail, MailSendError>, Error=()> + Send>;
+
+/// Future of one step of a session, the item is `None` if there was no mail left.
+type StepFuture<A, S> =
+    Box<Future<Item=(Option<Result<MailResponse, MailSendError>>, Session<A, S>), Error=()> + Send>;
+
+/// Connects to the server, sends all mails and then quits the connection.
+///
+/// - There is exactly one result per input, in the same order as the input.
+/// - The connection is only opened once the first mail which didn't fail
+///   to encode is send, so if all mails failed to encode no connection is
+///   opened.
+/// - If setting up the connection fails, the mail for which it was set up
+///   fails with the error, all later mails fail with an I/O error of the
+///   kind `NotConnected`. The same is true if the connection breaks (I/O
+///   error or timeout) while sending a mail, or if the server closes it
+///   with a `421` reply.
+/// - Once there are no more mails the connection is closed using `QUIT`.
+/// - If the stream is dropped before all mails are send, the connection
+///   is closed using `QUIT` on a best-effort basis, see `QuitOnDrop`.
+///
+/// The mails are taken from the source one at a time, i.e. the next
+/// mail is only requested once the previous one was send. With
+/// `pipelining` (and a server supporting it) the final reply to a
+/// mail is only read once the next mail was taken from the source,
+/// see `SendConfig::pipelining`.
+///
+/// If a `SetupFailure` is given, it records if setting up the connection failed.
+pub(crate) fn connect_send_quit<A, S>(
+    conconf: ConnectionConfig<A, S>,
+    mails: MailSource,
+    config: SendConfig,
+    setup_failure: Option<SetupFailure>
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    let mut session = Session::new(ConState::Pending(conconf), mails, config, None, false);
+    session.setup_failure = setup_failure;
+    run_session(session)
+}
+
+/// Like `connect_send_quit` but opens a new connection when the config asks for it.
+///
+/// This is the case once `max_mails_per_connection` or `max_bytes_per_connection`
+/// is reached and after a mail exceeded the `per_mail_deadline`. The server
+/// closing the connection (`421`) still fails all remaining mails, like
+/// with `connect_send_quit`.
+///
+/// If a `SetupFailure` is given, it records if setting up the first connection failed.
+pub(crate) fn connect_send_quit_reconnecting<A, S>(
+    conconf: ConnectionConfig<A, S>,
+    mails: MailSource,
+    config: SendConfig,
+    setup_failure: Option<SetupFailure>
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd + Clone, S: SetupTls + Clone
+{
+    let reconnect = reconnect_with(&conconf);
+    let mut session = Session::new(ConState::Pending(conconf), mails, config, Some(reconnect), false);
+    session.setup_failure = setup_failure;
+    run_session(session)
+}
+
+/// Records for which result setting up the first connection of a session failed.
+///
+/// This tells a session which couldn't send anything (e.g. because the
+/// server is unreachable or the authentication failed) apart from one where
+/// some mails were refused before they were send (e.g. `LoopDetected`) or
+/// the connection broke later on. Only the first connection is recorded,
+/// failing to reconnect after some mails were send is not.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SetupFailure(Arc<Mutex<Option<usize>>>);
+
+impl SetupFailure {
+
+    /// The index of the result of the mail for which setting up the first connection failed.
+    pub(crate) fn index(&self) -> Option<usize> {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn record(&self, index: usize) {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = Some(index);
+    }
+}
+
+/// Like `connect_send_quit_reconnecting` but also reconnects if the server closes the connection using `421`.
+///
+/// What happens then depends on the `ServiceClosingPolicy` of the config,
+/// by default the mail which was answered with `421` is send again over the
+/// new connection (once), all later mails are send over the new connection, too.
+/// The same is done if the authentication of the connection expired.
+pub(crate) fn connect_send_quit_resilient<A, S>(
+    conconf: ConnectionConfig<A, S>,
+    mails: MailSource,
+    config: SendConfig
+) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd + Clone, S: SetupTls + Clone
+{
+    let reconnect = reconnect_with(&conconf);
+    run_session(Session::new(ConState::Pending(conconf), mails, config, Some(reconnect), true))
+}
+
+fn reconnect_with<A, S>(conconf: &ConnectionConfig<A, S>) -> Reconnect<A, S>
+    where A: Cmd + Clone, S: SetupTls + Clone
+{
+    let template = conconf.clone();
+    Box::new(move || template.clone())
+}
+
+/// Creates a `MailSource` from already encoded mails.
+pub(crate) fn source_from_vec(mails: Vec<Result<OutgoingMail, MailSendError>>) -> MailSource {
+    Box::new(stream::iter_ok(mails))
+}
+
+fn run_session<A, S>(session: Session<A, S>) -> impl Stream<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, S: SetupTls
+{
+    stream::unfold(session, Session::send_next)
+        .filter_map(|result| result)
+        .then(|result| match result {
+            Ok(result) => result,
+            Err(()) => unreachable!("[BUG] session steps never fail")
+        })
+}
+
+/// Creates the connection config used to reconnect.
+type Reconnect<A, S> = Box<Fn() -> ConnectionConfig<A, S> + Send>;
+
+struct Session<A, S> {
+    con: ConState<A, S>,
+    mails: Option<MailSource>,
+    config: SendConfig,
+    reconnect: Option<Reconnect<A, S>>,
+    /// Whether to reconnect (and retry the mail) after a `421` or an expired authentication.
+    resilient: bool,
+    /// Whether the server confirmed the authentication of the current connection.
+    ///
+    /// Only then a `530` reply means that the authentication expired.
+    authenticated: bool,
+    /// A mail answered with `421` which is send again before the remaining mails.
+    retry: Option<OutgoingMail>,
+    /// The number of mails send over the current connection.
+    mails_over_con: usize,
+    /// The number of bytes (of encoded mails) send over the current connection.
+    bytes_over_con: usize,
+    /// A mail send pipelined whose data is send with the next mail.
+    pending: Option<PendingMail>,
+    /// The result of a mail which is returned by the next step.
+    queued: Option<Result<MailResponse, MailSendError>>,
+    /// The number of results returned so far.
+    results: usize,
+    /// Whether a connection was set up successfully before.
+    connected: bool,
+    /// Records if setting up the first connection failed, see `SetupFailure`.
+    setup_failure: Option<SetupFailure>
+}
+
+enum ConState<A, S> {
+    Pending(ConnectionConfig<A, S>),
+    Open(QuitOnDrop),
+    Closed
+}
+
+/// An open connection which is closed using `QUIT` when dropped.
+///
+/// This is used to hold the connection between sending two mails, so
+/// that dropping the stream returned by `connect_send_quit` early still
+/// closes the connection cleanly instead of just resetting it.
+///
+/// As `QUIT` needs to be send asynchronously it is spawned onto the
+/// default executor, which means it is only send if the stream is
+/// dropped from within a tokio runtime. If the stream is dropped while
+/// a mail is being send (i.e. the connection is in use) no `QUIT` is
+/// send, as the state of the mail transaction is unknown. The same is
+/// true for a connection held while the data of a mail is pending.
+pub(crate) struct QuitOnDrop {
+    con: Option<Connection>,
+    quit: bool
+}
+
+impl QuitOnDrop {
+    pub(crate) fn new(con: Connection) -> Self {
+        QuitOnDrop { con: Some(con), quit: true }
+    }
+
+    /// Holds a connection in the middle of a transaction, it's dropped without `QUIT`.
+    fn without_quit(con: Connection) -> Self {
+        QuitOnDrop { con: Some(con), quit: false }
+    }
+
+    pub(crate) fn into_inner(mut self) -> Connection {
+        self.con.take().expect("[BUG] connection is only taken once")
+    }
+}
+
+impl Drop for QuitOnDrop {
+    fn drop(&mut self) {
+        if let Some(con) = self.con.take() {
+            if !self.quit {
+                return;
+            }
+            let fut = con.quit().then(|_| Ok(()));
+            // best effort, if there is no executor the connection is just dropped
+            let _ = DefaultExecutor::current().spawn(Box::new(fut));
+        }
+    }
+}
+
+impl<A, S> Session<A, S>
+    where A: Cmd, S: SetupTls
+{
+    fn new(
+        con: ConState<A, S>,
+        mails: MailSource,
+        config: SendConfig,
+        reconnect: Option<Reconnect<A, S>>,
+        resilient: bool
+    ) -> Self {
+        debug_assert!(!resilient || reconnect.is_some(), "[BUG] resilient sessions need to reconnect");
+        let connected = match con {
+            ConState::Open(_) => true,
+            _ => false
+        };
+        Session {
+            con, config, reconnect, resilient, connected,
+            authenticated: false,
+            mails: Some(mails),
+            retry: None,
+            mails_over_con: 0,
+            bytes_over_con: 0,
+            pending: None,
+            queued: None,
+            results: 0,
+            setup_failure: None
+        }
+    }
+
+    /// Sends the next mail, or quits the connection if there are no more mails.
+    ///
+    /// Returns `None` once the session is done.
+    fn send_next(self) -> Option<StepFuture<A, S>> {
+        let fut = self.step()?
+            .map(|(result, mut session)| {
+                if result.is_some() {
+                    session.results += 1;
+                }
+                (result, session)
+            });
+        Some(Box::new(fut))
+    }
+
+    fn step(mut self) -> Option<StepFuture<A, S>> {
+        if let Some(result) = self.queued.take() {
+            return Some(Box::new(future::ok((Some(result), self))));
+        }
+        if let Some(mail) = self.retry.take() {
+            return Some(self.send_mail(Ok(mail), true));
+        }
+
+        let mails = self.mails.take()?;
+        let fut = mails.into_future()
+            .map_err(|((), _mails)| unreachable!("[BUG] mail sources never fail"))
+            .and_then(move |(next, mails)| match next {
+                Some(mail) => {
+                    self.mails = Some(mails);
+                    self.send_mail(mail, false)
+                },
+                None => self.finish()
+            });
+
+        Some(Box::new(fut))
+    }
+
+    fn send_mail(mut self, mail: Result<OutgoingMail, MailSendError>, is_retry: bool) -> StepFuture<A, S> {
+        let cancel_token = self.config.cancel_token.clone();
+        let cancelled = cancel_token.as_ref().map(CancelToken::is_cancelled).unwrap_or(false);
+        if self.pending.is_some() && (mail.is_err() || cancelled) {
+            // the pending mail is finished first, so that the results keep their order
+            return self.finish_pending(move |session| session.send_mail(mail, is_retry));
+        }
+
+        let mail = match mail {
+            Ok(mail) => mail,
+            Err(err) => return Box::new(future::ok((Some(Err(err)), self)))
+        };
+        if cancelled {
+            return Box::new(future::ok((Some(Err(MailSendError::CancelledBeforeSending)), self)));
+        }
+
+        let recorder = if self.config.record_timings { Some(TimingRecorder::default()) } else { None };
+        let size = mail.size();
+        // the mail is consumed while it's send, so the size is kept in the budget until it's done
+        let in_flight = mail.in_flight.clone();
+        let con_fut = match mem::replace(&mut self.con, ConState::Closed) {
+            ConState::Pending(conconf) => Either::A(self.open(conconf, recorder.clone())),
+            ConState::Open(con) => match self.reconnect_before(size) {
+                Some(conconf) => {
+                    let connecting = self.open(conconf, recorder.clone());
+                    // errors on quit don't matter, the previous mails are already send
+                    Either::B(Either::A(con.into_inner().quit().then(move |_| connecting)))
+                },
+                None => Either::B(Either::B(future::ok((con.into_inner(), self.authenticated))))
+            },
+            ConState::Closed => return Box::new(future::ok((Some(Err(no_connection())), self)))
+        };
+
+        // a mail is only retried once, and only by resilient sessions
+        let retry_mail = if self.resilient && !is_retry { Some(mail.clone()) } else { None };
+
+        // retrying needs to know the result of each mail before sending the next one,
+        // the options of the config disabling pipelining are checked by `can_pipeline`
+        let may_pipeline = !self.resilient;
+        let previous = self.pending.take();
+        let had_previous = previous.is_some();
+        let send_config = self.config.clone();
+        let deadline = self.config.per_mail_deadline;
+        // nothing of the mail was send while the connection is set up,
+        // the flag of the error tells if setting up the connection failed
+        let sending = cancellable(con_fut, cancel_token.clone())
+            .map_err(|err| if err.is_cancelled() { (MailSendError::CancelledBeforeSending, false) } else { (err, true) })
+            .and_then(move |(con, authenticated)| {
+                let fut: PipelinedFuture = if may_pipeline && can_pipeline(&con, &send_config) {
+                    send_envelop_pipelined(con, mail, previous, &send_config, recorder)
+                } else {
+                    debug_assert!(previous.is_none(), "[BUG] pending mails are only send over pipelining connections");
+                    let fut = send_envelop_recorded(con, mail, &send_config, recorder)
+                        .map(|(con, result)| (con, None, Pipelined::Done(result)));
+                    Box::new(fut)
+                };
+                let fut = with_timeout(fut, deadline, TimeoutPhase::Mail)
+                    .map(move |(con, previous, outcome)| (con, previous, outcome, authenticated));
+                cancellable(fut, cancel_token)
+                    .map_err(|err| (err, false))
+            });
+        let fut = sending
+            .then(move |result| -> StepFuture<A, S> {
+                // a pending mail keeps its own reference until its body was send
+                drop(in_flight);
+                let (con, previous, outcome) = match result {
+                    Ok((con, previous, outcome, authenticated)) => {
+                        self.connected = true;
+                        self.authenticated = authenticated;
+                        let previous = previous.map(|previous| self.check_auth_expired(previous));
+                        (Some(con), previous, outcome)
+                    },
+                    Err((err, setup_failed)) => if had_previous {
+                        // the connection broke (or the send was cancelled) before the
+                        // final reply to the previous mail was read
+                        let (previous, current) = if err.is_cancelled() {
+                            (MailSendError::Cancelled, err)
+                        } else {
+                            (err, no_connection())
+                        };
+                        (None, Some(Err(previous)), Pipelined::Done(Err(current)))
+                    } else {
+                        if setup_failed {
+                            self.record_setup_failure();
+                        }
+                        (None, None, Pipelined::Done(Err(err)))
+                    }
+                };
+
+                let result = match outcome {
+                    Pipelined::Pending(pending) => {
+                        self.pending = Some(pending);
+                        self.mails_over_con += 1;
+                        self.bytes_over_con += size;
+                        let con = con.expect("[BUG] pending mails have a connection");
+                        self.con = ConState::Open(QuitOnDrop::without_quit(con));
+                        return Box::new(future::ok((previous, self)));
+                    },
+                    Pipelined::Done(result) => self.check_auth_expired(result)
+                };
+
+                let previous = match previous {
+                    Some(previous) => previous,
+                    None => return self.on_sent(con, result, size, retry_mail)
+                };
+                if previous.as_ref().err().map(MailSendError::is_service_closing).unwrap_or(false) {
+                    // the server closed the connection after the final reply to the previous mail
+                    drop(con);
+                    self.con = ConState::Closed;
+                    self.queued = Some(result);
+                    return Box::new(future::ok((Some(previous), self)));
+                }
+                let fut = self.on_sent(con, result, size, retry_mail)
+                    .map(move |(result, mut session)| {
+                        session.queued = result;
+                        (Some(previous), session)
+                    });
+                Box::new(fut)
+            });
+
+        Box::new(fut)
+    }
+
+    /// Records that setting up the connection failed, if it's the first connection.
+    ///
+    /// The result of the current mail is the next one returned.
+    fn record_setup_failure(&self) {
+        if let (false, Some(setup_failure)) = (self.connected, self.setup_failure.as_ref()) {
+            setup_failure.record(self.results);
+        }
+    }
+
+    /// Updates the connection state after a mail was send, returning the result of the mail.
+    ///
+    /// If the mail is retried (e.g. after a `421`) the result of the retry is returned.
+    fn on_sent(
+        mut self,
+        con: Option<Connection>,
+        result: Result<MailResponse, MailSendError>,
+        size: usize,
+        retry_mail: Option<OutgoingMail>
+    ) -> StepFuture<A, S> {
+        let closed_by_server = result.as_ref()
+            .err()
+            .map(MailSendError::is_service_closing)
+            .unwrap_or(false);
+        let auth_expired = self.resilient && result.as_ref()
+            .err()
+            .map(MailSendError::is_auth_expired)
+            .unwrap_or(false);
+
+        if let Err(MailSendError::Timeout { phase: TimeoutPhase::Mail }) = result {
+            // the connection was dropped with the mail, the next mail is send over a new one
+            self.con = match self.reconnect.as_ref() {
+                Some(reconnect) => ConState::Pending(reconnect()),
+                None => ConState::Closed
+            };
+            return Box::new(future::ok((Some(result), self)));
+        }
+
+        if !closed_by_server && !auth_expired {
+            return match con {
+                Some(con) => {
+                    self.bytes_over_con += size;
+                    self.con = ConState::Open(QuitOnDrop::new(con));
+                    self.cycle_if_exhausted(result)
+                },
+                None => {
+                    self.con = ConState::Closed;
+                    Box::new(future::ok((Some(result), self)))
+                }
+            };
+        }
+
+        // a new connection authenticates again (e.g. with a fresh OAuth token)
+        let policy = if closed_by_server { self.config.service_closing } else { ServiceClosingPolicy::RetryMail };
+        match con {
+            // the server already closed the connection, so it's dropped without QUIT
+            Some(con) if closed_by_server => drop(con),
+            Some(con) => drop(QuitOnDrop::new(con)),
+            None => {}
+        }
+        self.con = match (self.reconnect.as_ref(), policy) {
+            (Some(reconnect), _) if self.resilient && policy != ServiceClosingPolicy::FailRemaining =>
+                ConState::Pending(reconnect()),
+            _ => ConState::Closed
+        };
+        match retry_mail {
+            Some(mail) if policy == ServiceClosingPolicy::RetryMail && self.acquire_retry() =>
+                self.send_mail(Ok(mail), true),
+            _ => Box::new(future::ok((Some(result), self)))
+        }
+    }
+
+    /// Opens a new connection, resetting the per connection counters.
+    ///
+    /// Also returns if the connection was authenticated.
+    fn open(&mut self, conconf: ConnectionConfig<A, S>, recorder: Option<TimingRecorder>)
+        -> impl Future<Item=(Connection, bool), Error=MailSendError>
+    {
+        self.mails_over_con = 0;
+        self.bytes_over_con = 0;
+        let connecting = connect_recorded(conconf, &self.config, recorder);
+        with_timeout(connecting, self.config.timeouts.connect, TimeoutPhase::Connect)
+    }
+
+    /// Returns the config for a new connection if sending `size` bytes would exceed `max_bytes_per_connection`.
+    ///
+    /// Nothing was send over the connection if `bytes_over_con` is zero, so a mail
+    /// larger than the limit is still send (instead of reconnecting forever).
+    fn reconnect_before(&self, size: usize) -> Option<ConnectionConfig<A, S>> {
+        let max_bytes = self.config.max_bytes_per_connection?;
+        let reconnect = self.reconnect.as_ref()?;
+        if self.bytes_over_con > 0 && self.bytes_over_con + size > max_bytes {
+            Some(reconnect())
+        } else {
+            None
+        }
+    }
+
+    /// Quits the connection and prepares a new one once `max_mails_per_connection` is reached.
+    ///
+    /// The result of the last mail send over the connection is returned once `QUIT` completed.
+    fn cycle_if_exhausted(mut self, result: Result<MailResponse, MailSendError>) -> StepFuture<A, S> {
+        self.mails_over_con += 1;
+        let exhausted = self.config.max_mails_per_connection
+            .map(|max| self.mails_over_con >= max)
+            .unwrap_or(false);
+
+        let conconf = match self.reconnect.as_ref() {
+            Some(reconnect) if exhausted => reconnect(),
+            _ => return Box::new(future::ok((Some(result), self)))
+        };
+        match mem::replace(&mut self.con, ConState::Pending(conconf)) {
+            // errors on quit don't matter, the mail is already send
+            ConState::Open(con) => Box::new(con.into_inner().quit()
+                .then(move |_| Ok::<_, ()>((Some(result), self)))),
+            _ => Box::new(future::ok((Some(result), self)))
+        }
+    }
+
+    /// Turns a `530` reply into `MailSendError::AuthExpired` if the connection was authenticated.
+    fn check_auth_expired(&self, result: Result<MailResponse, MailSendError>) -> Result<MailResponse, MailSendError> {
+        if self.authenticated {
+            result.map_err(MailSendError::auth_expired_if_required)
+        } else {
+            result
+        }
+    }
+
+    /// Takes a token from the retry budget, returns false if there is none left.
+    fn acquire_retry(&self) -> bool {
+        self.config.retry_budget.as_ref()
+            .map(RetryBudget::try_acquire)
+            .unwrap_or(true)
+    }
+
+    /// Quits the connection (if it is open), ending the session.
+    fn finish(mut self) -> StepFuture<A, S> {
+        if self.pending.is_some() {
+            return self.finish_pending(Session::finish);
+        }
+        match mem::replace(&mut self.con, ConState::Closed) {
+            // errors on quit don't matter, the mails are already send
+            ConState::Open(con) => Box::new(con.into_inner().quit()
+                .then(move |_| Ok::<_, ()>((None, self)))),
+            _ => Box::new(future::ok((None, self)))
+        }
+    }
+
+    /// Sends the data of the pending mail and reads its final reply, then continues with `next`.
+    ///
+    /// The result of the pending mail is returned first, the result of `next`
+    /// (if any) is returned by the following step.
+    fn finish_pending<F>(mut self, next: F) -> StepFuture<A, S>
+        where F: FnOnce(Self) -> StepFuture<A, S> + Send + 'static
+    {
+        let pending = self.pending.take().expect("[BUG] finish_pending without pending mail");
+        let con = match mem::replace(&mut self.con, ConState::Closed) {
+            ConState::Open(con) => con.into_inner(),
+            _ => unreachable!("[BUG] pending mail without open connection")
+        };
+        let fut = finish_pipelined(con, pending, &self.config)
+            .then(move |result| {
+                let result = match result {
+                    Ok((con, result)) => {
+                        let result = self.check_auth_expired(result);
+                        let closed_by_server = result.as_ref()
+                            .err()
+                            .map(MailSendError::is_service_closing)
+                            .unwrap_or(false);
+                        if !closed_by_server {
+                            self.con = ConState::Open(QuitOnDrop::new(con));
+                        }
+                        result
+                    },
+                    Err(err) => Err(err)
+                };
+                next(self).map(move |(next_result, mut session)| {
+                    session.queued = next_result;
+                    (Some(result), session)
+                })
+            });
+
+        Box::new(fut)
+    }
+}
+
+fn no_connection() -> MailSendError {
+    MailSendError::Io(std_io::Error::new(
+        std_io::ErrorKind::NotConnected,
+        "connection was closed because of a previous error"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+
+    mod cancel {
+        use std::{thread, time::Duration};
+        use futures::Stream;
+        use new_tokio_smtp::{ClientId, Domain, command::{Ehlo, Noop}};
+        use ::{
+            cancel::CancelToken,
+            config::SendConfig,
+            error::MailSendError,
+            misc::DefaultTlsSetup,
+            test_utils::{FakeServer, Reply, mock_envelop, run}
+        };
+        use super::super::{Session, ConState, QuitOnDrop, run_session, source_from_vec};
+
+        #[test]
+        fn cancelled_send_fails_with_cancelled() {
+            let server = FakeServer::new(vec![Reply::Stall]);
+            let token = CancelToken::new();
+            let mut config = SendConfig::default();
+            config.cancel_token = Some(token.clone());
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                config,
+                None,
+                false
+            );
+
+            let canceller = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            });
+
+            let results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+            canceller.join().unwrap();
+
+            assert_eq!(results.len(), 2);
+            match (&results[0], &results[1]) {
+                (&Err(MailSendError::Cancelled), &Err(MailSendError::CancelledBeforeSending)) => {},
+                other => panic!("unexpected results: {:?}", other)
+            }
+            assert!(!server.written().contains("RCPT TO:<b@test.test>"));
+        }
+
+        #[test]
+        fn cancelling_while_pipelining_fails_both_mails_with_cancelled() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-test.test greets you\r\n250 PIPELINING\r\n"),
+                Reply::Lines("250 Ok\r\n250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                // never answers the data of the first mail
+                Reply::Stall
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let (con, result) = run(server.connection().send(Ehlo::new(client_id))).unwrap();
+            result.unwrap();
+
+            let token = CancelToken::new();
+            let mut config = SendConfig::default();
+            config.pipelining = true;
+            config.cancel_token = Some(token.clone());
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(con)),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                config,
+                None,
+                false
+            );
+
+            let canceller = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            });
+
+            let results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+            canceller.join().unwrap();
+
+            assert_eq!(results.len(), 2);
+            for result in results {
+                assert!(result.unwrap_err().is_cancelled());
+            }
+            // the second mail was started together with the data of the first one
+            assert!(server.written().contains("RCPT TO:<b@test.test>"));
+        }
+    }
+
+    mod quit_on_drop {
+        use std::time::{Duration, Instant};
+        use futures::{Future, Stream};
+        use tokio_timer::Delay;
+        use new_tokio_smtp::command::Noop;
+        use ::{
+            config::SendConfig,
+            misc::DefaultTlsSetup,
+            test_utils::{FakeServer, Reply, mock_envelop, run}
+        };
+        use super::super::{Session, ConState, QuitOnDrop, run_session, source_from_vec};
+
+        #[test]
+        fn sends_quit_if_stream_is_dropped_early() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                SendConfig::default(),
+                None,
+                false
+            );
+
+            let fut = run_session(session)
+                .into_future()
+                .map_err(|(err, _)| panic!("sending failed: {}", err))
+                .and_then(|(first, stream)| {
+                    drop(stream);
+                    // give the spawned QUIT a chance to run
+                    Delay::new(Instant::now() + Duration::from_millis(20))
+                        .map(move |_| first)
+                        .map_err(|err| panic!("timer failed: {}", err))
+                });
+
+            run(fut).unwrap().expect("one result per mail");
+            let written = server.written();
+            assert!(written.ends_with("some body\r\n.\r\nQUIT\r\n"));
+            assert!(!written.contains("RCPT TO:<b@test.test>"));
+        }
+    }
+
+    mod pipelining {
+        use std::{
+            sync::{Arc, Mutex},
+            time::Duration
+        };
+        use futures::Stream;
+        use new_tokio_smtp::{ClientId, Domain, command::{Ehlo, Noop}};
+        use ::{
+            config::{SendConfig, CommandObserver, SmtpCommand},
+            misc::DefaultTlsSetup,
+            test_utils::{FakeServer, Reply, mock_envelop, run}
+        };
+        use super::super::{Session, ConState, QuitOnDrop, run_session, source_from_vec};
+
+        #[test]
+        fn overlaps_the_transactions_of_two_mails() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-test.test greets you\r\n250 PIPELINING\r\n"),
+                Reply::Lines("250 Ok\r\n250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued as ID1\r\n250 Ok\r\n250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued as ID2\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let (con, result) = run(server.connection().send(Ehlo::new(client_id))).unwrap();
+            result.unwrap();
+
+            let mut config = SendConfig::default();
+            config.pipelining = true;
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(con)),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                config,
+                None,
+                false
+            );
+
+            let results = run(run_session(session).collect()).unwrap();
+            let queue_ids = results.iter().map(|response| response.queue_id()).collect::<Vec<_>>();
+            assert_eq!(queue_ids, vec![Some("ID1".to_owned()), Some("ID2".to_owned())]);
+
+            let written = server.written();
+            let overlapped = ".\r\nMAIL FROM:<sender@test.test>\r\nRCPT TO:<b@test.test>\r\n";
+            let end_of_first_mail = written.find(overlapped).expect("data and envelop are send together") + 3;
+            // nothing was read between the end of the first mail and the envelop of the second
+            assert!(!server.read_offsets().contains(&end_of_first_mail));
+            assert!(written.ends_with("QUIT\r\n"));
+        }
+
+        #[test]
+        fn reports_pipelined_commands_to_the_observer_and_records_timings() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-test.test greets you\r\n250 PIPELINING\r\n"),
+                Reply::Lines("250 Ok\r\n250 Ok\r\n250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued as ID1\r\n250 Ok\r\n250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued as ID2\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let (con, result) = run(server.connection().send(Ehlo::new(client_id))).unwrap();
+            result.unwrap();
+
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let observed = commands.clone();
+            let mut config = SendConfig::default();
+            config.pipelining = true;
+            config.record_timings = true;
+            config.command_observer = Some(CommandObserver::new(move |command, _duration| {
+                observed.lock().unwrap().push(command);
+            }));
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(con)),
+                source_from_vec(vec![
+                    Ok(mock_envelop(&["a@test.test", "b@test.test"]).into()),
+                    Ok(mock_envelop(&["c@test.test"]).into())
+                ]),
+                config,
+                None,
+                false
+            );
+
+            let results = run(run_session(session).collect()).unwrap();
+            assert_eq!(results.len(), 2);
+            for response in &results {
+                let timings = response.timings().expect("timings are recorded for pipelined mails");
+                assert!(timings.envelope.is_some());
+                assert!(timings.data_upload.is_some());
+                assert!(timings.server_processing.is_some());
+            }
+            // the data of the first mail is answered after the envelope of the second one is send
+            assert_eq!(*commands.lock().unwrap(), vec![
+                SmtpCommand::Mail, SmtpCommand::Rcpt, SmtpCommand::Rcpt,
+                SmtpCommand::Data, SmtpCommand::Mail, SmtpCommand::Rcpt,
+                SmtpCommand::Data
+            ]);
+            assert!(server.written().contains(".\r\nMAIL FROM:<sender@test.test>\r\nRCPT TO:<c@test.test>\r\n"));
+        }
+
+        #[test]
+        fn is_not_used_with_a_per_mail_deadline() {
+            let server = FakeServer::new(vec![
+                Reply::Lines("250-test.test greets you\r\n250 PIPELINING\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued as ID1\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued as ID2\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+            let (con, result) = run(server.connection().send(Ehlo::new(client_id))).unwrap();
+            result.unwrap();
+
+            let mut config = SendConfig::default();
+            config.pipelining = true;
+            config.per_mail_deadline = Some(Duration::from_secs(5));
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(con)),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                config,
+                None,
+                false
+            );
+
+            let results = run(run_session(session).collect()).unwrap();
+            assert_eq!(results.len(), 2);
+
+            // the deadline of the first mail doesn't cover the second one
+            let written = server.written();
+            assert!(!written.contains(".\r\nMAIL FROM:"));
+        }
+    }
+
+    mod reconnect {
+        use std::{
+            io as std_io,
+            time::Duration
+        };
+        use futures::{Future, Stream};
+        use new_tokio_smtp::command::Noop;
+        use ::{
+            config::{SendConfig, RetryBudget, ServiceClosingPolicy},
+            error::{MailSendError, TimeoutPhase},
+            response::MailResponse,
+            misc::DefaultTlsSetup,
+            test_utils::{FakeServer, Reply, con_config, mock_envelop, run, spawn_smtp_server}
+        };
+        use super::super::{Session, ConState, QuitOnDrop, Reconnect, run_session, source_from_vec};
+
+        #[test]
+        fn retries_mail_over_new_connection_after_421() {
+            let closing_server = FakeServer::new(vec![
+                Reply::Lines("421 4.3.2 Service shutting down\r\n")
+            ]);
+            let addr = spawn_smtp_server();
+            let reconnect: Reconnect<Noop, DefaultTlsSetup> = Box::new(move || con_config(addr));
+            let session = Session::new(
+                ConState::Open(QuitOnDrop::new(closing_server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                SendConfig::default(),
+                Some(reconnect),
+                true
+            );
+
+            let results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+
+            assert_eq!(results.len(), 2);
+            for result in results {
+                result.unwrap();
+            }
+            assert!(!closing_server.written().contains("RSET"));
+        }
+
+        #[test]
+        fn continues_over_new_connection_after_per_mail_deadline() {
+            let stalling_server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Stall
+            ]);
+            let addr = spawn_smtp_server();
+            let reconnect: Reconnect<Noop, DefaultTlsSetup> = Box::new(move || con_config(addr));
+            let mut config = SendConfig::default();
+            config.per_mail_deadline = Some(Duration::from_millis(50));
+            let session = Session::new(
+                ConState::Open(QuitOnDrop::new(stalling_server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                config,
+                Some(reconnect),
+                false
+            );
+
+            let mut results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect())
+                .unwrap()
+                .into_iter();
+
+            match results.next().unwrap() {
+                Err(MailSendError::Timeout { phase: TimeoutPhase::Mail }) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            results.next().unwrap().unwrap();
+            assert!(!stalling_server.written().contains("RCPT TO:<b@test.test>"));
+        }
+
+        #[test]
+        fn fails_remaining_mails_after_421_without_reconnect() {
+            let closing_server = FakeServer::new(vec![
+                Reply::Lines("421 4.3.2 Service shutting down\r\n")
+            ]);
+            let session: Session<Noop, DefaultTlsSetup> = Session::new(
+                ConState::Open(QuitOnDrop::new(closing_server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                SendConfig::default(),
+                None,
+                false
+            );
+
+            let mut results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect())
+                .unwrap()
+                .into_iter();
+
+            assert!(results.next().unwrap().unwrap_err().is_service_closing());
+            assert!(!results.next().unwrap().unwrap_err().is_service_closing());
+        }
+
+        #[test]
+        fn does_not_retry_without_retry_token() {
+            let closing_server = FakeServer::new(vec![
+                Reply::Lines("421 4.3.2 Service shutting down\r\n")
+            ]);
+            let addr = spawn_smtp_server();
+            let reconnect: Reconnect<Noop, DefaultTlsSetup> = Box::new(move || con_config(addr));
+            let mut config = SendConfig::default();
+            config.retry_budget = Some(RetryBudget::new(0, Duration::from_secs(3600)));
+            let session = Session::new(
+                ConState::Open(QuitOnDrop::new(closing_server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into()), Ok(mock_envelop(&["b@test.test"]).into())]),
+                config,
+                Some(reconnect),
+                true
+            );
+
+            let mut results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect())
+                .unwrap()
+                .into_iter();
+
+            let err = results.next().unwrap().unwrap_err();
+            assert!(err.is_service_closing());
+            assert!(err.is_transient());
+            // the remaining mails are still send over a new connection
+            results.next().unwrap().unwrap();
+        }
+
+        fn closing_after_first_mail() -> FakeServer {
+            FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("421 4.3.2 Service shutting down\r\n")
+            ])
+        }
+
+        fn send_three_mails(closing_server: &FakeServer, config: SendConfig)
+            -> Vec<Result<MailResponse, MailSendError>>
+        {
+            send_three_mails_over(closing_server, config, true)
+        }
+
+        fn send_three_mails_over(closing_server: &FakeServer, config: SendConfig, resilient: bool)
+            -> Vec<Result<MailResponse, MailSendError>>
+        {
+            let addr = spawn_smtp_server();
+            let reconnect: Reconnect<Noop, DefaultTlsSetup> = Box::new(move || con_config(addr));
+            let mails = ["a@test.test", "b@test.test", "c@test.test"].iter()
+                .map(|recipient| Ok(mock_envelop(&[*recipient]).into()))
+                .collect();
+            let session = Session::new(
+                ConState::Open(QuitOnDrop::new(closing_server.connection())),
+                source_from_vec(mails),
+                config,
+                Some(reconnect),
+                resilient
+            );
+
+            run(run_session(session).then(|result| Ok::<_, ()>(result)).collect()).unwrap()
+        }
+
+        #[test]
+        fn retries_remaining_mails_on_new_connection_after_421_mid_batch() {
+            let closing_server = closing_after_first_mail();
+            let results = send_three_mails(&closing_server, SendConfig::default());
+
+            assert_eq!(results.len(), 3);
+            for result in results {
+                result.unwrap();
+            }
+            assert!(!closing_server.written().contains("RCPT TO:<b@test.test>"));
+        }
+
+        #[test]
+        fn resume_remaining_fails_the_closed_mail_but_sends_the_rest() {
+            let closing_server = closing_after_first_mail();
+            let mut config = SendConfig::default();
+            config.service_closing = ServiceClosingPolicy::ResumeRemaining;
+            let mut results = send_three_mails(&closing_server, config).into_iter();
+
+            results.next().unwrap().unwrap();
+            assert!(results.next().unwrap().unwrap_err().is_service_closing());
+            results.next().unwrap().unwrap();
+        }
+
+        #[test]
+        fn does_not_retry_after_421_if_not_resilient() {
+            let closing_server = closing_after_first_mail();
+            let mut results = send_three_mails_over(&closing_server, SendConfig::default(), false).into_iter();
+
+            results.next().unwrap().unwrap();
+            assert!(results.next().unwrap().unwrap_err().is_service_closing());
+            match results.next().unwrap() {
+                Err(MailSendError::Io(ref err)) => assert_eq!(err.kind(), std_io::ErrorKind::NotConnected),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn reconnects_after_max_mails_per_connection() {
+            let first_server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let mut config = SendConfig::default();
+            config.max_mails_per_connection = Some(2);
+            let results = send_three_mails_over(&first_server, config, false);
+
+            assert_eq!(results.len(), 3);
+            for result in results {
+                result.unwrap();
+            }
+            let written = first_server.written();
+            assert!(written.contains("RCPT TO:<b@test.test>"));
+            assert!(written.ends_with("QUIT\r\n"));
+            assert!(!written.contains("RCPT TO:<c@test.test>"));
+        }
+
+        fn send_three_mails_cycling_on_bytes(first_server: &FakeServer, max_bytes: usize)
+            -> Vec<Result<MailResponse, MailSendError>>
+        {
+            // each new connection goes to a new server, as it only accepts one connection
+            let reconnect: Reconnect<Noop, DefaultTlsSetup> = Box::new(|| con_config(spawn_smtp_server()));
+            let mut config = SendConfig::default();
+            config.max_bytes_per_connection = Some(max_bytes);
+            let mails = ["a@test.test", "b@test.test", "c@test.test"].iter()
+                .map(|recipient| Ok(mock_envelop(&[*recipient]).into()))
+                .collect();
+            let session = Session::new(
+                ConState::Open(QuitOnDrop::new(first_server.connection())),
+                source_from_vec(mails),
+                config,
+                Some(reconnect),
+                false
+            );
+
+            run(run_session(session).then(|result| Ok::<_, ()>(result)).collect()).unwrap()
+        }
+
+        #[test]
+        fn reconnects_before_exceeding_max_bytes_per_connection() {
+            // each mock mail has 28 bytes, so two of them fit into 60 bytes
+            let first_server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let results = send_three_mails_cycling_on_bytes(&first_server, 60);
+
+            assert_eq!(results.len(), 3);
+            for result in results {
+                result.unwrap();
+            }
+            let written = first_server.written();
+            assert!(written.contains("RCPT TO:<b@test.test>"));
+            assert!(written.ends_with("QUIT\r\n"));
+            assert!(!written.contains("RCPT TO:<c@test.test>"));
+        }
+
+        #[test]
+        fn sends_mails_larger_than_max_bytes_per_connection_on_their_own() {
+            let first_server = FakeServer::new(vec![
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("250 Ok\r\n"),
+                Reply::Lines("354 Go ahead\r\n"),
+                Reply::Lines("250 Ok: queued\r\n"),
+                Reply::Lines("221 Bye\r\n")
+            ]);
+            let results = send_three_mails_cycling_on_bytes(&first_server, 10);
+
+            assert_eq!(results.len(), 3);
+            for result in results {
+                result.unwrap();
+            }
+            let written = first_server.written();
+            assert!(written.ends_with("QUIT\r\n"));
+            assert!(!written.contains("RCPT TO:<b@test.test>"));
+        }
+
+        fn send_over_auth_required_server(authenticated: bool) -> (FakeServer, Result<MailResponse, MailSendError>) {
+            let auth_required_server = FakeServer::new(vec![
+                Reply::Lines("530 5.7.0 Authentication required\r\n"),
+                Reply::Lines("250 Ok\r\n")
+            ]);
+            let addr = spawn_smtp_server();
+            let reconnect: Reconnect<Noop, DefaultTlsSetup> = Box::new(move || con_config(addr));
+            let mut session = Session::new(
+                ConState::Open(QuitOnDrop::new(auth_required_server.connection())),
+                source_from_vec(vec![Ok(mock_envelop(&["a@test.test"]).into())]),
+                SendConfig::default(),
+                Some(reconnect),
+                true
+            );
+            session.authenticated = authenticated;
+
+            let mut results = run(run_session(session).then(|result| Ok::<_, ()>(result)).collect()).unwrap();
+
+            assert_eq!(results.len(), 1);
+            (auth_required_server, results.pop().unwrap())
+        }
+
+        #[test]
+        fn retries_mail_over_new_connection_if_auth_expired() {
+            let (expired_server, result) = send_over_auth_required_server(true);
+
+            result.unwrap();
+            assert!(!expired_server.written().contains("RCPT TO:"));
+        }
+
+        #[test]
+        fn auth_required_over_unauthenticated_connection_is_not_retried() {
+            let (_server, result) = send_over_auth_required_server(false);
+
+            let err = result.unwrap_err();
+            assert!(!err.is_auth_expired());
+            assert_eq!(err.reply_code(), Some(530));
+        }
+
+        #[test]
+        fn fail_remaining_does_not_reconnect() {
+            let closing_server = closing_after_first_mail();
+            let mut config = SendConfig::default();
+            config.service_closing = ServiceClosingPolicy::FailRemaining;
+            let mut results = send_three_mails(&closing_server, config).into_iter();
+
+            results.next().unwrap().unwrap();
+            assert!(results.next().unwrap().unwrap_err().is_service_closing());
+            match results.next().unwrap() {
+                Err(MailSendError::Io(ref err)) => assert_eq!(err.kind(), std_io::ErrorKind::NotConnected),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+}
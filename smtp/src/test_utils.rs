@@ -0,0 +1,246 @@
+//! Helpers for tests which need to talk to a (fake) smtp server.
+use std::{
+    thread,
+    io::{self as std_io, BufRead, BufReader, Read, Write},
+    net::{TcpListener, SocketAddr},
+    collections::VecDeque,
+    sync::{Arc, Mutex}
+};
+
+use futures::{Future, Poll, Async};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    runtime::current_thread::Runtime
+};
+use vec1::Vec1;
+
+use headers::{
+    headers::{_From, _To, Subject},
+    header_components::Domain
+};
+use mail::{Mail, default_impl::simple_context};
+use new_tokio_smtp::{
+    Connection, ConnectionConfig, Io, Socket, Security, ClientId,
+    Domain as SmtpDomain,
+    command::Noop,
+    mock::MockStream,
+    send_mail::{self as smtp, MailAddress, MailEnvelop, EnvelopData, EncodingRequirement}
+};
+
+use ::misc::DefaultTlsSetup;
+
+/// A reply of the fake server.
+#[derive(Debug, Clone)]
+pub(crate) enum Reply {
+    /// Send the given (`\r\n` terminated) lines.
+    Lines(&'static str),
+    /// Never send anything again.
+    Stall
+}
+
+/// A fake server socket replaying a fixed list of replies.
+///
+/// The replies are returned in order, regardless of what the client
+/// writes. Everything the client writes is recorded and can be accessed
+/// through `FakeServer::written`.
+#[derive(Debug, Clone)]
+pub(crate) struct FakeServer {
+    replies: Arc<Mutex<VecDeque<Reply>>>,
+    written: Arc<Mutex<Vec<u8>>>,
+    reads: Arc<Mutex<Vec<usize>>>
+}
+
+impl FakeServer {
+
+    pub(crate) fn new(replies: Vec<Reply>) -> Self {
+        FakeServer {
+            replies: Arc::new(Mutex::new(replies.into())),
+            written: Default::default(),
+            reads: Default::default()
+        }
+    }
+
+    /// Creates a connection which talks to this fake server.
+    ///
+    /// The connection starts after the greeting and EHLO.
+    pub(crate) fn connection(&self) -> Connection {
+        let socket = Socket::Mock(Box::new(self.clone()));
+        Connection::from(Io::from(socket))
+    }
+
+    /// Returns everything written by the client so far.
+    pub(crate) fn written(&self) -> String {
+        let written = self.written.lock().unwrap();
+        String::from_utf8(written.clone()).unwrap()
+    }
+
+    /// Returns how much the client had written when each reply was read.
+    pub(crate) fn read_offsets(&self) -> Vec<usize> {
+        self.reads.lock().unwrap().clone()
+    }
+}
+
+impl Read for FakeServer {
+    fn read(&mut self, buf: &mut [u8]) -> std_io::Result<usize> {
+        let mut replies = self.replies.lock().unwrap();
+        match replies.pop_front() {
+            Some(Reply::Lines(lines)) => {
+                let bytes = lines.as_bytes();
+                assert!(bytes.len() <= buf.len(), "[test bug] reply to long for read buffer");
+                buf[..bytes.len()].copy_from_slice(bytes);
+                self.reads.lock().unwrap().push(self.written.lock().unwrap().len());
+                Ok(bytes.len())
+            },
+            Some(Reply::Stall) => {
+                replies.push_front(Reply::Stall);
+                Err(std_io::ErrorKind::WouldBlock.into())
+            },
+            None => Ok(0)
+        }
+    }
+}
+
+impl Write for FakeServer {
+    fn write(&mut self, buf: &[u8]) -> std_io::Result<usize> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std_io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for FakeServer {}
+
+impl AsyncWrite for FakeServer {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl MockStream for FakeServer {}
+
+/// Creates a simple envelop with one mail address per given recipient.
+pub(crate) fn mock_envelop(recipients: &[&str]) -> MailEnvelop {
+    let to = recipients.iter()
+        .map(|recipient| MailAddress::new_unchecked((*recipient).to_owned(), false))
+        .collect::<Vec<_>>();
+
+    let envelop_data = EnvelopData {
+        from: Some(MailAddress::new_unchecked("sender@test.test".to_owned(), false)),
+        to: Vec1::from_vec(to).expect("[test bug] envelop needs at least one recipient")
+    };
+
+    let mail = smtp::Mail::new(
+        EncodingRequirement::None,
+        b"Subject: test\r\n\r\nsome body\r\n".to_vec()
+    );
+
+    MailEnvelop::from((mail, envelop_data))
+}
+
+/// Creates the context used to encode mails in tests.
+pub(crate) fn test_context() -> simple_context::Context {
+    simple_context::new(Domain::from_unchecked("example.com".to_owned()), "asdkds".parse().unwrap())
+        .expect("[test bug] failed to create context")
+}
+
+/// Creates a plain text mail from `from@example.com` to the given recipient.
+pub(crate) fn simple_mail(to: &str) -> Mail {
+    simple_mail_with_body(to, "body")
+}
+
+/// Like `simple_mail` but with the given text body.
+pub(crate) fn simple_mail_with_body(to: &str, body: &str) -> Mail {
+    let mut mail = Mail::plain_text(body);
+    mail.insert_headers(headers! {
+        _From: ["from@example.com"],
+        _To: [to],
+        Subject: "test"
+    }.expect("[test bug] invalid headers"));
+    mail
+}
+
+/// Creates an unencrypted, unauthenticated connection config for the given address.
+pub(crate) fn con_config(addr: SocketAddr) -> ConnectionConfig<Noop, DefaultTlsSetup> {
+    ConnectionConfig {
+        addr,
+        security: Security::None,
+        auth_cmd: Noop,
+        client_id: ClientId::Domain(SmtpDomain::from_unchecked("me.test".to_owned()))
+    }
+}
+
+/// Starts a TCP server accepting one connection and all mails send over it.
+///
+/// It replies `250` to all commands (`354` to `DATA`) and stops after `QUIT`.
+pub(crate) fn spawn_smtp_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        serve_smtp(stream.try_clone().unwrap(), stream);
+    });
+    addr
+}
+
+/// Starts a TCP server accepting the given number of connections one after another.
+///
+/// Each connection is handled like by `spawn_smtp_server`, the returned handle
+/// resolves to what the client wrote over each connection.
+pub(crate) fn spawn_smtp_server_for(connections: usize) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        (0..connections)
+            .map(|_| {
+                let (stream, _) = listener.accept().unwrap();
+                serve_smtp(stream.try_clone().unwrap(), stream)
+            })
+            .collect()
+    });
+    (addr, handle)
+}
+
+/// Talks smtp over the given stream like the server of `spawn_smtp_server`.
+///
+/// Returns everything the client wrote.
+pub(crate) fn serve_smtp<R, W>(reader: R, mut stream: W) -> String
+    where R: Read, W: Write
+{
+    let mut reader = BufReader::new(reader);
+    let mut written = String::new();
+    stream.write_all(b"220 test.test ready\r\n").unwrap();
+    let mut in_data = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        written.push_str(&line);
+        let reply: &[u8] = if in_data {
+            if line != ".\r\n" { continue; }
+            in_data = false;
+            b"250 Ok: queued\r\n"
+        } else if line.starts_with("DATA") {
+            in_data = true;
+            b"354 Go ahead\r\n"
+        } else if line.starts_with("QUIT") {
+            stream.write_all(b"221 Bye\r\n").unwrap();
+            break;
+        } else {
+            b"250 Ok\r\n"
+        };
+        stream.write_all(reply).unwrap();
+    }
+    written
+}
+
+/// Runs the future to completion on a new current thread runtime.
+pub(crate) fn run<F>(fut: F) -> Result<F::Item, F::Error>
+    where F: Future
+{
+    let mut runtime = Runtime::new().expect("[test bug] failed to create runtime");
+    runtime.block_on(fut)
+}
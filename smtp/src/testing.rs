@@ -0,0 +1,142 @@
+//! Module providing an in-memory transport for testing code using this crate.
+//!
+//! The `MockTransport` mirrors `send` and `send_batch` but instead of
+//! connecting to a server it records every `MailEnvelop` it would have
+//! send. Responses can be injected per mail using `push_response`, if
+//! no response was injected sending the mail succeeds with a `250`.
+//!
+//! This module is only available with the `testing` feature.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate futures;
+//! extern crate mail_core;
+//! extern crate mail_smtp;
+//! #[macro_use] extern crate mail_headers;
+//!
+//! use futures::Future;
+//! use mail_headers::{
+//!     headers::*,
+//!     header_components::Domain
+//! };
+//! use mail_core::{Mail, default_impl::simple_context};
+//! use mail_smtp::testing::MockTransport;
+//!
+//! # fn main() {
+//! let ctx = simple_context::new(Domain::from_unchecked("example.com".to_owned()), "asdkds".parse().unwrap())
+//!     .unwrap();
+//!
+//! let mut mail = Mail::plain_text("Some body");
+//! mail.insert_headers(headers! {
+//!     _From: ["bla@example.com"],
+//!     _To: ["blub@example.com"],
+//!     Subject: "Some Mail"
+//! }.unwrap());
+//!
+//! let transport = MockTransport::new();
+//! transport.send(mail.into(), ctx).wait().unwrap();
+//!
+//! let sent = transport.take_sent_mails();
+//! assert_eq!(sent.len(), 1);
+//! let recipients = sent[0].to_address()
+//!     .iter()
+//!     .map(|address| address.as_str())
+//!     .collect::<Vec<_>>();
+//! assert_eq!(recipients, vec!["blub@example.com"]);
+//! # }
+//! ```
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex}
+};
+
+use futures::{
+    stream::{self, Stream},
+    future::Future
+};
+
+use new_tokio_smtp::send_mail::MailEnvelop;
+use mail::Context;
+
+use ::{
+    error::MailSendError,
+    request::MailRequest,
+    response::MailResponse,
+    send_mail::encode
+};
+
+/// A transport which records mails instead of sending them.
+///
+/// Cloning a `MockTransport` creates a new handle to the same
+/// underlying state, i.e. mails send through a clone are
+/// visible through the original.
+#[derive(Clone, Debug, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    sent: Vec<MailEnvelop>,
+    responses: VecDeque<Result<MailResponse, MailSendError>>
+}
+
+impl MockTransport {
+
+    /// Creates a new `MockTransport` with no recorded mails.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Injects the response returned for the next mail "send" through this transport.
+    ///
+    /// Responses are used in the order they were pushed, one per mail.
+    /// Mails which fail to encode do not consume a response, as they
+    /// would never have reached a server.
+    pub fn push_response(&self, response: Result<MailResponse, MailSendError>) {
+        self.lock().responses.push_back(response);
+    }
+
+    /// Encodes and records the mail, resolving to the next injected response.
+    pub fn send(&self, mail: MailRequest, ctx: impl Context)
+        -> impl Future<Item=MailResponse, Error=MailSendError>
+    {
+        let transport = self.clone();
+        encode(mail, ctx)
+            .and_then(move |envelop| transport.record(envelop))
+    }
+
+    /// Encodes and records all mails, returning one result per mail in order.
+    pub fn send_batch<C>(&self, mails: Vec<MailRequest>, ctx: C)
+        -> impl Stream<Item=MailResponse, Error=MailSendError>
+        where C: Context
+    {
+        let transport = self.clone();
+        let iter = mails.into_iter().map(move |mail| encode(mail, ctx.clone()));
+        stream::futures_ordered(iter)
+            .then(move |res| res.and_then(|envelop| transport.record(envelop)))
+    }
+
+    /// Returns the number of mails recorded so far.
+    pub fn sent_count(&self) -> usize {
+        self.lock().sent.len()
+    }
+
+    /// Removes and returns all recorded mails in the order they were send.
+    pub fn take_sent_mails(&self) -> Vec<MailEnvelop> {
+        let mut state = self.lock();
+        state.sent.drain(..).collect()
+    }
+
+    fn record(&self, envelop: MailEnvelop) -> Result<MailResponse, MailSendError> {
+        let mut state = self.lock();
+        state.sent.push(envelop);
+        state.responses.pop_front()
+            .unwrap_or_else(|| Ok(MailResponse::new(250, vec!["Ok".to_owned()])))
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<MockState> {
+        self.state.lock().expect("[BUG] MockTransport state was poisoned")
+    }
+}
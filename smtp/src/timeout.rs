@@ -0,0 +1,31 @@
+use std::{
+    io as std_io,
+    time::Duration
+};
+
+use futures::future::{Future, Either};
+use tokio_timer::Timeout;
+
+use ::error::{MailSendError, TimeoutPhase};
+
+/// Wraps the future with a timeout if one is given.
+///
+/// If the timeout elapses a `MailSendError::Timeout` with the
+/// given phase is returned.
+pub(crate) fn with_timeout<F>(fut: F, timeout: Option<Duration>, phase: TimeoutPhase)
+    -> impl Future<Item=F::Item, Error=MailSendError>
+    where F: Future, F::Error: Into<MailSendError>
+{
+    match timeout {
+        None => Either::A(fut.map_err(Into::into)),
+        Some(duration) => Either::B(Timeout::new(fut, duration).map_err(move |err| {
+            if err.is_elapsed() {
+                MailSendError::Timeout { phase }
+            } else if let Some(err) = err.into_inner() {
+                err.into()
+            } else {
+                MailSendError::Io(std_io::Error::new(std_io::ErrorKind::Other, "timer failed"))
+            }
+        }))
+    }
+}
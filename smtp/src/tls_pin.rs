@@ -0,0 +1,44 @@
+//! Module implementing pinning `STARTTLS` for hosts which supported it before.
+use std::{
+    fmt,
+    collections::HashSet,
+    sync::Mutex
+};
+
+/// Remembers the hosts with which `STARTTLS` was used successfully.
+///
+/// Set it using `SendConfig::tls_host_cache` together with
+/// `SendConfig::opportunistic_starttls`, which makes connections using
+/// `Security::StartTls` continue unencrypted if the server doesn't announce
+/// `STARTTLS`. To prevent an attacker from stripping `STARTTLS` from the
+/// `EHLO` response, each host with which `STARTTLS` was used is recorded
+/// (pinned) and connections to pinned hosts which don't announce `STARTTLS`
+/// fail with `MailSendError::StartTlsDowngrade` instead of continuing
+/// unencrypted (similar to HSTS for HTTP).
+///
+/// The host is the domain of the `TlsConfig`. The cache is supplied by
+/// the caller, so it can be persisted (e.g. in a database). A simple in
+/// memory cache is implemented for `Mutex<HashSet<String>>`.
+pub trait TlsHostCache: Send + Sync {
+    /// Returns true if `STARTTLS` was used with the host before.
+    fn is_pinned(&self, host: &str) -> bool;
+
+    /// Records that `STARTTLS` was used successfully with the host.
+    fn pin(&self, host: &str);
+}
+
+impl TlsHostCache for Mutex<HashSet<String>> {
+    fn is_pinned(&self, host: &str) -> bool {
+        self.lock().expect("[BUG] tls host cache panicked").contains(host)
+    }
+
+    fn pin(&self, host: &str) {
+        self.lock().expect("[BUG] tls host cache panicked").insert(host.to_owned());
+    }
+}
+
+impl fmt::Debug for TlsHostCache {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("TlsHostCache { .. }")
+    }
+}
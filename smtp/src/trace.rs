@@ -0,0 +1,264 @@
+//! Module containing trace headers added when relaying mails.
+use std::time::SystemTime;
+
+use chrono;
+use headers::header_components::DateTime;
+use mail_internals::{
+    encoder::{EncodingWriter, EncodableInHeader},
+    error::EncodingError
+};
+use new_tokio_smtp::send_mail::MailAddress;
+
+use ::error::InvalidReceivedHeader;
+
+/// A `Received` header documenting a hop of the mail (RFC 5321, section 4.4).
+///
+/// It's formatted as
+///
+/// ```text
+/// Received: from <from>
+///     by <by> [with <protocol>] [id <id>]
+///     [for <recipient>]; <timestamp>
+/// ```
+///
+/// where the timestamp is a RFC 5322 `date-time` in UTC. The header is
+/// folded before each clause if it's too long.
+///
+/// Use `MailRequest::prepend_received` to add it to a mail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedHeader {
+    from: String,
+    by: String,
+    protocol: Option<String>,
+    id: Option<String>,
+    recipient: Option<MailAddress>,
+    timestamp: DateTime
+}
+
+impl ReceivedHeader {
+
+    /// Creates a new header with the sending (`from`) and receiving (`by`) host.
+    ///
+    /// Both are `Extended-Domain`s, i.e. a domain or address literal
+    /// which can be followed by TCP info in a comment, e.g.
+    /// `client.example (client.example [192.0.2.1])`. They must not be empty,
+    /// nor contain control characters (including line breaks) or `;`.
+    pub fn new(from: &str, by: &str, timestamp: SystemTime) -> Result<Self, InvalidReceivedHeader> {
+        if !is_domain_clause(from) {
+            return Err(InvalidReceivedHeader::From(from.to_owned()));
+        }
+        if !is_domain_clause(by) {
+            return Err(InvalidReceivedHeader::By(by.to_owned()));
+        }
+        Ok(ReceivedHeader {
+            from: from.to_owned(),
+            by: by.to_owned(),
+            protocol: None,
+            id: None,
+            recipient: None,
+            timestamp: DateTime::new(chrono::DateTime::<chrono::Utc>::from(timestamp))
+        })
+    }
+
+    /// Sets the protocol used to receive the mail (`with` clause), e.g. `ESMTPS`.
+    ///
+    /// The protocol must be a single word without control characters or `;`.
+    pub fn with_protocol(mut self, protocol: &str) -> Result<Self, InvalidReceivedHeader> {
+        if !is_word_clause(protocol) {
+            return Err(InvalidReceivedHeader::Protocol(protocol.to_owned()));
+        }
+        self.protocol = Some(protocol.to_owned());
+        Ok(self)
+    }
+
+    /// Sets the id the receiving host assigned to the mail (`id` clause).
+    ///
+    /// The id must be a single word without control characters or `;`.
+    pub fn with_id(mut self, id: &str) -> Result<Self, InvalidReceivedHeader> {
+        if !is_word_clause(id) {
+            return Err(InvalidReceivedHeader::Id(id.to_owned()));
+        }
+        self.id = Some(id.to_owned());
+        Ok(self)
+    }
+
+    /// Sets the recipient the mail was received for (`for` clause).
+    pub fn with_recipient(mut self, recipient: MailAddress) -> Self {
+        self.recipient = Some(recipient);
+        self
+    }
+}
+
+impl EncodableInHeader for ReceivedHeader {
+    fn encode(&self, handle: &mut EncodingWriter) -> Result<(), EncodingError> {
+        handle.write_str_unchecked("from ")?;
+        handle.write_str_unchecked(&self.from)?;
+        handle.write_fws();
+        handle.write_str_unchecked("by ")?;
+        handle.write_str_unchecked(&self.by)?;
+        if let Some(protocol) = self.protocol.as_ref() {
+            handle.write_fws();
+            handle.write_str_unchecked("with ")?;
+            handle.write_str_unchecked(protocol)?;
+        }
+        if let Some(id) = self.id.as_ref() {
+            handle.write_fws();
+            handle.write_str_unchecked("id ")?;
+            handle.write_str_unchecked(id)?;
+        }
+        if let Some(recipient) = self.recipient.as_ref() {
+            handle.write_fws();
+            handle.write_str_unchecked("for <")?;
+            handle.write_str_unchecked(recipient.as_str())?;
+            handle.write_str_unchecked(">")?;
+        }
+        handle.write_str_unchecked(";")?;
+        handle.write_fws();
+        self.timestamp.encode(handle)
+    }
+
+    fn boxed_clone(&self) -> Box<EncodableInHeader> {
+        Box::new(self.clone())
+    }
+}
+
+fn is_domain_clause(value: &str) -> bool {
+    !value.trim().is_empty() && value.chars().all(|ch| !ch.is_control() && ch != ';')
+}
+
+fn is_word_clause(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|ch| !ch.is_control() && !ch.is_whitespace() && ch != ';')
+}
+
+/// Counts the headers with the given name in the header section of the encoded mail.
+pub(crate) fn count_headers(raw: &[u8], name: &str) -> usize {
+    raw.split(|bch| *bch == b'\n')
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .filter(|line| {
+            line.len() > name.len()
+                && line[name.len()] == b':'
+                && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+        })
+        .count()
+}
+
+/// Returns the (unfolded) value of the first header with the given name.
+///
+/// Like `count_headers` this only looks at the header section of the mail.
+pub(crate) fn header_value(raw: &[u8], name: &str) -> Option<String> {
+    let mut lines = raw.split(|bch| *bch == b'\n')
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .skip_while(|line| {
+            !(line.len() > name.len()
+                && line[name.len()] == b':'
+                && line[..name.len()].eq_ignore_ascii_case(name.as_bytes()))
+        });
+
+    let first = lines.next()?;
+    let mut value = String::from_utf8_lossy(&first[name.len() + 1..]).trim().to_owned();
+    let continuations = lines.take_while(|line| line.starts_with(b" ") || line.starts_with(b"\t"));
+    for line in continuations {
+        value.push(' ');
+        value.push_str(String::from_utf8_lossy(line).trim());
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+
+    mod header_value {
+        use super::super::header_value;
+
+        #[test]
+        fn returns_the_unfolded_value_of_the_first_header() {
+            let raw = b"Subject: x\r\nmessage-id:\r\n <a@b.test>\r\nMessage-ID: <c@d.test>\r\n\r\nbody\r\n";
+            assert_eq!(header_value(raw, "Message-ID"), Some("<a@b.test>".to_owned()));
+        }
+
+        #[test]
+        fn ignores_the_body() {
+            let raw = b"Subject: x\r\n\r\nMessage-ID: <a@b.test>\r\n";
+            assert_eq!(header_value(raw, "Message-ID"), None);
+        }
+    }
+
+    mod count_headers {
+        use super::super::count_headers;
+
+        #[test]
+        fn counts_headers_case_insensitive() {
+            let raw = b"Received: a\r\n\tby b\r\nreceived: c\r\nSubject: x\r\n\r\nReceived: body\r\n";
+            assert_eq!(count_headers(raw, "Received"), 2);
+            assert_eq!(count_headers(raw, "Auto-Submitted"), 0);
+        }
+
+        #[test]
+        fn ignores_headers_with_the_name_as_prefix() {
+            assert_eq!(count_headers(b"Auto-Submitted-Not: x\r\n\r\n", "Auto-Submitted"), 0);
+        }
+    }
+
+    mod received_header {
+        use std::time::{Duration, UNIX_EPOCH};
+        use mail_internals::{
+            MailType,
+            encoder::{EncodingBuffer, EncodableInHeader}
+        };
+        use new_tokio_smtp::send_mail::MailAddress;
+        use ::error::InvalidReceivedHeader;
+        use super::super::ReceivedHeader;
+
+        /// Encodes the header body, with folding whitespace collapsed to a single space.
+        fn encoded(header: &ReceivedHeader) -> String {
+            let mut buffer = EncodingBuffer::new(MailType::Ascii);
+            {
+                let mut writer = buffer.writer();
+                header.encode(&mut writer).unwrap();
+                writer.commit_partial_header();
+            }
+            let raw: Vec<u8> = buffer.into();
+            String::from_utf8(raw).unwrap().split_whitespace().collect::<Vec<_>>().join(" ")
+        }
+
+        #[test]
+        fn formats_all_clauses() {
+            // 2003-07-01T10:52:37Z
+            let timestamp = UNIX_EPOCH + Duration::from_secs(1_057_056_757);
+            let header = ReceivedHeader::new("client.test (client.test [192.0.2.1])", "relay.test", timestamp)
+                .unwrap()
+                .with_protocol("ESMTPS").unwrap()
+                .with_id("4Ab3x").unwrap()
+                .with_recipient(MailAddress::new_unchecked("to@dest.test".to_owned(), false));
+
+            assert_eq!(encoded(&header), concat!(
+                "from client.test (client.test [192.0.2.1]) by relay.test with ESMTPS id 4Ab3x ",
+                "for <to@dest.test>; Tue, 1 Jul 2003 10:52:37 +0000"
+            ));
+        }
+
+        #[test]
+        fn formats_without_optional_clauses() {
+            let header = ReceivedHeader::new("a.test", "b.test", UNIX_EPOCH).unwrap();
+
+            assert_eq!(encoded(&header), "from a.test by b.test; Thu, 1 Jan 1970 00:00:00 +0000");
+        }
+
+        #[test]
+        fn rejects_values_which_would_break_the_header() {
+            match ReceivedHeader::new("a.test\r\nX-Injected: 1", "b.test", UNIX_EPOCH) {
+                Err(InvalidReceivedHeader::From(_)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            match ReceivedHeader::new("a.test", "b.test; x", UNIX_EPOCH) {
+                Err(InvalidReceivedHeader::By(_)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            let header = ReceivedHeader::new("a.test", "b.test", UNIX_EPOCH).unwrap();
+            match header.with_protocol("ESMTP S") {
+                Err(InvalidReceivedHeader::Protocol(_)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+}
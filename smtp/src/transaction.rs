@@ -0,0 +1,2057 @@
+//! Module implementing a single mail transaction (`MAIL`, `RCPT`, `DATA`).
+//!
+//! This is similar to `Connection::send_mail` from `new-tokio-smtp` but
+//! sends each command on it's own, which allows applying a timeout to
+//! each phase of the transaction.
+use std::{
+    io as std_io,
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+use futures::{
+    stream::{self, Stream},
+    future::{self, Future, Loop, Either}
+};
+
+use new_tokio_smtp::{
+    Cmd, Io, Connection, EhloData, ExecFuture, Response,
+    error::{LogicError, MissingCapabilities},
+    send_mail::{self as smtp, MailAddress, MailEnvelop, EnvelopData, EncodingRequirement}
+};
+
+use ::{
+    align::{DomainAlignment, check_alignment},
+    bounded::read_response,
+    budget::InFlight,
+    config::{SendConfig, Timeouts, ResponseLimits, RecipientPolicy, CommandObserver, RecipientProgress, SmtpCommand},
+    error::{MailSendError, TimeoutPhase, RecipientRejection},
+    observe::{observed, timed, TimingRecorder},
+    params::EsmtpParams,
+    reply::{reply_code, check_response},
+    response::{MailResponse, TransferMode},
+    rewrite::apply_recipient_options,
+    timeout::with_timeout,
+    trace::{count_headers, header_value}
+};
+
+/// Future returned by `send_envelop`.
+///
+/// Errors of the future (I/O and timeouts) mean the connection is broken,
+/// errors which leave the connection usable (e.g. the server rejected a
+/// recipient) are part of the item.
+pub(crate) type TransactionFuture =
+    Box<Future<Item=(Connection, Result<MailResponse, MailSendError>), Error=MailSendError> + Send>;
+
+/// An encoded mail together with the additional parameters used to send it.
+#[derive(Clone)]
+pub(crate) struct OutgoingMail {
+    pub(crate) envelop: MailEnvelop,
+    pub(crate) params: EsmtpParams,
+    /// How long encoding the mail took, if it was encoded by this crate.
+    pub(crate) encode_time: Option<Duration>,
+    /// The size of the mail counted against `SendConfig::max_in_flight_bytes`.
+    pub(crate) in_flight: Option<Arc<InFlight>>
+}
+
+impl OutgoingMail {
+    /// The size of the encoded mail in bytes.
+    pub(crate) fn size(&self) -> usize {
+        self.envelop.mail().raw_data().len()
+    }
+}
+
+impl OutgoingMail {
+    /// Applies the recipient options of the config, see `apply_recipient_options`.
+    ///
+    /// The `X-Original-To` header of a `RedirectPolicy` is added to the mail.
+    fn apply_recipient_options(self, config: &SendConfig) -> Result<Self, MailSendError> {
+        let OutgoingMail { envelop, params, encode_time, in_flight } = self;
+        let (mail, envelop_data): (smtp::Mail, EnvelopData) = envelop.into();
+        let (envelop_data, header) = apply_recipient_options(envelop_data, config)?;
+        let mail = match header {
+            Some(mut raw_data) => {
+                raw_data.extend_from_slice(mail.raw_data());
+                smtp::Mail::new(mail.encoding_requirement(), raw_data)
+            },
+            None => mail
+        };
+        let envelop = MailEnvelop::from((mail, envelop_data));
+        Ok(OutgoingMail { envelop, params, encode_time, in_flight })
+    }
+
+    /// Checks that the reverse path aligns with the `From` header.
+    fn check_alignment(self, alignment: DomainAlignment) -> Result<Self, MailSendError> {
+        let OutgoingMail { envelop, params, encode_time, in_flight } = self;
+        let (mail, envelop_data): (smtp::Mail, EnvelopData) = envelop.into();
+        check_alignment(mail.raw_data(), envelop_data.from.as_ref(), alignment)?;
+        let envelop = MailEnvelop::from((mail, envelop_data));
+        Ok(OutgoingMail { envelop, params, encode_time, in_flight })
+    }
+}
+
+impl From<MailEnvelop> for OutgoingMail {
+    fn from(envelop: MailEnvelop) -> Self {
+        OutgoingMail { envelop, params: Default::default(), encode_time: None, in_flight: None }
+    }
+}
+
+/// A mail body which is produced in chunks while it is send.
+pub(crate) type BodyStream = Box<Stream<Item=Vec<u8>, Error=std_io::Error> + Send>;
+
+/// Sends the mail in the envelop using the given connection.
+///
+/// If any command of the transaction fails `RSET` is send
+/// and the error is returned, leaving the connection usable
+/// for further mails.
+pub(crate) fn send_envelop(con: Connection, envelop: MailEnvelop, timeouts: Timeouts)
+    -> TransactionFuture
+{
+    let transfer_mode = transfer_mode(&con);
+    let limits = ResponseLimits::default();
+    let transaction = Transaction::new(OutgoingMail::from(envelop), transfer_mode, limits);
+    let options = TransactionOptions {
+        timeouts,
+        limits,
+        policy: RecipientPolicy::default(),
+        observer: None,
+        progress: None,
+        recorder: None
+    };
+    send_transaction(con, transaction, options)
+}
+
+/// Sends the mail using the send options of the config.
+///
+/// This applies the `recipient_policy` and `max_recipients_per_transaction`
+/// of the config. The limit of recipients per transaction is the smaller
+/// one of `max_recipients_per_transaction` and the `RCPTMAX` announced by
+/// the server using the `LIMITS` extension (RFC 9422).
+/// If there are more recipients than the limit, one transaction is
+/// used per (at most) limit recipients, all of them sending the same
+/// mail body. The recipient codes of all transactions are aggregated,
+/// the code and lines of the response are the ones of the last transaction.
+///
+/// If a transaction fails, the error is returned and no further transactions
+/// are done. Note that in this case the recipients of the previous transactions
+/// did already receive the mail.
+///
+/// If `record_timings` is enabled the response contains the timings of the mail.
+pub(crate) fn send_envelop_with(con: Connection, mail: OutgoingMail, config: &SendConfig)
+    -> TransactionFuture
+{
+    let recorder = if config.record_timings { Some(TimingRecorder::default()) } else { None };
+    send_envelop_recorded(con, mail, config, recorder)
+}
+
+/// Like `send_envelop_with` but records the timings using the given recorder.
+///
+/// The recorder can already contain timings, e.g. of opening the connection,
+/// all of them are set on the response.
+///
+/// The `Message-ID` of the mail (if it has one) is set on the response, too.
+pub(crate) fn send_envelop_recorded(
+    con: Connection,
+    mail: OutgoingMail,
+    config: &SendConfig,
+    recorder: Option<TimingRecorder>
+) -> TransactionFuture {
+    let message_id = header_value(mail.envelop.mail().raw_data(), "Message-ID");
+    let in_flight = mail.in_flight.clone();
+    if let (Some(recorder), Some(encode_time)) = (recorder.as_ref(), mail.encode_time) {
+        recorder.add(|timings| &mut timings.encode, encode_time);
+    }
+
+    let fut = send_split(con, mail, config, recorder.clone())
+        .map(move |(con, result)| {
+            let result = result.map(|mut response| {
+                if let Some(recorder) = recorder {
+                    response = response.with_timings(recorder.timings());
+                }
+                if let Some(message_id) = message_id {
+                    response = response.with_message_id(message_id);
+                }
+                response
+            });
+            (con, result)
+        });
+
+    Box::new(fut)
+}
+
+/// Sends the mail using as many transactions as needed for the recipient limit.
+///
+/// Mails exceeding `max_received_headers` or not matching the `domain_alignment`
+/// fail without sending anything. The recipient options (e.g. the
+/// `recipient_rewriter`) are applied before the recipients are split.
+fn send_split(con: Connection, mail: OutgoingMail, config: &SendConfig, recorder: Option<TimingRecorder>)
+    -> TransactionFuture
+{
+    match prepare(mail, config) {
+        Ok(mail) => send_prepared(con, mail, config, recorder),
+        Err(err) => Box::new(future::ok((con, Err(err))))
+    }
+}
+
+/// Checks the mail and applies the recipient options of the config, see `send_split`.
+fn prepare(mail: OutgoingMail, config: &SendConfig) -> Result<OutgoingMail, MailSendError> {
+    if let Some(limit) = config.max_received_headers {
+        let received = count_headers(mail.envelop.mail().raw_data(), "Received");
+        if received > limit {
+            return Err(MailSendError::LoopDetected { received, limit });
+        }
+    }
+
+    let mail = match config.domain_alignment {
+        Some(alignment) => mail.check_alignment(alignment)?,
+        None => mail
+    };
+
+    mail.apply_recipient_options(config)
+}
+
+/// Sends the (prepared) mail using as many transactions as needed for the recipient limit.
+fn send_prepared(con: Connection, mail: OutgoingMail, config: &SendConfig, recorder: Option<TimingRecorder>)
+    -> TransactionFuture
+{
+    let options = TransactionOptions {
+        timeouts: config.timeouts,
+        limits: config.response_limits,
+        policy: config.recipient_policy,
+        observer: config.command_observer.clone(),
+        progress: config.recipient_progress.clone(),
+        recorder
+    };
+    let limit = recipient_limit(&con, config);
+
+    let (mail_cmd, mut recipient_cmds, body) = Transaction::parts(mail, transfer_mode(&con), config.response_limits);
+    let limit = match limit {
+        Some(limit) if recipient_cmds.len() > limit => limit.max(1),
+        _ => {
+            let transaction = Transaction { mail_cmd, recipient_cmds, body: Body::Buffered(body) };
+            return send_transaction(con, transaction, options);
+        }
+    };
+
+    let mut chunks = Vec::new();
+    while recipient_cmds.len() > limit {
+        let rest = recipient_cmds.split_off(limit);
+        chunks.push(recipient_cmds);
+        recipient_cmds = rest;
+    }
+    chunks.push(recipient_cmds);
+
+    let init = (con, chunks.into_iter(), Vec::new(), Vec::new());
+    let fut = future::loop_fn(init, move |(con, mut chunks, mut codes, mut rejected)| {
+        let recipient_cmds = chunks.next().expect("[BUG] loop breaks after the last chunk");
+        let is_last = chunks.len() == 0;
+        let transaction = Transaction {
+            mail_cmd: mail_cmd.clone(),
+            recipient_cmds,
+            body: Body::Buffered(body.clone())
+        };
+
+        send_transaction(con, transaction, options.clone())
+            .map(move |(con, result)| match result {
+                Ok(response) => {
+                    codes.extend_from_slice(response.recipient_codes());
+                    rejected.extend_from_slice(response.rejected());
+                    if is_last {
+                        let response = MailResponse::new(response.code(), response.lines().to_owned())
+                            .with_transfer_mode(response.transfer_mode())
+                            .with_recipient_codes(codes)
+                            .with_rejected(rejected);
+                        Loop::Break((con, Ok(response)))
+                    } else {
+                        Loop::Continue((con, chunks, codes, rejected))
+                    }
+                },
+                Err(err) => Loop::Break((con, Err(err)))
+            })
+    });
+
+    Box::new(fut)
+}
+
+/// Sends a mail whose body is streamed to the server while it is produced.
+///
+/// The chunks of the body are dot-stashed and written to the server one
+/// by one, so the body is never buffered as a whole. `SMTPUTF8` is used if
+/// any address of the envelop data needs it.
+///
+/// As the body can only be send once, the transaction is never split, i.e.
+/// `max_recipients_per_transaction` and `RCPTMAX` are not applied. If the body
+/// stream fails after `DATA` was accepted the transaction can not be completed
+/// nor reset, so the error is returned as error of the future (i.e. the
+/// connection is treated as broken).
+pub(crate) fn send_streamed_envelop(
+    con: Connection,
+    envelop_data: EnvelopData,
+    body: BodyStream,
+    config: &SendConfig
+) -> TransactionFuture {
+    let (envelop_data, body) = match apply_recipient_options(envelop_data, config) {
+        Ok((envelop_data, Some(header))) => {
+            let body: BodyStream = Box::new(stream::once(Ok(header)).chain(body));
+            (envelop_data, body)
+        },
+        Ok((envelop_data, None)) => (envelop_data, body),
+        Err(err) => return Box::new(future::ok((con, Err(err))))
+    };
+    let needs_smtputf8 = envelop_data.from.iter()
+        .chain(envelop_data.to.iter())
+        .any(MailAddress::needs_smtputf8);
+    let (mail_cmd, recipient_cmds) = build_cmds(envelop_data, needs_smtputf8, None, &EsmtpParams::default(), config.response_limits);
+    let transaction = Transaction { mail_cmd, recipient_cmds, body: Body::Streamed(body) };
+    let options = TransactionOptions {
+        timeouts: config.timeouts,
+        limits: config.response_limits,
+        policy: config.recipient_policy,
+        observer: config.command_observer.clone(),
+        progress: config.recipient_progress.clone(),
+        recorder: None
+    };
+    send_transaction(con, transaction, options)
+}
+
+/// A mail whose `DATA` was accepted but whose data is only send with the next mail.
+///
+/// See `send_envelop_pipelined`.
+pub(crate) struct PendingMail {
+    body: Vec<u8>,
+    transfer_mode: TransferMode,
+    recipient_codes: Vec<u16>,
+    rejected: Vec<(MailAddress, u16)>,
+    message_id: Option<String>,
+    observer: Option<CommandObserver>,
+    recorder: Option<TimingRecorder>,
+    /// How long it took until `DATA` was accepted.
+    data_start: Duration,
+    /// Keeps the size of the mail in the budget until its body was send.
+    in_flight: Option<Arc<InFlight>>
+}
+
+impl PendingMail {
+    /// Records the upload of the data and the final reply to it.
+    ///
+    /// The data was send at `sent`, flushed at `flushed` and the final
+    /// reply was read at `read`. Like for mails send without pipelining
+    /// the `DATA` command is observed from sending it to the final reply,
+    /// except for the time the mail was pending.
+    fn record_data(&self, sent: Instant, flushed: Instant, read: Instant) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.add(|timings| &mut timings.data_upload, flushed.duration_since(sent));
+            recorder.add(|timings| &mut timings.server_processing, read.duration_since(flushed));
+        }
+        if let Some(observer) = self.observer.as_ref() {
+            observer.record(SmtpCommand::Data, self.data_start + read.duration_since(sent));
+        }
+    }
+
+    /// Turns the result of the final reply into the result of the mail.
+    fn into_result(self, result: Result<Response, LogicError>) -> Result<MailResponse, MailSendError> {
+        let PendingMail { transfer_mode, recipient_codes, rejected, message_id, recorder, .. } = self;
+        result
+            .map(|response| {
+                let mut response = MailResponse::new(reply_code(&response), response.msg().to_owned())
+                    .with_transfer_mode(transfer_mode)
+                    .with_recipient_codes(recipient_codes)
+                    .with_rejected(rejected);
+                if let Some(recorder) = recorder {
+                    response = response.with_timings(recorder.timings());
+                }
+                if let Some(message_id) = message_id {
+                    response = response.with_message_id(message_id);
+                }
+                response
+            })
+            .map_err(MailSendError::from)
+    }
+}
+
+/// The outcome of a mail send using `send_envelop_pipelined`.
+pub(crate) enum Pipelined {
+    /// `DATA` was accepted, the data is send with the next mail.
+    Pending(PendingMail),
+
+    /// The mail is done, e.g. because the server rejected a recipient.
+    Done(Result<MailResponse, MailSendError>)
+}
+
+/// Future returned by `send_envelop_pipelined`.
+///
+/// The item contains the result of the previous mail, if there was one.
+pub(crate) type PipelinedFuture = Box<Future<
+    Item=(Connection, Option<Result<MailResponse, MailSendError>>, Pipelined),
+    Error=MailSendError
+> + Send>;
+
+/// Returns true if mails are send over the connection using `send_envelop_pipelined`.
+///
+/// This is the case if the config allows `pipelining` and the server
+/// announced `PIPELINING`.
+pub(crate) fn can_pipeline(con: &Connection, config: &SendConfig) -> bool {
+    let supported = con.ehlo_data()
+        .map(|ehlo_data| ehlo_data.has_capability("PIPELINING"))
+        .unwrap_or(false);
+    config.allows_pipelining() && supported
+}
+
+/// Sends the mail pipelined (RFC 2920), see `SendConfig::pipelining`.
+///
+/// The data of the previous mail (if there is one) and the `MAIL` and `RCPT`
+/// commands of this mail are send at once, then the final reply to the
+/// previous mail and the replies to this mail are read. If all recipients
+/// are handled according to the `RecipientPolicy`, `DATA` is send. Once it
+/// is accepted the mail is returned as `Pipelined::Pending`, its data is
+/// send with the next mail or using `finish_pipelined`. If the mail fails
+/// before, `RSET` is send and it's returned as `Pipelined::Done`.
+///
+/// Mails which need more than one transaction are send without pipelining
+/// once the previous mail is finished.
+///
+/// Like `send_envelop_recorded` the timings are recorded using the given
+/// recorder, the replies read together are measured as described for
+/// `SendConfig::pipelining`.
+pub(crate) fn send_envelop_pipelined(
+    con: Connection,
+    mail: OutgoingMail,
+    previous: Option<PendingMail>,
+    config: &SendConfig,
+    recorder: Option<TimingRecorder>
+) -> PipelinedFuture {
+    let message_id = header_value(mail.envelop.mail().raw_data(), "Message-ID");
+    if let (Some(recorder), Some(encode_time)) = (recorder.as_ref(), mail.encode_time) {
+        recorder.add(|timings| &mut timings.encode, encode_time);
+    }
+    let mail = match prepare(mail, config) {
+        Ok(mail) => mail,
+        Err(err) => return finish_then(con, previous, config, move |con| Box::new(future::ok((con, Err(err)))))
+    };
+
+    let transfer_mode = transfer_mode(&con);
+    let (envelop_data, needs_smtputf8, body_type, params, body) = split_mail(mail, transfer_mode);
+    if let Err(err) = check_mail_capabilities(&con, needs_smtputf8) {
+        return finish_then(con, previous, config, move |con| Box::new(future::ok((con, Err(err)))));
+    }
+    if let Some(limit) = recipient_limit(&con, config) {
+        if envelop_data.to.len() > limit {
+            let requirement = if needs_smtputf8 { EncodingRequirement::Smtputf8 } else { EncodingRequirement::None };
+            let mail = MailEnvelop::from((smtp::Mail::new(requirement, body), envelop_data));
+            let mail = OutgoingMail { envelop: mail, params, encode_time: None, in_flight: None };
+            let send_config = config.clone();
+            return finish_then(con, previous, config, move |con| {
+                let fut = send_prepared(con, mail, &send_config, recorder.clone())
+                    .map(move |(con, result)| {
+                        let result = result.map(|mut response| {
+                            if let Some(recorder) = recorder {
+                                response = response.with_timings(recorder.timings());
+                            }
+                            if let Some(message_id) = message_id {
+                                response = response.with_message_id(message_id);
+                            }
+                            response
+                        });
+                        (con, result)
+                    });
+                Box::new(fut)
+            });
+        }
+    }
+
+    let (mail_line, recipient_lines) = command_lines(&envelop_data, needs_smtputf8, body_type, &params);
+    let mut lines = vec![mail_line];
+    lines.extend(recipient_lines);
+    let addresses: Vec<_> = envelop_data.to.into_iter().collect();
+
+    let mut previous = previous;
+    let data = previous.as_mut().map(|previous| prepare_data(&mem::replace(&mut previous.body, Vec::new())));
+    let (timeout, phase) = match data {
+        Some(_) => (config.timeouts.data, TimeoutPhase::Data),
+        None => (config.timeouts.command, TimeoutPhase::Command)
+    };
+    let limits = config.response_limits;
+    let replies = Arc::new(Mutex::new(PipelinedReplies::default()));
+    let cmd = PipelinedEnvelope { data, lines, replies: replies.clone(), limits };
+
+    let timeouts = config.timeouts;
+    let policy = config.recipient_policy;
+    let progress = config.recipient_progress.clone();
+    let observer = config.command_observer.clone();
+    let sent = Instant::now();
+    let fut = with_timeout(con.send(cmd), timeout, phase)
+        .and_then(move |(con, result)| {
+            let read = Instant::now();
+            let (flushed, mut responses) = {
+                let mut replies = replies.lock().expect("[BUG] pipelined envelope panicked");
+                (replies.flushed.unwrap_or(sent), mem::replace(&mut replies.responses, Vec::new()))
+            };
+            if let Ok(last) = result {
+                responses.push((last, read));
+            }
+
+            let mut responses = responses.into_iter();
+            let previous = previous.map(|previous| {
+                let (response, read) = responses.next().expect("[BUG] the final reply of the previous mail is read first");
+                previous.record_data(sent, flushed, read);
+                previous.into_result(check_response(response, 2))
+            });
+            let responses = record_envelope(responses, flushed, observer.as_ref(), recorder.as_ref());
+            let closed = previous.as_ref()
+                .and_then(|result| result.as_ref().err())
+                .map(MailSendError::is_service_closing)
+                .unwrap_or(false);
+            if closed {
+                return Either::A(future::ok((con, previous, Pipelined::Done(Err(closed_by_server())))));
+            }
+
+            let fut = match envelope_result(addresses, &mut responses.into_iter(), policy, progress.as_ref()) {
+                Ok((recipient_codes, rejected)) => {
+                    let data_start = Instant::now();
+                    let fut = timed(send_cmd(con, DataStart { limits }, timeouts), recorder.as_ref(), |timings| &mut timings.envelope)
+                        .and_then(move |(con, result)| match result {
+                            Ok(_) => {
+                                let pending = PendingMail {
+                                    body,
+                                    transfer_mode,
+                                    recipient_codes,
+                                    rejected,
+                                    message_id,
+                                    observer,
+                                    recorder,
+                                    data_start: data_start.elapsed(),
+                                    in_flight
+                                };
+                                Either::A(future::ok((con, Pipelined::Pending(pending))))
+                            },
+                            Err(err) => Either::B(reset_unless_closing(con, MailSendError::from(err), timeouts, limits)
+                                .map(|(con, result)| (con, Pipelined::Done(result))))
+                        });
+                    Either::A(fut)
+                },
+                Err(err) => Either::B(reset_unless_closing(con, err, timeouts, limits)
+                    .map(|(con, result)| (con, Pipelined::Done(result))))
+            };
+            Either::B(fut.map(move |(con, outcome)| (con, previous, outcome)))
+        });
+
+    Box::new(fut)
+}
+
+/// Sends the data of the pending mail and returns its result.
+pub(crate) fn finish_pipelined(con: Connection, pending: PendingMail, config: &SendConfig) -> TransactionFuture {
+    let limits = config.response_limits;
+    let mut pending = pending;
+    let body = Body::Buffered(mem::replace(&mut pending.body, Vec::new()));
+    let recorder = pending.recorder.clone();
+    let sent = Instant::now();
+    let fut = with_timeout(con.send(DataBody { body, recorder, limits }), config.timeouts.data, TimeoutPhase::Data)
+        .map(move |(con, result)| {
+            if let Some(observer) = pending.observer.as_ref() {
+                observer.record(SmtpCommand::Data, pending.data_start + sent.elapsed());
+            }
+            (con, pending.into_result(result))
+        });
+
+    Box::new(fut)
+}
+
+/// Finishes the previous mail (if there is one) and then sends a mail using `send`.
+fn finish_then<F>(con: Connection, previous: Option<PendingMail>, config: &SendConfig, send: F) -> PipelinedFuture
+    where F: FnOnce(Connection) -> TransactionFuture + Send + 'static
+{
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return Box::new(send(con).map(|(con, result)| (con, None, Pipelined::Done(result))))
+    };
+
+    let fut = finish_pipelined(con, previous, config)
+        .and_then(move |(con, previous)| {
+            let closed = previous.as_ref().err().map(MailSendError::is_service_closing).unwrap_or(false);
+            if closed {
+                Either::A(future::ok((con, Some(previous), Pipelined::Done(Err(closed_by_server())))))
+            } else {
+                Either::B(send(con).map(move |(con, result)| (con, Some(previous), Pipelined::Done(result))))
+            }
+        });
+
+    Box::new(fut)
+}
+
+/// Reports the replies to the pipelined `MAIL` and `RCPT` commands to the observer and recorder.
+///
+/// The `MAIL` command is measured from flushing all commands, each `RCPT`
+/// command from the reply to the previous command. The envelope phase
+/// covers all of them.
+fn record_envelope<I>(
+    replies: I,
+    flushed: Instant,
+    observer: Option<&CommandObserver>,
+    recorder: Option<&TimingRecorder>
+) -> Vec<Response>
+    where I: Iterator<Item=(Response, Instant)>
+{
+    let mut previous = flushed;
+    let responses = replies
+        .enumerate()
+        .map(|(index, (response, read))| {
+            if let Some(observer) = observer {
+                let command = if index == 0 { SmtpCommand::Mail } else { SmtpCommand::Rcpt };
+                observer.record(command, read.duration_since(previous));
+            }
+            previous = read;
+            response
+        })
+        .collect();
+    if let Some(recorder) = recorder {
+        recorder.add(|timings| &mut timings.envelope, previous.duration_since(flushed));
+    }
+    responses
+}
+
+/// Evaluates the replies to the pipelined `MAIL` and `RCPT` commands.
+///
+/// Returns the recipient codes and rejected recipients like `send_recipients`.
+fn envelope_result<I>(
+    addresses: Vec<MailAddress>,
+    responses: &mut I,
+    policy: RecipientPolicy,
+    progress: Option<&RecipientProgress>
+) -> Result<(Vec<u16>, Vec<(MailAddress, u16)>), MailSendError>
+    where I: Iterator<Item=Response>
+{
+    let response = next_reply(responses)?;
+    check_response(response, 2)?;
+
+    let mut state = RecipientsState::new(addresses.len());
+    for address in addresses {
+        let response = next_reply(responses)?;
+        state.on_result(&address, check_response(response, 2), policy, progress)?;
+    }
+    state.finish()
+}
+
+/// Returns the next reply, failing if the server closes the connection (`421`).
+///
+/// `PipelinedEnvelope` stops reading after a `421`, so there are
+/// replies for all commands unless the server is closing.
+fn next_reply<I>(responses: &mut I) -> Result<Response, MailSendError>
+    where I: Iterator<Item=Response>
+{
+    let response = responses.next().expect("[BUG] replies are read up to the first 421");
+    if reply_code(&response) == 421 {
+        Err(MailSendError::from(LogicError::Code(response)))
+    } else {
+        Ok(response)
+    }
+}
+
+/// The error of mails whose commands were not answered as the server closed the connection.
+fn closed_by_server() -> MailSendError {
+    MailSendError::Io(std_io::Error::new(
+        std_io::ErrorKind::NotConnected,
+        "server closed the connection while the mail was send"
+    ))
+}
+
+/// Checks if the server supports the pipelined `MAIL` command, like the `LineCmd` of `build_cmds` does.
+///
+/// The command lines are written as is, so without this a mail needing
+/// `SMTPUTF8` would be send to a server not supporting it.
+fn check_mail_capabilities(con: &Connection, needs_smtputf8: bool) -> Result<(), MailSendError> {
+    let has_smtputf8 = con.ehlo_data()
+        .map(|ehlo_data| ehlo_data.has_capability("SMTPUTF8"))
+        .unwrap_or(false);
+
+    if needs_smtputf8 && !has_smtputf8 {
+        let missing = MissingCapabilities::new_from_str_unchecked("SMTPUTF8");
+        Err(MailSendError::from(LogicError::MissingCapabilities(missing)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the `MAIL` and `RCPT` command lines.
+fn command_lines(
+    envelop_data: &EnvelopData,
+    needs_smtputf8: bool,
+    body_type: Option<&str>,
+    params: &EsmtpParams
+) -> (String, Vec<String>) {
+    let mut mail_params = Vec::new();
+    if let Some(body_type) = body_type {
+        mail_params.push(("BODY".to_owned(), Some(body_type.to_owned())));
+    }
+    if needs_smtputf8 {
+        mail_params.push(("SMTPUTF8".to_owned(), None));
+    }
+    mail_params.extend(params.mail_params());
+
+    let reverse_path = envelop_data.from.as_ref().map(MailAddress::as_str).unwrap_or("");
+    let mail_line = format!("MAIL FROM:<{}>{}", reverse_path, format_params(mail_params));
+    let rcpt_params = format_params(params.rcpt_params());
+    let recipient_lines = envelop_data.to.iter()
+        .map(|address| format!("RCPT TO:<{}>{}", address.as_str(), rcpt_params))
+        .collect();
+
+    (mail_line, recipient_lines)
+}
+
+/// Formats the parameters (each with a leading space), later ones override earlier ones with the same keyword.
+fn format_params(params: Vec<(String, Option<String>)>) -> String {
+    let mut unique: Vec<(String, Option<String>)> = Vec::new();
+    for (keyword, value) in params {
+        unique.retain(|&(ref existing, _)| !existing.eq_ignore_ascii_case(&keyword));
+        unique.push((keyword, value));
+    }
+
+    let mut out = String::new();
+    for (keyword, value) in unique {
+        out.push(' ');
+        out.push_str(&keyword);
+        if let Some(value) = value {
+            out.push('=');
+            out.push_str(&value);
+        }
+    }
+    out
+}
+
+/// Returns the transfer mode for mail bodies send over the connection.
+fn transfer_mode(con: &Connection) -> TransferMode {
+    let eight_bit = con.ehlo_data()
+        .map(|ehlo_data| ehlo_data.has_capability("8BITMIME"))
+        .unwrap_or(false);
+
+    if eight_bit {
+        TransferMode::EightBit
+    } else {
+        TransferMode::SevenBit
+    }
+}
+
+/// Returns the limit of recipients per transaction, see `send_envelop_with`.
+fn recipient_limit(con: &Connection, config: &SendConfig) -> Option<usize> {
+    match (config.max_recipients_per_transaction, server_rcpt_max(con)) {
+        (Some(configured), Some(announced)) => Some(configured.min(announced)),
+        (configured, announced) => configured.or(announced)
+    }
+}
+
+/// Returns the `RCPTMAX` limit announced by the server, if there is any.
+fn server_rcpt_max(con: &Connection) -> Option<usize> {
+    let params = con.ehlo_data()?.get_capability_params("LIMITS")?;
+    rcpt_max_from_limits(params.iter().map(|param| param.as_str()))
+}
+
+/// Parses the `RCPTMAX=<n>` parameter of the `LIMITS` ehlo keyword.
+fn rcpt_max_from_limits<'a, I>(params: I) -> Option<usize>
+    where I: IntoIterator<Item=&'a str>
+{
+    params.into_iter()
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next()?;
+            let value = parts.next()?;
+            if name.eq_ignore_ascii_case("RCPTMAX") {
+                value.parse().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// The options used for a single transaction.
+#[derive(Clone)]
+struct TransactionOptions {
+    timeouts: Timeouts,
+    limits: ResponseLimits,
+    policy: RecipientPolicy,
+    observer: Option<CommandObserver>,
+    progress: Option<RecipientProgress>,
+    recorder: Option<TimingRecorder>
+}
+
+fn send_transaction(con: Connection, transaction: Transaction, options: TransactionOptions)
+    -> TransactionFuture
+{
+    let Transaction { mail_cmd, recipient_cmds, body } = transaction;
+    let TransactionOptions { timeouts, limits, policy, observer, progress, recorder } = options;
+    let rcpt_observer = observer.clone();
+    let transfer_mode = transfer_mode(&con);
+
+    let envelope = observed(send_cmd(con, mail_cmd, timeouts), observer.as_ref(), SmtpCommand::Mail)
+        .and_then(move |(con, result)| match result {
+            Ok(_) => {
+                let fut = send_recipients(con, recipient_cmds, timeouts, policy, rcpt_observer, progress);
+                Either::A(fut)
+            },
+            Err(err) => Either::B(future::ok((con, Err(MailSendError::from(err)))))
+        });
+
+    let fut = timed(envelope, recorder.as_ref(), |timings| &mut timings.envelope)
+        .and_then(move |(con, result)| match result {
+            Ok((recipient_codes, rejected)) => {
+                let fut = send_data(con, body, timeouts, limits, recorder);
+                let fut = observed(fut, observer.as_ref(), SmtpCommand::Data)
+                    .map(move |(con, result)| {
+                        let result = result
+                            .map(|response| {
+                                MailResponse::new(reply_code(&response), response.msg().to_owned())
+                                    .with_transfer_mode(transfer_mode)
+                                    .with_recipient_codes(recipient_codes)
+                                    .with_rejected(rejected)
+                            })
+                            .map_err(MailSendError::from);
+                        (con, result)
+                    });
+                Either::A(fut)
+            },
+            Err(err) => Either::B(future::ok((con, Err(err))))
+        })
+        .and_then(move |(con, result)| match result {
+            Ok(response) => Either::A(future::ok((con, Ok(response)))),
+            // the server closes the connection after a 421, so there is nothing to reset
+            Err(err) => Either::B(reset_unless_closing(con, err, timeouts, limits))
+        });
+
+    Box::new(fut)
+}
+
+struct Transaction {
+    mail_cmd: LineCmd,
+    recipient_cmds: Vec<(MailAddress, LineCmd)>,
+    body: Body
+}
+
+enum Body {
+    Buffered(Vec<u8>),
+    Streamed(BodyStream)
+}
+
+impl Transaction {
+    fn new(mail: OutgoingMail, transfer_mode: TransferMode, limits: ResponseLimits) -> Self {
+        let (mail_cmd, recipient_cmds, body) = Transaction::parts(mail, transfer_mode, limits);
+        Transaction { mail_cmd, recipient_cmds, body: Body::Buffered(body) }
+    }
+
+    /// Returns the `MAIL` command, the `RCPT` commands and the body of the mail.
+    ///
+    /// The transfer mode is the one of the connection the mail is send over.
+    fn parts(mail: OutgoingMail, transfer_mode: TransferMode, limits: ResponseLimits)
+        -> (LineCmd, Vec<(MailAddress, LineCmd)>, Vec<u8>)
+    {
+        let (envelop_data, needs_smtputf8, body_type, params, body) = split_mail(mail, transfer_mode);
+        let (mail_cmd, recipient_cmds) = build_cmds(envelop_data, needs_smtputf8, body_type, &params, limits);
+        (mail_cmd, recipient_cmds, body)
+    }
+}
+
+/// Splits the mail into its envelop data, if `SMTPUTF8` is needed, the `BODY` parameter, params and body.
+fn split_mail(mail: OutgoingMail, transfer_mode: TransferMode)
+    -> (EnvelopData, bool, Option<&'static str>, EsmtpParams, Vec<u8>)
+{
+    let OutgoingMail { envelop, params, .. } = mail;
+    let needs_smtputf8 = envelop.needs_smtputf8();
+    let (mail, envelop_data): (smtp::Mail, EnvelopData) = envelop.into();
+
+    let needs_smtputf8 = needs_smtputf8 || mail.encoding_requirement() == EncodingRequirement::Smtputf8;
+    let body_type = body_type(mail.raw_data(), transfer_mode);
+    (envelop_data, needs_smtputf8, body_type, params, mail.raw_data().to_owned())
+}
+
+/// Returns the value of the `BODY` parameter (RFC 6152) of the `MAIL` command.
+///
+/// `BODY=8BITMIME` is only send if the mail data contains 8bit bytes and the
+/// server announced `8BITMIME`. Otherwise the parameter is omitted, which
+/// means `7BIT`, so mails with 8bit data are send as before to servers not
+/// supporting it. `BINARYMIME` (RFC 3030) is never used, as it requires
+/// sending the data using `BDAT` (`CHUNKING`) but this crate uses `DATA`.
+fn body_type(raw_data: &[u8], transfer_mode: TransferMode) -> Option<&'static str> {
+    let is_8bit = raw_data.iter().any(|&bch| bch >= 0x80);
+    if is_8bit && transfer_mode == TransferMode::EightBit {
+        Some("8BITMIME")
+    } else {
+        None
+    }
+}
+
+/// Builds the `MAIL` and `RCPT` commands, reading their responses within the limits.
+fn build_cmds(
+    envelop_data: EnvelopData,
+    needs_smtputf8: bool,
+    body_type: Option<&str>,
+    params: &EsmtpParams,
+    limits: ResponseLimits
+) -> (LineCmd, Vec<(MailAddress, LineCmd)>) {
+    let (mail_line, recipient_lines) = command_lines(&envelop_data, needs_smtputf8, body_type, params);
+    let requires = if needs_smtputf8 { Some("SMTPUTF8") } else { None };
+    let mail_cmd = LineCmd { line: mail_line, requires, limits };
+
+    let recipient_cmds = envelop_data.to
+        .into_iter()
+        .zip(recipient_lines)
+        .map(|(address, line)| (address, LineCmd { line, requires: None, limits }))
+        .collect();
+
+    (mail_cmd, recipient_cmds)
+}
+
+/// Sends the command with the command timeout.
+///
+/// A response exceeding the `ResponseLimits` fails the future, i.e.
+/// the connection is not used anymore.
+fn send_cmd<C>(con: Connection, cmd: C, timeouts: Timeouts)
+    -> impl Future<Item=(Connection, Result<Response, LogicError>), Error=MailSendError>
+    where C: Cmd
+{
+    with_timeout(con.send(cmd), timeouts.command, TimeoutPhase::Command)
+}
+
+/// Sends all `RCPT` commands returning the reply codes for them
+/// and the addresses and codes of the rejected recipients.
+///
+/// How rejected recipients are handled depends on the `RecipientPolicy`.
+fn send_recipients(
+    con: Connection,
+    cmds: Vec<(MailAddress, LineCmd)>,
+    timeouts: Timeouts,
+    policy: RecipientPolicy,
+    observer: Option<CommandObserver>,
+    progress: Option<RecipientProgress>
+) -> impl Future<Item=(Connection, Result<(Vec<u16>, Vec<(MailAddress, u16)>), MailSendError>), Error=MailSendError> {
+    let state = RecipientsState::new(cmds.len());
+
+    future::loop_fn((con, cmds.into_iter(), state), move |(con, mut cmds, mut state)| {
+        let (address, cmd) = match cmds.next() {
+            Some(next) => next,
+            None => return Either::A(future::ok(Loop::Break((con, state.finish()))))
+        };
+
+        let fut = observed(send_cmd(con, cmd, timeouts), observer.as_ref(), SmtpCommand::Rcpt);
+        let progress = progress.clone();
+        Either::B(fut.map(move |(con, result)| {
+            match state.on_result(&address, result, policy, progress.as_ref()) {
+                Ok(()) => Loop::Continue((con, cmds, state)),
+                Err(err) => Loop::Break((con, Err(err)))
+            }
+        }))
+    })
+}
+
+struct RecipientsState {
+    codes: Vec<u16>,
+    rejected: Vec<(MailAddress, u16)>,
+    any_accepted: bool,
+    /// The `MailSendError::RecipientRejected` for the first rejected recipient.
+    first_rejection: Option<MailSendError>
+}
+
+impl RecipientsState {
+    fn new(recipients: usize) -> Self {
+        RecipientsState {
+            codes: Vec::with_capacity(recipients),
+            rejected: Vec::new(),
+            any_accepted: false,
+            first_rejection: None
+        }
+    }
+
+    /// Handles the result of the `RCPT` command for the address.
+    ///
+    /// Returns an error if the mail fails and no further recipients
+    /// should be send, which depends on the `RecipientPolicy`.
+    fn on_result(
+        &mut self,
+        address: &MailAddress,
+        result: Result<Response, LogicError>,
+        policy: RecipientPolicy,
+        progress: Option<&RecipientProgress>
+    ) -> Result<(), MailSendError> {
+        let report = |outcome: Result<(), &MailSendError>| if let Some(progress) = progress {
+            progress.report(address, outcome);
+        };
+
+        let response = match result {
+            Ok(response) => {
+                report(Ok(()));
+                self.codes.push(reply_code(&response));
+                self.any_accepted = true;
+                return Ok(());
+            },
+            Err(LogicError::Code(response)) => response,
+            Err(err) => {
+                let err = MailSendError::from(err);
+                report(Err(&err));
+                return Err(err);
+            }
+        };
+
+        let code = reply_code(&response);
+        let is_first = self.codes.is_empty();
+        if policy == RecipientPolicy::RequireAll {
+            let err = MailSendError::from(LogicError::Code(response));
+            report(Err(&err));
+            return Err(err);
+        }
+
+        let err = MailSendError::RecipientRejected(RecipientRejection::new(address.clone(), response));
+        report(Err(&err));
+        if policy == RecipientPolicy::FailFastOnFirstRecipient && is_first && code / 100 == 5 {
+            return Err(err);
+        }
+
+        self.codes.push(code);
+        self.rejected.push((address.clone(), code));
+        if self.first_rejection.is_none() {
+            self.first_rejection = Some(err);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(Vec<u16>, Vec<(MailAddress, u16)>), MailSendError> {
+        if self.any_accepted {
+            return Ok((self.codes, self.rejected));
+        }
+        let rejection = self.first_rejection
+            .expect("[BUG] transactions have at least one recipient");
+        Err(rejection)
+    }
+}
+
+/// Sends `DATA` and then the body.
+///
+/// Waiting for the `354` response is recorded as part of the envelope.
+fn send_data(
+    con: Connection,
+    body: Body,
+    timeouts: Timeouts,
+    limits: ResponseLimits,
+    recorder: Option<TimingRecorder>
+) -> impl Future<Item=(Connection, Result<Response, LogicError>), Error=MailSendError> {
+    let start = send_cmd(con, DataStart { limits }, timeouts);
+    timed(start, recorder.as_ref(), |timings| &mut timings.envelope)
+        .and_then(move |(con, result)| match result {
+            Ok(_) => {
+                let fut = with_timeout(con.send(DataBody { body, recorder, limits }), timeouts.data, TimeoutPhase::Data);
+                Either::A(fut)
+            },
+            Err(err) => Either::B(future::ok((con, Err(err))))
+        })
+}
+
+/// Like `reset` but doesn't send `RSET` if the server is closing the connection (`421`).
+fn reset_unless_closing(con: Connection, err: MailSendError, timeouts: Timeouts, limits: ResponseLimits)
+    -> impl Future<Item=(Connection, Result<MailResponse, MailSendError>), Error=MailSendError>
+{
+    if err.is_service_closing() {
+        Either::A(future::ok((con, Err(err))))
+    } else {
+        Either::B(reset(con, err, timeouts, limits))
+    }
+}
+
+fn reset(con: Connection, err: MailSendError, timeouts: Timeouts, limits: ResponseLimits)
+    -> impl Future<Item=(Connection, Result<MailResponse, MailSendError>), Error=MailSendError>
+{
+    // the result of RSET doesn't matter, if the connection
+    // is broken the next command will fail anyway
+    let cmd = LineCmd { line: "RSET".to_owned(), requires: None, limits };
+    send_cmd(con, cmd, timeouts)
+        .map(move |(con, _)| (con, Err(err)))
+}
+
+/// Sends a single command line expecting a `2xx` response.
+///
+/// This is used for `MAIL`, `RCPT` and `RSET` instead of the commands of
+/// `new-tokio-smtp`, so that the response is read within the limits.
+#[derive(Clone)]
+struct LineCmd {
+    line: String,
+    /// The capability the server has to announce for the command, e.g. `SMTPUTF8`.
+    requires: Option<&'static str>,
+    limits: ResponseLimits
+}
+
+impl Cmd for LineCmd {
+    fn check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        match self.requires {
+            Some(capability) if !caps.map(|caps| caps.has_capability(capability)).unwrap_or(false) =>
+                Err(MissingCapabilities::new_from_str_unchecked(capability)),
+            _ => Ok(())
+        }
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        let LineCmd { line, limits, .. } = self;
+        io.write_line_from_parts(&[line.as_str()]);
+        let fut = io.flush()
+            .and_then(move |io| read_response(io, limits))
+            .map(|(io, response)| (io, check_response(response, 2)));
+
+        Box::new(fut)
+    }
+}
+
+/// Sends `DATA` expecting the intermediate `354` response.
+struct DataStart {
+    limits: ResponseLimits
+}
+
+impl Cmd for DataStart {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        let limits = self.limits;
+        io.write_line_from_parts(&["DATA"]);
+        let fut = io.flush()
+            .and_then(move |io| read_response(io, limits))
+            .map(|(io, response)| (io, check_response(response, 3)));
+
+        Box::new(fut)
+    }
+}
+
+/// Sends the mail body followed by the terminating `.`.
+///
+/// The body is normalized and dot-stashed using a `DotStasher`
+/// and then written as is, so it's never dot-stashed twice.
+/// A streamed body is written (and flushed) chunk by chunk.
+struct DataBody {
+    body: Body,
+    recorder: Option<TimingRecorder>,
+    limits: ResponseLimits
+}
+
+impl Cmd for DataBody {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        let DataBody { body, recorder, limits } = self;
+        let upload_start = Instant::now();
+        let body = match body {
+            Body::Buffered(body) => {
+                let data = prepare_data(&body);
+                io.out_buffer(data.len()).extend_from_slice(&data);
+                return Box::new(finish_data(io.flush(), recorder, upload_start, limits));
+            },
+            Body::Streamed(body) => body
+        };
+
+        let fut = future::loop_fn((io, body, DotStasher::new()), move |(mut io, body, mut stasher)| {
+            let recorder = recorder.clone();
+            body.into_future()
+                .map_err(|(err, _body)| err)
+                .and_then(move |(chunk, body)| {
+                    let mut data = Vec::new();
+                    match chunk {
+                        Some(chunk) => {
+                            stasher.push(&chunk, &mut data);
+                            io.out_buffer(data.len()).extend_from_slice(&data);
+                            Either::A(io.flush().map(move |io| Loop::Continue((io, body, stasher))))
+                        },
+                        None => {
+                            stasher.finish(&mut data);
+                            io.out_buffer(data.len()).extend_from_slice(&data);
+                            let fut = finish_data(io.flush(), recorder, upload_start, limits)
+                                .map(Loop::Break);
+                            Either::B(fut)
+                        }
+                    }
+                })
+        });
+
+        Box::new(fut)
+    }
+}
+
+/// Sends `MAIL` and all `RCPT` commands at once (RFC 2920) and reads all replies.
+///
+/// If there is the (prepared) data of a previous mail it's send first and
+/// its final reply is read first. Reading stops after a `421` reply, as the
+/// server closes the connection afterwards. All replies except the last one
+/// are put into `replies`, the last one is returned.
+struct PipelinedEnvelope {
+    data: Option<Vec<u8>>,
+    lines: Vec<String>,
+    replies: Arc<Mutex<PipelinedReplies>>,
+    limits: ResponseLimits
+}
+
+/// The replies read by `PipelinedEnvelope` and when they were read.
+#[derive(Default)]
+struct PipelinedReplies {
+    /// When the data and commands were flushed.
+    flushed: Option<Instant>,
+    responses: Vec<(Response, Instant)>
+}
+
+impl Cmd for PipelinedEnvelope {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        let PipelinedEnvelope { data, lines, replies, limits } = self;
+        let expected = lines.len() + if data.is_some() { 1 } else { 0 };
+        if let Some(data) = data {
+            io.out_buffer(data.len()).extend_from_slice(&data);
+        }
+        for line in &lines {
+            io.write_line_from_parts(&[line.as_str()]);
+        }
+
+        let fut = io.flush()
+            .and_then(move |io| {
+                replies.lock().expect("[BUG] pipelined envelope panicked").flushed = Some(Instant::now());
+                future::loop_fn((io, 1), move |(io, count)| {
+                    let replies = replies.clone();
+                    read_response(io, limits).map(move |(io, response)| {
+                        if count == expected || reply_code(&response) == 421 {
+                            Loop::Break((io, Ok(response)))
+                        } else {
+                            let read = Instant::now();
+                            replies.lock().expect("[BUG] pipelined envelope panicked").responses.push((response, read));
+                            Loop::Continue((io, count + 1))
+                        }
+                    })
+                })
+            });
+
+        Box::new(fut)
+    }
+}
+
+/// Waits for the final response once the body is flushed.
+///
+/// Records the time until the body is flushed as upload and
+/// the time until the final response as server processing.
+fn finish_data<F>(flushed: F, recorder: Option<TimingRecorder>, upload_start: Instant, limits: ResponseLimits)
+    -> impl Future<Item=(Io, Result<Response, LogicError>), Error=std_io::Error>
+    where F: Future<Item=Io, Error=std_io::Error>
+{
+    let processing_recorder = recorder.clone();
+    flushed
+        .map(move |io| {
+            if let Some(recorder) = recorder {
+                recorder.add(|timings| &mut timings.data_upload, upload_start.elapsed());
+            }
+            io
+        })
+        .and_then(move |io| timed(read_response(io, limits), processing_recorder.as_ref(), |timings| &mut timings.server_processing))
+        .map(|(io, response)| (io, check_response(response, 2)))
+}
+
+/// Turns the mail body into the data send after `DATA` (RFC 5321 4.5.2).
+///
+/// See `DotStasher` for the changes done to the body.
+fn prepare_data(body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(body.len() + body.len() / 64 + 5);
+    let mut stasher = DotStasher::new();
+    stasher.push(body, &mut data);
+    stasher.finish(&mut data);
+    data
+}
+
+/// Normalizes and dot-stashes a body which can be split into arbitrary chunks.
+///
+/// - Bare `\n` line endings are turned into `\r\n`.
+/// - Lines starting with `.` get an additional `.` prepended (dot-stashing).
+/// - A final `\r\n` is added if the body doesn't end with one.
+/// - The terminating `.\r\n` is appended.
+struct DotStasher {
+    at_line_start: bool,
+    last: Option<u8>
+}
+
+impl DotStasher {
+    fn new() -> Self {
+        DotStasher { at_line_start: true, last: None }
+    }
+
+    fn push(&mut self, chunk: &[u8], data: &mut Vec<u8>) {
+        data.reserve(chunk.len() + chunk.len() / 64);
+        for &bch in chunk {
+            if self.at_line_start && bch == b'.' {
+                data.push(b'.');
+            }
+            if bch == b'\n' && self.last != Some(b'\r') {
+                data.push(b'\r');
+            }
+            data.push(bch);
+            self.at_line_start = bch == b'\n';
+            self.last = Some(bch);
+        }
+    }
+
+    fn finish(self, data: &mut Vec<u8>) {
+        if !self.at_line_start {
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(b".\r\n");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration
+    };
+
+    use futures::{Future, stream};
+    use vec1::Vec1;
+    use new_tokio_smtp::{
+        ClientId, Domain,
+        command::Ehlo,
+        error::LogicError,
+        send_mail::{self as smtp, MailAddress, MailEnvelop, EnvelopData, EncodingRequirement}
+    };
+
+    use ::{
+        align::DomainAlignment,
+        config::{SendConfig, Timeouts, RecipientPolicy, CommandObserver, RecipientProgress, SmtpCommand},
+        error::{MailSendError, TimeoutPhase},
+        params::{EsmtpParams, EsmtpParam},
+        response::TransferMode,
+        rewrite::RedirectPolicy,
+        test_utils::{FakeServer, Reply, mock_envelop, run}
+    };
+    use super::{
+        OutgoingMail, Pipelined, send_envelop, send_envelop_with, send_envelop_pipelined, send_streamed_envelop,
+        prepare_data, rcpt_max_from_limits
+    };
+
+    fn short_timeouts() -> Timeouts {
+        Timeouts {
+            connect: None,
+            greeting: None,
+            command: Some(Duration::from_millis(50)),
+            data: Some(Duration::from_millis(50))
+        }
+    }
+
+    fn config_with(update: impl FnOnce(&mut SendConfig)) -> SendConfig {
+        let mut config = SendConfig::default();
+        config.timeouts = short_timeouts();
+        update(&mut config);
+        config
+    }
+
+    fn assert_timeout_in_phase(replies: Vec<Reply>, expected: TimeoutPhase) {
+        let server = FakeServer::new(replies);
+        let fut = send_envelop(server.connection(), mock_envelop(&["a@test.test"]), short_timeouts());
+
+        match run(fut) {
+            Err(MailSendError::Timeout { phase }) => assert_eq!(phase, expected),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("expected sending to time out")
+        }
+    }
+
+    #[test]
+    fn prepare_data_converts_bare_lf_and_dot_stashes() {
+        let data = prepare_data(b"Subject: x\n\nbody\n.\n..two\nend");
+        assert_eq!(data, b"Subject: x\r\n\r\nbody\r\n..\r\n...two\r\nend\r\n.\r\n".to_vec());
+    }
+
+    #[test]
+    fn prepare_data_keeps_crlf_and_does_not_double_stash() {
+        let data = prepare_data(b"a\r\n.b\r\n");
+        assert_eq!(data, b"a\r\n..b\r\n.\r\n".to_vec());
+    }
+
+    #[test]
+    fn prepare_data_stashes_dot_at_body_start() {
+        let data = prepare_data(b".\r\n");
+        assert_eq!(data, b"..\r\n.\r\n".to_vec());
+    }
+
+    #[test]
+    fn sends_mail_transaction() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let fut = send_envelop(server.connection(), mock_envelop(&["a@test.test"]), short_timeouts());
+
+        let (_con, result) = run(fut).unwrap();
+        let response = result.unwrap();
+        assert_eq!(response.code(), 250);
+        assert_eq!(response.recipient_codes(), &[250]);
+
+        let written = server.written();
+        assert!(written.starts_with("MAIL FROM:<sender@test.test>\r\nRCPT TO:<a@test.test>\r\nDATA\r\n"));
+        assert!(written.ends_with("some body\r\n.\r\n"));
+    }
+
+    #[test]
+    fn streams_chunked_body_with_dot_stashing() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let envelop_data = EnvelopData {
+            from: Some(MailAddress::new_unchecked("sender@test.test".to_owned(), false)),
+            to: Vec1::new(MailAddress::new_unchecked("a@test.test".to_owned(), false))
+        };
+        // line breaks and line starting dots are split across chunks
+        let chunks = vec![
+            b"Subject: test\r\n\r\n.first\r".to_vec(),
+            b"\n".to_vec(),
+            b".second\n".to_vec(),
+            b"third\n.".to_vec(),
+            b"\nlast".to_vec()
+        ];
+        let body = Box::new(stream::iter_ok(chunks));
+        let fut = send_streamed_envelop(server.connection(), envelop_data, body, &config_with(|_| {}));
+
+        let (_con, result) = run(fut).unwrap();
+        assert_eq!(result.unwrap().code(), 250);
+        assert_eq!(server.written(), concat!(
+            "MAIL FROM:<sender@test.test>\r\n",
+            "RCPT TO:<a@test.test>\r\n",
+            "DATA\r\n",
+            "Subject: test\r\n\r\n..first\r\n..second\r\nthird\r\n..\r\nlast\r\n.\r\n"
+        ));
+    }
+
+    #[test]
+    fn reports_each_command_to_the_observer() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let config = config_with(|config| {
+            let recorded = recorded.clone();
+            config.command_observer = Some(CommandObserver::new(move |command, _duration| {
+                recorded.lock().unwrap().push(command)
+            }));
+        });
+        let mail = OutgoingMail::from(mock_envelop(&["a@test.test", "b@test.test"]));
+
+        let (_con, result) = run(send_envelop_with(server.connection(), mail, &config)).unwrap();
+        result.unwrap();
+
+        assert_eq!(*recorded.lock().unwrap(), vec![
+            SmtpCommand::Mail, SmtpCommand::Rcpt, SmtpCommand::Rcpt, SmtpCommand::Data
+        ]);
+    }
+
+    #[test]
+    fn reports_the_outcome_of_each_recipient() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("550 5.1.1 unknown user\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let config = config_with(|config| {
+            let reported = reported.clone();
+            config.recipient_policy = RecipientPolicy::AcceptPartial;
+            config.recipient_progress = Some(RecipientProgress::new(move |recipient, outcome| {
+                let code = outcome.err().and_then(MailSendError::enhanced_status);
+                reported.lock().unwrap().push((recipient.as_str().to_owned(), code));
+            }));
+        });
+        let mail = OutgoingMail::from(mock_envelop(&["a@test.test", "b@test.test", "c@test.test"]));
+
+        let (_con, result) = run(send_envelop_with(server.connection(), mail, &config)).unwrap();
+        result.unwrap();
+
+        assert_eq!(*reported.lock().unwrap(), vec![
+            ("a@test.test".to_owned(), None),
+            ("b@test.test".to_owned(), Some((5, 1, 1))),
+            ("c@test.test".to_owned(), None)
+        ]);
+    }
+
+    #[test]
+    fn sends_to_rewritten_recipients() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let config = config_with(|config| {
+            config.recipient_rewriter = Some(Arc::new(|addr: &MailAddress| {
+                if addr.as_str() == "support@test.test" {
+                    vec![
+                        MailAddress::new_unchecked("alice@test.test".to_owned(), false),
+                        MailAddress::new_unchecked("bob@test.test".to_owned(), false)
+                    ]
+                } else {
+                    vec![addr.clone()]
+                }
+            }));
+        });
+        let fut = send_envelop_with(server.connection(), mock_envelop(&["support@test.test"]).into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        assert_eq!(result.unwrap().recipient_codes(), &[250, 250]);
+        assert!(server.written().starts_with(
+            "MAIL FROM:<sender@test.test>\r\nRCPT TO:<alice@test.test>\r\nRCPT TO:<bob@test.test>\r\nDATA\r\n"
+        ));
+    }
+
+    #[test]
+    fn sends_redirected_mails_only_to_the_redirect_address() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let config = config_with(|config| {
+            config.redirect = Some(RedirectPolicy {
+                redirect_to: MailAddress::new_unchecked("inbox@staging.test".to_owned(), false),
+                annotate: true
+            });
+        });
+        let envelop = mock_envelop(&["a@test.test", "b@test.test"]);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        assert_eq!(result.unwrap().recipient_codes(), &[250]);
+        let written = server.written();
+        assert_eq!(written.matches("RCPT TO:").count(), 1);
+        assert!(written.starts_with(concat!(
+            "MAIL FROM:<sender@test.test>\r\nRCPT TO:<inbox@staging.test>\r\nDATA\r\n",
+            "X-Original-To: a@test.test, b@test.test\r\nSubject: test\r\n"
+        )), "{}", written);
+    }
+
+    #[test]
+    fn lowercases_recipient_domains_if_enabled() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let config = config_with(|config| config.lowercase_recipient_domains = true);
+        let envelop = mock_envelop(&["User@EXAMPLE.com"]);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        assert!(result.is_ok());
+        assert!(server.written().contains("RCPT TO:<User@example.com>\r\n"), "{}", server.written());
+    }
+
+    #[test]
+    fn sends_additional_params() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let mut params = EsmtpParams::default();
+        params.push_mail_param(EsmtpParam::new("X-VENDOR", Some("abc")).unwrap());
+        params.push_rcpt_param(EsmtpParam::new("X-TRACK", None).unwrap());
+        let mail = OutgoingMail { envelop: mock_envelop(&["a@test.test"]), params, encode_time: None, in_flight: None };
+        let fut = send_envelop_with(server.connection(), mail, &config_with(|_| {}));
+
+        let (_con, result) = run(fut).unwrap();
+        result.unwrap();
+        let written = server.written();
+        assert!(written.starts_with("MAIL FROM:<sender@test.test> X-VENDOR=abc\r\nRCPT TO:<a@test.test> X-TRACK\r\n"));
+    }
+
+    #[test]
+    fn retains_non_250_success_codes() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("251 User not local; will forward\r\n"),
+            Reply::Lines("252 Cannot verify user, but will attempt delivery\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test", "c@test.test"]);
+        let fut = send_envelop(server.connection(), envelop, short_timeouts());
+
+        let (_con, result) = run(fut).unwrap();
+        let response = result.unwrap();
+        assert_eq!(response.code(), 250);
+        assert_eq!(response.recipient_codes(), &[250, 251, 252]);
+        assert!(response.is_forwarded());
+    }
+
+    #[test]
+    fn plain_250_and_252_are_not_forwarded() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("252 Cannot verify user, but will attempt delivery\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let fut = send_envelop(server.connection(), mock_envelop(&["a@test.test"]), short_timeouts());
+
+        let (_con, result) = run(fut).unwrap();
+        let response = result.unwrap();
+        assert_eq!(response.recipient_codes(), &[252]);
+        assert!(!response.is_forwarded());
+    }
+
+    #[test]
+    fn refuses_mails_with_too_many_received_headers() {
+        let server = FakeServer::new(vec![]);
+        let mail = smtp::Mail::new(
+            EncodingRequirement::None,
+            b"Received: from a by b; x\r\nReceived: from b by c; x\r\nSubject: test\r\n\r\nbody\r\n".to_vec()
+        );
+        let (_, envelop_data): (smtp::Mail, EnvelopData) = mock_envelop(&["a@test.test"]).into();
+        let envelop = MailEnvelop::from((mail, envelop_data));
+        let config = config_with(|config| config.max_received_headers = Some(1));
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        match result {
+            Err(MailSendError::LoopDetected { received: 2, limit: 1 }) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert_eq!(server.written(), "");
+    }
+
+    #[test]
+    fn refuses_mails_with_misaligned_from_domain() {
+        let server = FakeServer::new(vec![]);
+        let mail = smtp::Mail::new(
+            EncodingRequirement::None,
+            b"From: <news@example.com>\r\nSubject: test\r\n\r\nbody\r\n".to_vec()
+        );
+        let (_, envelop_data): (smtp::Mail, EnvelopData) = mock_envelop(&["a@test.test"]).into();
+        let envelop = MailEnvelop::from((mail, envelop_data));
+        let config = config_with(|config| config.domain_alignment = Some(DomainAlignment::Relaxed));
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        match result {
+            Err(MailSendError::DomainMisaligned { ref header_from, ref envelop_from })
+                if header_from == "example.com" && envelop_from == "test.test" => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert_eq!(server.written(), "");
+    }
+
+    #[test]
+    fn auth_required_reply_to_mail_is_a_plain_smtp_error() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("530 5.7.0 Authentication required\r\n"),
+            Reply::Lines("250 Ok\r\n")
+        ]);
+        let fut = send_envelop(server.connection(), mock_envelop(&["a@test.test"]), short_timeouts());
+
+        let (_con, result) = run(fut).unwrap();
+        let err = result.unwrap_err();
+        // the transaction doesn't know if the connection was authenticated, see `Session`
+        assert!(!err.is_auth_expired());
+        assert_eq!(err.reply_code(), Some(530));
+        assert_eq!(err.enhanced_status(), Some((5, 7, 0)));
+        assert_eq!(server.written(), "MAIL FROM:<sender@test.test>\r\nRSET\r\n");
+    }
+
+    #[test]
+    fn retains_final_251_reply_to_data() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("251 Ok: will forward\r\n")
+        ]);
+        let fut = send_envelop(server.connection(), mock_envelop(&["a@test.test"]), short_timeouts());
+
+        let (_con, result) = run(fut).unwrap();
+        let response = result.unwrap();
+        assert_eq!(response.code(), 251);
+        assert_eq!(response.lines(), &["Ok: will forward".to_owned()]);
+        assert!(response.is_forwarded());
+    }
+
+    #[test]
+    fn splits_transaction_if_there_are_too_many_recipients() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("251 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued as 1\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("252 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued as 2\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test", "c@test.test"]);
+        let config = config_with(|config| config.max_recipients_per_transaction = Some(2));
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        let response = result.unwrap();
+        assert_eq!(response.recipient_codes(), &[250, 251, 252]);
+        assert_eq!(response.lines(), &["Ok: queued as 2".to_owned()]);
+
+        let written = server.written();
+        assert_eq!(written.matches("MAIL FROM:<sender@test.test>\r\n").count(), 2);
+        assert_eq!(written.matches("some body\r\n.\r\n").count(), 2);
+        assert!(written.contains("RCPT TO:<a@test.test>\r\nRCPT TO:<b@test.test>\r\nDATA\r\n"));
+        assert!(written.contains("MAIL FROM:<sender@test.test>\r\nRCPT TO:<c@test.test>\r\nDATA\r\n"));
+    }
+
+    #[test]
+    fn splits_five_recipients_into_three_transactions() {
+        let mut replies = Vec::new();
+        for recipients in &[2, 2, 1] {
+            replies.push(Reply::Lines("250 Ok\r\n"));
+            for _ in 0..*recipients {
+                replies.push(Reply::Lines("250 Ok\r\n"));
+            }
+            replies.push(Reply::Lines("354 Go ahead\r\n"));
+            replies.push(Reply::Lines("250 Ok: queued\r\n"));
+        }
+        let server = FakeServer::new(replies);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test", "c@test.test", "d@test.test", "e@test.test"]);
+        let config = config_with(|config| config.max_recipients_per_transaction = Some(2));
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        assert_eq!(result.unwrap().recipient_codes(), &[250, 250, 250, 250, 250]);
+
+        let written = server.written();
+        assert_eq!(written.matches("MAIL FROM:<sender@test.test>\r\n").count(), 3);
+        assert_eq!(written.matches("DATA\r\n").count(), 3);
+        assert!(written.contains("RCPT TO:<c@test.test>\r\nRCPT TO:<d@test.test>\r\nDATA\r\n"));
+        assert!(written.contains("MAIL FROM:<sender@test.test>\r\nRCPT TO:<e@test.test>\r\nDATA\r\n"));
+    }
+
+    #[test]
+    fn does_not_split_if_below_the_limit() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let config = config_with(|config| config.max_recipients_per_transaction = Some(1));
+        let fut = send_envelop_with(server.connection(), mock_envelop(&["a@test.test"]).into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        assert_eq!(result.unwrap().recipient_codes(), &[250]);
+        assert_eq!(server.written().matches("MAIL FROM").count(), 1);
+    }
+
+    #[test]
+    fn rejected_recipient_fails_the_mail_by_default() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("550 5.1.1 unknown user\r\n"),
+            Reply::Lines("250 Ok\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test", "c@test.test"]);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config_with(|_| {}));
+
+        let (_con, result) = run(fut).unwrap();
+        match result {
+            Err(MailSendError::Smtp(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let written = server.written();
+        assert!(written.ends_with("RCPT TO:<b@test.test>\r\nRSET\r\n"));
+    }
+
+    #[test]
+    fn fail_fast_aborts_if_the_first_recipient_is_rejected() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("550 5.1.1 unknown user\r\n"),
+            Reply::Lines("250 Ok\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test"]);
+        let config = config_with(|config| config.recipient_policy = RecipientPolicy::FailFastOnFirstRecipient);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        match result {
+            Err(MailSendError::RecipientRejected(rejection)) => {
+                assert_eq!(rejection.recipient().as_str(), "a@test.test");
+                assert_eq!(rejection.code(), 550);
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let written = server.written();
+        assert!(written.ends_with("RCPT TO:<a@test.test>\r\nRSET\r\n"));
+    }
+
+    #[test]
+    fn fail_fast_sends_to_accepted_recipients_if_a_later_one_is_rejected() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("550 5.1.1 unknown user\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test"]);
+        let config = config_with(|config| config.recipient_policy = RecipientPolicy::FailFastOnFirstRecipient);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        assert_eq!(result.unwrap().recipient_codes(), &[250, 550]);
+    }
+
+    #[test]
+    fn accept_partial_summarizes_accepted_and_rejected_recipients() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("550 5.1.1 unknown user\r\n"),
+            Reply::Lines("251 will forward\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test", "c@test.test"]);
+        let config = config_with(|config| config.recipient_policy = RecipientPolicy::AcceptPartial);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        let response = result.unwrap();
+        assert_eq!(response.accepted(), 2);
+        let rejected = response.rejected().iter()
+            .map(|&(ref address, code)| (address.as_str(), code))
+            .collect::<Vec<_>>();
+        assert_eq!(rejected, vec![("b@test.test", 550)]);
+    }
+
+    #[test]
+    fn accept_partial_fails_if_all_recipients_are_rejected() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("450 4.2.1 try again later\r\n"),
+            Reply::Lines("550 5.1.1 unknown user\r\n"),
+            Reply::Lines("250 Ok\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test", "b@test.test"]);
+        let config = config_with(|config| config.recipient_policy = RecipientPolicy::AcceptPartial);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config);
+
+        let (_con, result) = run(fut).unwrap();
+        match result {
+            Err(MailSendError::RecipientRejected(rejection)) => {
+                assert_eq!(rejection.recipient().as_str(), "a@test.test");
+                assert_eq!(rejection.code(), 450);
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(!server.written().contains("DATA"));
+    }
+
+    fn transfer_mode_for_ehlo_response(ehlo_response: &'static str) -> TransferMode {
+        let server = FakeServer::new(vec![
+            Reply::Lines(ehlo_response),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+        let fut = server.connection()
+            .send(Ehlo::new(client_id))
+            .map_err(MailSendError::Io)
+            .and_then(|(con, result)| {
+                result.unwrap();
+                send_envelop_with(con, mock_envelop(&["a@test.test"]).into(), &SendConfig::default())
+            });
+
+        let (_con, result) = run(fut).unwrap();
+        result.unwrap().transfer_mode()
+    }
+
+    fn mail_cmd_for(ehlo_response: &'static str, raw_mail: &'static [u8]) -> String {
+        let server = FakeServer::new(vec![
+            Reply::Lines(ehlo_response),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Lines("250 Ok: queued\r\n")
+        ]);
+        let mail = smtp::Mail::new(EncodingRequirement::None, raw_mail.to_vec());
+        let (_, envelop_data): (smtp::Mail, EnvelopData) = mock_envelop(&["a@test.test"]).into();
+        let envelop = MailEnvelop::from((mail, envelop_data));
+        let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+        let fut = server.connection()
+            .send(Ehlo::new(client_id))
+            .map_err(MailSendError::Io)
+            .and_then(|(con, result)| {
+                result.unwrap();
+                send_envelop_with(con, envelop.into(), &SendConfig::default())
+            });
+
+        let (_con, result) = run(fut).unwrap();
+        result.unwrap();
+        let written = server.written();
+        written.lines()
+            .find(|line| line.starts_with("MAIL FROM:"))
+            .expect("[test bug] MAIL command not send")
+            .to_owned()
+    }
+
+    #[test]
+    fn sends_body_8bitmime_for_8bit_data_if_supported() {
+        let ehlo_response = "250-mx.test.test greets you\r\n250 8BITMIME\r\n";
+        assert_eq!(
+            mail_cmd_for(ehlo_response, "Subject: test\r\n\r\nGr\u{fc}\u{df}e\r\n".as_bytes()),
+            "MAIL FROM:<sender@test.test> BODY=8BITMIME"
+        );
+        assert_eq!(
+            mail_cmd_for(ehlo_response, b"Subject: test\r\n\r\nbody\r\n"),
+            "MAIL FROM:<sender@test.test>"
+        );
+    }
+
+    #[test]
+    fn omits_body_parameter_if_8bitmime_is_not_supported() {
+        assert_eq!(
+            mail_cmd_for("250-mx.test.test greets you\r\n250 SIZE 1000\r\n", "Subject: test\r\n\r\nGr\u{fc}\u{df}e\r\n".as_bytes()),
+            "MAIL FROM:<sender@test.test>"
+        );
+    }
+
+    #[test]
+    fn pipelined_mail_needing_smtputf8_requires_the_capability() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250-mx.test.test greets you\r\n250 PIPELINING\r\n")
+        ]);
+        // e.g. a mail with `force_smtputf8` set
+        let mail = smtp::Mail::new(EncodingRequirement::Smtputf8, b"Subject: test\r\n\r\nbody\r\n".to_vec());
+        let (_, envelop_data): (smtp::Mail, EnvelopData) = mock_envelop(&["a@test.test"]).into();
+        let envelop = MailEnvelop::from((mail, envelop_data));
+        let config = config_with(|config| config.pipelining = true);
+        let client_id = ClientId::Domain(Domain::from_unchecked("me.test".to_owned()));
+        let fut = server.connection()
+            .send(Ehlo::new(client_id))
+            .map_err(MailSendError::Io)
+            .and_then(move |(con, result)| {
+                result.unwrap();
+                send_envelop_pipelined(con, envelop.into(), None, &config, None)
+            });
+
+        let (_con, previous, outcome) = run(fut).unwrap();
+        assert!(previous.is_none());
+        match outcome {
+            Pipelined::Done(Err(MailSendError::Smtp(LogicError::MissingCapabilities(_)))) => {},
+            Pipelined::Done(other) => panic!("unexpected result: {:?}", other),
+            Pipelined::Pending(_) => panic!("mail should not be send")
+        }
+        assert_eq!(server.written(), "EHLO me.test\r\n");
+    }
+
+    #[test]
+    fn transfer_mode_reflects_8bitmime_support() {
+        assert_eq!(
+            transfer_mode_for_ehlo_response("250-mx.test.test greets you\r\n250 8BITMIME\r\n"),
+            TransferMode::EightBit
+        );
+        assert_eq!(
+            transfer_mode_for_ehlo_response("250-mx.test.test greets you\r\n250 SIZE 1000\r\n"),
+            TransferMode::SevenBit
+        );
+    }
+
+    #[test]
+    fn does_not_reset_after_421() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("421 4.3.2 Service shutting down\r\n")
+        ]);
+        let envelop = mock_envelop(&["a@test.test"]);
+        let fut = send_envelop_with(server.connection(), envelop.into(), &config_with(|_| {}));
+
+        let (_con, result) = run(fut).unwrap();
+        let err = result.unwrap_err();
+        assert!(err.is_service_closing());
+        assert!(err.is_transient());
+        assert!(!server.written().contains("RSET"));
+    }
+
+    #[test]
+    fn fails_on_responses_exceeding_the_limits() {
+        let server = FakeServer::new(vec![
+            Reply::Lines("250-Ok\r\n250-and\r\n250-some\r\n250 more\r\n")
+        ]);
+        let config = config_with(|config| config.response_limits.max_lines = 3);
+        let fut = send_envelop_with(server.connection(), mock_envelop(&["a@test.test"]).into(), &config);
+
+        match run(fut) {
+            Err(MailSendError::ResponseTooLarge { lines, bytes }) => {
+                assert_eq!(lines, 4);
+                assert_eq!(bytes, 37);
+            },
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("expected the response to be too large")
+        }
+        assert!(!server.written().contains("RCPT"));
+    }
+
+    #[test]
+    fn stops_reading_a_response_once_it_exceeds_the_limits() {
+        // a server streaming a response which never ends
+        let mut replies = vec![Reply::Lines("250-Ok\r\n"); 2000];
+        replies.push(Reply::Stall);
+        let server = FakeServer::new(replies);
+        let config = config_with(|config| config.response_limits.max_lines = 10);
+        let fut = send_envelop_with(server.connection(), mock_envelop(&["a@test.test"]).into(), &config);
+
+        match run(fut) {
+            Err(MailSendError::ResponseTooLarge { lines, .. }) => assert_eq!(lines, 11),
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("expected the response to be too large")
+        }
+        assert_eq!(server.read_offsets().len(), 11);
+    }
+
+    #[test]
+    fn parses_rcpt_max_from_limits() {
+        assert_eq!(rcpt_max_from_limits(vec!["MAILMAX=10", "RCPTMAX=50"]), Some(50));
+        assert_eq!(rcpt_max_from_limits(vec!["rcptmax=7"]), Some(7));
+        assert_eq!(rcpt_max_from_limits(vec!["MAILMAX=10"]), None);
+        assert_eq!(rcpt_max_from_limits(vec!["RCPTMAX=many"]), None);
+    }
+
+    #[test]
+    fn reports_command_phase_when_stalling_before_354() {
+        assert_timeout_in_phase(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Stall
+        ], TimeoutPhase::Command);
+    }
+
+    #[test]
+    fn reports_data_phase_when_stalling_before_final_250() {
+        assert_timeout_in_phase(vec![
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("250 Ok\r\n"),
+            Reply::Lines("354 Go ahead\r\n"),
+            Reply::Stall
+        ], TimeoutPhase::Data);
+    }
+}
@@ -0,0 +1,224 @@
+//! Module implementing sending mails to a server listening on a Unix domain socket.
+//!
+//! `new-tokio-smtp` only has socket variants for TCP (with or without TLS),
+//! so the socket is passed to it as "mock" socket, which accepts any stream.
+//! This is why this module requires the `mock-support` feature of
+//! `new-tokio-smtp`, which is enabled by the (opt-in) `unix-socket` feature.
+//! All of this is confined to `mock_socket`, which can be replaced once
+//! `new-tokio-smtp` has a socket variant for other streams.
+//!
+//! # Risks
+//!
+//! - `mock-support` is meant for testing `new-tokio-smtp` based code, so
+//!   it might change or go away with any `new-tokio-smtp` update.
+//! - Cargo unifies features, so with `unix-socket` enabled the mock support
+//!   is compiled into the `new-tokio-smtp` used by all crates of the build,
+//!   i.e. mock sockets can be created anywhere in a production build.
+//! - Connections over Unix domain sockets are `Socket::Mock` connections,
+//!   so code inspecting the socket of a connection can't tell them apart
+//!   from mocked connections used in tests.
+use std::{
+    fmt,
+    io::{self as std_io, Read, Write},
+    path::PathBuf
+};
+
+use futures::{
+    Poll,
+    future::Future
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UnixStream
+};
+
+use mail::Context;
+use new_tokio_smtp::{
+    Cmd, ClientId, Connection, Socket,
+    command::Noop,
+    error::ConnectingFailed,
+    mock::MockStream
+};
+
+use ::{
+    config::{SendConfig, PostAuthCmds},
+    connect::{ConnectFuture, read_greeting, send_ehlo, select_auth, authenticate_selected, run_post_auth_cmds},
+    error::MailSendError,
+    request::MailRequest,
+    response::MailResponse,
+    send_mail::encode_outgoing,
+    transaction::send_envelop_with
+};
+
+/// Configuration for connecting to a server listening on a Unix domain socket.
+///
+/// This is the counterpart of `ConnectionConfig` for e.g. a local sendmail
+/// compatible daemon. As the connection never leaves the host no TLS is used.
+#[derive(Debug, Clone)]
+pub struct UnixConnectionConfig<A = Noop>
+    where A: Cmd
+{
+    /// The path of the socket.
+    pub path: PathBuf,
+
+    /// The command used to authenticate.
+    pub auth_cmd: A,
+
+    /// The client id send with `EHLO`.
+    pub client_id: ClientId
+}
+
+impl UnixConnectionConfig<Noop> {
+
+    /// Creates a config for connecting to the socket at given path without authenticating.
+    pub fn new<P>(path: P, client_id: ClientId) -> Self
+        where P: Into<PathBuf>
+    {
+        UnixConnectionConfig { path: path.into(), auth_cmd: Noop, client_id }
+    }
+}
+
+/// Opens a connection over a Unix domain socket and sets it up.
+///
+/// This works like setting up a TCP connection without TLS: The greeting
+/// is read (waiting at most `config.timeouts.greeting`), then `EHLO` is
+/// send, followed by authenticating (like for TCP connections) and the
+/// `config.post_auth_cmds`. Options only affecting TCP connections (e.g.
+/// `local_addr`) are ignored. The connection can be used with `send_over`.
+///
+/// The stream is passed to `new-tokio-smtp` as `Socket::Mock`, using its
+/// mock support (meant for tests) which the `unix-socket` feature enables
+/// for the whole build. Updating `new-tokio-smtp` might break this, and
+/// code inspecting the socket can't tell these connections apart from
+/// mocked ones.
+pub fn connect_unix<A>(conconf: UnixConnectionConfig<A>, config: &SendConfig) -> ConnectFuture
+    where A: Cmd
+{
+    let UnixConnectionConfig { path, auth_cmd, client_id } = conconf;
+    let auth = select_auth(auth_cmd, config);
+    let post_auth_cmds = config.post_auth_cmds.as_ref()
+        .map(PostAuthCmds::create_cmds)
+        .unwrap_or_default();
+    let greeting_timeout = config.timeouts.greeting;
+    let limits = config.response_limits;
+    let ehlo_observer = config.command_observer.clone();
+    let auth_observer = config.command_observer.clone();
+
+    let fut = UnixStream::connect(&path)
+        .map_err(ConnectingFailed::Io)
+        .and_then(move |stream| read_greeting(mock_socket(stream), greeting_timeout, limits))
+        .and_then(move |con| send_ehlo(con, client_id, limits, ehlo_observer, None))
+        .and_then(move |con| authenticate_selected(con, auth, auth_observer, None))
+        .and_then(move |(con, _authenticated)| run_post_auth_cmds(con, post_auth_cmds));
+
+    Box::new(fut)
+}
+
+/// Sends a mail to a server listening on a Unix domain socket.
+///
+/// Like `send_with` this encodes the mail, opens a connection (see
+/// `connect_unix`), sends the mail and closes the connection again.
+/// To send multiple mails over one connection use `connect_unix`
+/// together with `send_over`.
+pub fn send_unix<A, C>(
+    mail: MailRequest,
+    conconf: UnixConnectionConfig<A>,
+    ctx: C,
+    config: SendConfig
+) -> impl Future<Item=MailResponse, Error=MailSendError>
+    where A: Cmd, C: Context
+{
+    encode_outgoing(mail, ctx, config.send_target)
+        .and_then(move |mail| {
+            connect_unix(conconf, &config)
+                .map_err(MailSendError::from)
+                .and_then(move |con| send_envelop_with(con, mail, &config))
+        })
+        .and_then(|(con, result)| con.quit().then(move |_| result))
+}
+
+/// Passes the stream to `new-tokio-smtp` as mock socket.
+///
+/// This is the only place using the mock support of `new-tokio-smtp`
+/// outside of tests, see the module documentation for the risks.
+fn mock_socket(stream: UnixStream) -> Socket {
+    Socket::Mock(Box::new(UnixSocket(stream)))
+}
+
+/// Wrapper allowing to pass a `UnixStream` to `new-tokio-smtp`.
+struct UnixSocket(UnixStream);
+
+impl fmt::Debug for UnixSocket {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.debug_tuple("UnixSocket").field(&self.0).finish()
+    }
+}
+
+impl Read for UnixSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std_io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixSocket {
+    fn write(&mut self, buf: &[u8]) -> std_io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std_io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncRead for UnixSocket {}
+
+impl AsyncWrite for UnixSocket {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        AsyncWrite::shutdown(&mut self.0)
+    }
+}
+
+impl MockStream for UnixSocket {}
+
+#[cfg(test)]
+mod test {
+
+    mod send_unix {
+        use std::{
+            env, fs, process,
+            os::unix::net::UnixListener
+        };
+        use new_tokio_smtp::{ClientId, Domain as SmtpDomain};
+        use ::{
+            config::SendConfig,
+            request::MailRequest,
+            test_utils::{run, serve_smtp, simple_mail, test_context}
+        };
+        use super::super::{UnixConnectionConfig, send_unix};
+
+        #[test]
+        fn sends_mail_over_unix_socket() {
+            let path = env::temp_dir().join(format!("mail-smtp-test-{}.sock", process::id()));
+            let _ = fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let server = ::std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                serve_smtp(stream.try_clone().unwrap(), stream)
+            });
+
+            let ctx = test_context();
+            let mail = simple_mail("to@example.com");
+            let client_id = ClientId::Domain(SmtpDomain::from_unchecked("me.test".to_owned()));
+            let conconf = UnixConnectionConfig::new(path.clone(), client_id);
+
+            let result = run(send_unix(MailRequest::new(mail), conconf, ctx, SendConfig::default()));
+            let written = server.join().unwrap();
+            let _ = fs::remove_file(&path);
+
+            result.unwrap();
+            assert!(written.starts_with("EHLO me.test\r\n"));
+            assert!(written.contains("RCPT TO:<to@example.com>\r\n"));
+            assert!(written.ends_with("QUIT\r\n"));
+        }
+    }
+}
@@ -0,0 +1,617 @@
+//! Module containing utilities which are useful when sending mails.
+use std::{
+    fmt,
+    collections::HashMap,
+    time::{Duration, Instant}
+};
+
+use futures::{Stream, Future, Poll, Async, stream};
+use tokio_timer::{self, Delay};
+use vec1::Vec1;
+
+use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+use mail::{Context, error::MailError};
+
+use ::{
+    error::{MailSendError, BatchError},
+    request::MailRequest,
+    send_mail::encode
+};
+
+/// Limits the rate at which items are yielded by the given stream.
+///
+/// The returned stream yields the items of the input stream, but no
+/// more than `per_second` items per second. Items are only pulled
+/// from the input stream once they can be yielded, so nothing is
+/// buffered and back pressure is applied to the input stream.
+///
+/// Errors of the input stream are passed through without counting
+/// towards the rate.
+///
+/// # Panics
+///
+/// If `per_second` is `0`.
+pub fn rate_limit<S>(stream: S, per_second: u32) -> RateLimit<S>
+    where S: Stream
+{
+    assert!(per_second > 0, "rate limit needs to allow at least one item per second");
+    let period = Duration::from_secs(1) / per_second;
+    RateLimit { stream, period, delay: None }
+}
+
+/// Stream returned by `rate_limit`.
+#[derive(Debug)]
+pub struct RateLimit<S> {
+    stream: S,
+    period: Duration,
+    delay: Option<Delay>
+}
+
+impl<S> Stream for RateLimit<S>
+    where S: Stream
+{
+    type Item = S::Item;
+    type Error = RateLimitError<S::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(delay) = self.delay.as_mut() {
+            match delay.poll() {
+                Ok(Async::Ready(())) => {},
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => return Err(RateLimitError::Timer(err))
+            }
+        }
+        self.delay = None;
+
+        match self.stream.poll() {
+            Ok(Async::Ready(Some(item))) => {
+                self.delay = Some(Delay::new(Instant::now() + self.period));
+                Ok(Async::Ready(Some(item)))
+            },
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => Err(RateLimitError::Stream(err))
+        }
+    }
+}
+
+/// Error of the stream returned by `rate_limit`.
+#[derive(Debug)]
+pub enum RateLimitError<E> {
+    /// The input stream returned an error.
+    Stream(E),
+    /// The timer used to limit the rate failed.
+    Timer(tokio_timer::Error)
+}
+
+impl<E> fmt::Display for RateLimitError<E>
+    where E: fmt::Display
+{
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RateLimitError::Stream(ref err) => fmt::Display::fmt(err, fter),
+            RateLimitError::Timer(ref err) => write!(fter, "rate limit timer failed: {}", err)
+        }
+    }
+}
+
+/// The domain part of a recipient address.
+///
+/// Domains are normalized to lower case (as they are case insensitive),
+/// address literals (e.g. `[127.0.0.1]`) are kept as they are.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RecipientDomain {
+    /// A domain name, e.g. `example.com`.
+    Domain(String),
+    /// An address literal, e.g. `[127.0.0.1]` or `[IPv6:::1]`.
+    AddressLiteral(String)
+}
+
+impl RecipientDomain {
+
+    /// Returns the domain of the given address.
+    pub fn of_address(address: &MailAddress) -> Self {
+        let address = address.as_str();
+        let domain = address.rsplitn(2, '@').next().unwrap_or(address);
+        if domain.starts_with('[') {
+            RecipientDomain::AddressLiteral(domain.to_owned())
+        } else {
+            RecipientDomain::Domain(domain.to_lowercase())
+        }
+    }
+
+    /// Returns the domain (or address literal) as a string.
+    pub fn as_str(&self) -> &str {
+        match *self {
+            RecipientDomain::Domain(ref domain) => domain,
+            RecipientDomain::AddressLiteral(ref literal) => literal
+        }
+    }
+}
+
+impl fmt::Display for RecipientDomain {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str(self.as_str())
+    }
+}
+
+/// Groups the recipients of the envelop by their domain.
+///
+/// Recipients using an address literal instead of a domain form
+/// their own group (one per address literal).
+pub fn group_recipients_by_domain(envelop: &EnvelopData) -> HashMap<RecipientDomain, Vec<MailAddress>> {
+    let mut groups = HashMap::new();
+    for (domain, addresses) in ordered_recipient_groups(envelop) {
+        groups.insert(domain, addresses);
+    }
+    groups
+}
+
+/// Splits a mail request into one mail request per recipient domain.
+///
+/// Each of the returned requests contains a clone of the mail and an
+/// envelop with the same reverse path but only the recipients of one
+/// domain. The requests are ordered by the first occurrence of their
+/// domain in the recipients.
+///
+/// This is useful when sending directly to the MX of the recipients,
+/// as a connection to a different server is needed for each domain.
+///
+/// # Error
+///
+/// Fails if the envelop data can not be derived from the mail, see
+/// `derive_envelop_data_from_mail`.
+pub fn split_by_recipient_domain(request: MailRequest) -> Result<Vec<MailRequest>, MailError> {
+    let envelop = request.resolve_envelop()?;
+    let requests = ordered_recipient_groups(&envelop)
+        .into_iter()
+        .map(|(_domain, addresses)| {
+            let mut request = request.clone();
+            request.override_envelop(EnvelopData {
+                from: envelop.from.clone(),
+                to: Vec1::from_vec(addresses).expect("[BUG] recipient groups are never empty")
+            });
+            request
+        })
+        .collect();
+
+    Ok(requests)
+}
+
+/// Groups a batch of mail requests by the domain of their first recipient.
+///
+/// Each request is returned together with its index in `requests`, so that
+/// the results of sending the groups can be mapped back to the original
+/// order. The groups are ordered by the first occurrence of their domain
+/// and keep the order of their requests. Sending each group with its own
+/// `send_batch` improves connection reuse, e.g. when sending to the MX
+/// of the recipients (use `split_by_recipient_domain` first if a request
+/// has recipients of multiple domains).
+///
+/// Only the envelop data is inspected, which is cheap if it was set
+/// explicitly and otherwise derived from the mail headers (without
+/// encoding the mail).
+///
+/// # Error
+///
+/// Fails if the envelop data of any request can not be derived from
+/// the mail, see `derive_envelop_data_from_mail`.
+pub fn group_requests_by_domain(requests: Vec<MailRequest>)
+    -> Result<Vec<(RecipientDomain, Vec<(usize, MailRequest)>)>, MailError>
+{
+    let mut groups: Vec<(RecipientDomain, Vec<(usize, MailRequest)>)> = Vec::new();
+    for (idx, request) in requests.into_iter().enumerate() {
+        let domain = RecipientDomain::of_address(request.resolve_envelop()?.to.first());
+        if let Some(&mut (_, ref mut requests)) = groups.iter_mut().find(|group| group.0 == domain) {
+            requests.push((idx, request));
+            continue;
+        }
+        groups.push((domain, vec![(idx, request)]));
+    }
+    Ok(groups)
+}
+
+/// Describes which addresses of an envelop need `SMTPUTF8`.
+///
+/// Returned by `validate_smtputf8_consistency`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Smtputf8Consistency {
+    /// No address needs `SMTPUTF8`.
+    Ascii,
+
+    /// All addresses (reverse path and recipients) need `SMTPUTF8`.
+    Smtputf8,
+
+    /// Some, but not all, addresses need `SMTPUTF8`.
+    Mixed {
+        /// The addresses which need `SMTPUTF8`.
+        smtputf8_addresses: Vec<MailAddress>
+    }
+}
+
+impl Smtputf8Consistency {
+
+    /// Returns true if the mail can only be send to a server supporting `SMTPUTF8`.
+    ///
+    /// This is the case if any address of the envelop needs `SMTPUTF8`.
+    pub fn needs_smtputf8(&self) -> bool {
+        *self != Smtputf8Consistency::Ascii
+    }
+}
+
+/// Checks which addresses of the envelop need `SMTPUTF8`.
+///
+/// If any address (reverse path or recipient) needs `SMTPUTF8` the whole
+/// mail transaction needs it, which means it can only be send to a server
+/// supporting `SMTPUTF8`. As sending it to a server not supporting it fails
+/// for the whole mail (and not just for the affected recipients) this can be
+/// used to decide up front to which relay a mail should be routed, or whether
+/// the affected recipients should be send a separate mail.
+pub fn validate_smtputf8_consistency(envelop: &EnvelopData) -> Smtputf8Consistency {
+    let addresses = envelop.from.iter().chain(envelop.to.iter()).collect::<Vec<_>>();
+    let smtputf8_addresses = addresses.iter()
+        .filter(|address| address.needs_smtputf8())
+        .map(|&address| address.clone())
+        .collect::<Vec<_>>();
+
+    if smtputf8_addresses.is_empty() {
+        Smtputf8Consistency::Ascii
+    } else if smtputf8_addresses.len() == addresses.len() {
+        Smtputf8Consistency::Smtputf8
+    } else {
+        Smtputf8Consistency::Mixed { smtputf8_addresses }
+    }
+}
+
+/// Splits mail requests into the ones needing `SMTPUTF8` and the ones which don't.
+///
+/// Returns `(needs_smtputf8, ascii_only)`, both in the order of the input.
+/// This allows sending each group using a `ConnectionConfig` of a relay
+/// which supports what the mails need.
+///
+/// Only the addresses of the envelop data (which is derived from the mail
+/// if it wasn't set explicitly) are inspected, the mails are not encoded.
+/// Requests whose envelop data can't be derived are put into the second
+/// group, sending them fails with the same error regardless of the relay.
+pub fn partition_by_smtputf8<C>(requests: Vec<MailRequest>, ctx: C)
+    -> impl Future<Item=(Vec<MailRequest>, Vec<MailRequest>), Error=MailSendError>
+    where C: Context
+{
+    ctx.offload_fn(move || {
+        let partitions: (Vec<_>, Vec<_>) = requests.into_iter()
+            .partition(|request| {
+                request.resolve_envelop()
+                    .map(|envelop| validate_smtputf8_consistency(&envelop).needs_smtputf8())
+                    .unwrap_or(false)
+            });
+        Ok::<_, MailSendError>(partitions)
+    })
+}
+
+/// Returns the size of each mail of a batch once encoded, without sending them.
+///
+/// This allows planning (e.g. checking a quota) before sending a batch. The
+/// mails are encoded like by `send_batch` one after another, and each mail is
+/// dropped once it's measured, so only one encoded mail is held at a time.
+/// The size is the size of the encoded mail as used for e.g.
+/// `SendConfig::max_bytes_per_connection`, which excludes the few bytes added
+/// by the smtp protocol (the dot-stuffing and the terminating `.`).
+///
+/// There is one result per mail in the order of the requests, mails which
+/// fail to encode are reported with their error. The future itself doesn't fail.
+pub fn estimate_batch_size<C>(requests: Vec<MailRequest>, ctx: C)
+    -> impl Future<Item=Vec<Result<usize, MailSendError>>, Error=MailSendError>
+    where C: Context
+{
+    stream::iter_ok(requests)
+        .and_then(move |request| {
+            encode(request, ctx.clone())
+                .map(|envelop| envelop.mail().raw_data().len())
+                .then(Ok)
+        })
+        .collect()
+}
+
+/// Combines the results of a batch into one result.
+///
+/// Returns `Ok(())` if all results are successful, otherwise a `BatchError`
+/// containing all failures (with their index in the batch) is returned.
+/// This is meant for callers which only need to know if the whole batch
+/// succeeded, e.g. with the collected results of `send_batch_collected`.
+pub fn combine_results<T, I>(results: I) -> Result<(), BatchError>
+    where I: IntoIterator<Item=Result<T, MailSendError>>
+{
+    let mut total = 0;
+    let mut failures = Vec::new();
+    for (idx, result) in results.into_iter().enumerate() {
+        total += 1;
+        if let Err(err) = result {
+            failures.push((idx, err));
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(BatchError::new(total, failures))
+    }
+}
+
+fn ordered_recipient_groups(envelop: &EnvelopData) -> Vec<(RecipientDomain, Vec<MailAddress>)> {
+    let mut groups: Vec<(RecipientDomain, Vec<MailAddress>)> = Vec::new();
+    for address in envelop.to.iter() {
+        let domain = RecipientDomain::of_address(address);
+        if let Some(&mut (_, ref mut addresses)) = groups.iter_mut().find(|group| group.0 == domain) {
+            addresses.push(address.clone());
+            continue;
+        }
+        groups.push((domain, vec![address.clone()]));
+    }
+    groups
+}
+
+#[cfg(test)]
+mod test {
+
+    mod group_recipients_by_domain {
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use super::super::{group_recipients_by_domain, RecipientDomain};
+
+        fn address(raw: &str) -> MailAddress {
+            MailAddress::new_unchecked(raw.to_owned(), false)
+        }
+
+        fn envelop(recipients: &[&str]) -> EnvelopData {
+            EnvelopData {
+                from: Some(address("sender@test.test")),
+                to: Vec1::from_vec(recipients.iter().map(|raw| address(raw)).collect()).unwrap()
+            }
+        }
+
+        fn as_strs(addresses: &[MailAddress]) -> Vec<&str> {
+            addresses.iter().map(|address| address.as_str()).collect()
+        }
+
+        #[test]
+        fn groups_recipients_of_two_domains() {
+            let envelop = envelop(&["a@one.test", "b@two.test", "c@One.Test"]);
+            let groups = group_recipients_by_domain(&envelop);
+
+            assert_eq!(groups.len(), 2);
+            let one = &groups[&RecipientDomain::Domain("one.test".to_owned())];
+            assert_eq!(as_strs(one), vec!["a@one.test", "c@One.Test"]);
+            let two = &groups[&RecipientDomain::Domain("two.test".to_owned())];
+            assert_eq!(as_strs(two), vec!["b@two.test"]);
+        }
+
+        #[test]
+        fn address_literals_form_their_own_group() {
+            let envelop = envelop(&["a@one.test", "b@[127.0.0.1]"]);
+            let groups = group_recipients_by_domain(&envelop);
+
+            assert_eq!(groups.len(), 2);
+            let literal = &groups[&RecipientDomain::AddressLiteral("[127.0.0.1]".to_owned())];
+            assert_eq!(as_strs(literal), vec!["b@[127.0.0.1]"]);
+        }
+    }
+
+    mod group_requests_by_domain {
+        use vec1::Vec1;
+        use mail::Mail;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use ::request::MailRequest;
+        use super::super::{group_requests_by_domain, RecipientDomain};
+
+        fn request(recipients: &[&str]) -> MailRequest {
+            let address = |raw: &str| MailAddress::new_unchecked(raw.to_owned(), false);
+            let envelop = EnvelopData {
+                from: Some(address("sender@test.test")),
+                to: Vec1::from_vec(recipients.iter().map(|raw| address(raw)).collect()).unwrap()
+            };
+            MailRequest::new_with_envelop(Mail::plain_text("body"), envelop)
+        }
+
+        #[test]
+        fn groups_by_first_recipient_keeping_the_indices() {
+            let requests = vec![
+                request(&["a@one.test"]),
+                request(&["b@two.test", "c@one.test"]),
+                request(&["d@One.Test"]),
+                request(&["e@[127.0.0.1]"])
+            ];
+
+            let groups = group_requests_by_domain(requests).unwrap();
+
+            let summary = groups.iter()
+                .map(|&(ref domain, ref requests)| {
+                    (domain.clone(), requests.iter().map(|&(idx, _)| idx).collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(summary, vec![
+                (RecipientDomain::Domain("one.test".to_owned()), vec![0, 2]),
+                (RecipientDomain::Domain("two.test".to_owned()), vec![1]),
+                (RecipientDomain::AddressLiteral("[127.0.0.1]".to_owned()), vec![3])
+            ]);
+        }
+
+        #[test]
+        fn fails_if_an_envelop_can_not_be_derived() {
+            let requests = vec![request(&["a@one.test"]), MailRequest::new(Mail::plain_text("body"))];
+            assert!(group_requests_by_domain(requests).is_err());
+        }
+    }
+
+    mod validate_smtputf8_consistency {
+        use vec1::Vec1;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use super::super::{validate_smtputf8_consistency, Smtputf8Consistency};
+
+        fn address(raw: &str) -> MailAddress {
+            let needs_smtputf8 = !raw.is_ascii();
+            MailAddress::new_unchecked(raw.to_owned(), needs_smtputf8)
+        }
+
+        fn envelop(from: &str, recipients: &[&str]) -> EnvelopData {
+            EnvelopData {
+                from: Some(address(from)),
+                to: Vec1::from_vec(recipients.iter().map(|raw| address(raw)).collect()).unwrap()
+            }
+        }
+
+        #[test]
+        fn flags_mixed_envelop() {
+            let envelop = envelop("sender@test.test", &["a@test.test", "jö@test.test", "b@test.test"]);
+            let consistency = validate_smtputf8_consistency(&envelop);
+
+            assert!(consistency.needs_smtputf8());
+            assert_eq!(consistency, Smtputf8Consistency::Mixed {
+                smtputf8_addresses: vec![address("jö@test.test")]
+            });
+        }
+
+        #[test]
+        fn ascii_envelop_does_not_need_smtputf8() {
+            let envelop = envelop("sender@test.test", &["a@test.test", "b@test.test"]);
+            let consistency = validate_smtputf8_consistency(&envelop);
+
+            assert!(!consistency.needs_smtputf8());
+            assert_eq!(consistency, Smtputf8Consistency::Ascii);
+        }
+
+        #[test]
+        fn only_utf8_addresses() {
+            let envelop = envelop("sänder@test.test", &["jö@test.test"]);
+            assert_eq!(validate_smtputf8_consistency(&envelop), Smtputf8Consistency::Smtputf8);
+        }
+    }
+
+    mod partition_by_smtputf8 {
+        use vec1::Vec1;
+        use mail::Mail;
+        use new_tokio_smtp::send_mail::{MailAddress, EnvelopData};
+        use ::{
+            request::MailRequest,
+            test_utils::{run, test_context}
+        };
+        use super::super::partition_by_smtputf8;
+
+        fn request(from: &str, to: &str) -> MailRequest {
+            let address = |raw: &str| MailAddress::new_unchecked(raw.to_owned(), !raw.is_ascii());
+            let envelop = EnvelopData { from: Some(address(from)), to: Vec1::new(address(to)) };
+            MailRequest::new_with_envelop(Mail::plain_text("body"), envelop)
+        }
+
+        fn recipient(request: &MailRequest) -> String {
+            request.parts().1.unwrap().to.first().as_str().to_owned()
+        }
+
+        #[test]
+        fn splits_by_address_requirements() {
+            let ctx = test_context();
+            let requests = vec![
+                request("a@test.test", "jö@test.test"),
+                request("a@test.test", "b@test.test"),
+                request("sänder@test.test", "c@test.test"),
+                // without headers the envelop can't be derived
+                MailRequest::new(Mail::plain_text("body"))
+            ];
+
+            let (smtputf8, ascii) = run(partition_by_smtputf8(requests, ctx)).unwrap();
+
+            let smtputf8 = smtputf8.iter().map(recipient).collect::<Vec<_>>();
+            assert_eq!(smtputf8, vec!["jö@test.test", "c@test.test"]);
+            assert_eq!(ascii.len(), 2);
+            assert_eq!(recipient(&ascii[0]), "b@test.test");
+            assert!(ascii[1].parts().1.is_none());
+        }
+    }
+
+    mod estimate_batch_size {
+        use mail::Mail;
+        use ::{
+            error::MailSendError,
+            request::MailRequest,
+            test_utils::{run, simple_mail_with_body, test_context}
+        };
+        use super::super::estimate_batch_size;
+
+        fn request(body: &str) -> MailRequest {
+            MailRequest::new(simple_mail_with_body("to@example.com", body))
+        }
+
+        #[test]
+        fn returns_the_size_of_each_mail_in_order() {
+            let ctx = test_context();
+            let requests = vec![
+                request("short body"),
+                // without headers the mail can't be encoded
+                MailRequest::new(Mail::plain_text("body")),
+                request(&"long body ".repeat(100))
+            ];
+
+            let sizes = run(estimate_batch_size(requests, ctx)).unwrap();
+
+            assert_eq!(sizes.len(), 3);
+            let short = *sizes[0].as_ref().unwrap();
+            match sizes[1] {
+                Err(MailSendError::Mail(_)) => {},
+                ref other => panic!("unexpected result: {:?}", other)
+            }
+            let long = *sizes[2].as_ref().unwrap();
+            assert!(short > "short body".len());
+            assert!(long >= short + 990, "{} vs {}", long, short);
+        }
+    }
+
+    mod combine_results {
+        use std::io as std_io;
+        use ::error::MailSendError;
+        use super::super::combine_results;
+
+        fn io_error(msg: &str) -> MailSendError {
+            MailSendError::Io(std_io::Error::new(std_io::ErrorKind::Other, msg.to_owned()))
+        }
+
+        #[test]
+        fn is_ok_if_all_results_are_ok() {
+            combine_results(vec![Ok(1), Ok(2)]).unwrap();
+            combine_results(Vec::<Result<(), MailSendError>>::new()).unwrap();
+        }
+
+        #[test]
+        fn collects_all_failures_with_their_index() {
+            let results = vec![Ok(()), Err(io_error("first")), Ok(()), Err(io_error("second"))];
+
+            let err = combine_results(results).unwrap_err();
+
+            assert_eq!(err.total(), 4);
+            let indices = err.failures().iter().map(|&(idx, _)| idx).collect::<Vec<_>>();
+            assert_eq!(indices, vec![1, 3]);
+            assert_eq!(err.to_string(), "2 of 4 failed, first failure (#1): first");
+        }
+    }
+
+    mod rate_limit {
+        use std::time::{Duration, Instant};
+        use futures::{Stream, stream};
+        use ::test_utils::run;
+        use super::super::rate_limit;
+
+        #[test]
+        fn yields_all_items_in_order() {
+            let limited = rate_limit(stream::iter_ok::<_, ()>(vec![1, 2, 3]), 1000);
+            let items = run(limited.collect()).unwrap();
+            assert_eq!(items, vec![1, 2, 3]);
+        }
+
+        #[test]
+        fn does_not_yield_faster_than_the_rate() {
+            let start = Instant::now();
+            let limited = rate_limit(stream::iter_ok::<_, ()>(vec![1, 2, 3]), 20);
+            run(limited.collect()).unwrap();
+            // the first item is yielded immediately, then 2 x 50ms
+            assert!(start.elapsed() >= Duration::from_millis(100));
+        }
+    }
+}